@@ -0,0 +1,28 @@
+use std::process::Command;
+
+fn main() {
+    // shown on `/status`, builds outside a git checkout report "unknown"
+    let sha = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|sha| sha.trim().to_owned())
+        .unwrap_or_else(|| "unknown".to_owned());
+    println!("cargo:rustc-env=GIT_SHA={}", sha);
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+
+    // protoc comes with the build, nothing has to be installed for `--features grpc`
+    #[cfg(feature = "grpc")]
+    {
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("protoc for this platform");
+        std::env::set_var("PROTOC", protoc);
+        tonic_prost_build::configure()
+            .build_client(false)
+            .compile_protos(&["proto/shortener.proto"], &["proto"])
+            .expect("proto/shortener.proto compiles");
+        println!("cargo:rerun-if-changed=proto/shortener.proto");
+    }
+}
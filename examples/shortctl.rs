@@ -0,0 +1,172 @@
+//! Command line client for the shortener's HTTP API.
+//!
+//! Settings are read from `~/.config/shortctl/config.toml` (or `$XDG_CONFIG_HOME`), and
+//! `SHORTCTL_URL` / `SHORTCTL_API_KEY` override them:
+//!
+//! ```toml
+//! url = "https://sho.rt"
+//! api_key = "the server's security.admin_token"
+//! ```
+
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use clap::{Parser, Subcommand};
+use figment::{
+    providers::{Env, Format, Serialized, Toml},
+    Figment,
+};
+use serde::{Deserialize, Serialize};
+use shortener::{client::ShortenerClient, Interval, ShortenRequest, TimeseriesQuery, Visibility};
+use url::Url;
+
+const DEFAULT_SERVER: &str = "http://localhost:9876";
+
+#[derive(Debug, Parser)]
+#[command(version, about = "Command line client for the URL shortener")]
+struct Args {
+    /// Settings file, `~/.config/shortctl/config.toml` by default
+    #[arg(long, env = "SHORTCTL_CONFIG")]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Shorten a URL and print the short link
+    Shorten {
+        url: String,
+        /// Custom id instead of a generated one
+        #[arg(long)]
+        alias: Option<String>,
+        /// Tag the link, can be repeated
+        #[arg(long = "tag")]
+        tags: Vec<String>,
+        /// Keep its stats to tokens with stats:read and the link off public listings
+        #[arg(long)]
+        private: bool,
+    },
+    /// Print where a short link goes, without counting a click
+    Resolve { id: String },
+    /// Print the clicks of a short link
+    Stats {
+        id: String,
+        #[arg(long, value_enum, default_value_t = Interval::Day)]
+        interval: Interval,
+        /// First day to count, 30 days before `--to` by default
+        #[arg(long)]
+        from: Option<NaiveDate>,
+        /// Last day to count, today by default
+        #[arg(long)]
+        to: Option<NaiveDate>,
+        /// Leave out clicks from crawlers, previews and scripts
+        #[arg(long)]
+        exclude_bots: bool,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Config {
+    /// where the shortener is served
+    url: Url,
+    /// sent as a bearer token, the server's `security.admin_token`
+    api_key: Option<String>,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let config = Config::load(args.config)?;
+    let mut client = ShortenerClient::new(config.url);
+    if let Some(api_key) = config.api_key {
+        client = client.with_api_key(api_key);
+    }
+
+    match args.command {
+        Command::Shorten {
+            url,
+            alias,
+            tags,
+            private,
+        } => {
+            let request = ShortenRequest {
+                url,
+                alias,
+                tags,
+                visibility: private.then_some(Visibility::Private),
+                ..Default::default()
+            };
+            let created = client.shorten(&request).await?;
+            println!("{}", created.url);
+        }
+        Command::Resolve { id } => {
+            let preview = client.preview(&id).await?;
+            if preview.flagged {
+                eprintln!(
+                    "warning: {} is flagged as unsafe and no longer redirects",
+                    id
+                );
+            }
+            println!("{}", preview.url);
+        }
+        Command::Stats {
+            id,
+            interval,
+            from,
+            to,
+            exclude_bots,
+        } => {
+            let query = TimeseriesQuery {
+                interval,
+                from,
+                to,
+                exclude_bots,
+            };
+            let stats = client.timeseries(&id, &query).await?;
+            let format = match interval {
+                Interval::Hour => "%Y-%m-%d %H:00",
+                Interval::Day | Interval::Week => "%Y-%m-%d",
+            };
+            for point in &stats.points {
+                println!("{}\t{}", point.start.format(format), point.clicks);
+            }
+            let total: i64 = stats.points.iter().map(|point| point.clicks).sum();
+            println!("total\t{}", total);
+        }
+    }
+
+    Ok(())
+}
+
+impl Config {
+    fn load(file: Option<PathBuf>) -> Result<Self> {
+        let mut figment = Figment::from(Serialized::defaults(Config::default()));
+        // a missing file is fine, the defaults talk to a local server
+        if let Some(file) = file.or_else(default_config_file) {
+            figment = figment.merge(Toml::file(file));
+        }
+        figment
+            .merge(Env::prefixed("SHORTCTL_").only(&["url", "api_key"]))
+            .extract()
+            .context("Invalid shortctl settings")
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            url: DEFAULT_SERVER.parse().expect("default server URL is valid"),
+            api_key: None,
+        }
+    }
+}
+
+fn default_config_file() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("shortctl").join("config.toml"))
+}
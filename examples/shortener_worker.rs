@@ -0,0 +1,7 @@
+use clap::Parser;
+use shortener::WorkerArgs;
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    shortener::run_worker(WorkerArgs::parse()).await
+}
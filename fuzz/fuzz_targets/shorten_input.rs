@@ -0,0 +1,7 @@
+//! Bodies of `POST /` through validation and normalization: `cargo +nightly fuzz run shorten_input`
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| shortener::fuzzing::shorten_input(data));
@@ -0,0 +1,1010 @@
+//! Bodies and query strings of the HTTP API, shared by the handlers, the store and
+//! [`crate::client`]
+
+use std::{collections::BTreeMap, net::IpAddr, str::FromStr};
+
+use anyhow::Result;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::{DateTime, Days, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use url::Url;
+
+use crate::error::AppError;
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ShortenRequest {
+    pub url: String,
+    /// custom id to use instead of a generated one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    /// remove utm_* and click id parameters, defaults to the server wide setting
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strip_tracking: Option<bool>,
+    /// labels to group links by, added to the existing ones if the URL is shortened already
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// more destinations taking turns with `url`, one per click in this order. Rotating links
+    /// aren't shared, `url` can't be shortened again afterwards.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rotate: Vec<String>,
+    /// public for new links, changed if given for a URL that is shortened already
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub visibility: Option<Visibility>,
+    /// `off` for new links, changed if given for a URL that is shortened already
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub forward_query: Option<ForwardQuery>,
+    /// `Cache-Control` of its redirects, e.g. `no-store` for a link that will be edited or
+    /// `max-age=31536000` for one that won't. Changed if given for a URL that is shortened
+    /// already, an empty one goes back to `features.redirect_cache_control`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_control: Option<String>,
+}
+
+/// Who gets to see a link besides its visitors
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    /// anyone can read its stats, and it can be listed on `/directory`
+    #[default]
+    Public,
+    /// its stats need a token with `stats:read` and it isn't listed anywhere public
+    Private,
+}
+
+impl Visibility {
+    pub fn is_private(&self) -> bool {
+        *self == Visibility::Private
+    }
+}
+
+/// What a redirect does with the query string of the short link it was asked for, e.g. the
+/// click ids ad platforms add
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardQuery {
+    /// drops it, the destination is used as is
+    #[default]
+    Off,
+    /// adds its parameters after those of the destination, keeping both when a name is in each
+    Append,
+    /// adds its parameters, replacing those of the destination with the same name
+    Replace,
+    /// adds only the parameters the destination doesn't have
+    Keep,
+}
+
+impl ForwardQuery {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ForwardQuery::Off => "off",
+            ForwardQuery::Append => "append",
+            ForwardQuery::Replace => "replace",
+            ForwardQuery::Keep => "keep",
+        }
+    }
+
+    pub fn is_off(&self) -> bool {
+        *self == ForwardQuery::Off
+    }
+
+    /// `destination` with the parameters of `query` merged in. Both are left as encoded, so
+    /// parameters that aren't touched come out byte for byte.
+    pub(crate) fn apply(&self, destination: &str, query: &str) -> String {
+        if self.is_off() || query.is_empty() {
+            return destination.to_owned();
+        }
+        let Ok(mut url) = Url::parse(destination) else {
+            return destination.to_owned();
+        };
+        let own = url.query().unwrap_or_default().to_owned();
+        let params = |query: &str| -> Vec<String> {
+            query
+                .split('&')
+                .filter(|param| !param.is_empty())
+                .map(ToOwned::to_owned)
+                .collect()
+        };
+        let name = |param: &str| {
+            url::form_urlencoded::parse(param.as_bytes())
+                .next()
+                .map(|(name, _)| name.into_owned())
+        };
+        let has = |params: &[String], param: &str| params.iter().any(|p| name(p) == name(param));
+        let (own, incoming) = (params(&own), params(query));
+        let merged: Vec<_> = match self {
+            ForwardQuery::Off | ForwardQuery::Append => own.into_iter().chain(incoming).collect(),
+            ForwardQuery::Replace => own
+                .iter()
+                .filter(|param| !has(&incoming, param))
+                .cloned()
+                .chain(incoming.iter().cloned())
+                .collect(),
+            ForwardQuery::Keep => {
+                let extra: Vec<_> = incoming
+                    .iter()
+                    .filter(|param| !has(&own, param))
+                    .cloned()
+                    .collect();
+                own.into_iter().chain(extra).collect()
+            }
+        };
+        url.set_query(
+            Some(&merged.join("&"))
+                .filter(|query| !query.is_empty())
+                .map(String::as_str),
+        );
+        url.into()
+    }
+}
+
+/// From the `forward_query` column of the links
+impl TryFrom<String> for ForwardQuery {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        [
+            ForwardQuery::Off,
+            ForwardQuery::Append,
+            ForwardQuery::Replace,
+            ForwardQuery::Keep,
+        ]
+        .into_iter()
+        .find(|mode| mode.as_str() == value)
+        .ok_or_else(|| format!("unknown forward_query {}", value))
+    }
+}
+
+/// From the `private` column of the links
+impl From<bool> for Visibility {
+    fn from(private: bool) -> Self {
+        if private {
+            Visibility::Private
+        } else {
+            Visibility::Public
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ShortenResponse {
+    pub url: String,
+    /// QR code of `url` as a `data:image/png;base64,...` URL, with `?include_qr=true`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub qr: Option<String>,
+}
+
+/// Body of `POST /admin/bundles` and `PUT /admin/bundles/:id`
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct BundleRequest {
+    /// custom id to use instead of a generated one, only when creating the bundle
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub alias: Option<String>,
+    pub title: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// ids of the links in the order they are listed
+    #[serde(default)]
+    pub links: Vec<BundleEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BundleEntry {
+    pub id: String,
+    /// shown instead of the destination
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+}
+
+/// A named list of links with a page of its own under `/b/:id`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BundleResponse {
+    pub id: String,
+    pub title: String,
+    pub description: Option<String>,
+    /// the list page, under the public base URL
+    pub url: String,
+    pub links: Vec<BundleLink>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BundleLink {
+    pub id: String,
+    pub title: Option<String>,
+    /// the destination, as stored
+    pub url: String,
+    pub short_url: String,
+    /// flagged links are left off the bundle's page
+    pub flagged: bool,
+    /// so are private ones
+    pub visibility: Visibility,
+}
+
+/// Body of `POST /:id/report`
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct ReportRequest {
+    #[serde(default)]
+    pub category: ReportCategory,
+    /// what is wrong with the link, shown to operators as is. Needed for `other`.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub reason: String,
+}
+
+/// What kind of abuse a link is reported for
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReportCategory {
+    Phishing,
+    Malware,
+    Spam,
+    Illegal,
+    #[default]
+    Other,
+}
+
+impl ReportCategory {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ReportCategory::Phishing => "phishing",
+            ReportCategory::Malware => "malware",
+            ReportCategory::Spam => "spam",
+            ReportCategory::Illegal => "illegal",
+            ReportCategory::Other => "other",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PreviewResponse {
+    pub id: String,
+    /// the destination as stored and redirected to, with internationalized hosts in punycode
+    pub url: String,
+    /// the destination with its host in Unicode, for showing to people
+    pub display_url: String,
+    /// the short link itself, under the public base URL
+    pub short_url: String,
+    pub flagged: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, clap::ValueEnum)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::Enum))]
+#[serde(rename_all = "lowercase")]
+pub enum Interval {
+    Hour,
+    #[default]
+    Day,
+    Week,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct TimeseriesQuery {
+    #[serde(default)]
+    pub interval: Interval,
+    /// first day to include, defaults to 30 days before `to`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<NaiveDate>,
+    /// last day to include, defaults to today
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<NaiveDate>,
+    /// leave out clicks from crawlers, previews and scripts, and ones taken for click fraud
+    #[serde(default)]
+    pub exclude_bots: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TimeseriesResponse {
+    pub id: String,
+    pub interval: Interval,
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    pub points: Vec<TimeseriesPoint>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, FromRow)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::SimpleObject))]
+pub struct TimeseriesPoint {
+    pub start: DateTime<Utc>,
+    pub clicks: i64,
+    /// visitors are told apart per day, so a week adds up the visitors of its days
+    pub unique_visitors: i64,
+}
+
+/// Days to break the clicks of a link down over
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct BreakdownQuery {
+    /// first day to include, defaults to 30 days before `to`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<NaiveDate>,
+    /// last day to include, defaults to today
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<NaiveDate>,
+    /// leave out clicks from crawlers, previews and scripts, and ones taken for click fraud
+    #[serde(default)]
+    pub exclude_bots: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ReferrersResponse {
+    pub id: String,
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    /// most clicks first, out of the top referrers of each day
+    pub referrers: Vec<ReferrerClicks>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, FromRow)]
+pub struct ReferrerClicks {
+    pub referrer: String,
+    pub clicks: i64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CountriesResponse {
+    pub id: String,
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    /// most clicks first
+    pub countries: Vec<CountryClicks>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, FromRow)]
+pub struct CountryClicks {
+    /// ISO 3166 code, missing for clicks that couldn't be located
+    pub country: Option<String>,
+    pub clicks: i64,
+}
+
+/// Leaderboard window such as `7d` or `4w`, always a whole number of days
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Period {
+    pub(crate) days: u64,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ListLinksQuery {
+    /// `next` of the previous page, with the same filters and sort
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after: Option<LinkCursor>,
+    #[serde(default = "default_page_size")]
+    pub limit: i64,
+    /// only links with this tag
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tag: Option<String>,
+    /// only links to this domain or its subdomains
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub domain: Option<String>,
+    /// only links created since
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created_after: Option<DateTime<Utc>>,
+    /// only links whose destination was found like this when it was last checked
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<LinkStatus>,
+    #[serde(default)]
+    pub sort: LinkSort,
+    #[serde(default)]
+    pub order: SortOrder,
+    /// comma separated fields of each link to return, all of them if missing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fields: Option<LinkFields>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkSort {
+    #[default]
+    CreatedAt,
+    /// rolled up clicks of all time
+    Clicks,
+}
+
+/// How the destination of a link answered when it was last checked
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkStatus {
+    /// with a 4xx or 5xx, or not at all
+    Broken,
+    Reachable,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Query of `GET /api/links/:id`
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct LinkQuery {
+    /// comma separated fields to return, all of them if missing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fields: Option<LinkFields>,
+}
+
+/// Fields of an [`ExportedLink`] asked for with `?fields=id,url,clicks`, the rest are left out
+/// and not looked up
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct LinkFields(Vec<LinkField>);
+
+/// One field of an [`ExportedLink`], named as in responses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkField {
+    Id,
+    Url,
+    Flagged,
+    Tags,
+    Visibility,
+    Aliases,
+    CreatedAt,
+    Clicks,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ListLinksResponse {
+    /// newest first unless `sort` and `order` say otherwise
+    pub links: Vec<ExportedLink>,
+    /// `after` for the next page, missing on the last one
+    pub next: Option<LinkCursor>,
+}
+
+/// Where a page of links ends, opaque to clients. It keeps its place while links are added
+/// or removed.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct LinkCursor {
+    pub(crate) key: CursorKey,
+    pub(crate) id: String,
+}
+
+/// The sort value of the last link of a page
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum CursorKey {
+    CreatedAt(DateTime<Utc>),
+    Clicks(i64),
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TopLinksQuery {
+    #[serde(default = "default_top_period")]
+    pub period: Period,
+    #[serde(default = "default_top_limit")]
+    pub limit: i64,
+    /// leave out clicks from crawlers, previews and scripts, and ones taken for click fraud
+    #[serde(default)]
+    pub exclude_bots: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TopLinksResponse {
+    pub period: Period,
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    pub links: Vec<TopLink>,
+}
+
+/// Body of `POST /api/stats/bulk`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BulkStatsRequest {
+    pub ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BulkStatsResponse {
+    /// rolled up clicks of all time by link id, `0` for links never clicked
+    pub clicks: BTreeMap<String, i64>,
+    /// asked for ids without a link
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub not_found: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, FromRow)]
+#[cfg_attr(feature = "graphql", derive(async_graphql::SimpleObject))]
+pub struct TopLink {
+    pub id: String,
+    pub url: String,
+    pub clicks: i64,
+    /// added up over the days, like in [`TimeseriesPoint`]
+    pub unique_visitors: i64,
+}
+
+/// One row of `daily_stats`, as exported for analysts
+#[derive(Debug, Serialize, FromRow)]
+pub(crate) struct DailyStats {
+    url_id: String,
+    day: NaiveDate,
+    clicks: i64,
+    unique_visitors: i64,
+    /// JSON array of `{"referrer", "clicks"}`, the most frequent first
+    top_referrers: String,
+}
+
+/// Who sent a request, looking through trusted proxies. Set by the `resolve_client` middleware.
+#[derive(Debug, Clone)]
+pub(crate) struct Client {
+    pub(crate) ip: IpAddr,
+    /// scheme and host the client originally asked for, as reported by a trusted proxy
+    pub(crate) forwarded_base: Option<Url>,
+}
+
+/// Body of `/admin/maintenance`. While enabled every change is refused with a 503.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Maintenance {
+    pub(crate) enabled: bool,
+    /// shown to clients instead of the default message
+    #[serde(default)]
+    pub(crate) message: Option<String>,
+    /// sent to clients as `Retry-After`
+    #[serde(default)]
+    pub(crate) retry_after_secs: Option<u64>,
+    /// keep redirecting and answering other reads, on unless turned off
+    #[serde(default = "default_serve_reads")]
+    pub(crate) serve_reads: bool,
+}
+
+/// One line of `export` and `import`, and a link as listed by `/api/links`
+#[derive(Debug, Clone, Serialize, Deserialize, FromRow)]
+pub struct ExportedLink {
+    pub id: String,
+    pub url: String,
+    #[serde(default)]
+    pub flagged: bool,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    #[sqlx(rename = "private", try_from = "bool")]
+    pub visibility: Visibility,
+    #[serde(default, skip_serializing_if = "ForwardQuery::is_off")]
+    #[sqlx(default, try_from = "String")]
+    pub forward_query: ForwardQuery,
+    /// its own `Cache-Control` for redirects, instead of `features.redirect_cache_control`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[sqlx(default)]
+    pub cache_control: Option<String>,
+    /// more ids redirecting to the same destination, their clicks count for this link
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub aliases: Vec<String>,
+    /// missing in exports from before links kept it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    /// HTTP status the destination answered when it was last checked, missing when it
+    /// couldn't be reached
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[sqlx(default)]
+    pub check_status: Option<i16>,
+    /// when the destination was last checked, missing when it never was
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[sqlx(default)]
+    pub checked_at: Option<DateTime<Utc>>,
+    /// rolled up clicks of all time, only as listed by `/api/links` or asked for with `fields`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[sqlx(default)]
+    pub clicks: Option<i64>,
+}
+
+/// Body of `POST /api/links/:id/aliases`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct AliasRequest {
+    pub alias: String,
+}
+
+/// Upper bound on the number of points in a single time series response
+const MAX_TIMESERIES_POINTS: i64 = 1000;
+const DEFAULT_TIMESERIES_DAYS: u64 = 30;
+const DEFAULT_TOP_PERIOD: Period = Period { days: 7 };
+const DEFAULT_TOP_LIMIT: i64 = 20;
+
+const MAX_TOP_PERIOD_DAYS: u64 = 366;
+
+pub(crate) const DEFAULT_PAGE_SIZE: i64 = 50;
+
+/// A destination is broken when it answers with an error or not at all
+pub(crate) fn is_broken(status: Option<i16>) -> bool {
+    status.is_none_or(|status| status >= 400)
+}
+
+impl LinkStatus {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Self::Broken => "broken",
+            Self::Reachable => "reachable",
+        }
+    }
+
+    /// What the last check of `link` found, `None` when it was never checked
+    pub(crate) fn of(link: &ExportedLink) -> Option<Self> {
+        link.checked_at?;
+        Some(if is_broken(link.check_status) {
+            Self::Broken
+        } else {
+            Self::Reachable
+        })
+    }
+}
+
+impl BreakdownQuery {
+    pub(crate) fn range(&self) -> Result<(NaiveDate, NaiveDate), AppError> {
+        day_range(self.from, self.to)
+    }
+}
+
+/// `from` and `to` with the last 30 days as defaults
+fn day_range(
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+) -> Result<(NaiveDate, NaiveDate), AppError> {
+    let to = to.unwrap_or_else(|| Utc::now().date_naive());
+    let from = from.unwrap_or_else(|| to - Days::new(DEFAULT_TIMESERIES_DAYS));
+    if from > to {
+        return Err(AppError::validation("from", "must not be after `to`"));
+    }
+    Ok((from, to))
+}
+
+impl TimeseriesQuery {
+    /// The requested days with defaults filled in, as long as they fit in one response
+    pub(crate) fn range(&self) -> Result<(NaiveDate, NaiveDate), AppError> {
+        let (from, to) = day_range(self.from, self.to)?;
+        let days = (to - from).num_days() + 1;
+        let points = match self.interval {
+            Interval::Hour => days * 24,
+            Interval::Day => days,
+            Interval::Week => days / 7 + 1,
+        };
+        if points > MAX_TIMESERIES_POINTS {
+            return Err(AppError::validation(
+                "interval",
+                format!(
+                    "range too large, at most {} points can be returned",
+                    MAX_TIMESERIES_POINTS
+                ),
+            ));
+        }
+        Ok((from, to))
+    }
+}
+
+impl TryFrom<String> for Period {
+    type Error = String;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid period `{}`, expected e.g. `7d` or `4w`", raw);
+        let (count, multiplier) = if let Some(count) = raw.strip_suffix('d') {
+            (count, 1)
+        } else if let Some(count) = raw.strip_suffix('w') {
+            (count, 7)
+        } else {
+            return Err(invalid());
+        };
+        let days = count
+            .parse::<u64>()
+            .ok()
+            .and_then(|count| count.checked_mul(multiplier))
+            .ok_or_else(invalid)?;
+        if !(1..=MAX_TOP_PERIOD_DAYS).contains(&days) {
+            return Err(format!(
+                "period must be between 1 and {} days",
+                MAX_TOP_PERIOD_DAYS
+            ));
+        }
+        Ok(Self { days })
+    }
+}
+
+impl From<Period> for String {
+    fn from(period: Period) -> Self {
+        format!("{}d", period.days)
+    }
+}
+
+impl FromStr for Period {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        raw.to_owned().try_into()
+    }
+}
+
+impl Period {
+    pub fn days(self) -> u64 {
+        self.days
+    }
+}
+
+impl LinkCursor {
+    /// The cursor of a page sorted by `sort` that ends with `link`
+    pub(crate) fn after(link: &ExportedLink, sort: LinkSort) -> Option<Self> {
+        let key = match sort {
+            LinkSort::CreatedAt => CursorKey::CreatedAt(link.created_at?),
+            LinkSort::Clicks => CursorKey::Clicks(link.clicks?),
+        };
+        Some(Self {
+            key,
+            id: link.id.clone(),
+        })
+    }
+
+    pub(crate) fn sort(&self) -> LinkSort {
+        match self.key {
+            CursorKey::CreatedAt(_) => LinkSort::CreatedAt,
+            CursorKey::Clicks(_) => LinkSort::Clicks,
+        }
+    }
+}
+
+impl TryFrom<String> for LinkCursor {
+    type Error = String;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        let invalid = || format!("invalid cursor `{}`, pass `next` of the previous page", raw);
+        let decoded = URL_SAFE_NO_PAD.decode(&raw).map_err(|_| invalid())?;
+        let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+        let (key, id) = decoded.split_once('.').ok_or_else(invalid)?;
+        let key = match key.strip_prefix('c') {
+            Some(clicks) => CursorKey::Clicks(clicks.parse().map_err(|_| invalid())?),
+            None => key
+                .parse()
+                .ok()
+                .and_then(DateTime::from_timestamp_micros)
+                .map(CursorKey::CreatedAt)
+                .ok_or_else(invalid)?,
+        };
+        Ok(Self {
+            key,
+            id: id.to_owned(),
+        })
+    }
+}
+
+impl From<LinkCursor> for String {
+    fn from(cursor: LinkCursor) -> Self {
+        let key = match cursor.key {
+            CursorKey::CreatedAt(created_at) => created_at.timestamp_micros().to_string(),
+            CursorKey::Clicks(clicks) => format!("c{}", clicks),
+        };
+        URL_SAFE_NO_PAD.encode(format!("{}.{}", key, cursor.id))
+    }
+}
+
+impl FromStr for LinkCursor {
+    type Err = String;
+
+    fn from_str(raw: &str) -> Result<Self, Self::Err> {
+        raw.to_owned().try_into()
+    }
+}
+
+impl std::fmt::Display for LinkCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&String::from(self.clone()))
+    }
+}
+
+impl LinkField {
+    const ALL: [Self; 8] = [
+        Self::Id,
+        Self::Url,
+        Self::Flagged,
+        Self::Tags,
+        Self::Visibility,
+        Self::Aliases,
+        Self::CreatedAt,
+        Self::Clicks,
+    ];
+
+    /// Name of the field in responses
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Id => "id",
+            Self::Url => "url",
+            Self::Flagged => "flagged",
+            Self::Tags => "tags",
+            Self::Visibility => "visibility",
+            Self::Aliases => "aliases",
+            Self::CreatedAt => "created_at",
+            Self::Clicks => "clicks",
+        }
+    }
+}
+
+impl LinkFields {
+    /// Whether `field` is wanted, every field is when no fields were asked for
+    pub(crate) fn wants(fields: Option<&Self>, field: LinkField) -> bool {
+        fields.is_none_or(|fields| fields.0.contains(&field))
+    }
+
+    /// `link` with only these fields
+    pub(crate) fn select(&self, link: &ExportedLink) -> serde_json::Value {
+        let mut value = serde_json::to_value(link).expect("links serialize to JSON");
+        if let serde_json::Value::Object(map) = &mut value {
+            map.retain(|name, _| self.0.iter().any(|field| field.as_str() == name));
+        }
+        value
+    }
+}
+
+impl TryFrom<String> for LinkFields {
+    type Error = String;
+
+    fn try_from(raw: String) -> Result<Self, Self::Error> {
+        let mut fields = Vec::new();
+        for name in raw
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let field = LinkField::ALL
+                .into_iter()
+                .find(|field| field.as_str() == name)
+                .ok_or_else(|| {
+                    format!(
+                        "unknown field `{}`, expected some of {}",
+                        name,
+                        LinkField::ALL.map(|field| field.as_str()).join(", ")
+                    )
+                })?;
+            if !fields.contains(&field) {
+                fields.push(field);
+            }
+        }
+        if fields.is_empty() {
+            return Err("expected at least one field".to_owned());
+        }
+        Ok(Self(fields))
+    }
+}
+
+impl From<LinkFields> for String {
+    fn from(fields: LinkFields) -> Self {
+        fields
+            .0
+            .iter()
+            .map(LinkField::as_str)
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+impl Default for ListLinksQuery {
+    fn default() -> Self {
+        Self {
+            after: None,
+            limit: DEFAULT_PAGE_SIZE,
+            tag: None,
+            domain: None,
+            created_after: None,
+            status: None,
+            sort: LinkSort::default(),
+            order: SortOrder::default(),
+            fields: None,
+        }
+    }
+}
+
+impl Default for TopLinksQuery {
+    fn default() -> Self {
+        Self {
+            period: DEFAULT_TOP_PERIOD,
+            limit: DEFAULT_TOP_LIMIT,
+            exclude_bots: false,
+        }
+    }
+}
+
+fn default_top_period() -> Period {
+    DEFAULT_TOP_PERIOD
+}
+
+fn default_top_limit() -> i64 {
+    DEFAULT_TOP_LIMIT
+}
+
+fn default_page_size() -> i64 {
+    DEFAULT_PAGE_SIZE
+}
+
+fn default_serve_reads() -> bool {
+    true
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            message: None,
+            retry_after_secs: None,
+            serve_reads: default_serve_reads(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use figment::{
+        providers::{Format, Toml},
+        Figment,
+    };
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::validate::{ALIAS_LENGTH, ID_ALPHABET, ID_LENGTH, RESERVED_ALIASES};
+
+    fn id_char() -> impl Strategy<Value = char> {
+        proptest::sample::select(&ID_ALPHABET[..])
+    }
+
+    /// Ids as [`random_link_id`] makes them and any valid alias
+    fn link_id() -> impl Strategy<Value = String> {
+        let generated = proptest::collection::vec(id_char(), ID_LENGTH);
+        let alias = proptest::collection::vec(id_char(), ALIAS_LENGTH);
+        prop_oneof![generated, alias]
+            .prop_map(|chars| chars.into_iter().collect::<String>())
+            .prop_filter("reserved", |id| !RESERVED_ALIASES.contains(&id.as_str()))
+    }
+
+    #[test]
+    fn forwarded_queries_are_merged_into_the_destination() {
+        let url = "https://example.com/docs?ref=a&q=b%20c#top";
+        let query = "ref=x&gclid=123";
+
+        assert_eq!(ForwardQuery::Off.apply(url, query), url);
+        assert_eq!(
+            ForwardQuery::Append.apply(url, query),
+            "https://example.com/docs?ref=a&q=b%20c&ref=x&gclid=123#top"
+        );
+        assert_eq!(
+            ForwardQuery::Replace.apply(url, query),
+            "https://example.com/docs?q=b%20c&ref=x&gclid=123#top"
+        );
+        assert_eq!(
+            ForwardQuery::Keep.apply(url, query),
+            "https://example.com/docs?ref=a&q=b%20c&gclid=123#top"
+        );
+        assert_eq!(
+            ForwardQuery::Append.apply("https://example.com/", "gclid=1"),
+            "https://example.com/?gclid=1"
+        );
+        assert_eq!(ForwardQuery::Append.apply(url, ""), url);
+    }
+
+    proptest! {
+        #[test]
+        fn ids_survive_the_url_path(id in link_id(), base in "https://sho\\.rt(/[a-z]{1,8}){0,2}/?") {
+            let config: Config = Figment::from(Toml::string(&format!(
+                "[listener]\npublic_base_url = \"{}\"",
+                base
+            )))
+            .extract()
+            .unwrap();
+            let client = Client {
+                ip: Ipv4Addr::LOCALHOST.into(),
+                forwarded_base: None,
+            };
+
+            let short_url = config.short_url(&id, &client);
+            prop_assert!(short_url.ends_with(&format!("/{}", id)), "{}", short_url);
+            let parsed = Url::parse(&short_url).unwrap();
+            prop_assert_eq!(parsed.as_str(), short_url.as_str());
+            prop_assert_eq!(parsed.path_segments().unwrap().next_back(), Some(id.as_str()));
+        }
+
+        #[test]
+        fn cursors_survive_the_query_string(id in link_id(), micros in 0i64..4_102_444_800_000_000) {
+            let cursor = LinkCursor {
+                key: CursorKey::CreatedAt(DateTime::from_timestamp_micros(micros).unwrap()),
+                id: id.clone(),
+            };
+            let encoded = cursor.to_string();
+            prop_assert!(encoded.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_'));
+            prop_assert_eq!(encoded.parse::<LinkCursor>(), Ok(cursor));
+            let cursor = LinkCursor { key: CursorKey::Clicks(micros), id };
+            prop_assert_eq!(cursor.to_string().parse::<LinkCursor>(), Ok(cursor));
+        }
+    }
+}
@@ -0,0 +1,465 @@
+//! Recording clicks: bot detection, anonymized addresses, GeoIP and batched writes
+
+use std::{
+    net::IpAddr,
+    path::{Path as FsPath, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use axum::http::{
+    header::{REFERER, USER_AGENT},
+    HeaderMap, HeaderName, HeaderValue,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::{
+    sync::{broadcast, mpsc, watch},
+    task::JoinHandle,
+};
+#[cfg(feature = "geoip")]
+use tracing::info;
+use tracing::warn;
+
+use crate::jobs::Jobs;
+use crate::store::Store;
+
+/// MaxMind GeoLite2/GeoIP2 City database, picked up again whenever the file changes
+#[derive(Clone, Default)]
+pub(crate) struct GeoIp {
+    path: Arc<RwLock<Option<PathBuf>>>,
+    db: Arc<RwLock<Option<GeoIpDb>>>,
+}
+
+struct GeoIpDb {
+    #[cfg(feature = "geoip")]
+    reader: maxminddb::Reader<Vec<u8>>,
+    modified: SystemTime,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct GeoLocation {
+    country: Option<String>,
+    city: Option<String>,
+}
+
+/// A single redirect, as stored in the `clicks` table and sent to live subscribers
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct ClickEvent {
+    pub(crate) url_id: String,
+    pub(crate) referrer: Option<String>,
+    pub(crate) user_agent: Option<String>,
+    /// salted hash so visitors can be told apart without keeping their address
+    #[serde(skip)]
+    pub(crate) ip_hash: String,
+    /// address and user agent hashed with a salt for the day, what unique visitors are counted
+    /// by. A visitor can't be followed from one day to the next with it.
+    #[serde(skip)]
+    pub(crate) visitor_hash: String,
+    /// going by the user agent, a crawler, link preview or script rather than a person
+    pub(crate) bot: bool,
+    pub(crate) country: Option<String>,
+    pub(crate) city: Option<String>,
+    pub(crate) clicked_at: DateTime<Utc>,
+}
+
+/// Hands clicks to a background writer so redirects never wait on the insert
+#[derive(Debug, Clone)]
+pub(crate) struct ClickRecorder {
+    tx: mpsc::Sender<ClickEvent>,
+    live: broadcast::Sender<ClickEvent>,
+    ip_salt: Arc<str>,
+    /// see [`anonymize_ip`]
+    anonymize_ips: bool,
+    stop: Arc<watch::Sender<bool>>,
+    writer: Arc<Mutex<Option<JoinHandle<()>>>>,
+}
+
+/// Found in the user agents of crawlers, link previews and HTTP libraries, matched in lower case
+const BOT_USER_AGENTS: &[&str] = &[
+    "bot",
+    "crawler",
+    "spider",
+    "slurp",
+    "facebookexternalhit",
+    "embedly",
+    "preview",
+    "headless",
+    "lighthouse",
+    "curl/",
+    "wget/",
+    "python-requests",
+    "python-urllib",
+    "go-http-client",
+    "okhttp",
+    "java/",
+    "libwww-perl",
+    "httpclient",
+    "axios/",
+    "node-fetch",
+];
+
+/// Clicks buffered between the redirect handlers and the writer, more are dropped
+const CLICK_BUFFER_SIZE: usize = 10_000;
+/// Upper bound on rows per insert and on how long a click waits to be written
+const CLICK_BATCH_SIZE: usize = 500;
+const CLICK_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+/// Clicks kept for slow live subscribers before they start missing events
+const LIVE_CLICK_BUFFER: usize = 1024;
+
+const GEOIP_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+const ANONYMIZED_IPV4_PREFIX: u8 = 24;
+const ANONYMIZED_IPV6_PREFIX: u8 = 48;
+
+/// The network `ip` is in rather than the address itself, like Google Analytics does it: the
+/// last byte of IPv4 addresses and all but 48 bits of IPv6 ones are zeroed
+pub(crate) fn anonymize_ip(ip: IpAddr) -> IpAddr {
+    let prefix = match ip {
+        IpAddr::V4(_) => ANONYMIZED_IPV4_PREFIX,
+        IpAddr::V6(_) => ANONYMIZED_IPV6_PREFIX,
+    };
+    ipnet::IpNet::new(ip, prefix)
+        .expect("prefixes fit the address family")
+        .network()
+}
+
+/// Whether `user_agent` belongs to a crawler, link preview or script. People's browsers always
+/// send one, so a missing user agent counts as a bot.
+fn is_bot(user_agent: Option<&str>) -> bool {
+    let Some(user_agent) = user_agent.filter(|ua| !ua.trim().is_empty()) else {
+        return true;
+    };
+    let user_agent = user_agent.to_ascii_lowercase();
+    BOT_USER_AGENTS
+        .iter()
+        .any(|pattern| user_agent.contains(pattern))
+}
+
+impl ClickRecorder {
+    /// Start the writer task, client addresses are hashed with `ip_salt` and first anonymized
+    /// with `anonymize_ips`. With `publish`, clicks go to the outbox too.
+    pub(crate) fn spawn(
+        store: Arc<dyn Store>,
+        ip_salt: Option<String>,
+        anonymize_ips: bool,
+        publish: bool,
+    ) -> Self {
+        let ip_salt = ip_salt.unwrap_or_else(|| {
+            warn!("No IP hash salt is configured, visitor hashes will change on every restart");
+            nanoid::nanoid!(32)
+        });
+        let (tx, rx) = mpsc::channel(CLICK_BUFFER_SIZE);
+        let (stop, stopped) = watch::channel(false);
+        let writer = tokio::spawn(write_clicks(store, rx, stopped, publish));
+        let (live, _) = broadcast::channel(LIVE_CLICK_BUFFER);
+
+        Self {
+            tx,
+            live,
+            ip_salt: ip_salt.into(),
+            anonymize_ips,
+            stop: Arc::new(stop),
+            writer: Arc::new(Mutex::new(Some(writer))),
+        }
+    }
+
+    /// Write out every buffered click and stop the writer, later clicks are dropped
+    pub(crate) async fn flush(&self) {
+        self.stop.send_replace(true);
+        let writer = self.writer.lock().unwrap().take();
+        if let Some(writer) = writer {
+            if let Err(e) = writer.await {
+                warn!("Click writer failed: {:?}", e);
+            }
+        }
+    }
+
+    pub(crate) fn buffered(&self) -> usize {
+        self.tx.max_capacity() - self.tx.capacity()
+    }
+
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<ClickEvent> {
+        self.live.subscribe()
+    }
+
+    /// Stands in for a visitor's address wherever one is stored, only for its network when
+    /// addresses are anonymized
+    pub(crate) fn hash_ip(&self, ip: IpAddr) -> String {
+        let salted = format!("{}{}", self.ip_salt, self.anonymized(ip));
+        format!("{:x}", Sha256::digest(salted))
+    }
+
+    fn anonymized(&self, ip: IpAddr) -> IpAddr {
+        if self.anonymize_ips {
+            anonymize_ip(ip)
+        } else {
+            ip
+        }
+    }
+
+    /// Tells the visitors of `day` apart, the salt changes with the day
+    fn hash_visitor(&self, ip: IpAddr, user_agent: Option<&str>, day: NaiveDate) -> String {
+        let salted = format!(
+            "{}{}{}\n{}",
+            self.ip_salt,
+            day,
+            ip,
+            user_agent.unwrap_or_default()
+        );
+        format!("{:x}", Sha256::digest(salted))
+    }
+
+    pub(crate) fn record(
+        &self,
+        url_id: String,
+        ip: IpAddr,
+        headers: &HeaderMap,
+        location: GeoLocation,
+    ) {
+        let header = |name| {
+            headers
+                .get(name)
+                .and_then(|v: &HeaderValue| v.to_str().ok())
+                .map(ToOwned::to_owned)
+        };
+        let clicked_at = Utc::now();
+        let user_agent = header(USER_AGENT);
+        // `location` was looked up with the full address already
+        let ip = self.anonymized(ip);
+        let event = ClickEvent {
+            url_id,
+            referrer: header(REFERER),
+            visitor_hash: self.hash_visitor(ip, user_agent.as_deref(), clicked_at.date_naive()),
+            bot: is_bot(user_agent.as_deref()),
+            user_agent,
+            // a salt for the day too, so an anonymized visitor can't be followed across days
+            ip_hash: if self.anonymize_ips {
+                self.hash_visitor(ip, None, clicked_at.date_naive())
+            } else {
+                self.hash_ip(ip)
+            },
+            country: location.country.or_else(|| {
+                // set by CDNs like Cloudflare in front of us
+                header(HeaderName::from_static("cf-ipcountry"))
+                    .filter(|c| c.len() == 2 && c != "XX")
+            }),
+            city: location.city,
+            clicked_at,
+        };
+        if self.live.receiver_count() > 0 {
+            // only fails when the last subscriber just went away
+            let _ = self.live.send(event.clone());
+        }
+        if self.tx.try_send(event).is_err() {
+            warn!("Click buffer is full, dropping click");
+        }
+    }
+}
+
+impl GeoIp {
+    pub(crate) fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let geoip = Self::default();
+        geoip.set_path(Some(path.into()))?;
+        Ok(geoip)
+    }
+
+    fn enabled(&self) -> bool {
+        self.path.read().unwrap().is_some()
+    }
+
+    #[cfg(feature = "geoip")]
+    pub(crate) fn lookup(&self, ip: IpAddr) -> GeoLocation {
+        let db = self.db.read().unwrap();
+        let Some(db) = db.as_ref() else {
+            return GeoLocation::default();
+        };
+        let city = db
+            .reader
+            .lookup(ip)
+            .and_then(|result| result.decode::<maxminddb::geoip2::City>());
+        match city {
+            Ok(Some(city)) => GeoLocation {
+                country: city.country.iso_code.map(ToOwned::to_owned),
+                city: city.city.names.english.map(ToOwned::to_owned),
+            },
+            _ => GeoLocation::default(),
+        }
+    }
+
+    /// Built without the `geoip` feature, there are no databases to look in
+    #[cfg(not(feature = "geoip"))]
+    pub(crate) fn lookup(&self, _ip: IpAddr) -> GeoLocation {
+        GeoLocation::default()
+    }
+
+    /// Load the database again if the file was replaced since the last load
+    fn reload_if_changed(&self) -> Result<bool> {
+        let Some(path) = self.path.read().unwrap().clone() else {
+            return Ok(false);
+        };
+        let modified = std::fs::metadata(&path)?.modified()?;
+        if let Some(db) = self.db.read().unwrap().as_ref() {
+            if db.modified == modified {
+                return Ok(false);
+            }
+        }
+
+        *self.db.write().unwrap() = Some(GeoIpDb::open(&path)?);
+        Ok(true)
+    }
+
+    /// Switch to the database at `path`, or stop locating clicks with `None`. Nothing changes
+    /// if the new file can't be read.
+    pub(crate) fn set_path(&self, path: Option<PathBuf>) -> Result<()> {
+        let db = path.as_deref().map(GeoIpDb::open).transpose()?;
+        *self.path.write().unwrap() = path;
+        *self.db.write().unwrap() = db;
+        Ok(())
+    }
+}
+
+impl GeoIpDb {
+    #[cfg(feature = "geoip")]
+    fn open(path: &FsPath) -> Result<Self> {
+        let modified = std::fs::metadata(path)?.modified()?;
+        let reader = maxminddb::Reader::open_readfile(path)?;
+        info!(
+            "Loaded GeoIP database {} built at {}",
+            path.display(),
+            reader.metadata().build_epoch
+        );
+        Ok(Self { reader, modified })
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    fn open(path: &FsPath) -> Result<Self> {
+        anyhow::bail!(
+            "Can't load GeoIP database {}, built without the geoip feature",
+            path.display()
+        )
+    }
+}
+
+pub(crate) async fn reload_geoip_periodically(geoip: GeoIp, jobs: Jobs) {
+    let mut interval = tokio::time::interval(GEOIP_RELOAD_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        // a reload can turn lookups on later
+        if !geoip.enabled() {
+            continue;
+        }
+        // keep serving from the old copy if the new one can't be read, e.g. mid-download
+        let ret = geoip.reload_if_changed();
+        if let Err(e) = &ret {
+            warn!("Failed to reload GeoIP database: {:?}", e);
+        }
+        jobs.record("geoip_reload", GEOIP_RELOAD_INTERVAL, &ret);
+    }
+}
+
+/// Drains the click buffer into the database in batches
+async fn write_clicks(
+    store: Arc<dyn Store>,
+    mut rx: mpsc::Receiver<ClickEvent>,
+    mut stopped: watch::Receiver<bool>,
+    publish: bool,
+) {
+    let mut batch = Vec::with_capacity(CLICK_BATCH_SIZE);
+    loop {
+        let first = tokio::select! {
+            event = rx.recv() => event,
+            // nothing new gets in, but what is already buffered still gets written
+            _ = stopped.changed() => {
+                rx.close();
+                rx.recv().await
+            }
+        };
+        let Some(first) = first else {
+            break;
+        };
+        batch.push(first);
+        let deadline = tokio::time::sleep(CLICK_FLUSH_INTERVAL);
+        tokio::pin!(deadline);
+        while batch.len() < CLICK_BATCH_SIZE {
+            tokio::select! {
+                Some(event) = rx.recv() => batch.push(event),
+                _ = &mut deadline => break,
+                else => break,
+            }
+        }
+
+        if let Err(e) = store.insert_clicks(&batch, publish).await {
+            warn!("Failed to write {} clicks: {:?}", batch.len(), e);
+        }
+        batch.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::store::mock::MockStore;
+
+    #[tokio::test]
+    async fn visitors_are_told_apart_per_day() {
+        let clicks = ClickRecorder::spawn(
+            Arc::new(MockStore::default()),
+            Some("salt".to_owned()),
+            false,
+            false,
+        );
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1));
+        let day = NaiveDate::from_ymd_opt(2024, 8, 1).unwrap();
+        let visitor = clicks.hash_visitor(ip, Some("curl/8.0"), day);
+
+        assert_eq!(visitor, clicks.hash_visitor(ip, Some("curl/8.0"), day));
+        assert_ne!(visitor, clicks.hash_visitor(ip, Some("Firefox"), day));
+        assert_ne!(visitor, clicks.hash_visitor(ip, None, day));
+        assert_ne!(
+            visitor,
+            clicks.hash_visitor(ip, Some("curl/8.0"), day.succ_opt().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn anonymized_addresses_keep_only_their_network() {
+        assert_eq!(
+            anonymize_ip("192.0.2.123".parse().unwrap()),
+            "192.0.2.0".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(
+            anonymize_ip("2001:db8:1:2::42".parse().unwrap()),
+            "2001:db8:1::".parse::<IpAddr>().unwrap()
+        );
+
+        let clicks = ClickRecorder::spawn(
+            Arc::new(MockStore::default()),
+            Some("salt".to_owned()),
+            true,
+            false,
+        );
+        assert_eq!(
+            clicks.hash_ip(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1))),
+            clicks.hash_ip(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 200)))
+        );
+    }
+
+    #[test]
+    fn crawlers_and_scripts_are_bots() {
+        let firefox = "Mozilla/5.0 (X11; Linux x86_64; rv:128.0) Gecko/20100101 Firefox/128.0";
+        assert!(!is_bot(Some(firefox)));
+        assert!(is_bot(Some(
+            "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)"
+        )));
+        assert!(is_bot(Some("facebookexternalhit/1.1")));
+        assert!(is_bot(Some("curl/8.5.0")));
+        assert!(is_bot(Some(" ")));
+        assert!(is_bot(None));
+    }
+}
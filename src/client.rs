@@ -0,0 +1,257 @@
+//! Typed client for the shortener's HTTP API, using the same request and response types as
+//! the server
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), shortener::client::ClientError> {
+//! use shortener::{client::ShortenerClient, ShortenRequest};
+//!
+//! let client = ShortenerClient::new("https://sho.rt".parse().unwrap()).with_api_key("secret");
+//! let created = client
+//!     .shorten(&ShortenRequest {
+//!         url: "https://example.com/a/long/path".to_owned(),
+//!         ..Default::default()
+//!     })
+//!     .await?;
+//! println!("{}", created.url);
+//! # Ok(())
+//! # }
+//! ```
+
+use reqwest::{
+    header::{ACCEPT, CONTENT_TYPE},
+    Method, RequestBuilder, Response, StatusCode,
+};
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+use url::Url;
+
+use crate::api::{
+    AliasRequest, BreakdownQuery, BulkStatsRequest, BulkStatsResponse, CountriesResponse,
+    ExportedLink, ListLinksQuery, ListLinksResponse, PreviewResponse, ReferrersResponse,
+    ReportRequest, ShortenRequest, ShortenResponse, TimeseriesQuery, TimeseriesResponse,
+    TopLinksQuery, TopLinksResponse,
+};
+use crate::error::ErrorResponse;
+
+#[derive(Debug, Error)]
+pub enum ClientError {
+    /// the server couldn't be reached or sent something that isn't the expected JSON
+    #[error("request to the shortener failed")]
+    Http(#[from] reqwest::Error),
+    /// the server sent something that isn't the expected MessagePack
+    #[error("unexpected response from the shortener")]
+    Decode(#[from] rmp_serde::decode::Error),
+    /// the server answered with an error, `body.code` tells which
+    #[error("{} ({})", .body.message, .body.code)]
+    Api {
+        status: StatusCode,
+        body: ErrorResponse,
+    },
+}
+
+/// Client for one shortener, cheap to clone
+#[derive(Debug, Clone)]
+pub struct ShortenerClient {
+    http: reqwest::Client,
+    base_url: Url,
+    api_key: Option<String>,
+    msgpack: bool,
+}
+
+impl ShortenerClient {
+    /// Client for the shortener at `base_url`, which may include a path prefix
+    pub fn new(base_url: Url) -> Self {
+        Self {
+            http: reqwest::Client::new(),
+            base_url,
+            api_key: None,
+            msgpack: false,
+        }
+    }
+
+    /// Sends `api_key` as a bearer token, needed for everything under `/api` and `/admin`.
+    /// Either the admin token or one of `security.tokens` with the scopes of the calls.
+    pub fn with_api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    /// Uses `http` instead of a default client, e.g. to share its connection pool or set
+    /// timeouts
+    pub fn with_http_client(mut self, http: reqwest::Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// Asks for MessagePack instead of JSON responses, which are smaller and quicker to decode
+    pub fn with_msgpack(mut self) -> Self {
+        self.msgpack = true;
+        self
+    }
+
+    pub fn base_url(&self) -> &Url {
+        &self.base_url
+    }
+
+    pub async fn shorten(&self, request: &ShortenRequest) -> Result<ShortenResponse, ClientError> {
+        self.send(self.request(Method::POST, &[]).json(request))
+            .await
+    }
+
+    /// Where `id` goes, without counting a click
+    pub async fn preview(&self, id: &str) -> Result<PreviewResponse, ClientError> {
+        self.send(self.request(Method::GET, &[id, "preview"])).await
+    }
+
+    pub async fn timeseries(
+        &self,
+        id: &str,
+        query: &TimeseriesQuery,
+    ) -> Result<TimeseriesResponse, ClientError> {
+        self.send(
+            self.request(Method::GET, &[id, "stats", "timeseries"])
+                .query(query),
+        )
+        .await
+    }
+
+    /// Tells the operators something is wrong with `id`
+    pub async fn report(&self, id: &str, request: &ReportRequest) -> Result<(), ClientError> {
+        let res = self
+            .request(Method::POST, &[id, "report"])
+            .json(request)
+            .send()
+            .await?;
+        check_status(res).await.map(drop)
+    }
+
+    pub async fn referrers(
+        &self,
+        id: &str,
+        query: &BreakdownQuery,
+    ) -> Result<ReferrersResponse, ClientError> {
+        self.send(
+            self.request(Method::GET, &[id, "stats", "referrers"])
+                .query(query),
+        )
+        .await
+    }
+
+    pub async fn countries(
+        &self,
+        id: &str,
+        query: &BreakdownQuery,
+    ) -> Result<CountriesResponse, ClientError> {
+        self.send(
+            self.request(Method::GET, &[id, "stats", "countries"])
+                .query(query),
+        )
+        .await
+    }
+
+    pub async fn link(&self, id: &str) -> Result<ExportedLink, ClientError> {
+        self.send(self.request(Method::GET, &["api", "links", id]))
+            .await
+    }
+
+    /// One page of links, newest first unless `query` sorts them otherwise. Pass `next` of the
+    /// response as `after` for the next, along with the same filters and sort. Links only decode
+    /// with `fields` that include `id` and `url`.
+    pub async fn list_links(
+        &self,
+        query: &ListLinksQuery,
+    ) -> Result<ListLinksResponse, ClientError> {
+        self.send(self.request(Method::GET, &["api", "links"]).query(query))
+            .await
+    }
+
+    pub async fn top_links(&self, query: &TopLinksQuery) -> Result<TopLinksResponse, ClientError> {
+        self.send(
+            self.request(Method::GET, &["api", "stats", "top"])
+                .query(query),
+        )
+        .await
+    }
+
+    /// Clicks of all time of each of `ids`, in one request
+    pub async fn bulk_stats(&self, ids: &[String]) -> Result<BulkStatsResponse, ClientError> {
+        self.send(
+            self.request(Method::POST, &["api", "stats", "bulk"])
+                .json(&BulkStatsRequest { ids: ids.to_vec() }),
+        )
+        .await
+    }
+
+    /// Lets `alias` redirect like `id` too, returns the link with all its aliases
+    pub async fn add_alias(&self, id: &str, alias: &str) -> Result<ExportedLink, ClientError> {
+        self.send(
+            self.request(Method::POST, &["api", "links", id, "aliases"])
+                .json(&AliasRequest {
+                    alias: alias.to_owned(),
+                }),
+        )
+        .await
+    }
+
+    pub async fn delete_link(&self, id: &str) -> Result<(), ClientError> {
+        let res = self
+            .request(Method::DELETE, &["admin", "links", id])
+            .send()
+            .await?;
+        check_status(res).await.map(drop)
+    }
+
+    fn request(&self, method: Method, segments: &[&str]) -> RequestBuilder {
+        let mut url = self.base_url.clone();
+        url.path_segments_mut()
+            .expect("http URLs have a path")
+            .pop_if_empty()
+            .extend(segments);
+        let mut request = self.http.request(method, url);
+        if self.msgpack {
+            request = request.header(ACCEPT, "application/msgpack");
+        }
+        match &self.api_key {
+            Some(api_key) => request.bearer_auth(api_key),
+            None => request,
+        }
+    }
+
+    async fn send<T: DeserializeOwned>(&self, request: RequestBuilder) -> Result<T, ClientError> {
+        let res = check_status(request.send().await?).await?;
+        decode(res).await
+    }
+}
+
+async fn check_status(res: Response) -> Result<Response, ClientError> {
+    let status = res.status();
+    if status.is_success() {
+        return Ok(res);
+    }
+    match decode::<ErrorResponse>(res).await {
+        Ok(body) => Err(ClientError::Api { status, body }),
+        // not from the shortener itself, e.g. a proxy in front of it
+        Err(_) => Err(ClientError::Api {
+            status,
+            body: ErrorResponse {
+                code: "http_error".into(),
+                message: format!("The shortener answered with {}", status),
+                details: None,
+                request_id: None,
+            },
+        }),
+    }
+}
+
+/// JSON or MessagePack, going by the content type the server answered with
+async fn decode<T: DeserializeOwned>(res: Response) -> Result<T, ClientError> {
+    let msgpack = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == "application/msgpack");
+    if msgpack {
+        Ok(rmp_serde::from_slice(&res.bytes().await?)?)
+    } else {
+        Ok(res.json().await?)
+    }
+}
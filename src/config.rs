@@ -0,0 +1,852 @@
+//! Settings of both binaries, loaded from the config file and the environment
+
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path as FsPath, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+use axum::http::HeaderValue;
+use figment::{
+    providers::{Env, Format, Toml, Yaml},
+    Figment,
+};
+use ipnet::IpNet;
+use serde::{Deserialize, Deserializer};
+use tracing::level_filters::LevelFilter;
+use url::Url;
+
+use crate::api::Client;
+use crate::validate::RESERVED_ALIASES;
+
+/// Settings from the config file, with environment variables layered on top
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// where the settings were read from, for reloads
+    #[serde(skip)]
+    pub(crate) file: Option<PathBuf>,
+    #[serde(default)]
+    pub(crate) database: DatabaseConfig,
+    #[serde(default)]
+    pub(crate) listener: ListenerConfig,
+    #[serde(default)]
+    pub(crate) log: LogConfig,
+    #[serde(default)]
+    pub(crate) rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub(crate) features: FeatureConfig,
+    #[serde(default)]
+    pub(crate) security: SecurityConfig,
+    #[serde(default)]
+    pub(crate) events: EventsConfig,
+    #[serde(default)]
+    pub(crate) metrics: MetricsConfig,
+    #[serde(default)]
+    pub(crate) export: ExportConfig,
+    #[serde(default)]
+    pub(crate) retention: RetentionConfig,
+    #[serde(default)]
+    pub(crate) schedule: ScheduleConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct DatabaseConfig {
+    pub(crate) url: String,
+    /// failed calls in a row after which the database isn't called for `breaker_cooldown_secs`,
+    /// `0` calls it no matter what
+    pub(crate) breaker_failures: u32,
+    pub(crate) breaker_cooldown_secs: u64,
+    /// waiting longer than this for a pooled connection logs a warning
+    pub(crate) slow_acquire_ms: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ListenerConfig {
+    /// `host:port`, or `unix:/path/to.sock` to sit behind nginx or a systemd socket
+    pub(crate) bind: BindAddr,
+    /// permissions of the Unix socket as an octal string, e.g. `"660"` so the proxy's group
+    /// can connect
+    #[serde(deserialize_with = "deserialize_mode")]
+    pub(crate) socket_mode: u32,
+    /// load balancers whose `Forwarded` and `X-Forwarded-*` headers are believed, addresses
+    /// or CIDR ranges. The proxy in front of a Unix socket is always believed.
+    #[serde(deserialize_with = "deserialize_proxies")]
+    pub(crate) trusted_proxies: Vec<IpNet>,
+    /// scheme and host short links are handed out under, e.g. `https://sho.rt`,
+    /// defaults to the bind address
+    public_base_url: Option<Url>,
+    /// also serve the `Links` gRPC service on this `host:port`, needs the `grpc` feature
+    pub(crate) grpc_bind: Option<SocketAddr>,
+    /// how long in-flight requests get to finish on shutdown
+    pub(crate) shutdown_timeout_secs: u64,
+    /// requests other than API mutations handled at once, more are turned away with a 503.
+    /// `0` takes any number.
+    pub(crate) redirect_concurrency: usize,
+    /// `POST`, `PUT`, `PATCH` and `DELETE` requests handled at once, `0` takes any number
+    pub(crate) mutation_concurrency: usize,
+    /// serve HTTPS directly instead of plain HTTP
+    pub(crate) tls: Option<TlsConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum BindAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TlsConfig {
+    /// PEM certificate chain
+    pub(crate) cert: PathBuf,
+    /// PEM private key
+    pub(crate) key: PathBuf,
+    /// pick up renewed certificates without a restart
+    #[serde(default)]
+    pub(crate) watch: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LogConfig {
+    #[serde(deserialize_with = "deserialize_level")]
+    pub(crate) level: LevelFilter,
+    pub(crate) format: LogFormat,
+    /// storage calls slower than this are logged at WARN
+    pub(crate) slow_query_ms: u64,
+    /// combined-format access log, `-` for stdout or a file to append to
+    pub(crate) access_log: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogFormat {
+    #[default]
+    Pretty,
+    /// one JSON object per line for log shippers
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RateLimitConfig {
+    /// links a single client may create per window
+    pub(crate) shorten_requests: u32,
+    pub(crate) shorten_window_secs: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct FeatureConfig {
+    /// drop `utm_*` and friends unless a request says otherwise
+    pub(crate) strip_tracking_params: bool,
+    /// only links to these domains (and their subdomains) can be shortened
+    #[serde(deserialize_with = "deserialize_list")]
+    pub(crate) allowed_domains: Option<Vec<String>>,
+    /// MaxMind City database used to locate clicks
+    pub(crate) geoip_db: Option<PathBuf>,
+    /// enables Google Safe Browsing checks
+    pub(crate) safe_browsing_api_key: Option<String>,
+    /// links reported by this many different visitors stop redirecting until an operator has
+    /// reviewed them, reports never disable links when unset
+    pub(crate) disable_after_reports: Option<u32>,
+    /// new links from visitors showing this many spam signals (a burst of shortens, a
+    /// suspicious TLD, another shortener as destination, a mashed alias) wait for a review
+    /// before they redirect, nothing is quarantined when unset
+    pub(crate) quarantine_score: Option<u32>,
+    /// gets a `link.click_spike` event as JSON for every link with clicks taken for click fraud
+    pub(crate) click_fraud_webhook: Option<Url>,
+    /// lists the public links on `/directory`
+    pub(crate) public_directory: bool,
+    /// `Cache-Control` of redirects for links without one of their own, e.g. `no-store` so
+    /// edits reach repeat visitors. Browsers cache redirects as they see fit when unset.
+    pub(crate) redirect_cache_control: Option<String>,
+    /// requests the destination of new links and what to do when it answers with an error or
+    /// can't be reached
+    pub(crate) reachability_check: ReachabilityCheck,
+    /// probes the destinations of all links that aren't flagged once a day, or as
+    /// `schedule.dead_link_check` says, `GET /api/links?status=broken` lists those that failed
+    pub(crate) dead_link_check: bool,
+    /// follows the redirects of new destinations to refuse those coming back to us, e.g.
+    /// through another shortener. Only links straight to us are refused when off.
+    pub(crate) remote_loop_check: bool,
+    /// what to do with a trailing `/` on the path of new destinations
+    pub(crate) trailing_slash: TrailingSlash,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ReachabilityCheck {
+    #[default]
+    Off,
+    /// log it and create the link anyway
+    Warn,
+    /// refuse the link
+    Reject,
+}
+
+/// What to do with a trailing `/` on the path of a destination URL
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum TrailingSlash {
+    /// store the path as submitted
+    #[default]
+    Keep,
+    /// `https://a.com/docs/` and `https://a.com/docs` are treated as the same link
+    Strip,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct SecurityConfig {
+    /// bearer token for `/admin`, `/api` and `/ws` with every scope, those are closed to
+    /// anything but `tokens` without it
+    pub(crate) admin_token: Option<String>,
+    /// salt for hashing visitor addresses, random per process when unset
+    pub(crate) ip_hash_salt: Option<String>,
+    /// privacy mode: visitor addresses are cut down to their /24 or /48 network once they were
+    /// located, before they are hashed or written to the access log. Clicks hash them with a
+    /// salt that changes every day.
+    pub(crate) anonymize_ips: bool,
+    /// further bearer tokens, each only good for its scopes
+    pub(crate) tokens: Vec<TokenConfig>,
+    pub(crate) headers: HeadersConfig,
+    pub(crate) honeypot: HoneypotConfig,
+    /// lets the requests to destinations reach loopback, private and link-local addresses,
+    /// e.g. for a shortener of intranet links. They are refused when off.
+    pub(crate) allow_private_destinations: bool,
+    /// domains to seed the blocklist with, one per line. `blocklist.txt` in the working
+    /// directory is used when unset, if there is one.
+    pub(crate) blocklist_file: Option<PathBuf>,
+}
+
+/// Headers every response gets for the browser's sake, an empty value leaves one out
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct HeadersConfig {
+    /// `max-age` of `Strict-Transport-Security`, `0` leaves it out. Browsers ignore it over
+    /// plain HTTP.
+    pub(crate) hsts_max_age_secs: u64,
+    pub(crate) hsts_include_subdomains: bool,
+    /// `X-Content-Type-Options: nosniff`
+    pub(crate) nosniff: bool,
+    pub(crate) referrer_policy: String,
+    /// `Content-Security-Policy` of the HTML pages, GraphiQL is left alone
+    pub(crate) content_security_policy: String,
+}
+
+/// Decoy slugs that no link can have. Whoever asks for one is taken for a scanner, turned away
+/// for a while and listed on `/admin/scanners`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct HoneypotConfig {
+    /// first path segments that give a scanner away, e.g. `.git` catches `/.git/config` too.
+    /// Links already using one stop redirecting, empty turns the honeypot off.
+    pub(crate) slugs: Vec<String>,
+    /// how long a scanner gets 429 for everything, `0` only lists it
+    pub(crate) block_secs: u64,
+}
+
+/// A bearer token for the API limited to some scopes, e.g. one for CI that may create and
+/// delete links but not read their stats
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct TokenConfig {
+    /// who the token was handed to, for the logs
+    pub(crate) name: String,
+    pub(crate) token: String,
+    pub(crate) scopes: Vec<Scope>,
+}
+
+/// What a token may do, `admin` covers everything else too
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub(crate) enum Scope {
+    /// `/api/links`
+    #[serde(rename = "links:read")]
+    LinksRead,
+    /// deleting links and managing bundles under `/admin`
+    #[serde(rename = "links:write")]
+    LinksWrite,
+    /// `/api/stats`, the click events and the `/ws` feed
+    #[serde(rename = "stats:read")]
+    StatsRead,
+    /// the rest of `/admin` and GraphQL
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl Scope {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Scope::LinksRead => "links:read",
+            Scope::LinksWrite => "links:write",
+            Scope::StatsRead => "stats:read",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+/// Where `link.created` and `link.clicked` events go, nowhere unless one broker is set
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct EventsConfig {
+    pub(crate) kafka: Option<KafkaConfig>,
+    pub(crate) nats: Option<NatsConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "kafka"), allow(dead_code))]
+pub(crate) struct KafkaConfig {
+    /// `host:port` of one or more brokers, comma separated
+    pub(crate) brokers: String,
+    #[serde(default = "default_kafka_topic")]
+    pub(crate) topic: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "nats"), allow(dead_code))]
+pub(crate) struct NatsConfig {
+    /// e.g. `nats://localhost:4222`, several servers can be given comma separated
+    pub(crate) url: String,
+    /// events go to `<subject>.link.created` and `<subject>.link.clicked`
+    #[serde(default = "default_nats_subject")]
+    pub(crate) subject: String,
+}
+
+/// Where the [`Metrics`](crate::telemetry::Metrics) counters are pushed, besides the dashboard feed
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct MetricsConfig {
+    pub(crate) statsd: Option<StatsdConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct StatsdConfig {
+    /// `host:port` of the agent, e.g. `127.0.0.1:8125` for a local Datadog agent
+    pub(crate) addr: String,
+    #[serde(default = "default_statsd_prefix")]
+    pub(crate) prefix: String,
+    /// DogStatsD tags added to every metric, `key:value` or just a value
+    #[serde(default, deserialize_with = "deserialize_list")]
+    pub(crate) tags: Option<Vec<String>>,
+}
+
+/// Where the rollup job copies each finished day of `daily_stats`, nowhere unless a bucket is
+/// set
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ExportConfig {
+    pub(crate) s3: Option<S3Config>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[cfg_attr(not(feature = "s3"), allow(dead_code))]
+pub(crate) struct S3Config {
+    pub(crate) bucket: String,
+    /// for S3-compatible stores, AWS itself when unset
+    pub(crate) endpoint: Option<String>,
+    pub(crate) region: Option<String>,
+    /// read from the usual `AWS_*` variables when unset
+    pub(crate) access_key_id: Option<String>,
+    pub(crate) secret_access_key: Option<String>,
+    /// prepended to `day=<YYYY-MM-DD>/daily_stats.csv`
+    #[serde(default)]
+    pub(crate) prefix: String,
+}
+
+/// How long click data is kept, forever unless set. The retention job deletes what's older
+/// every hour, or as `schedule.retention` says.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RetentionConfig {
+    /// days raw clicks are kept with their referrers, user agents and visitor hashes, at least
+    /// 2 so the rollup still finds yesterday's
+    pub(crate) clicks_days: Option<u32>,
+    /// days the daily and hourly rollups behind the stats are kept, at least as long as the
+    /// raw clicks
+    pub(crate) rollups_days: Option<u32>,
+}
+
+/// When the periodic jobs run, as cron expressions with seconds in UTC such as `0 30 3 * * *`.
+/// Unset ones run on their interval from startup.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ScheduleConfig {
+    /// rolls up clicks and copies finished days to `export.s3`, hourly by default
+    #[serde(deserialize_with = "deserialize_cron")]
+    pub(crate) click_rollup: Option<cron::Schedule>,
+    /// hourly by default
+    #[serde(deserialize_with = "deserialize_cron")]
+    pub(crate) retention: Option<cron::Schedule>,
+    /// every 6 hours by default
+    #[serde(deserialize_with = "deserialize_cron")]
+    pub(crate) threat_recheck: Option<cron::Schedule>,
+    /// every 10 minutes by default
+    #[serde(deserialize_with = "deserialize_cron")]
+    pub(crate) click_fraud: Option<cron::Schedule>,
+    /// daily by default, with `features.dead_link_check`
+    #[serde(deserialize_with = "deserialize_cron")]
+    pub(crate) dead_link_check: Option<cron::Schedule>,
+}
+
+const BREAKER_FAILURES: u32 = 5;
+const BREAKER_COOLDOWN: Duration = Duration::from_secs(10);
+const SLOW_ACQUIRE: Duration = Duration::from_millis(250);
+
+const DEFAULT_BIND_ADDR: &str = "0.0.0.0:9876";
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_HSTS_MAX_AGE: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+const DEFAULT_REFERRER_POLICY: &str = "strict-origin-when-cross-origin";
+/// The pages only load their own styles, scripts and images
+const DEFAULT_CONTENT_SECURITY_POLICY: &str =
+    "default-src 'self'; img-src 'self' data:; base-uri 'none'; form-action 'self'; frame-ancestors 'none'";
+/// Paths vulnerability scanners try on every host, none of them is a link anyone would want
+const DEFAULT_HONEYPOT_SLUGS: &[&str] = &[
+    ".env",
+    ".git",
+    "cgi-bin",
+    "phpmyadmin",
+    "wp-admin",
+    "wp-login.php",
+    "xmlrpc.php",
+];
+const DEFAULT_SCANNER_BLOCK: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_REDIRECT_CONCURRENCY: usize = 1024;
+const DEFAULT_MUTATION_CONCURRENCY: usize = 64;
+/// owner and group, which is what a proxy running under its own user needs
+const DEFAULT_SOCKET_MODE: u32 = 0o660;
+
+/// Environment variables that set a config key directly, kept short for the common settings
+const ENV_ALIASES: &[(&str, &str)] = &[
+    ("DATABASE_URL", "database.url"),
+    ("BIND_ADDR", "listener.bind"),
+    ("PUBLIC_BASE_URL", "listener.public_base_url"),
+    ("TRUSTED_PROXIES", "listener.trusted_proxies"),
+    ("GRPC_BIND", "listener.grpc_bind"),
+    ("LOG_LEVEL", "log.level"),
+    ("LOG_FORMAT", "log.format"),
+    ("SLOW_QUERY_MS", "log.slow_query_ms"),
+    ("ACCESS_LOG", "log.access_log"),
+    ("STRIP_TRACKING_PARAMS", "features.strip_tracking_params"),
+    ("ALLOWED_DOMAINS", "features.allowed_domains"),
+    ("GEOIP_DB", "features.geoip_db"),
+    ("SAFE_BROWSING_API_KEY", "features.safe_browsing_api_key"),
+    ("ADMIN_TOKEN", "security.admin_token"),
+    ("IP_HASH_SALT", "security.ip_hash_salt"),
+    ("ANONYMIZE_IPS", "security.anonymize_ips"),
+    ("KAFKA_BROKERS", "events.kafka.brokers"),
+    ("NATS_URL", "events.nats.url"),
+    ("STATSD_ADDR", "metrics.statsd.addr"),
+    ("EXPORT_S3_BUCKET", "export.s3.bucket"),
+];
+
+/// What connections to a Unix socket report as their peer, they don't have an address
+pub(crate) const UNIX_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+/// Default for the links a single client may create per [`SHORTEN_RATE_WINDOW`]
+const SHORTEN_RATE_LIMIT: u32 = 30;
+const SHORTEN_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Storage calls taking longer than this are logged unless `log.slow_query_ms` says otherwise
+const SLOW_QUERY_THRESHOLD: Duration = Duration::from_millis(200);
+
+const DEFAULT_KAFKA_TOPIC: &str = "shortener.events";
+const DEFAULT_NATS_SUBJECT: &str = "shortener";
+
+const DEFAULT_STATSD_PREFIX: &str = "shortener";
+
+/// Raw clicks of today and yesterday are rolled up again on every run
+const MIN_CLICK_RETENTION_DAYS: u32 = 2;
+
+impl Config {
+    /// Layers, from lowest to highest priority: built-in defaults, the config file,
+    /// `SHORTENER_<SECTION>__<KEY>` variables and the plain variables in `ENV_ALIASES`
+    pub fn load(file: Option<&FsPath>) -> Result<Self> {
+        let mut figment = Figment::new();
+        if let Some(file) = file {
+            anyhow::ensure!(file.is_file(), "Config file {} not found", file.display());
+            figment = match file.extension().and_then(|ext| ext.to_str()) {
+                Some("toml") => figment.merge(Toml::file(file)),
+                Some("yaml" | "yml") => figment.merge(Yaml::file(file)),
+                _ => anyhow::bail!(
+                    "Config file {} must end in .toml, .yaml or .yml",
+                    file.display()
+                ),
+            };
+        }
+        let mut config: Self = figment
+            .merge(Env::prefixed("SHORTENER_").split("__"))
+            .merge(Env::raw().filter_map(|key| {
+                ENV_ALIASES
+                    .iter()
+                    .find(|(name, _)| key == *name)
+                    .map(|(_, path)| (*path).into())
+            }))
+            .extract()
+            .context("Invalid configuration")?;
+        config.file = file.map(ToOwned::to_owned);
+
+        anyhow::ensure!(
+            !config.database.url.is_empty(),
+            "No database configured, set DATABASE_URL or database.url in the config file"
+        );
+        for token in &config.security.tokens {
+            anyhow::ensure!(
+                !token.token.is_empty() && !token.scopes.is_empty(),
+                "security.tokens `{}` needs a token and at least one scope",
+                token.name
+            );
+        }
+        let retention = &config.retention;
+        if let Some(days) = retention.clicks_days {
+            anyhow::ensure!(
+                days >= MIN_CLICK_RETENTION_DAYS,
+                "retention.clicks_days must be at least {}, the rollup needs yesterday's clicks",
+                MIN_CLICK_RETENTION_DAYS
+            );
+        }
+        if let Some(days) = retention.rollups_days {
+            anyhow::ensure!(
+                days >= retention.clicks_days.unwrap_or(u32::MAX),
+                "retention.rollups_days needs a retention.clicks_days that isn't longer"
+            );
+        }
+        let headers = &config.security.headers;
+        for (key, value) in [
+            ("referrer_policy", &headers.referrer_policy),
+            ("content_security_policy", &headers.content_security_policy),
+        ] {
+            anyhow::ensure!(
+                HeaderValue::from_str(value).is_ok(),
+                "security.headers.{} must be a valid header value, got `{}`",
+                key,
+                value
+            );
+        }
+        for slug in &config.security.honeypot.slugs {
+            anyhow::ensure!(
+                !slug.is_empty() && !slug.contains('/'),
+                "security.honeypot.slugs must be single path segments, got `{}`",
+                slug
+            );
+            // a decoy in front of a route would turn away whoever uses it
+            anyhow::ensure!(
+                !RESERVED_ALIASES.contains(&slug.as_str()),
+                "security.honeypot.slugs can't include `{}`, it is taken by a route",
+                slug
+            );
+        }
+        if let Some(cache_control) = &config.features.redirect_cache_control {
+            anyhow::ensure!(
+                HeaderValue::from_str(cache_control).is_ok(),
+                "features.redirect_cache_control must be a valid header value, got `{}`",
+                cache_control
+            );
+        }
+        if let Some(url) = &config.listener.public_base_url {
+            check_base_url(url).with_context(|| {
+                format!(
+                    "listener.public_base_url must be an http(s) URL like https://sho.rt, got `{}`",
+                    url
+                )
+            })?;
+        }
+        Ok(config)
+    }
+
+    pub(crate) fn public_base_url(&self) -> Url {
+        match &self.listener.public_base_url {
+            Some(url) => url.clone(),
+            None => {
+                let scheme = if self.listener.tls.is_some() {
+                    "https"
+                } else {
+                    "http"
+                };
+                let host = match &self.listener.bind {
+                    BindAddr::Tcp(addr) => addr.to_string(),
+                    // only reachable through the proxy, which should set public_base_url
+                    BindAddr::Unix(_) => "localhost".to_owned(),
+                };
+                Url::parse(&format!("{}://{}", scheme, host))
+                    .expect("socket addresses are valid hosts")
+            }
+        }
+    }
+
+    /// Base URL of links handed to `client`. A configured `public_base_url` always wins, without
+    /// one links follow the scheme and host the client used through a trusted proxy.
+    pub(crate) fn base_url_for(&self, client: &Client) -> Url {
+        match (&self.listener.public_base_url, &client.forwarded_base) {
+            (None, Some(forwarded)) => forwarded.clone(),
+            _ => self.public_base_url(),
+        }
+    }
+
+    pub(crate) fn short_url(&self, id: &str, client: &Client) -> String {
+        format!(
+            "{}/{}",
+            self.base_url_for(client).as_str().trim_end_matches('/'),
+            id
+        )
+    }
+
+    /// The page listing the links of bundle `id`
+    pub(crate) fn bundle_url(&self, id: &str, client: &Client) -> String {
+        format!(
+            "{}/b/{}",
+            self.base_url_for(client).as_str().trim_end_matches('/'),
+            id
+        )
+    }
+
+    /// The database URL without its password, for logs
+    pub(crate) fn redacted_database_url(&self) -> String {
+        match Url::parse(&self.database.url) {
+            Ok(mut url) => {
+                if url.password().is_some() {
+                    let _ = url.set_password(Some("***"));
+                }
+                url.to_string()
+            }
+            Err(_) => "<invalid url>".to_owned(),
+        }
+    }
+}
+
+impl ListenerConfig {
+    /// Whether the forwarding headers sent by `ip` are believed. Only the proxy can connect to
+    /// a Unix socket, otherwise every client behind it would share the proxy's address.
+    pub(crate) fn trusts(&self, ip: &IpAddr) -> bool {
+        (matches!(self.bind, BindAddr::Unix(_)) && *ip == UNIX_PEER.ip())
+            || self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            breaker_failures: BREAKER_FAILURES,
+            breaker_cooldown_secs: BREAKER_COOLDOWN.as_secs(),
+            slow_acquire_ms: SLOW_ACQUIRE.as_millis() as u64,
+        }
+    }
+}
+
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        Self {
+            bind: DEFAULT_BIND_ADDR
+                .parse()
+                .expect("default bind address is valid"),
+            socket_mode: DEFAULT_SOCKET_MODE,
+            trusted_proxies: Vec::new(),
+            public_base_url: None,
+            grpc_bind: None,
+            shutdown_timeout_secs: SHUTDOWN_TIMEOUT.as_secs(),
+            redirect_concurrency: DEFAULT_REDIRECT_CONCURRENCY,
+            mutation_concurrency: DEFAULT_MUTATION_CONCURRENCY,
+            tls: None,
+        }
+    }
+}
+
+impl Default for HeadersConfig {
+    fn default() -> Self {
+        Self {
+            hsts_max_age_secs: DEFAULT_HSTS_MAX_AGE.as_secs(),
+            hsts_include_subdomains: false,
+            nosniff: true,
+            referrer_policy: DEFAULT_REFERRER_POLICY.to_owned(),
+            content_security_policy: DEFAULT_CONTENT_SECURITY_POLICY.to_owned(),
+        }
+    }
+}
+
+impl Default for HoneypotConfig {
+    fn default() -> Self {
+        Self {
+            slugs: DEFAULT_HONEYPOT_SLUGS
+                .iter()
+                .map(|slug| slug.to_string())
+                .collect(),
+            block_secs: DEFAULT_SCANNER_BLOCK.as_secs(),
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: LevelFilter::INFO,
+            format: LogFormat::default(),
+            slow_query_ms: SLOW_QUERY_THRESHOLD.as_millis() as u64,
+            access_log: None,
+        }
+    }
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            shorten_requests: SHORTEN_RATE_LIMIT,
+            shorten_window_secs: SHORTEN_RATE_WINDOW.as_secs(),
+        }
+    }
+}
+
+fn deserialize_level<'de, D: Deserializer<'de>>(deserializer: D) -> Result<LevelFilter, D::Error> {
+    let level = String::deserialize(deserializer)?;
+    level.parse().map_err(|_| {
+        serde::de::Error::custom(format!(
+            "expected one of off, error, warn, info, debug or trace, got `{}`",
+            level
+        ))
+    })
+}
+
+impl FromStr for BindAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err("expected a socket path after `unix:`".to_owned()),
+            Some(path) => Ok(Self::Unix(path.into())),
+            None => s
+                .parse()
+                .map(Self::Tcp)
+                .map_err(|_| format!("expected `host:port` or `unix:/path/to.sock`, got `{}`", s)),
+        }
+    }
+}
+
+impl std::fmt::Display for BindAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => addr.fmt(f),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for BindAddr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Octal permissions, env vars hand `660` over as a number so that's read as octal too
+fn deserialize_mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Mode {
+        Digits(u64),
+        Octal(String),
+    }
+
+    let raw = match Mode::deserialize(deserializer)? {
+        Mode::Digits(digits) => digits.to_string(),
+        Mode::Octal(raw) => raw,
+    };
+    u32::from_str_radix(raw.trim_start_matches("0o"), 8)
+        .ok()
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| {
+            serde::de::Error::custom(format!(
+                "expected octal permissions like 660, got `{}`",
+                raw
+            ))
+        })
+}
+
+/// Accepts a list as well as a comma separated string, which is what env vars give us
+fn deserialize_list<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<String>>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum List {
+        List(Vec<String>),
+        CommaSeparated(String),
+    }
+
+    let list = Option::<List>::deserialize(deserializer)?.map(|list| match list {
+        List::List(list) => list,
+        List::CommaSeparated(raw) => raw
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(ToOwned::to_owned)
+            .collect(),
+    });
+    Ok(list)
+}
+
+fn deserialize_cron<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<cron::Schedule>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|raw| {
+            raw.parse().map_err(|e| {
+                serde::de::Error::custom(format!(
+                    "expected a cron expression with seconds like `0 30 3 * * *`, got `{}`: {}",
+                    raw, e
+                ))
+            })
+        })
+        .transpose()
+}
+
+/// Addresses as well as CIDR ranges, `10.0.0.1` is read as `10.0.0.1/32`
+fn deserialize_proxies<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<IpNet>, D::Error> {
+    deserialize_list(deserializer)?
+        .unwrap_or_default()
+        .iter()
+        .map(|raw| {
+            raw.parse()
+                .or_else(|_| raw.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| {
+                    serde::de::Error::custom(format!(
+                        "expected an IP address or CIDR range, got `{}`",
+                        raw
+                    ))
+                })
+        })
+        .collect()
+}
+
+fn check_base_url(url: &Url) -> Result<()> {
+    anyhow::ensure!(
+        matches!(url.scheme(), "http" | "https") && url.host().is_some(),
+        "not an http(s) URL with a host"
+    );
+    anyhow::ensure!(
+        url.query().is_none() && url.fragment().is_none(),
+        "must not have a query or fragment"
+    );
+    Ok(())
+}
+
+fn default_kafka_topic() -> String {
+    DEFAULT_KAFKA_TOPIC.to_owned()
+}
+
+fn default_nats_subject() -> String {
+    DEFAULT_NATS_SUBJECT.to_owned()
+}
+
+fn default_statsd_prefix() -> String {
+    DEFAULT_STATSD_PREFIX.to_owned()
+}
@@ -0,0 +1,460 @@
+//! Errors of the HTTP, GraphQL and gRPC APIs and how they are reported to clients
+
+use std::{borrow::Cow, time::Duration};
+
+#[cfg(feature = "graphql")]
+use async_graphql::ErrorExtensions;
+use axum::{
+    extract::rejection::{JsonRejection, QueryRejection},
+    http::{header::RETRY_AFTER, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tower::timeout::error::Elapsed;
+use tracing::error;
+
+use crate::handlers::{retry_after_secs, AppResponse, REQUEST_ID, REQUEST_TIMEOUT};
+use crate::store::Tombstone;
+
+/// Body of every error response
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ErrorResponse {
+    /// stable machine readable kind of error such as `not_found`
+    pub code: Cow<'static, str>,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<serde_json::Value>,
+    /// the `x-request-id` of the failed request, for reporting problems
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+}
+
+#[derive(Debug, Error)]
+pub(crate) enum AppError {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("Failed to parse JSON: {0}")]
+    JsonRejection(#[from] JsonRejection),
+
+    #[error("Invalid query string: {0}")]
+    QueryRejection(#[from] QueryRejection),
+
+    #[error("Link {0} not found")]
+    NotFound(String),
+
+    #[error("Bundle {0} not found")]
+    BundleNotFound(String),
+
+    #[error("Link {} was removed", .0.id)]
+    Gone(Tombstone),
+
+    #[error("Invalid {field}: {message}")]
+    Validation {
+        field: &'static str,
+        message: String,
+    },
+
+    #[error("Database error: {0}")]
+    Db(#[from] sqlx::Error),
+
+    #[error("Domain {0} is blocked")]
+    BlockedDomain(String),
+
+    #[error("Domain {0} is not in the list of allowed destinations")]
+    DomainNotAllowed(String),
+
+    #[error("Destination {0} has been flagged as unsafe")]
+    UnsafeUrl(String),
+
+    /// Visitors of a flagged link, who must not learn where it points
+    #[error("Link {0} has been flagged as unsafe")]
+    FlaggedLink(String),
+
+    #[error("Alias {alias} is already taken")]
+    AliasTaken {
+        alias: String,
+        suggestions: Vec<String>,
+    },
+
+    #[error("{url} is already shortened as {id}")]
+    AlreadyShortened { url: String, id: String },
+
+    #[error("Link {0} points to a destination that can't be served")]
+    InvalidDestination(String),
+
+    #[error("Too many requests, retry in {} seconds", retry_after_secs(.0))]
+    TooManyRequests(Duration),
+
+    #[error("Missing or invalid token")]
+    Unauthorized,
+
+    #[error("The token has no {0} scope")]
+    MissingScope(&'static str),
+
+    #[error("{}", .message.as_deref().unwrap_or(MAINTENANCE_MESSAGE))]
+    Maintenance {
+        message: Option<String>,
+        retry_after_secs: Option<u64>,
+    },
+
+    #[error("The database is unavailable, retry in {} seconds", retry_after_secs(.0))]
+    DatabaseUnavailable(Duration),
+
+    #[error("Too busy to take the request, retry in {} seconds", retry_after_secs(.0))]
+    Overloaded(Duration),
+
+    #[error("The request took longer than {} seconds", REQUEST_TIMEOUT.as_secs())]
+    Timeout(#[from] Elapsed),
+
+    #[error("Internal server error {0}")]
+    InternalServer(#[from] anyhow::Error),
+}
+
+const MAINTENANCE_MESSAGE: &str = "Down for maintenance, please try again shortly";
+const INTERNAL_ERROR_MESSAGE: &str = "Internal server error";
+
+impl AppError {
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            AppError::JsonRejection(rejection) => rejection.status(),
+            AppError::QueryRejection(rejection) => rejection.status(),
+            AppError::NotFound(_) | AppError::BundleNotFound(_) => StatusCode::NOT_FOUND,
+            AppError::Gone(_) => StatusCode::GONE,
+            AppError::Validation { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::BlockedDomain(_)
+            | AppError::DomainNotAllowed(_)
+            | AppError::UnsafeUrl(_)
+            | AppError::FlaggedLink(_) => StatusCode::FORBIDDEN,
+            AppError::AliasTaken { .. } | AppError::AlreadyShortened { .. } => StatusCode::CONFLICT,
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::MissingScope(_) => StatusCode::FORBIDDEN,
+            AppError::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Timeout(_) => StatusCode::REQUEST_TIMEOUT,
+            AppError::Maintenance { .. }
+            | AppError::DatabaseUnavailable(_)
+            | AppError::Overloaded(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Io(_)
+            | AppError::Db(_)
+            | AppError::InvalidDestination(_)
+            | AppError::InternalServer(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Stable, machine readable identifier of the error kind
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            AppError::Io(_) => "io_error",
+            AppError::JsonRejection(_) => "invalid_json",
+            AppError::QueryRejection(_) => "invalid_query",
+            AppError::NotFound(_) | AppError::BundleNotFound(_) => "not_found",
+            AppError::Gone(_) => "gone",
+            AppError::Validation { .. } => "validation_failed",
+            AppError::Db(_) => "database_error",
+            AppError::BlockedDomain(_) => "domain_blocked",
+            AppError::DomainNotAllowed(_) => "domain_not_allowed",
+            AppError::UnsafeUrl(_) | AppError::FlaggedLink(_) => "unsafe_url",
+            AppError::AliasTaken { .. } => "alias_taken",
+            AppError::AlreadyShortened { .. } => "already_shortened",
+            AppError::InvalidDestination(_) => "invalid_destination",
+            AppError::Unauthorized => "unauthorized",
+            AppError::MissingScope(_) => "insufficient_scope",
+            AppError::TooManyRequests(_) => "too_many_requests",
+            AppError::Timeout(_) => "timeout",
+            AppError::Maintenance { .. } => "maintenance",
+            AppError::DatabaseUnavailable(_) => "database_unavailable",
+            AppError::Overloaded(_) => "overloaded",
+            AppError::InternalServer(_) => "internal_error",
+        }
+    }
+
+    pub(crate) fn message(&self) -> String {
+        match self {
+            AppError::JsonRejection(rejection) => rejection.body_text(),
+            AppError::QueryRejection(rejection) => rejection.body_text(),
+            // don't leak connection, file system or other internal details, `report` logs them
+            AppError::Io(_) | AppError::Db(_) | AppError::InternalServer(_) => {
+                INTERNAL_ERROR_MESSAGE.to_owned()
+            }
+            _ => self.to_string(),
+        }
+    }
+
+    fn details(&self) -> Option<serde_json::Value> {
+        let details = match self {
+            AppError::Validation { field, .. } => serde_json::json!({ "field": field }),
+            AppError::BlockedDomain(domain) | AppError::DomainNotAllowed(domain) => {
+                serde_json::json!({ "domain": domain })
+            }
+            AppError::UnsafeUrl(url) => serde_json::json!({ "url": url }),
+            AppError::AliasTaken { alias, suggestions } => {
+                serde_json::json!({ "alias": alias, "suggestions": suggestions })
+            }
+            AppError::AlreadyShortened { url, id } => serde_json::json!({ "url": url, "id": id }),
+            AppError::InvalidDestination(id)
+            | AppError::FlaggedLink(id)
+            | AppError::NotFound(id)
+            | AppError::BundleNotFound(id) => {
+                serde_json::json!({ "id": id })
+            }
+            AppError::MissingScope(scope) => serde_json::json!({ "scope": scope }),
+            AppError::Gone(tombstone) => serde_json::json!(tombstone),
+            AppError::TooManyRequests(delay)
+            | AppError::DatabaseUnavailable(delay)
+            | AppError::Overloaded(delay) => {
+                serde_json::json!({ "retry_after": retry_after_secs(delay) })
+            }
+            AppError::Maintenance {
+                retry_after_secs: Some(secs),
+                ..
+            } => serde_json::json!({ "retry_after": secs }),
+            _ => return None,
+        };
+        Some(details)
+    }
+
+    /// Logs server side failures, which clients only see as [`INTERNAL_ERROR_MESSAGE`], and
+    /// sends them to Sentry. The hub set up by the tower layer adds the request.
+    pub(crate) fn report(&self) {
+        match self {
+            AppError::InternalServer(err) => {
+                error!("{:?}", err);
+                #[cfg(feature = "sentry")]
+                sentry::integrations::anyhow::capture_anyhow(err);
+            }
+            AppError::Db(err) => {
+                error!("Database error: {}", err);
+                #[cfg(feature = "sentry")]
+                sentry::capture_error(err);
+            }
+            AppError::Io(err) => {
+                error!("I/O error: {}", err);
+                #[cfg(feature = "sentry")]
+                sentry::capture_error(err);
+            }
+            _ => {}
+        }
+    }
+
+    pub(crate) fn validation(field: &'static str, message: impl Into<String>) -> Self {
+        AppError::Validation {
+            field,
+            message: message.into(),
+        }
+    }
+}
+
+/// Same message as over HTTP, with the error code in the `code` extension
+#[cfg(feature = "graphql")]
+impl ErrorExtensions for AppError {
+    fn extend(&self) -> async_graphql::Error {
+        self.report();
+
+        async_graphql::Error::new(self.message()).extend_with(|_, extensions| {
+            extensions.set("code", self.code());
+        })
+    }
+}
+
+/// Same message as over HTTP, with the HTTP error code in `x-error-code`
+#[cfg(feature = "grpc")]
+impl From<AppError> for tonic::Status {
+    fn from(err: AppError) -> Self {
+        err.report();
+
+        let code = match err.status() {
+            StatusCode::NOT_FOUND | StatusCode::GONE => tonic::Code::NotFound,
+            StatusCode::UNAUTHORIZED => tonic::Code::Unauthenticated,
+            StatusCode::FORBIDDEN => tonic::Code::PermissionDenied,
+            StatusCode::CONFLICT => tonic::Code::AlreadyExists,
+            StatusCode::TOO_MANY_REQUESTS => tonic::Code::ResourceExhausted,
+            StatusCode::REQUEST_TIMEOUT => tonic::Code::DeadlineExceeded,
+            StatusCode::SERVICE_UNAVAILABLE => tonic::Code::Unavailable,
+            status if status.is_client_error() => tonic::Code::InvalidArgument,
+            _ => tonic::Code::Internal,
+        };
+        let mut status = tonic::Status::new(code, err.message());
+        status.metadata_mut().insert(
+            "x-error-code",
+            tonic::metadata::MetadataValue::from_static(err.code()),
+        );
+        status
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        self.report();
+
+        let status = self.status();
+        let body = ErrorResponse {
+            code: self.code().into(),
+            message: self.message(),
+            details: self.details(),
+            request_id: REQUEST_ID.try_with(Clone::clone).ok(),
+        };
+
+        let mut headers = HeaderMap::new();
+        match &self {
+            AppError::TooManyRequests(delay)
+            | AppError::DatabaseUnavailable(delay)
+            | AppError::Overloaded(delay) => {
+                headers.insert(RETRY_AFTER, retry_after_secs(delay).into());
+            }
+            AppError::Maintenance {
+                retry_after_secs: Some(secs),
+                ..
+            } => {
+                headers.insert(RETRY_AFTER, (*secs).into());
+            }
+            _ => {}
+        }
+        (status, headers, AppResponse(body)).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use axum::{
+        body::{to_bytes, Body},
+        extract::{FromRequest, Query},
+        http::Request,
+        Json,
+    };
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    #[derive(Debug, Deserialize)]
+    #[allow(dead_code)]
+    struct Page {
+        limit: u32,
+    }
+
+    async fn json_rejection() -> JsonRejection {
+        let req = Request::post("/").body(Body::from("{}")).unwrap();
+        Json::<Value>::from_request(req, &()).await.unwrap_err()
+    }
+
+    fn query_rejection() -> QueryRejection {
+        Query::<Page>::try_from_uri(&"/?limit=many".parse().unwrap()).unwrap_err()
+    }
+
+    /// Status, `Retry-After` and body the way a client sees them
+    async fn response(err: AppError) -> Value {
+        let res = err.into_response();
+        let retry_after = res
+            .headers()
+            .get(RETRY_AFTER)
+            .map(|v| v.to_str().unwrap().to_owned());
+        let status = res.status().as_u16();
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        json!({
+            "status": status,
+            "retry_after": retry_after,
+            "body": serde_json::from_slice::<Value>(&body).unwrap(),
+        })
+    }
+
+    // one snapshot per variant, new variants belong here too
+    #[tokio::test]
+    async fn error_responses() {
+        let cases = [
+            ("io", AppError::Io(std::io::Error::other("disk full"))),
+            ("json_rejection", json_rejection().await.into()),
+            ("query_rejection", query_rejection().into()),
+            ("not_found", AppError::NotFound("abc123".to_owned())),
+            (
+                "bundle_not_found",
+                AppError::BundleNotFound("favourites".to_owned()),
+            ),
+            (
+                "gone",
+                AppError::Gone(Tombstone {
+                    id: "abc123".to_owned(),
+                    reason: Some("The campaign is over".to_owned()),
+                    removed_at: chrono::DateTime::from_timestamp(1_722_470_400, 0).unwrap(),
+                }),
+            ),
+            ("validation", AppError::validation("alias", "is reserved")),
+            ("db", AppError::Db(sqlx::Error::PoolTimedOut)),
+            (
+                "blocked_domain",
+                AppError::BlockedDomain("evil.example".to_owned()),
+            ),
+            (
+                "domain_not_allowed",
+                AppError::DomainNotAllowed("other.example".to_owned()),
+            ),
+            (
+                "unsafe_url",
+                AppError::UnsafeUrl("https://evil.example/malware".to_owned()),
+            ),
+            ("flagged_link", AppError::FlaggedLink("abc123".to_owned())),
+            (
+                "alias_taken",
+                AppError::AliasTaken {
+                    alias: "docs".to_owned(),
+                    suggestions: vec!["docs-a1".to_owned(), "docs-b2".to_owned()],
+                },
+            ),
+            (
+                "already_shortened",
+                AppError::AlreadyShortened {
+                    url: "https://example.com/docs".to_owned(),
+                    id: "abc123".to_owned(),
+                },
+            ),
+            (
+                "invalid_destination",
+                AppError::InvalidDestination("abc123".to_owned()),
+            ),
+            (
+                "too_many_requests",
+                AppError::TooManyRequests(Duration::from_millis(1500)),
+            ),
+            ("unauthorized", AppError::Unauthorized),
+            ("missing_scope", AppError::MissingScope("stats:read")),
+            (
+                "maintenance",
+                AppError::Maintenance {
+                    message: None,
+                    retry_after_secs: None,
+                },
+            ),
+            (
+                "maintenance_with_message",
+                AppError::Maintenance {
+                    message: Some("Moving to a new database".to_owned()),
+                    retry_after_secs: Some(120),
+                },
+            ),
+            (
+                "database_unavailable",
+                AppError::DatabaseUnavailable(Duration::from_millis(7300)),
+            ),
+            ("overloaded", AppError::Overloaded(Duration::from_secs(1))),
+            ("timeout", AppError::Timeout(Elapsed::new())),
+            (
+                "internal_server",
+                AppError::InternalServer(anyhow!("Link id abc123 is taken")),
+            ),
+        ];
+        for (name, err) in cases {
+            insta::assert_json_snapshot!(name, response(err).await);
+        }
+    }
+
+    #[tokio::test]
+    async fn error_responses_carry_the_request_id() {
+        let res = REQUEST_ID
+            .scope(
+                "V1StGXR8_Z5jdHi6B-myT".to_owned(),
+                response(AppError::NotFound("abc123".to_owned())),
+            )
+            .await;
+        insta::assert_json_snapshot!(res);
+    }
+}
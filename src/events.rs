@@ -0,0 +1,344 @@
+//! Link events and the brokers they are published to through the outbox
+
+use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
+
+#[cfg(any(feature = "kafka", feature = "nats"))]
+use anyhow::Context as _;
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+#[cfg(feature = "kafka")]
+use rdkafka::{
+    producer::{FutureProducer, FutureRecord, Producer},
+    util::Timeout,
+    ClientConfig,
+};
+use serde::{Deserialize, Serialize};
+#[cfg(any(feature = "kafka", feature = "nats"))]
+use tracing::info;
+use tracing::warn;
+
+use crate::clicks::ClickEvent;
+use crate::config::EventsConfig;
+#[cfg(feature = "kafka")]
+use crate::config::KafkaConfig;
+#[cfg(feature = "nats")]
+use crate::config::NatsConfig;
+use crate::error::AppError;
+use crate::state::AppState;
+use crate::store::OutboxEntry;
+
+/// Published to the event sink, tagged as `"type": "link.created"`, `"type": "link.clicked"`
+/// or `"type": "link.click_spike"`. They are written to the outbox along with what they are
+/// about and delivered from there by [`AppState::relay_events`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub(crate) enum LinkEvent {
+    #[serde(rename = "link.created")]
+    Created {
+        id: String,
+        url: String,
+        tags: Vec<String>,
+        created_at: DateTime<Utc>,
+    },
+    #[serde(rename = "link.clicked")]
+    Clicked(ClickEvent),
+    /// clicks on `id` from a single address were marked as suspicious
+    #[serde(rename = "link.click_spike")]
+    ClickSpike {
+        id: String,
+        suspicious_clicks: i64,
+        detected_at: DateTime<Utc>,
+    },
+}
+
+/// Where [`LinkEvent`]s are published, one implementation per broker behind its own Cargo feature
+#[async_trait]
+pub(crate) trait EventSink: Send + Sync {
+    /// Queues `event`, the [`Delivery`] tells whether the broker took it
+    async fn publish(&self, event: &LinkEvent) -> Result<Delivery>;
+    /// Waits until everything published so far is delivered
+    async fn flush(&self) -> Result<()>;
+}
+
+/// Resolves once the broker has a published event, or refused it
+type Delivery = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// [`EventSink`] writing JSON messages keyed by link id, so the events of a link stay in order
+#[cfg(feature = "kafka")]
+struct KafkaSink {
+    producer: FutureProducer,
+    topic: String,
+}
+
+/// [`EventSink`] publishing JSON messages to one subject per event type
+#[cfg(feature = "nats")]
+struct NatsSink {
+    client: async_nats::Client,
+    subject: String,
+}
+
+/// events the relay picks up from the outbox at a time
+pub(crate) const OUTBOX_BATCH: i64 = 500;
+pub(crate) const OUTBOX_INTERVAL: Duration = Duration::from_secs(1);
+/// longest wait before an event that failed to deliver is tried again
+const OUTBOX_MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+#[cfg(any(feature = "kafka", feature = "nats"))]
+const EVENT_FLUSH_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl AppState {
+    /// The `link.created` event that goes to the outbox with link `id`, when there is a sink
+    pub(crate) fn created_event(&self, id: &str, url: &str, tags: &[String]) -> Option<LinkEvent> {
+        self.events.as_ref().map(|_| LinkEvent::Created {
+            id: id.to_owned(),
+            url: url.to_owned(),
+            tags: tags.to_vec(),
+            created_at: Utc::now(),
+        })
+    }
+
+    /// Delivers a batch of due events from the outbox and removes them, the ones that fail are
+    /// tried again later on. Returns how many events the batch had.
+    pub(crate) async fn relay_events(&self) -> Result<usize, AppError> {
+        let pending = self.store.pending_events(OUTBOX_BATCH).await?;
+        let mut delivered = Vec::with_capacity(pending.len());
+        let mut deliveries = Vec::with_capacity(pending.len());
+        for entry in &pending {
+            let event: LinkEvent = match serde_json::from_str(&entry.payload) {
+                Ok(event) => event,
+                Err(e) => {
+                    warn!("Dropping event {} from the outbox: {}", entry.id, e);
+                    delivered.push(entry.id);
+                    continue;
+                }
+            };
+            match self.deliver(&event).await {
+                Ok(delivery) => deliveries.push((entry, delivery)),
+                Err(e) => self.retry_event(entry, e).await?,
+            }
+        }
+        if let Some(sink) = &self.events {
+            sink.flush().await.map_err(AppError::InternalServer)?;
+        }
+        // queued events only count as delivered once the broker has them
+        for (entry, delivery) in deliveries {
+            match delivery.await {
+                Ok(()) => delivered.push(entry.id),
+                Err(e) => self.retry_event(entry, e).await?,
+            }
+        }
+        self.store.delete_events(&delivered).await?;
+        Ok(pending.len())
+    }
+
+    /// Hands `event` to the sink, and click spikes to the webhook too
+    async fn deliver(&self, event: &LinkEvent) -> Result<Delivery> {
+        let delivery = match &self.events {
+            Some(sink) => sink.publish(event).await?,
+            None => Box::pin(std::future::ready(Ok(()))),
+        };
+        if let (LinkEvent::ClickSpike { .. }, Some(webhook)) =
+            (event, &self.config.features.click_fraud_webhook)
+        {
+            self.http
+                .post(webhook.clone())
+                .json(event)
+                .send()
+                .await
+                .and_then(|res| res.error_for_status())?;
+        }
+        Ok(delivery)
+    }
+
+    /// Leaves `entry` in the outbox for a later try, backing off with every failed one
+    async fn retry_event(&self, entry: &OutboxEntry, e: anyhow::Error) -> Result<(), AppError> {
+        warn!("Failed to deliver event {}: {:?}", entry.id, e);
+        let backoff = OUTBOX_INTERVAL
+            .saturating_mul(2u32.saturating_pow(entry.attempts.clamp(0, 31) as u32))
+            .min(OUTBOX_MAX_BACKOFF);
+        self.store.retry_event(entry.id, Utc::now() + backoff).await
+    }
+}
+
+impl LinkEvent {
+    #[cfg(feature = "kafka")]
+    fn link_id(&self) -> &str {
+        match self {
+            LinkEvent::Created { id, .. } | LinkEvent::ClickSpike { id, .. } => id,
+            LinkEvent::Clicked(click) => &click.url_id,
+        }
+    }
+
+    /// The `type` it is tagged with
+    #[cfg(feature = "nats")]
+    fn kind(&self) -> &'static str {
+        match self {
+            LinkEvent::Created { .. } => "link.created",
+            LinkEvent::Clicked(_) => "link.clicked",
+            LinkEvent::ClickSpike { .. } => "link.click_spike",
+        }
+    }
+}
+
+pub(crate) async fn event_sink(config: &EventsConfig) -> Result<Option<Arc<dyn EventSink>>> {
+    match (&config.kafka, &config.nats) {
+        (Some(_), Some(_)) => anyhow::bail!("Set either events.kafka or events.nats, not both"),
+        #[cfg(feature = "kafka")]
+        (Some(kafka), None) => Ok(Some(Arc::new(KafkaSink::new(kafka)?))),
+        #[cfg(not(feature = "kafka"))]
+        (Some(_), None) => {
+            anyhow::bail!("events.kafka is set, but this build has no Kafka support")
+        }
+        #[cfg(feature = "nats")]
+        (None, Some(nats)) => Ok(Some(Arc::new(NatsSink::connect(nats).await?))),
+        #[cfg(not(feature = "nats"))]
+        (None, Some(_)) => anyhow::bail!("events.nats is set, but this build has no NATS support"),
+        (None, None) => Ok(None),
+    }
+}
+
+#[cfg(feature = "kafka")]
+impl KafkaSink {
+    fn new(config: &KafkaConfig) -> Result<Self> {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", &config.brokers)
+            .create()
+            .context("Failed to create Kafka producer")?;
+        info!("Publishing events to Kafka topic {}", config.topic);
+        Ok(Self {
+            producer,
+            topic: config.topic.clone(),
+        })
+    }
+}
+
+#[cfg(feature = "kafka")]
+#[async_trait]
+impl EventSink for KafkaSink {
+    async fn publish(&self, event: &LinkEvent) -> Result<Delivery> {
+        let payload = serde_json::to_vec(event)?;
+        let record = FutureRecord::to(&self.topic)
+            .key(event.link_id())
+            .payload(&payload);
+        // only queues the message, waiting for each delivery in turn would cap the throughput
+        let delivery = self.producer.send_result(record).map_err(|(e, _)| e)?;
+        Ok(Box::pin(async move {
+            match delivery.await {
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err((e, _))) => Err(anyhow::Error::from(e).context("Kafka refused the event")),
+                Err(_) => anyhow::bail!("The Kafka producer dropped the event"),
+            }
+        }))
+    }
+
+    async fn flush(&self) -> Result<()> {
+        let producer = self.producer.clone();
+        tokio::task::spawn_blocking(move || producer.flush(Timeout::After(EVENT_FLUSH_TIMEOUT)))
+            .await??;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "nats")]
+impl NatsSink {
+    async fn connect(config: &NatsConfig) -> Result<Self> {
+        // keeps reconnecting in the background, a NATS outage doesn't stop the shortener
+        let client = async_nats::ConnectOptions::new()
+            .retry_on_initial_connect()
+            .connect(config.url.as_str())
+            .await
+            .context("Failed to connect to NATS")?;
+        info!("Publishing events to NATS subjects {}.*", config.subject);
+        Ok(Self {
+            client,
+            subject: config.subject.clone(),
+        })
+    }
+}
+
+#[cfg(feature = "nats")]
+#[async_trait]
+impl EventSink for NatsSink {
+    async fn publish(&self, event: &LinkEvent) -> Result<Delivery> {
+        let payload = serde_json::to_vec(event)?;
+        let subject = format!("{}.{}", self.subject, event.kind());
+        self.client.publish(subject, payload.into()).await?;
+        // core NATS doesn't acknowledge, the flush is all there is to wait for
+        Ok(Box::pin(std::future::ready(Ok(()))))
+    }
+
+    async fn flush(&self) -> Result<()> {
+        tokio::time::timeout(EVENT_FLUSH_TIMEOUT, self.client.flush())
+            .await
+            .context("Timed out flushing events to NATS")??;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    };
+
+    use super::*;
+    use crate::api::ShortenRequest;
+    use crate::state::tests::state_with;
+
+    /// Keeps what it's handed, failing everything while `down` and refusing the deliveries
+    /// while `refusing`
+    #[derive(Default)]
+    struct RecordingSink {
+        events: Mutex<Vec<LinkEvent>>,
+        down: AtomicBool,
+        refusing: AtomicBool,
+    }
+
+    #[async_trait]
+    impl EventSink for RecordingSink {
+        async fn publish(&self, event: &LinkEvent) -> Result<Delivery> {
+            anyhow::ensure!(!self.down.load(Ordering::Relaxed), "broker is down");
+            if self.refusing.load(Ordering::Relaxed) {
+                return Ok(Box::pin(async { anyhow::bail!("message too large") }));
+            }
+            self.events.lock().unwrap().push(event.clone());
+            Ok(Box::pin(std::future::ready(Ok(()))))
+        }
+
+        async fn flush(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn created_links_reach_the_sink_through_the_outbox() {
+        let mut state = state_with("", Arc::default()).await;
+        let sink = Arc::new(RecordingSink::default());
+        state.events = Some(sink.clone());
+        let req = ShortenRequest {
+            url: "https://example.com/launch".to_owned(),
+            tags: vec!["launch".to_owned()],
+            ..ShortenRequest::default()
+        };
+        let id = state.create_link(&req, None, None).await.unwrap();
+        assert!(sink.events.lock().unwrap().is_empty());
+
+        // stays in the outbox until the broker takes it
+        sink.down.store(true, Ordering::Relaxed);
+        assert_eq!(state.relay_events().await.unwrap(), 1);
+        sink.down.store(false, Ordering::Relaxed);
+        sink.refusing.store(true, Ordering::Relaxed);
+        assert_eq!(state.relay_events().await.unwrap(), 1);
+        sink.refusing.store(false, Ordering::Relaxed);
+        assert_eq!(state.relay_events().await.unwrap(), 1);
+        assert_eq!(state.relay_events().await.unwrap(), 0);
+
+        let events = sink.events.lock().unwrap();
+        assert!(matches!(
+            events.as_slice(),
+            [LinkEvent::Created { id: created, tags, .. }] if *created == id && tags == &["launch"]
+        ));
+    }
+}
@@ -0,0 +1,44 @@
+//! Entry points for the targets in `fuzz/`, not a stable API
+
+use url::Url;
+
+use crate::api::ShortenRequest;
+use crate::config::TrailingSlash;
+use crate::validate::{
+    display_url, normalize_url, storable_url, strip_tracking_params, validate_alias, validate_tags,
+    validate_url, Blocklist,
+};
+
+/// Everything `POST /` does with its body before the network and the database are involved.
+/// `data` is the JSON body, or the destination itself when it isn't one.
+pub fn shorten_input(data: &[u8]) {
+    let req = serde_json::from_slice::<ShortenRequest>(data).unwrap_or_else(|_| ShortenRequest {
+        url: String::from_utf8_lossy(data).into_owned(),
+        ..Default::default()
+    });
+    if let Some(alias) = &req.alias {
+        if validate_alias(alias).is_ok() {
+            let short_url = format!("https://sho.rt/{}", alias);
+            assert_eq!(Url::parse(&short_url).unwrap().as_str(), short_url);
+        }
+    }
+    let _ = validate_tags(&req.tags);
+
+    let Ok(mut url) = validate_url(&req.url) else {
+        return;
+    };
+    if req.strip_tracking.unwrap_or(true) {
+        strip_tracking_params(&mut url);
+    }
+    let blocklist = Blocklist::default();
+    blocklist.insert("example.com".to_owned());
+    if blocklist.check(&url).is_err() {
+        return;
+    }
+    let Ok(stored) = storable_url(url, TrailingSlash::default()) else {
+        return;
+    };
+    let reparsed = Url::parse(&stored).expect("stored URLs parse again");
+    assert_eq!(normalize_url(reparsed, TrailingSlash::default()), stored);
+    let _ = display_url(&stored);
+}
@@ -0,0 +1,158 @@
+//! The GraphQL API under `/graphql`
+
+use async_graphql::{
+    connection::{Connection, Edge},
+    EmptyMutation, EmptySubscription, ErrorExtensions, ResultExt as _,
+};
+use chrono::NaiveDate;
+
+use crate::api::{
+    Client, ExportedLink, Interval, LinkCursor, LinkSort, ListLinksQuery, Period, TimeseriesPoint,
+    TimeseriesQuery, TopLink, DEFAULT_PAGE_SIZE,
+};
+use crate::error::AppError;
+use crate::state::AppState;
+
+pub(crate) type GraphQlSchema =
+    async_graphql::Schema<GraphQlQuery, EmptyMutation, EmptySubscription>;
+
+/// Root of `/graphql`, which only reads
+pub(crate) struct GraphQlQuery;
+
+struct GraphQlLink(ExportedLink);
+
+/// Request data of `/graphql` calls made with the admin token
+pub(crate) struct Admin;
+
+/// Field-level auth for `/graphql`, guarded fields fail with `unauthorized` for everyone but
+/// admins while the rest of the query still resolves
+struct AdminGuard;
+
+#[async_graphql::Object]
+impl GraphQlQuery {
+    /// The link behind a short id, null if there is none
+    async fn link(
+        &self,
+        ctx: &async_graphql::Context<'_>,
+        id: String,
+    ) -> async_graphql::Result<Option<GraphQlLink>> {
+        match ctx.data_unchecked::<AppState>().link(&id).await {
+            Ok(link) => Ok(Some(GraphQlLink(link))),
+            Err(AppError::NotFound(_) | AppError::Gone(_)) => Ok(None),
+            Err(e) => Err(e.extend()),
+        }
+    }
+
+    /// Every link newest first, paged with `first` and `after`
+    #[graphql(guard = "AdminGuard")]
+    async fn links(
+        &self,
+        ctx: &async_graphql::Context<'_>,
+        after: Option<String>,
+        first: Option<i32>,
+    ) -> async_graphql::Result<Connection<String, GraphQlLink>> {
+        let limit = first.map_or(DEFAULT_PAGE_SIZE, i64::from);
+        let after = after
+            .map(LinkCursor::try_from)
+            .transpose()
+            .map_err(|e| AppError::validation("after", e).extend())?;
+        let query = ListLinksQuery {
+            after,
+            limit,
+            ..Default::default()
+        };
+        let (links, next) = ctx
+            .data_unchecked::<AppState>()
+            .list_links(&query)
+            .await
+            .extend()?;
+        let mut connection = Connection::new(query.after.is_some(), next.is_some());
+        connection
+            .edges
+            .extend(links.into_iter().filter_map(|link| {
+                let cursor = LinkCursor::after(&link, LinkSort::CreatedAt)?;
+                Some(Edge::new(cursor.to_string(), GraphQlLink(link)))
+            }));
+        Ok(connection)
+    }
+
+    /// The most clicked links over `period`, such as `7d` or `4w`, ending today
+    #[graphql(guard = "AdminGuard")]
+    async fn top_links(
+        &self,
+        ctx: &async_graphql::Context<'_>,
+        #[graphql(default = "7d")] period: String,
+        #[graphql(default = 20)] limit: i64,
+        #[graphql(default)] exclude_bots: bool,
+    ) -> async_graphql::Result<Vec<TopLink>> {
+        let period =
+            Period::try_from(period).map_err(|e| AppError::validation("period", e).extend())?;
+        let (_, _, links) = ctx
+            .data_unchecked::<AppState>()
+            .top_links(period, limit, exclude_bots)
+            .await
+            .extend()?;
+        Ok(links)
+    }
+}
+
+#[async_graphql::Object(name = "Link")]
+impl GraphQlLink {
+    async fn id(&self) -> &str {
+        &self.0.id
+    }
+
+    async fn url(&self) -> &str {
+        &self.0.url
+    }
+
+    /// Under the address the request came in on
+    async fn short_url(&self, ctx: &async_graphql::Context<'_>) -> String {
+        let client = ctx.data_unchecked::<Client>();
+        ctx.data_unchecked::<AppState>()
+            .config
+            .short_url(&self.0.id, client)
+    }
+
+    /// Known to be malicious, the link no longer redirects
+    async fn flagged(&self) -> bool {
+        self.0.flagged
+    }
+
+    async fn tags(&self) -> &[String] {
+        &self.0.tags
+    }
+
+    /// Clicks per bucket between the `from` and `to` days, the last 30 days by default
+    #[graphql(guard = "AdminGuard")]
+    async fn clicks(
+        &self,
+        ctx: &async_graphql::Context<'_>,
+        #[graphql(default)] interval: Interval,
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+        #[graphql(default)] exclude_bots: bool,
+    ) -> async_graphql::Result<Vec<TimeseriesPoint>> {
+        let query = TimeseriesQuery {
+            interval,
+            from,
+            to,
+            exclude_bots,
+        };
+        let (from, to) = query.range().extend()?;
+        ctx.data_unchecked::<AppState>()
+            .store
+            .timeseries(&self.0.id, interval, from, to, exclude_bots)
+            .await
+            .extend()
+    }
+}
+
+impl async_graphql::Guard for AdminGuard {
+    async fn check(&self, ctx: &async_graphql::Context<'_>) -> async_graphql::Result<()> {
+        match ctx.data_opt::<Admin>() {
+            Some(_) => Ok(()),
+            None => Err(AppError::Unauthorized.extend()),
+        }
+    }
+}
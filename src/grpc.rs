@@ -0,0 +1,179 @@
+//! The gRPC API, see `proto/shortener.proto`
+
+use std::{net::Ipv4Addr, time::Duration};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::http::header::AUTHORIZATION;
+use chrono::NaiveDate;
+use tokio::{net::TcpListener, sync::watch};
+use tracing::{info, instrument, warn};
+
+use crate::api::{Client, Interval, ShortenRequest, TimeseriesQuery, Visibility};
+use crate::config::Scope;
+use crate::error::AppError;
+use crate::server::shutdown_signal;
+use crate::state::AppState;
+
+/// Generated from `proto/shortener.proto`
+mod proto {
+    tonic::include_proto!("shortener.v1");
+}
+
+/// `Links` gRPC service, a second way into the same [`AppState`] as the HTTP handlers
+struct GrpcLinks {
+    state: AppState,
+}
+
+pub(crate) async fn serve_grpc(
+    state: AppState,
+    listener: TcpListener,
+    timeout: Duration,
+) -> Result<()> {
+    info!("Listening for gRPC on {}", listener.local_addr()?);
+
+    let (draining, mut drain_started) = watch::channel(false);
+    let server = tonic::transport::Server::builder()
+        .add_service(proto::links_server::LinksServer::new(GrpcLinks { state }))
+        .serve_with_incoming_shutdown(
+            tonic::transport::server::TcpIncoming::from(listener),
+            async move {
+                shutdown_signal().await;
+                draining.send_replace(true);
+            },
+        );
+    let deadline = async move {
+        let _ = drain_started.wait_for(|draining| *draining).await;
+        tokio::time::sleep(timeout).await;
+    };
+    tokio::select! {
+        ret = server => ret?,
+        _ = deadline => warn!("gRPC calls still running after {:?}, shutting down anyway", timeout),
+    }
+
+    Ok(())
+}
+
+#[async_trait]
+impl proto::links_server::Links for GrpcLinks {
+    #[instrument(skip(self))]
+    async fn shorten(
+        &self,
+        request: tonic::Request<proto::ShortenRequest>,
+    ) -> Result<tonic::Response<proto::ShortenResponse>, tonic::Status> {
+        self.state.check_maintenance(false)?;
+        let client = Client {
+            ip: request
+                .remote_addr()
+                .map_or(Ipv4Addr::LOCALHOST.into(), |addr| addr.ip()),
+            forwarded_base: None,
+        };
+        // the same budget as `POST /`, or this would be the way around it
+        self.state
+            .shorten_limiter
+            .check(client.ip)
+            .map_err(AppError::TooManyRequests)?;
+        let request = request.into_inner();
+        let id = self
+            .state
+            .create_link(
+                &ShortenRequest {
+                    url: request.url,
+                    alias: request.alias,
+                    strip_tracking: request.strip_tracking,
+                    tags: request.tags,
+                    rotate: request.rotate,
+                    visibility: request.private.map(Visibility::from),
+                    forward_query: None,
+                    cache_control: None,
+                },
+                Some(client.ip),
+                None,
+            )
+            .await?;
+        Ok(tonic::Response::new(proto::ShortenResponse {
+            short_url: self.state.config.short_url(&id, &client),
+            id,
+        }))
+    }
+
+    #[instrument(skip(self))]
+    async fn resolve(
+        &self,
+        request: tonic::Request<proto::ResolveRequest>,
+    ) -> Result<tonic::Response<proto::ResolveResponse>, tonic::Status> {
+        self.state.check_maintenance(true)?;
+        let (_, url) = self
+            .state
+            .destination(&request.into_inner().id, None)
+            .await?;
+        Ok(tonic::Response::new(proto::ResolveResponse { url }))
+    }
+
+    #[instrument(skip(self))]
+    async fn delete(
+        &self,
+        request: tonic::Request<proto::DeleteRequest>,
+    ) -> Result<tonic::Response<proto::DeleteResponse>, tonic::Status> {
+        self.state
+            .check_scope(grpc_bearer_token(&request), Scope::LinksWrite)?;
+        self.state.check_maintenance(false)?;
+        self.state
+            .delete_link(&request.into_inner().id, None)
+            .await?;
+        Ok(tonic::Response::new(proto::DeleteResponse {}))
+    }
+
+    #[instrument(skip(self))]
+    async fn stats(
+        &self,
+        request: tonic::Request<proto::StatsRequest>,
+    ) -> Result<tonic::Response<proto::StatsResponse>, tonic::Status> {
+        self.state.check_maintenance(true)?;
+        let token = grpc_bearer_token(&request).map(str::to_owned);
+        let request = request.into_inner();
+        let query = TimeseriesQuery {
+            interval: Interval::Day,
+            from: parse_day("from", request.from.as_deref())?,
+            to: parse_day("to", request.to.as_deref())?,
+            exclude_bots: false,
+        };
+        let (from, to) = query.range()?;
+        self.state
+            .check_stats_access(&request.id, token.as_deref())
+            .await?;
+
+        let points = self
+            .state
+            .store
+            .timeseries(&request.id, Interval::Day, from, to, query.exclude_bots)
+            .await?;
+        Ok(tonic::Response::new(proto::StatsResponse {
+            total_clicks: points.iter().map(|point| point.clicks).sum(),
+            days: points
+                .into_iter()
+                .map(|point| proto::DailyClicks {
+                    day: point.start.date_naive().to_string(),
+                    clicks: point.clicks,
+                })
+                .collect(),
+        }))
+    }
+}
+
+fn parse_day(field: &'static str, day: Option<&str>) -> Result<Option<NaiveDate>, AppError> {
+    day.map(|day| {
+        day.parse()
+            .map_err(|_| AppError::validation(field, "expected a day such as 2024-08-31"))
+    })
+    .transpose()
+}
+
+/// The token of an `authorization: Bearer <token>` metadata entry
+fn grpc_bearer_token<T>(request: &tonic::Request<T>) -> Option<&str> {
+    request
+        .metadata()
+        .get(AUTHORIZATION.as_str())
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
@@ -0,0 +1,2811 @@
+//! HTTP routes, their handlers and the middleware around them
+
+mod bundles;
+mod ui;
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+#[cfg(feature = "qr")]
+use anyhow::Context as _;
+use anyhow::Result;
+#[cfg(feature = "graphql")]
+use async_graphql::{EmptyMutation, EmptySubscription};
+use axum::{
+    error_handling::HandleErrorLayer,
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        ConnectInfo, Extension, FromRequest, FromRequestParts, MatchedPath, Path, Query, RawQuery,
+        Request, State,
+    },
+    http::{
+        header::{
+            ACCEPT, AUTHORIZATION, CACHE_CONTROL, CONTENT_LENGTH, CONTENT_SECURITY_POLICY,
+            CONTENT_TYPE, ETAG, EXPIRES, FORWARDED, HOST, IF_NONE_MATCH, LOCATION, REFERER,
+            REFERRER_POLICY, STRICT_TRANSPORT_SECURITY, USER_AGENT, VARY, X_CONTENT_TYPE_OPTIONS,
+        },
+        HeaderMap, HeaderName, HeaderValue, Method, StatusCode,
+    },
+    middleware::{self, Next},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::{delete, get, post},
+    BoxError, Json, Router,
+};
+#[cfg(feature = "qr")]
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::{DateTime, TimeDelta, Utc};
+#[cfg(feature = "qr")]
+use qrcode::QrCode;
+use rust_embed::{EmbeddedFile, RustEmbed};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use tokio_stream::{
+    wrappers::{errors::BroadcastStreamRecvError, BroadcastStream},
+    Stream, StreamExt,
+};
+use tower::{timeout::error::Elapsed, ServiceBuilder};
+use tower_http::trace::TraceLayer;
+use tracing::{info, info_span, instrument, warn, Span};
+use url::Url;
+
+use crate::api::{
+    AliasRequest, BreakdownQuery, BulkStatsRequest, Client, CountriesResponse, LinkCursor,
+    LinkQuery, ListLinksQuery, ListLinksResponse, Maintenance, PreviewResponse, ReferrersResponse,
+    ReportRequest, ShortenRequest, ShortenResponse, TimeseriesQuery, TimeseriesResponse,
+    TopLinksQuery, TopLinksResponse,
+};
+use crate::clicks::anonymize_ip;
+use crate::config::{ListenerConfig, Scope};
+use crate::error::AppError;
+#[cfg(feature = "graphql")]
+use crate::graphql::{Admin, GraphQlQuery, GraphQlSchema};
+use crate::jobs::JobStatus;
+use crate::state::{AppState, OPEN_REPORTS_LIMIT, SCANNERS_LIMIT};
+use crate::store::PoolStats;
+use crate::telemetry::FeedEvent;
+use crate::validate::{display_url, normalize_domain};
+use bundles::{
+    bundle_page, create_bundle, delete_bundle, directory_page, get_bundle, gone_page, list_bundles,
+    update_bundle,
+};
+use ui::{
+    require_session, ui_block_domain, ui_blocklist, ui_create_link, ui_delete_link,
+    ui_disable_link, ui_disable_reported, ui_dismiss_reports, ui_edit_form, ui_edit_link,
+    ui_enable_link, ui_link_stats, ui_links, ui_login, ui_login_form, ui_logout, ui_reports,
+    ui_unblock_domain,
+};
+
+#[cfg(feature = "qr")]
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum QrFormat {
+    #[default]
+    Svg,
+    Png,
+}
+
+/// Query of `POST /`, the QR code comes as the body instead with `Accept: image/png`
+#[cfg(feature = "qr")]
+#[derive(Debug, Deserialize)]
+struct ShortenQuery {
+    #[serde(default)]
+    include_qr: bool,
+    #[serde(default = "default_qr_size")]
+    qr_size: u32,
+}
+
+#[cfg(feature = "qr")]
+#[derive(Debug, Deserialize)]
+pub(crate) struct QrQuery {
+    #[serde(default)]
+    pub(crate) format: QrFormat,
+    /// minimum width and height in pixels, the code is scaled by whole modules
+    #[serde(default = "default_qr_size")]
+    size: u32,
+}
+
+tokio::task_local! {
+    /// id of the request currently being handled, set by [`propagate_request_id`]
+    pub(crate) static REQUEST_ID: String;
+    /// how the current request wants its response body, set by [`negotiate_format`]
+    static RESPONSE_FORMAT: ResponseFormat;
+}
+
+/// Body format picked from the `Accept` header
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum ResponseFormat {
+    #[default]
+    Json,
+    MessagePack,
+}
+
+/// Response body in the [`ResponseFormat`] of the current request, JSON unless MessagePack was
+/// asked for
+pub(crate) struct AppResponse<T>(pub(crate) T);
+
+/// `Json` extractor that reports bad bodies through [`AppError`]
+#[derive(FromRequest)]
+#[from_request(via(Json), rejection(AppError))]
+pub(crate) struct AppJson<T>(T);
+
+/// `Query` extractor that reports bad query strings through [`AppError`]
+#[derive(FromRequestParts)]
+#[from_request(via(Query), rejection(AppError))]
+pub(crate) struct AppQuery<T>(T);
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct FeedQuery {
+    /// comma separated link ids or aliases, only what happens to these links is counted
+    pub(crate) links: Option<String>,
+    /// comma separated tags, links with any of them are counted along with `links`
+    pub(crate) tags: Option<String>,
+}
+
+/// Which links a dashboard feed follows, when it doesn't follow all of them
+#[derive(Debug)]
+pub(crate) struct FeedFilter {
+    links: HashSet<String>,
+    tags: HashSet<String>,
+    /// whether the ids and aliases in recent events are followed, so each is looked up once
+    seen: HashMap<String, bool>,
+    seen_since: Instant,
+}
+
+/// Link request made through one of the `/:id` routes, for [`count_requests`]
+#[derive(Debug, Clone)]
+struct LinkRequest(String);
+
+/// One sample of the dashboard feed, counts cover the time since the previous sample
+#[derive(Debug, Serialize)]
+pub(crate) struct FeedSample {
+    at: DateTime<Utc>,
+    redirects_per_sec: f64,
+    new_links: u64,
+    pub(crate) requests: u64,
+    pub(crate) errors: u64,
+    error_rate: f64,
+    /// events a filtered feed fell behind on, its counts are short by as many
+    missed_events: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct StatusResponse {
+    pub(crate) version: &'static str,
+    git_sha: &'static str,
+    uptime_secs: u64,
+    pub(crate) database: DatabaseStatus,
+    /// clicks waiting to be written
+    clicks_buffered: usize,
+    pub(crate) maintenance: bool,
+    pub(crate) jobs: BTreeMap<&'static str, JobStatus>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct DatabaseStatus {
+    pub(crate) ok: bool,
+    latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pool: Option<PoolStats>,
+}
+
+/// One hop of a `Forwarded` or `X-Forwarded-For` chain
+#[derive(Debug, Default)]
+pub(crate) struct ForwardedHop {
+    /// `None` for `unknown` and obfuscated identifiers
+    pub(crate) ip: Option<IpAddr>,
+    pub(crate) proto: Option<String>,
+    pub(crate) host: Option<String>,
+}
+
+/// [`ListLinksResponse`] with only the `fields` asked for of each link
+#[derive(Debug, Serialize)]
+pub(crate) struct SparseLinksResponse {
+    links: Vec<serde_json::Value>,
+    next: Option<LinkCursor>,
+}
+
+/// Settings in effect after a reload
+#[derive(Debug, Serialize)]
+pub(crate) struct ReloadResponse {
+    pub(crate) log_level: String,
+    pub(crate) shorten_requests: u32,
+    pub(crate) shorten_window_secs: u64,
+    pub(crate) geoip_db: Option<PathBuf>,
+    pub(crate) blocked_domains: usize,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct BlockDomainRequest {
+    pub(crate) domain: String,
+    pub(crate) reason: Option<String>,
+}
+
+/// Requests still running after this are answered with a 408
+pub(crate) const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const DASHBOARD_FEED_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a filtered feed trusts its lookups, so links tagged meanwhile are picked up
+const FEED_FILTER_TTL: Duration = Duration::from_secs(60);
+const FEED_FILTER_MAX_SEEN: usize = 10_000;
+
+/// Keeps a single `/graphql` query from fanning out into thousands of lookups
+#[cfg(feature = "graphql")]
+const GRAPHQL_MAX_DEPTH: usize = 8;
+#[cfg(feature = "graphql")]
+const GRAPHQL_MAX_COMPLEXITY: usize = 500;
+#[cfg(feature = "qr")]
+const DEFAULT_QR_SIZE: u32 = 256;
+#[cfg(feature = "qr")]
+const MAX_QR_SIZE: u32 = 2048;
+
+/// IMF-fixdate, the one date format HTTP headers should use
+const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// How long `/status` waits for the database before reporting it as down
+const STATUS_DB_TIMEOUT: Duration = Duration::from_secs(2);
+
+const MSGPACK_CONTENT_TYPES: &[&str] = &["application/msgpack", "application/x-msgpack"];
+const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// All HTTP routes with their middleware, the same on every listener
+pub fn build_router(state: AppState) -> Router {
+    let stats = Router::new()
+        .route("/stats/top", get(top_links))
+        .route("/stats/bulk", post(bulk_stats))
+        .route("/events/clicks", get(click_events))
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), Scope::StatsRead),
+            require_scope,
+        ));
+    let aliases = Router::new()
+        .route("/links/:id/aliases", post(add_alias))
+        .route("/links/:id/aliases/:alias", delete(remove_alias))
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), Scope::LinksWrite),
+            require_scope,
+        ));
+    let api = Router::new()
+        .route("/links", get(list_links))
+        .route("/links/:id", get(link_details))
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), Scope::LinksRead),
+            require_scope,
+        ))
+        .merge(stats)
+        .merge(aliases);
+
+    let feed = Router::new().route("/ws", get(dashboard_feed)).route_layer(
+        middleware::from_fn_with_state((state.clone(), Scope::StatsRead), require_scope),
+    );
+
+    let manage_links = Router::new()
+        .route("/links/:id", delete(delete_link))
+        .route("/bundles", get(list_bundles).post(create_bundle))
+        .route(
+            "/bundles/:id",
+            get(get_bundle).put(update_bundle).delete(delete_bundle),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), Scope::LinksWrite),
+            require_scope,
+        ));
+    let operate = Router::new()
+        .route("/reload", post(reload_config))
+        .route("/maintenance", get(get_maintenance).put(set_maintenance))
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), Scope::Admin),
+            require_scope,
+        ));
+    let admin = Router::new()
+        .route("/blocklist", get(list_blocked).post(block_domain))
+        .route("/blocklist/:domain", delete(unblock_domain))
+        .route("/reports", get(list_reports))
+        .route("/reports/:id/disable", post(disable_reported))
+        .route("/reports/:id/dismiss", post(dismiss_reports))
+        .route("/scanners", get(list_scanners))
+        .route("/scanners/:ip", delete(forget_scanner))
+        .route_layer(middleware::from_fn_with_state(
+            (state.clone(), Scope::Admin),
+            require_scope,
+        ))
+        .merge(manage_links)
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            check_maintenance,
+        ))
+        .merge(operate);
+
+    let ui = Router::new()
+        .route("/", get(ui_links))
+        .route("/links", post(ui_create_link))
+        .route("/links/:id", get(ui_link_stats).post(ui_edit_link))
+        .route("/links/:id/edit", get(ui_edit_form))
+        .route("/links/:id/delete", post(ui_delete_link))
+        .route("/links/:id/disable", post(ui_disable_link))
+        .route("/links/:id/enable", post(ui_enable_link))
+        .route("/reports", get(ui_reports))
+        .route("/reports/:id/disable", post(ui_disable_reported))
+        .route("/reports/:id/dismiss", post(ui_dismiss_reports))
+        .route("/blocklist", get(ui_blocklist).post(ui_block_domain))
+        .route("/blocklist/:domain/delete", post(ui_unblock_domain))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            require_session,
+        ))
+        .route("/login", get(ui_login_form).post(ui_login))
+        .route("/logout", post(ui_logout));
+
+    let graphql = Router::new();
+    #[cfg(feature = "graphql")]
+    let graphql = graphql
+        .route("/graphql", get(graphiql).post(graphql_request))
+        .layer(Extension(graphql_schema(state.clone())));
+
+    let links = Router::new()
+        .route(
+            "/:id/report",
+            post(report_link).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                limit_shorten_rate,
+            )),
+        )
+        .route("/:id", get(redirect))
+        .route("/:id/preview", get(preview))
+        .route("/:id/stats/timeseries", get(timeseries))
+        .route("/:id/stats/referrers", get(referrers))
+        .route("/:id/stats/countries", get(countries));
+    #[cfg(feature = "qr")]
+    let links = links.route("/:id/qr", get(qr_code));
+    let links = links
+        .route_layer(middleware::from_fn(mark_link_request))
+        // ids are at least 3 characters, so `b` is never a link
+        .route("/b/:id", get(bundle_page))
+        .route("/directory", get(directory_page));
+
+    let router = Router::new()
+        .route(
+            "/",
+            post(shorten).route_layer(middleware::from_fn_with_state(
+                state.clone(),
+                limit_shorten_rate,
+            )),
+        )
+        .merge(links)
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            check_maintenance,
+        ))
+        .route("/status", get(status))
+        .route("/static/*path", get(static_asset))
+        .merge(graphql)
+        .nest("/api", api)
+        .merge(feed)
+        .nest("/admin", admin)
+        .nest("/ui", ui)
+        .layer(
+            ServiceBuilder::new()
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    set_security_headers,
+                ))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    resolve_client,
+                ))
+                .layer(middleware::from_fn_with_state(state.clone(), log_access))
+                .layer(middleware::from_fn(propagate_request_id))
+                .layer(middleware::from_fn(negotiate_format))
+                .layer(middleware::from_fn_with_state(
+                    state.clone(),
+                    count_requests,
+                ))
+                .layer(middleware::from_fn_with_state(state.clone(), trap_scanners))
+                .layer(middleware::from_fn_with_state(state.clone(), shed_load))
+                .layer(
+                    TraceLayer::new_for_http()
+                        .make_span_with(make_request_span)
+                        .on_request(())
+                        .on_response(log_response),
+                )
+                .layer(HandleErrorLayer::new(handle_timeout_error))
+                .timeout(REQUEST_TIMEOUT),
+        )
+        .with_state(state);
+    #[cfg(feature = "sentry")]
+    let router = router.layer(
+        ServiceBuilder::new()
+            .layer(sentry::integrations::tower::NewSentryLayer::<Request>::new_from_top())
+            .layer(sentry::integrations::tower::SentryHttpLayer::new().enable_transaction()),
+    );
+
+    router
+}
+
+#[cfg(feature = "graphql")]
+fn graphql_schema(state: AppState) -> GraphQlSchema {
+    async_graphql::Schema::build(GraphQlQuery, EmptyMutation, EmptySubscription)
+        .data(state)
+        .limit_depth(GRAPHQL_MAX_DEPTH)
+        .limit_complexity(GRAPHQL_MAX_COMPLEXITY)
+        .finish()
+}
+
+/// GraphiQL, to try out queries in the browser
+#[cfg(feature = "graphql")]
+async fn graphiql() -> impl IntoResponse {
+    axum::response::Html(
+        async_graphql::http::GraphiQLSource::build()
+            .endpoint("/graphql")
+            .finish(),
+    )
+}
+
+#[cfg(feature = "graphql")]
+async fn graphql_request(
+    State(state): State<AppState>,
+    Extension(schema): Extension<GraphQlSchema>,
+    Extension(client): Extension<Client>,
+    headers: HeaderMap,
+    AppJson(request): AppJson<async_graphql::Request>,
+) -> Result<impl IntoResponse, AppError> {
+    state.check_maintenance(true)?;
+    let mut request = request.data(client);
+    if state
+        .check_scope(bearer_token(&headers), Scope::Admin)
+        .is_ok()
+    {
+        request = request.data(Admin);
+    }
+    Ok(Json(schema.execute(request).await))
+}
+
+#[instrument(skip_all)]
+async fn shorten(
+    State(state): State<AppState>,
+    Extension(client): Extension<Client>,
+    #[cfg(feature = "qr")] AppQuery(query): AppQuery<ShortenQuery>,
+    #[cfg(feature = "qr")] headers: HeaderMap,
+    AppJson(data): AppJson<ShortenRequest>,
+) -> Result<Response, AppError> {
+    #[cfg(feature = "qr")]
+    check_qr_size("qr_size", query.qr_size)?;
+    let id = state
+        .create_link(&data, Some(client.ip), client.forwarded_base.as_ref())
+        .await?;
+    let url = state.config.short_url(&id, &client);
+
+    #[cfg(feature = "qr")]
+    if accepts_png(&headers) {
+        let png = render_qr(&url, QrFormat::Png, query.qr_size)?;
+        let location = HeaderValue::from_str(&url).context("Short URL isn't a valid header")?;
+        return Ok((
+            StatusCode::CREATED,
+            [
+                (CONTENT_TYPE, HeaderValue::from_static("image/png")),
+                (LOCATION, location),
+            ],
+            png,
+        )
+            .into_response());
+    }
+    #[cfg(feature = "qr")]
+    let qr = if query.include_qr {
+        let png = render_qr(&url, QrFormat::Png, query.qr_size)?;
+        Some(format!("data:image/png;base64,{}", STANDARD.encode(png)))
+    } else {
+        None
+    };
+    #[cfg(not(feature = "qr"))]
+    let qr = None;
+
+    Ok((
+        StatusCode::CREATED,
+        AppResponse(ShortenResponse { url, qr }),
+    )
+        .into_response())
+}
+
+/// Whether `image/png` is asked for by name, wildcards don't count so browsers get JSON
+#[cfg(feature = "qr")]
+fn accepts_png(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|range| {
+            let mut params = range.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or_default();
+            media_type.eq_ignore_ascii_case("image/png")
+                && !params.any(|param| {
+                    param
+                        .strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .is_some_and(|q| q == 0.0)
+                })
+        })
+}
+
+#[instrument(skip(state, headers))]
+async fn redirect(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Extension(client): Extension<Client>,
+    RawQuery(query): RawQuery,
+    headers: HeaderMap,
+) -> Result<Response, AppError> {
+    let (record, url) = match state.destination(&id, query.as_deref()).await {
+        Err(AppError::Gone(tombstone)) if accepts_html(&headers) => {
+            return Ok(gone_page(&tombstone))
+        }
+        ret => ret?,
+    };
+    let destination =
+        HeaderValue::from_str(&url).map_err(|_| AppError::InvalidDestination(record.id.clone()))?;
+
+    state.metrics.publish(FeedEvent::Redirect {
+        id: record.id.clone(),
+    });
+    let location = state.geoip.lookup(client.ip);
+    state
+        .clicks
+        .record(record.id, client.ip, &headers, location);
+    state.metrics.redirects.fetch_add(1, Ordering::Relaxed);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(LOCATION, destination);
+    // a rotating link has to be asked again for the next destination
+    if record.rotating {
+        insert_cache_headers(&mut headers, "no-store");
+        return Ok((StatusCode::TEMPORARY_REDIRECT, headers).into_response());
+    }
+    let cache_control = record.cache_control.as_deref().or(state
+        .config
+        .features
+        .redirect_cache_control
+        .as_deref());
+    if let Some(cache_control) = cache_control {
+        insert_cache_headers(&mut headers, cache_control);
+    }
+    Ok((StatusCode::PERMANENT_REDIRECT, headers).into_response())
+}
+
+/// Whether the client is a browser that would rather see a page than JSON
+fn accepts_html(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|range| range.trim().starts_with("text/html"))
+}
+
+/// `cache_control` along with an `Expires` to match for caches that only know that one, in the
+/// past for `no-store` and `no-cache`
+fn insert_cache_headers(headers: &mut HeaderMap, cache_control: &str) {
+    let Ok(value) = HeaderValue::from_str(cache_control) else {
+        return;
+    };
+    headers.insert(CACHE_CONTROL, value);
+
+    let directives: Vec<_> = cache_control
+        .split(',')
+        .map(|directive| directive.trim().to_ascii_lowercase())
+        .collect();
+    let expires = if directives
+        .iter()
+        .any(|directive| directive == "no-store" || directive == "no-cache")
+    {
+        Some(DateTime::UNIX_EPOCH)
+    } else {
+        directives.iter().find_map(|directive| {
+            let secs = directive.strip_prefix("max-age=")?.parse().ok()?;
+            Utc::now().checked_add_signed(TimeDelta::try_seconds(secs)?)
+        })
+    };
+    if let Some(expires) = expires {
+        let date = expires.format(HTTP_DATE_FORMAT).to_string();
+        headers.insert(
+            EXPIRES,
+            HeaderValue::from_str(&date).expect("dates are valid header values"),
+        );
+    }
+}
+
+#[instrument(skip(state))]
+async fn preview(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Extension(client): Extension<Client>,
+) -> Result<impl IntoResponse, AppError> {
+    let record = state.get_url(&id).await?;
+    let display_url = display_url(&record.url);
+    Ok(AppResponse(PreviewResponse {
+        short_url: state.config.short_url(&id, &client),
+        id,
+        url: record.url,
+        display_url,
+        flagged: record.flagged,
+    }))
+}
+
+/// QR code of the short link, so printed codes keep working if the destination changes
+#[cfg(feature = "qr")]
+#[instrument(skip(state))]
+async fn qr_code(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Extension(client): Extension<Client>,
+    AppQuery(query): AppQuery<QrQuery>,
+) -> Result<Response, AppError> {
+    check_qr_size("size", query.size)?;
+    if !state.store.link_exists(&id).await? {
+        return Err(AppError::NotFound(id));
+    }
+
+    let code = render_qr(
+        &state.config.short_url(&id, &client),
+        query.format,
+        query.size,
+    )?;
+    let content_type = match query.format {
+        QrFormat::Svg => "image/svg+xml",
+        QrFormat::Png => "image/png",
+    };
+    Ok(([(CONTENT_TYPE, content_type)], code).into_response())
+}
+
+#[cfg(feature = "qr")]
+fn check_qr_size(field: &'static str, size: u32) -> Result<(), AppError> {
+    if !(1..=MAX_QR_SIZE).contains(&size) {
+        return Err(AppError::validation(
+            field,
+            format!("must be between 1 and {}", MAX_QR_SIZE),
+        ));
+    }
+    Ok(())
+}
+
+/// QR code of `url` at least `size` pixels wide and high
+#[cfg(feature = "qr")]
+fn render_qr(url: &str, format: QrFormat, size: u32) -> Result<Vec<u8>, AppError> {
+    let code = QrCode::new(url).context("Failed to encode QR code")?;
+    match format {
+        QrFormat::Svg => Ok(code
+            .render::<qrcode::render::svg::Color>()
+            .min_dimensions(size, size)
+            .build()
+            .into_bytes()),
+        QrFormat::Png => {
+            let image = code
+                .render::<image::Luma<u8>>()
+                .min_dimensions(size, size)
+                .build();
+            let mut png = Vec::new();
+            image
+                .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+                .context("Failed to encode PNG")?;
+            Ok(png)
+        }
+    }
+}
+
+#[instrument(skip(state))]
+async fn timeseries(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    AppQuery(query): AppQuery<TimeseriesQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let (from, to) = query.range()?;
+    let id = state
+        .check_stats_access(&id, bearer_token(&headers))
+        .await?;
+
+    let points = state
+        .store
+        .timeseries(&id, query.interval, from, to, query.exclude_bots)
+        .await?;
+    Ok(AppResponse(TimeseriesResponse {
+        id,
+        interval: query.interval,
+        from,
+        to,
+        points,
+    }))
+}
+
+#[instrument(skip(state, client, data))]
+async fn report_link(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Extension(client): Extension<Client>,
+    AppJson(data): AppJson<ReportRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    state.report_link(&id, &data, &client).await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[instrument(skip(state))]
+async fn referrers(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    AppQuery(query): AppQuery<BreakdownQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let (from, to) = query.range()?;
+    let id = state
+        .check_stats_access(&id, bearer_token(&headers))
+        .await?;
+
+    let referrers = state
+        .store
+        .referrers(&id, from, to, query.exclude_bots)
+        .await?;
+    Ok(AppResponse(ReferrersResponse {
+        id,
+        from,
+        to,
+        referrers,
+    }))
+}
+
+#[instrument(skip(state))]
+async fn countries(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    AppQuery(query): AppQuery<BreakdownQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let (from, to) = query.range()?;
+    let id = state
+        .check_stats_access(&id, bearer_token(&headers))
+        .await?;
+
+    let countries = state
+        .store
+        .countries(&id, from, to, query.exclude_bots)
+        .await?;
+    Ok(AppResponse(CountriesResponse {
+        id,
+        from,
+        to,
+        countries,
+    }))
+}
+
+#[instrument(skip(state))]
+async fn list_links(
+    State(state): State<AppState>,
+    AppQuery(query): AppQuery<ListLinksQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let (links, next) = state.list_links(&query).await?;
+    let res = match &query.fields {
+        Some(fields) => AppResponse(SparseLinksResponse {
+            links: links.iter().map(|link| fields.select(link)).collect(),
+            next,
+        })
+        .into_response(),
+        None => AppResponse(ListLinksResponse { links, next }).into_response(),
+    };
+    Ok(res)
+}
+
+#[instrument(skip(state))]
+async fn link_details(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    AppQuery(query): AppQuery<LinkQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let link = state.link_fields(&id, query.fields.as_ref()).await?;
+    let res = match &query.fields {
+        Some(fields) => AppResponse(fields.select(&link)).into_response(),
+        None => AppResponse(link).into_response(),
+    };
+    Ok(res)
+}
+
+/// Another id for link `id`, which redirects the same and counts its clicks for `id`
+#[instrument(skip(state))]
+async fn add_alias(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    AppJson(data): AppJson<AliasRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    state.check_maintenance(false)?;
+    state.add_alias(&id, &data.alias).await?;
+    Ok((StatusCode::CREATED, AppResponse(state.link(&id).await?)))
+}
+
+#[instrument(skip(state))]
+async fn remove_alias(
+    State(state): State<AppState>,
+    Path((id, alias)): Path<(String, String)>,
+) -> Result<impl IntoResponse, AppError> {
+    state.check_maintenance(false)?;
+    if !state.store.remove_alias(&id, &alias).await? {
+        return Err(AppError::NotFound(alias));
+    }
+    info!("Removed alias {} of {}", alias, id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[instrument(skip(state))]
+async fn top_links(
+    State(state): State<AppState>,
+    AppQuery(query): AppQuery<TopLinksQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    let (from, to, links) = state
+        .top_links(query.period, query.limit, query.exclude_bots)
+        .await?;
+    Ok(AppResponse(TopLinksResponse {
+        period: query.period,
+        from,
+        to,
+        links,
+    }))
+}
+
+/// Click totals of many links at once, for dashboards that would otherwise ask link by link
+#[instrument(skip(state, data))]
+async fn bulk_stats(
+    State(state): State<AppState>,
+    AppJson(data): AppJson<BulkStatsRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    Ok(AppResponse(state.bulk_stats(&data.ids).await?))
+}
+
+/// Streams every click as a `click` event, subscribers that fall behind get a `lagged`
+/// event with the number of clicks they missed
+async fn click_events(
+    State(state): State<AppState>,
+) -> Sse<impl Stream<Item = Result<Event, axum::Error>>> {
+    let stream = BroadcastStream::new(state.clicks.subscribe()).map(|click| match click {
+        Ok(click) => Event::default().event("click").json_data(click),
+        Err(BroadcastStreamRecvError::Lagged(missed)) => {
+            Ok(Event::default().event("lagged").data(missed.to_string()))
+        }
+    });
+
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+/// Pushes a [`FeedSample`] every second until the client disconnects
+async fn dashboard_feed(
+    State(state): State<AppState>,
+    AppQuery(query): AppQuery<FeedQuery>,
+    ws: WebSocketUpgrade,
+) -> Result<Response, AppError> {
+    let filter = FeedFilter::new(&state, &query).await?;
+    Ok(ws.on_upgrade(move |socket| push_feed(socket, state, filter)))
+}
+
+impl FeedFilter {
+    /// `None` when `query` follows every link, listed aliases stand for their links
+    pub(crate) async fn new(state: &AppState, query: &FeedQuery) -> Result<Option<Self>, AppError> {
+        let split = |list: &Option<String>| -> HashSet<String> {
+            list.iter()
+                .flat_map(|list| list.split(','))
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(ToOwned::to_owned)
+                .collect()
+        };
+        if query.links.is_none() && query.tags.is_none() {
+            return Ok(None);
+        }
+        let mut links = HashSet::new();
+        for id in split(&query.links) {
+            // a link that doesn't exist yet is followed once it is created
+            match state.store.get_link(&id).await? {
+                Some(record) => links.insert(record.id),
+                None => links.insert(id),
+            };
+        }
+        Ok(Some(Self {
+            links,
+            tags: split(&query.tags),
+            seen: HashMap::new(),
+            seen_since: Instant::now(),
+        }))
+    }
+
+    /// Whether `id`, a link or one of its aliases, is followed
+    pub(crate) async fn matches(&mut self, state: &AppState, id: &str) -> bool {
+        if self.seen.len() >= FEED_FILTER_MAX_SEEN || self.seen_since.elapsed() >= FEED_FILTER_TTL {
+            self.seen.clear();
+            self.seen_since = Instant::now();
+        }
+        if let Some(&matches) = self.seen.get(id) {
+            return matches;
+        }
+        let matches = match self.lookup(state, id).await {
+            Ok(matches) => matches,
+            Err(e) => {
+                // not remembered, the next event for the link asks again
+                warn!(
+                    "Could not tell whether the dashboard feed follows {}: {}",
+                    id, e
+                );
+                return false;
+            }
+        };
+        self.seen.insert(id.to_owned(), matches);
+        matches
+    }
+
+    async fn lookup(&self, state: &AppState, id: &str) -> Result<bool, AppError> {
+        if self.links.contains(id) {
+            return Ok(true);
+        }
+        let Some(record) = state.store.get_link(id).await? else {
+            return Ok(false);
+        };
+        if self.links.contains(&record.id) {
+            return Ok(true);
+        }
+        if self.tags.is_empty() {
+            return Ok(false);
+        }
+        let tags = state.store.link_tags(&record.id).await?;
+        Ok(tags.iter().any(|tag| self.tags.contains(tag)))
+    }
+}
+
+/// Adds `event` to `[requests, errors, redirects, new_links]`
+fn count_event(counts: &mut [u64; 4], event: &FeedEvent) {
+    match event {
+        FeedEvent::Request { error, .. } => {
+            counts[0] += 1;
+            counts[1] += u64::from(*error);
+        }
+        FeedEvent::Redirect { .. } => counts[2] += 1,
+        FeedEvent::Created { .. } => counts[3] += 1,
+    }
+}
+
+async fn push_feed(mut socket: WebSocket, state: AppState, mut filter: Option<FeedFilter>) {
+    let metrics = &state.metrics;
+    let counters = || {
+        [
+            metrics.requests.load(Ordering::Relaxed),
+            metrics.errors.load(Ordering::Relaxed),
+            metrics.redirects.load(Ordering::Relaxed),
+            metrics.links_created.load(Ordering::Relaxed),
+        ]
+    };
+    let mut events = metrics.subscribe();
+    let mut ticker = tokio::time::interval(DASHBOARD_FEED_INTERVAL);
+    let mut last_tick = Instant::now();
+    let mut last = counters();
+    let mut filtered = [0; 4];
+    let mut missed_events = 0;
+
+    loop {
+        tokio::select! {
+            _ = ticker.tick() => {
+                let current = counters();
+                let [requests, errors, redirects, new_links] = if filter.is_some() {
+                    std::mem::take(&mut filtered)
+                } else {
+                    std::array::from_fn(|i| current[i] - last[i])
+                };
+                let elapsed = last_tick.elapsed().as_secs_f64().max(f64::EPSILON);
+                let sample = FeedSample {
+                    at: Utc::now(),
+                    redirects_per_sec: redirects as f64 / elapsed,
+                    new_links,
+                    requests,
+                    errors,
+                    error_rate: if requests == 0 { 0.0 } else { errors as f64 / requests as f64 },
+                    missed_events: std::mem::take(&mut missed_events),
+                };
+                last = current;
+                last_tick = Instant::now();
+
+                let Ok(sample) = serde_json::to_string(&sample) else {
+                    break;
+                };
+                if socket.send(Message::Text(sample)).await.is_err() {
+                    break;
+                }
+            }
+            event = events.recv(), if filter.is_some() => match event {
+                Ok(event) => {
+                    if let Some(filter) = &mut filter {
+                        if filter.matches(&state, event.link()).await {
+                            count_event(&mut filtered, &event);
+                        }
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(missed)) => missed_events += missed,
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                _ => {}
+            },
+        }
+    }
+}
+
+async fn status(State(state): State<AppState>) -> impl IntoResponse {
+    let started = Instant::now();
+    let ping = match tokio::time::timeout(STATUS_DB_TIMEOUT, state.store.ping()).await {
+        Ok(ret) => ret.map_err(|e| e.to_string()),
+        Err(_) => Err("timed out".to_owned()),
+    };
+    let database = DatabaseStatus {
+        ok: ping.is_ok(),
+        latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+        error: ping.err(),
+        pool: state.store.pool_stats(),
+    };
+
+    AppResponse(StatusResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: env!("GIT_SHA"),
+        uptime_secs: state.started_at.elapsed().as_secs(),
+        database,
+        clicks_buffered: state.clicks.buffered(),
+        maintenance: state.maintenance.read().unwrap().enabled,
+        jobs: state.jobs.snapshot(),
+    })
+}
+
+async fn list_blocked(State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    let domains = state.store.list_blocked().await?;
+    Ok(AppResponse(domains))
+}
+
+async fn list_reports(State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    let reports = state.store.open_reports(OPEN_REPORTS_LIMIT).await?;
+    Ok(AppResponse(reports))
+}
+
+async fn list_scanners(State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    let scanners = state.store.list_scanners(SCANNERS_LIMIT).await?;
+    Ok(AppResponse(scanners))
+}
+
+async fn forget_scanner(
+    State(state): State<AppState>,
+    Path(ip): Path<IpAddr>,
+) -> Result<impl IntoResponse, AppError> {
+    state.forget_scanner(ip).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn disable_reported(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    state.set_flagged(&id, true).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn dismiss_reports(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    if !state.store.link_exists(&id).await? {
+        return Err(AppError::NotFound(id));
+    }
+    let dismissed = state.store.resolve_reports(&id).await?;
+    info!("Dismissed {} reports against {}", dismissed, id);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn reload_config(State(state): State<AppState>) -> Result<impl IntoResponse, AppError> {
+    Ok(AppResponse(state.reload().await?))
+}
+
+async fn get_maintenance(State(state): State<AppState>) -> impl IntoResponse {
+    AppResponse(state.maintenance.read().unwrap().clone())
+}
+
+async fn set_maintenance(
+    State(state): State<AppState>,
+    AppJson(maintenance): AppJson<Maintenance>,
+) -> Result<impl IntoResponse, AppError> {
+    state.set_maintenance(maintenance.clone()).await?;
+    Ok(AppResponse(maintenance))
+}
+
+async fn block_domain(
+    State(state): State<AppState>,
+    AppJson(data): AppJson<BlockDomainRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let domain = normalize_domain(&data.domain)?;
+    let blocked = state.block_domain(&domain, data.reason.as_deref()).await?;
+    Ok((StatusCode::CREATED, AppResponse(blocked)))
+}
+
+async fn unblock_domain(
+    State(state): State<AppState>,
+    Path(domain): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    let domain = normalize_domain(&domain)?;
+    state.unblock_domain(&domain).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct DeleteLinkQuery {
+    /// shown to visitors of the link from now on
+    reason: Option<String>,
+}
+
+async fn delete_link(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    AppQuery(query): AppQuery<DeleteLinkQuery>,
+) -> Result<impl IntoResponse, AppError> {
+    state.delete_link(&id, query.reason.as_deref()).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Guard for the `/admin`, `/api` and `/ws` routes, they take the admin token from the
+/// `ADMIN_TOKEN` env var or one of `security.tokens` that has `scope`. Unknown tokens get a
+/// 401, known ones without the scope a 403.
+async fn require_scope(
+    State((state, scope)): State<(AppState, Scope)>,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    state.check_scope(bearer_token(req.headers()), scope)?;
+    Ok(next.run(req).await)
+}
+
+/// The token of an `Authorization: Bearer <token>` header
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+}
+
+/// CSS, scripts and icons of the `/ui` pages, built into the binary so it stays the only thing
+/// to deploy. Debug builds read them from `static/` on every request instead.
+#[derive(RustEmbed)]
+#[folder = "static/"]
+pub(crate) struct Assets;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct AssetQuery {
+    /// version from [`asset_url`], files asked for under the current one never change
+    pub(crate) v: Option<String>,
+}
+
+/// `/static/<path>` with the version of the file, so browsers can keep it for good
+pub(crate) fn asset_url(path: &str) -> String {
+    match Assets::get(path) {
+        Some(file) => format!("/static/{}?v={}", path, asset_version(&file)),
+        None => format!("/static/{}", path),
+    }
+}
+
+fn asset_version(file: &EmbeddedFile) -> String {
+    file.metadata.sha256_hash()[..8]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+async fn static_asset(
+    Path(path): Path<String>,
+    Query(query): Query<AssetQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(file) = Assets::get(&path) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let version = asset_version(&file);
+    let etag = format!("\"{}\"", version);
+    // anything else has to be checked again, the file may have changed since
+    let cache_control = if query.v.as_deref() == Some(version.as_str()) {
+        "public, max-age=31536000, immutable"
+    } else {
+        "no-cache"
+    };
+    let mut res_headers = HeaderMap::new();
+    res_headers.insert(CACHE_CONTROL, HeaderValue::from_static(cache_control));
+    res_headers.insert(
+        ETAG,
+        HeaderValue::try_from(etag.as_str()).expect("etags are hex"),
+    );
+    if headers
+        .get(IF_NONE_MATCH)
+        .is_some_and(|tag| tag.as_bytes() == etag.as_bytes())
+    {
+        return (StatusCode::NOT_MODIFIED, res_headers).into_response();
+    }
+    res_headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_str(file.metadata.mimetype())
+            .unwrap_or(HeaderValue::from_static("application/octet-stream")),
+    );
+    (res_headers, file.data).into_response()
+}
+
+/// Works out the real client behind any trusted proxies for the rest of the stack, see
+/// [`Client`]
+async fn resolve_client(State(state): State<AppState>, mut req: Request, next: Next) -> Response {
+    let peer = req
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+    let client = forwarded_client(peer, req.headers(), &state.config.listener);
+    req.extensions_mut().insert(client);
+    next.run(req).await
+}
+
+/// Walks the forwarding chain from the nearest hop outwards, the client is the first address
+/// that isn't one of our proxies. Headers from anyone else are ignored, they're trivial to
+/// forge.
+fn forwarded_client(peer: IpAddr, headers: &HeaderMap, listener: &ListenerConfig) -> Client {
+    let is_trusted = |ip: &IpAddr| listener.trusts(ip);
+    if !is_trusted(&peer) {
+        return Client {
+            ip: peer,
+            forwarded_base: None,
+        };
+    }
+
+    let values = |name: &HeaderName| -> Vec<&str> {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .map(str::trim)
+            .filter(|v| !v.is_empty())
+            .collect()
+    };
+    let forwarded = values(&FORWARDED);
+    let standard = !forwarded.is_empty();
+    let hops: Vec<ForwardedHop> = if standard {
+        forwarded.into_iter().map(parse_forwarded_element).collect()
+    } else {
+        values(&X_FORWARDED_FOR)
+            .into_iter()
+            .map(|node| ForwardedHop {
+                ip: parse_forwarded_node(node),
+                ..Default::default()
+            })
+            .collect()
+    };
+
+    let mut ip = peer;
+    let mut origin = None;
+    for hop in hops.iter().rev() {
+        // an obfuscated hop hides everything before it
+        let Some(hop_ip) = hop.ip else { break };
+        ip = hop_ip;
+        origin = Some(hop);
+        if !is_trusted(&hop_ip) {
+            break;
+        }
+    }
+
+    let (proto, host) = match origin {
+        Some(hop) if standard => (hop.proto.clone(), hop.host.clone()),
+        // X-Forwarded-Proto and -Host don't line up with the hops, only the values added by
+        // the proxy in front of us can be relied on
+        _ => (
+            values(&X_FORWARDED_PROTO).last().map(|v| v.to_string()),
+            values(&X_FORWARDED_HOST).last().map(|v| v.to_string()),
+        ),
+    };
+    let forwarded_base = if proto.is_some() || host.is_some() {
+        let host = host
+            .as_deref()
+            .or_else(|| headers.get(HOST).and_then(|v| v.to_str().ok()));
+        forwarded_base_url(proto.as_deref().unwrap_or("http"), host)
+    } else {
+        None
+    };
+    Client { ip, forwarded_base }
+}
+
+/// `for=192.0.2.60;proto=https;host=sho.rt`, see RFC 7239
+fn parse_forwarded_element(element: &str) -> ForwardedHop {
+    let mut hop = ForwardedHop::default();
+    for pair in element.split(';') {
+        let Some((key, value)) = pair.split_once('=') else {
+            continue;
+        };
+        let value = value.trim().trim_matches('"');
+        match key.trim().to_ascii_lowercase().as_str() {
+            "for" => hop.ip = parse_forwarded_node(value),
+            "proto" => hop.proto = Some(value.to_owned()),
+            "host" => hop.host = Some(value.to_owned()),
+            _ => {}
+        }
+    }
+    hop
+}
+
+/// An address with or without a port, `[2001:db8::1]:4711`, `192.0.2.43:47011` or a bare IP
+fn parse_forwarded_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.parse()
+        .ok()
+        .or_else(|| node.rsplit_once(':')?.0.parse().ok())
+}
+
+fn forwarded_base_url(proto: &str, host: Option<&str>) -> Option<Url> {
+    let proto = proto.to_ascii_lowercase();
+    let host = host?;
+    // a host smuggling in a path or credentials would end up in every link we hand out
+    let valid_host = host
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | ':' | '[' | ']'));
+    if !matches!(proto.as_str(), "http" | "https") || !valid_host {
+        return None;
+    }
+    Url::parse(&format!("{}://{}", proto, host)).ok()
+}
+
+/// Writes a line per request to the access log, when one is configured
+async fn log_access(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let Some(access_log) = state.access_log else {
+        return next.run(req).await;
+    };
+    let client = req
+        .extensions()
+        .get::<Client>()
+        .map(|client| {
+            if state.config.security.anonymize_ips {
+                anonymize_ip(client.ip).to_string()
+            } else {
+                client.ip.to_string()
+            }
+        })
+        .unwrap_or_else(|| "-".to_owned());
+    let [referrer, user_agent] = [REFERER, USER_AGENT].map(|name| {
+        req.headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.replace('"', "\\\""))
+            .unwrap_or_else(|| "-".to_owned())
+    });
+    let request_line = format!("{} {} {:?}", req.method(), req.uri(), req.version());
+    let received_at = Utc::now();
+
+    let res = next.run(req).await;
+    let size = res
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("-");
+    access_log.record(format!(
+        "{} - - [{}] \"{}\" {} {} \"{}\" \"{}\"\n",
+        client,
+        received_at.format("%d/%b/%Y:%H:%M:%S %z"),
+        request_line,
+        res.status().as_u16(),
+        size,
+        referrer,
+        user_agent,
+    ));
+    res
+}
+
+async fn count_requests(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    let res = next.run(req).await;
+    let error = res.status().is_server_error();
+    state.metrics.requests.fetch_add(1, Ordering::Relaxed);
+    if error {
+        state.metrics.errors.fetch_add(1, Ordering::Relaxed);
+    }
+    if let Some(LinkRequest(id)) = res.extensions().get() {
+        state.metrics.publish(FeedEvent::Request {
+            id: id.clone(),
+            error,
+        });
+    }
+    res
+}
+
+/// Tells [`count_requests`] which link a `/:id` route was asked for
+async fn mark_link_request(Path(id): Path<String>, req: Request, next: Next) -> Response {
+    let mut res = next.run(req).await;
+    res.extensions_mut().insert(LinkRequest(id));
+    res
+}
+
+/// Takes the request id from the client's `x-request-id` header or generates one, makes it
+/// available to the handlers and sends it back in the same header.
+async fn propagate_request_id(mut req: Request, next: Next) -> Response {
+    let request_id = req
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .map(ToOwned::to_owned)
+        .unwrap_or_else(|| nanoid::nanoid!());
+    let header = HeaderValue::from_str(&request_id).expect("request id is a valid header value");
+    req.headers_mut().insert(REQUEST_ID_HEADER, header.clone());
+
+    let mut res = REQUEST_ID.scope(request_id, next.run(req)).await;
+    res.headers_mut().insert(REQUEST_ID_HEADER, header);
+    res
+}
+
+async fn negotiate_format(req: Request, next: Next) -> Response {
+    let wants_msgpack = req
+        .headers()
+        .get_all(ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|range| {
+            let mut params = range.split(';').map(str::trim);
+            let media_type = params.next().unwrap_or_default();
+            // `q=0` means not acceptable
+            let refused = params.any(|param| {
+                param
+                    .strip_prefix("q=")
+                    .and_then(|q| q.parse::<f32>().ok())
+                    .is_some_and(|q| q == 0.0)
+            });
+            !refused
+                && MSGPACK_CONTENT_TYPES
+                    .iter()
+                    .any(|msgpack| media_type.eq_ignore_ascii_case(msgpack))
+        });
+    let format = if wants_msgpack {
+        ResponseFormat::MessagePack
+    } else {
+        ResponseFormat::Json
+    };
+    let mut res = RESPONSE_FORMAT.scope(format, next.run(req)).await;
+    res.headers_mut()
+        .append(VARY, HeaderValue::from_static("accept"));
+    res
+}
+
+/// Span for [`TraceLayer`], every log line of a request carries its route and request id
+fn make_request_span(req: &Request) -> Span {
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(MatchedPath::as_str)
+        .unwrap_or_default();
+    let request_id = req
+        .headers()
+        .get(&REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    info_span!(
+        "request",
+        method = %req.method(),
+        path = %req.uri().path(),
+        route,
+        request_id,
+    )
+}
+
+fn log_response(res: &Response, latency: Duration, _span: &Span) {
+    info!(
+        status = res.status().as_u16(),
+        latency_ms = latency.as_millis() as u64,
+        "finished processing request"
+    );
+}
+
+/// Answers with a 503 in maintenance mode, for everything but reads when those are still served
+async fn check_maintenance(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    let read = matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS);
+    state.check_maintenance(read)?;
+    Ok(next.run(req).await)
+}
+
+/// Adds `security.headers` to responses that don't have them already
+async fn set_security_headers(State(state): State<AppState>, req: Request, next: Next) -> Response {
+    // GraphiQL runs inline scripts from a CDN
+    let graphiql = req.uri().path() == "/graphql";
+    let mut res = next.run(req).await;
+
+    let config = &state.config.security.headers;
+    let html = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/html"));
+    let mut hsts = String::new();
+    if config.hsts_max_age_secs > 0 {
+        hsts = format!("max-age={}", config.hsts_max_age_secs);
+        if config.hsts_include_subdomains {
+            hsts.push_str("; includeSubDomains");
+        }
+    }
+    let headers = [
+        (STRICT_TRANSPORT_SECURITY, hsts.as_str()),
+        (
+            X_CONTENT_TYPE_OPTIONS,
+            if config.nosniff { "nosniff" } else { "" },
+        ),
+        (REFERRER_POLICY, config.referrer_policy.as_str()),
+        (
+            CONTENT_SECURITY_POLICY,
+            match html && !graphiql {
+                true => config.content_security_policy.as_str(),
+                false => "",
+            },
+        ),
+    ];
+    for (name, value) in headers {
+        if value.is_empty() {
+            continue;
+        }
+        if let Ok(value) = HeaderValue::from_str(value) {
+            res.headers_mut().entry(name).or_insert(value);
+        }
+    }
+    res
+}
+
+/// Turns requests away with a 503 once their budget is used up, `/status` always gets through
+/// so probes can still tell the instance is alive
+async fn shed_load(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    if req.uri().path() == "/status" {
+        return Ok(next.run(req).await);
+    }
+    let mutation = matches!(
+        *req.method(),
+        Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    );
+    let _permit = state.load.admit(mutation)?;
+    Ok(next.run(req).await)
+}
+
+/// Turns away blocked scanners, and blocks whoever asks for a decoy slug. Decoys answer like
+/// any unknown link so nothing gives the honeypot away.
+async fn trap_scanners(
+    State(state): State<AppState>,
+    Extension(client): Extension<Client>,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    state
+        .honeypot
+        .check(client.ip)
+        .map_err(AppError::TooManyRequests)?;
+    let slug = req
+        .uri()
+        .path()
+        .trim_start_matches('/')
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    if state.honeypot.is_decoy(slug) {
+        let slug = slug.to_owned();
+        state.trip_honeypot(client.ip, &slug).await?;
+        return Err(AppError::NotFound(slug));
+    }
+    Ok(next.run(req).await)
+}
+
+async fn limit_shorten_rate(
+    State(state): State<AppState>,
+    Extension(client): Extension<Client>,
+    req: Request,
+    next: Next,
+) -> Result<Response, AppError> {
+    state
+        .shorten_limiter
+        .check(client.ip)
+        .map_err(AppError::TooManyRequests)?;
+    Ok(next.run(req).await)
+}
+
+pub(crate) fn retry_after_secs(delay: &Duration) -> u64 {
+    // never tell clients to retry "in 0 seconds"
+    delay.as_secs() + u64::from(delay.subsec_nanos() > 0)
+}
+
+async fn handle_timeout_error(err: BoxError) -> Result<(), AppError> {
+    if err.is::<Elapsed>() {
+        Err(AppError::Timeout(Elapsed::new()))
+    } else {
+        Err(AppError::InternalServer(anyhow::Error::msg(format!(
+            "Request failed in the middleware: {}",
+            err
+        ))))
+    }
+}
+
+#[cfg(feature = "qr")]
+fn default_qr_size() -> u32 {
+    DEFAULT_QR_SIZE
+}
+
+impl<T: Serialize> IntoResponse for AppResponse<T> {
+    fn into_response(self) -> Response {
+        match RESPONSE_FORMAT
+            .try_with(|format| *format)
+            .unwrap_or_default()
+        {
+            ResponseFormat::Json => Json(self.0).into_response(),
+            // with field names, so clients can decode into the same structs as the JSON
+            ResponseFormat::MessagePack => match rmp_serde::to_vec_named(&self.0) {
+                Ok(body) => ([(CONTENT_TYPE, MSGPACK_CONTENT_TYPES[0])], body).into_response(),
+                Err(e) => {
+                    warn!("Failed to encode MessagePack response: {:?}", e);
+                    StatusCode::INTERNAL_SERVER_ERROR.into_response()
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{
+        body::{to_bytes, Body},
+        http::header::{COOKIE, RETRY_AFTER, SET_COOKIE},
+    };
+    use figment::{
+        providers::{Format, Toml},
+        Figment,
+    };
+    use tower::ServiceExt;
+    use tracing::level_filters::LevelFilter;
+
+    use super::*;
+    use crate::api::{
+        AliasRequest, BulkStatsResponse, BundleEntry, BundleRequest, BundleResponse, ExportedLink,
+        ForwardQuery, ReportCategory, Visibility,
+    };
+    use crate::config::UNIX_PEER;
+    use crate::state::LOGIN_ATTEMPTS;
+    use crate::store::{mock::MockStore, Store};
+    use crate::telemetry::LogLevel;
+    use crate::{Config, ErrorResponse};
+
+    const ADMIN_TOKEN: &str = "secret";
+
+    async fn router(store: MockStore) -> Router {
+        router_with(store, "").await
+    }
+
+    /// The router with `extra` merged into the test config
+    async fn router_with(store: MockStore, extra: &str) -> Router {
+        let config: Config = Figment::from(Toml::string(&format!(
+            r#"
+            [listener]
+            public_base_url = "https://sho.rt"
+
+            [security]
+            admin_token = "{ADMIN_TOKEN}"
+            "#
+        )))
+        .merge(Toml::string(extra))
+        .extract()
+        .unwrap();
+        let state = AppState::try_new(config, Arc::new(store), LogLevel::new(LevelFilter::OFF))
+            .await
+            .unwrap();
+        build_router(state)
+    }
+
+    async fn send(router: &Router, req: Request<Body>) -> Response {
+        router.clone().oneshot(req).await.unwrap()
+    }
+
+    fn shorten_request(request: &ShortenRequest) -> Request<Body> {
+        Request::post("/")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(request).unwrap()))
+            .unwrap()
+    }
+
+    fn delete_request(id: &str) -> Request<Body> {
+        Request::delete(format!("/admin/links/{}", id))
+            .header(AUTHORIZATION, format!("Bearer {}", ADMIN_TOKEN))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    fn get(path: &str) -> Request<Body> {
+        Request::get(path).body(Body::empty()).unwrap()
+    }
+
+    async fn error(res: Response) -> ErrorResponse {
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    // `.invalid` never resolves, so the redirect loop check gives up right away
+    #[tokio::test]
+    async fn shorten_returns_the_short_link() {
+        let router = router(MockStore::default()).await;
+        let res = send(
+            &router,
+            shorten_request(&ShortenRequest {
+                url: "https://example.invalid/docs".to_owned(),
+                alias: Some("docs".to_owned()),
+                ..Default::default()
+            }),
+        )
+        .await;
+
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let created: ShortenResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(created.url, "https://sho.rt/docs");
+    }
+
+    #[tokio::test]
+    async fn trailing_slashes_can_be_stripped() {
+        let router = router_with(
+            MockStore::default(),
+            "[features]\ntrailing_slash = \"strip\"",
+        )
+        .await;
+        let res = send(
+            &router,
+            shorten_request(&ShortenRequest {
+                url: "https://example.invalid/docs/".to_owned(),
+                alias: Some("docs".to_owned()),
+                ..Default::default()
+            }),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = send(&router, get("/docs")).await;
+        assert_eq!(res.headers()[LOCATION], "https://example.invalid/docs");
+    }
+
+    #[cfg(feature = "qr")]
+    #[tokio::test]
+    async fn shorten_can_answer_with_the_qr_code() {
+        const PNG_MAGIC: &[u8] = b"\x89PNG";
+        let router = router(MockStore::default()).await;
+        let request = |alias: &str| ShortenRequest {
+            url: format!("https://example.invalid/{}", alias),
+            alias: Some(alias.to_owned()),
+            ..Default::default()
+        };
+
+        let mut req = shorten_request(&request("docs"));
+        *req.uri_mut() = "/?include_qr=true".parse().unwrap();
+        let res = send(&router, req).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let created: ShortenResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(created.url, "https://sho.rt/docs");
+        let qr = created.qr.unwrap();
+        let png = STANDARD
+            .decode(qr.strip_prefix("data:image/png;base64,").unwrap())
+            .unwrap();
+        assert!(png.starts_with(PNG_MAGIC));
+
+        let mut req = shorten_request(&request("blog"));
+        req.headers_mut()
+            .insert(ACCEPT, HeaderValue::from_static("image/png"));
+        let res = send(&router, req).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        assert_eq!(res.headers()[CONTENT_TYPE], "image/png");
+        assert_eq!(res.headers()[LOCATION], "https://sho.rt/blog");
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert!(body.starts_with(PNG_MAGIC));
+    }
+
+    #[tokio::test]
+    async fn redirect_goes_to_the_stored_url() {
+        let router = router(MockStore::with_links(&[(
+            "docs",
+            "https://example.com/docs",
+        )]))
+        .await;
+        let res = send(&router, get("/docs")).await;
+
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(res.headers()[LOCATION], "https://example.com/docs");
+    }
+
+    #[tokio::test]
+    async fn redirects_forward_the_query_when_asked_to() {
+        let router = router(MockStore::default()).await;
+        let res = send(
+            &router,
+            shorten_request(&ShortenRequest {
+                url: "https://example.invalid/docs?ref=home".to_owned(),
+                alias: Some("docs".to_owned()),
+                forward_query: Some(ForwardQuery::Replace),
+                ..Default::default()
+            }),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = send(&router, get("/docs?ref=ad&gclid=123")).await;
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            res.headers()[LOCATION],
+            "https://example.invalid/docs?ref=ad&gclid=123"
+        );
+        let res = send(&router, get("/docs")).await;
+        assert_eq!(
+            res.headers()[LOCATION],
+            "https://example.invalid/docs?ref=home"
+        );
+    }
+
+    #[tokio::test]
+    async fn redirects_carry_the_cache_control_of_their_link() {
+        let router = router_with(
+            MockStore::with_links(&[("docs", "https://example.com/docs")]),
+            "[features]\nredirect_cache_control = \"no-store\"",
+        )
+        .await;
+        let res = send(&router, get("/docs")).await;
+        assert_eq!(res.headers()[CACHE_CONTROL], "no-store");
+        assert_eq!(res.headers()[EXPIRES], "Thu, 01 Jan 1970 00:00:00 GMT");
+
+        let res = send(
+            &router,
+            shorten_request(&ShortenRequest {
+                url: "https://example.invalid/logo.png".to_owned(),
+                alias: Some("logo".to_owned()),
+                cache_control: Some("public, max-age=86400".to_owned()),
+                ..Default::default()
+            }),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = send(&router, get("/logo")).await;
+        assert_eq!(res.headers()[CACHE_CONTROL], "public, max-age=86400");
+        let expires = res.headers()[EXPIRES].to_str().unwrap();
+        let expires = DateTime::parse_from_rfc2822(expires).unwrap();
+        assert!(expires > Utc::now() + TimeDelta::hours(23));
+
+        let res = send(
+            &router,
+            shorten_request(&ShortenRequest {
+                url: "https://example.invalid/other".to_owned(),
+                cache_control: Some("max-age=1\nx".to_owned()),
+                ..Default::default()
+            }),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error(res).await.details.unwrap()["field"], "cache_control");
+    }
+
+    #[tokio::test]
+    async fn responses_carry_the_security_headers() {
+        let router = router_with(
+            MockStore::with_links(&[("docs", "https://example.com/docs")]),
+            "[security.headers]\nreferrer_policy = \"\"",
+        )
+        .await;
+        let res = send(&router, get("/ui/login")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[STRICT_TRANSPORT_SECURITY], "max-age=31536000");
+        assert_eq!(res.headers()[X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert!(res.headers()[CONTENT_SECURITY_POLICY]
+            .to_str()
+            .unwrap()
+            .contains("frame-ancestors 'none'"));
+        assert!(!res.headers().contains_key(REFERRER_POLICY));
+
+        // only pages get a Content-Security-Policy
+        let res = send(&router, get("/docs")).await;
+        assert_eq!(res.headers()[X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert!(!res.headers().contains_key(CONTENT_SECURITY_POLICY));
+    }
+
+    #[tokio::test]
+    async fn unknown_links_are_not_found() {
+        let router = router(MockStore::default()).await;
+
+        let res = send(&router, get("/missing")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(error(res).await.code, "not_found");
+
+        let res = send(&router, get("/missing/preview")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn taken_aliases_are_a_conflict() {
+        let router = router(MockStore::with_links(&[(
+            "docs",
+            "https://example.com/docs",
+        )]))
+        .await;
+        let res = send(
+            &router,
+            shorten_request(&ShortenRequest {
+                url: "https://example.invalid/other".to_owned(),
+                alias: Some("docs".to_owned()),
+                ..Default::default()
+            }),
+        )
+        .await;
+
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let error = error(res).await;
+        assert_eq!(error.code, "alias_taken");
+        let suggestions = &error.details.unwrap()["suggestions"];
+        assert!(!suggestions.as_array().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn shortened_urls_keep_their_id() {
+        let router = router(MockStore::with_links(&[(
+            "docs",
+            "https://example.invalid/docs",
+        )]))
+        .await;
+        let res = send(
+            &router,
+            shorten_request(&ShortenRequest {
+                url: "https://example.invalid/docs".to_owned(),
+                alias: Some("manual".to_owned()),
+                ..Default::default()
+            }),
+        )
+        .await;
+
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        assert_eq!(error(res).await.code, "already_shortened");
+    }
+
+    #[tokio::test]
+    async fn deleted_links_are_gone() {
+        let router = router(MockStore::with_links(&[(
+            "docs",
+            "https://example.com/docs",
+        )]))
+        .await;
+
+        let res = send(
+            &router,
+            delete_request("docs?reason=Moved%20to%20the%20wiki"),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = send(&router, get("/docs")).await;
+        assert_eq!(res.status(), StatusCode::GONE);
+        let details = error(res).await.details.unwrap();
+        assert_eq!(details["reason"], "Moved to the wiki");
+
+        let res = send(
+            &router,
+            Request::get("/docs")
+                .header(ACCEPT, "text/html,application/xhtml+xml")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::GONE);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("Moved to the wiki"));
+
+        let res = send(&router, delete_request("docs")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn rotating_links_take_turns() {
+        let router = router(MockStore::default()).await;
+        let res = send(
+            &router,
+            shorten_request(&ShortenRequest {
+                url: "https://example.invalid/form-a".to_owned(),
+                alias: Some("signup".to_owned()),
+                rotate: vec![
+                    "https://example.invalid/form-b".to_owned(),
+                    "https://example.invalid/form-c".to_owned(),
+                ],
+                ..Default::default()
+            }),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let mut locations = Vec::new();
+        for _ in 0..4 {
+            let res = send(&router, get("/signup")).await;
+            assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+            assert_eq!(res.headers()[CACHE_CONTROL], "no-store");
+            locations.push(res.headers()[LOCATION].to_str().unwrap().to_owned());
+        }
+        assert_eq!(
+            locations,
+            [
+                "https://example.invalid/form-a",
+                "https://example.invalid/form-b",
+                "https://example.invalid/form-c",
+                "https://example.invalid/form-a",
+            ]
+        );
+
+        let res = send(
+            &router,
+            shorten_request(&ShortenRequest {
+                url: "https://example.invalid/form-a".to_owned(),
+                ..Default::default()
+            }),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        assert_eq!(error(res).await.code, "already_shortened");
+    }
+
+    fn bundle_request(method: Method, path: &str, bundle: &BundleRequest) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .header(AUTHORIZATION, format!("Bearer {}", ADMIN_TOKEN))
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(serde_json::to_vec(bundle).unwrap()))
+            .unwrap()
+    }
+
+    fn entry(id: &str) -> BundleEntry {
+        BundleEntry {
+            id: id.to_owned(),
+            title: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn bundles_list_their_links_in_order() {
+        let router = router(MockStore::with_links(&[
+            ("docs", "https://example.com/docs"),
+            ("blog", "https://example.com/blog"),
+        ]))
+        .await;
+        let mut bundle = BundleRequest {
+            alias: Some("mine".to_owned()),
+            title: "My links".to_owned(),
+            links: vec![entry("docs"), entry("blog")],
+            ..Default::default()
+        };
+
+        let res = send(
+            &router,
+            bundle_request(Method::POST, "/admin/bundles", &bundle),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let created: BundleResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(created.url, "https://sho.rt/b/mine");
+        assert_eq!(created.links[0].short_url, "https://sho.rt/docs");
+
+        bundle.links.reverse();
+        let res = send(
+            &router,
+            bundle_request(Method::PUT, "/admin/bundles/mine", &bundle),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = send(&router, get("/b/mine")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let page = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let page = String::from_utf8(page.to_vec()).unwrap();
+        let blog = page.find("https://sho.rt/blog").unwrap();
+        let docs = page.find("https://sho.rt/docs").unwrap();
+        assert!(blog < docs, "{}", page);
+    }
+
+    #[tokio::test]
+    async fn bundles_only_take_existing_links() {
+        let router = router(MockStore::with_links(&[(
+            "docs",
+            "https://example.com/docs",
+        )]))
+        .await;
+        let bundle = BundleRequest {
+            title: "My links".to_owned(),
+            links: vec![entry("docs"), entry("missing")],
+            ..Default::default()
+        };
+
+        let res = send(
+            &router,
+            bundle_request(Method::POST, "/admin/bundles", &bundle),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error(res).await.details.unwrap()["field"], "links");
+
+        let res = send(&router, get("/b/missing")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn links_reported_by_enough_visitors_stop_redirecting() {
+        let store = MockStore::with_links(&[("docs", "https://example.com/docs")]);
+        // the peer of test requests is 0.0.0.0, trusting it lets each report come from elsewhere
+        let router = router_with(
+            store,
+            r#"
+            [listener]
+            trusted_proxies = ["0.0.0.0/32"]
+
+            [features]
+            disable_after_reports = 2
+            "#,
+        )
+        .await;
+        let report = |ip: &str| {
+            let request = ReportRequest {
+                category: ReportCategory::Phishing,
+                ..Default::default()
+            };
+            Request::post("/docs/report")
+                .header(CONTENT_TYPE, "application/json")
+                .header(X_FORWARDED_FOR, ip)
+                .body(Body::from(serde_json::to_vec(&request).unwrap()))
+                .unwrap()
+        };
+
+        for ip in ["192.0.2.1", "192.0.2.1"] {
+            assert_eq!(
+                send(&router, report(ip)).await.status(),
+                StatusCode::ACCEPTED
+            );
+        }
+        let res = send(&router, get("/docs")).await;
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+
+        assert_eq!(
+            send(&router, report("192.0.2.2")).await.status(),
+            StatusCode::ACCEPTED
+        );
+        let res = send(&router, get("/docs")).await;
+        let error = error(res).await;
+        assert_eq!(error.code, "unsafe_url");
+        // visitors don't get to see where the link pointed
+        assert_eq!(error.details, Some(serde_json::json!({ "id": "docs" })));
+    }
+
+    #[tokio::test]
+    async fn blocked_domains_are_refused_with_a_root_dot_too() {
+        let router = router(MockStore::with_links(&[(
+            "promo",
+            "https://Evil.Example.invalid./promo",
+        )]))
+        .await;
+        let block = Request::post("/admin/blocklist")
+            .header(AUTHORIZATION, format!("Bearer {}", ADMIN_TOKEN))
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"domain": "evil.example.invalid"}"#))
+            .unwrap();
+        assert_eq!(send(&router, block).await.status(), StatusCode::CREATED);
+
+        let res = send(
+            &router,
+            shorten_request(&ShortenRequest {
+                url: "https://Evil.Example.invalid./other".to_owned(),
+                ..Default::default()
+            }),
+        )
+        .await;
+        assert_eq!(error(res).await.code, "domain_blocked");
+        let res = send(&router, get("/promo")).await;
+        assert_eq!(error(res).await.code, "domain_blocked");
+    }
+
+    #[tokio::test]
+    async fn tokens_only_reach_the_routes_of_their_scopes() {
+        let store = MockStore::with_links(&[("docs", "https://example.com/docs")]);
+        let router = router_with(
+            store,
+            r#"
+            [[security.tokens]]
+            name = "ci"
+            token = "ci-token"
+            scopes = ["links:write"]
+            "#,
+        )
+        .await;
+        let with_token = |method: Method, path: &str, token: &str| {
+            Request::builder()
+                .method(method)
+                .uri(path)
+                .header(AUTHORIZATION, format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let res = send(
+            &router,
+            with_token(Method::GET, "/api/stats/top", "ci-token"),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        let error = error(res).await;
+        assert_eq!(error.code, "insufficient_scope");
+        assert_eq!(error.details.unwrap()["scope"], "stats:read");
+        let res = send(&router, with_token(Method::GET, "/api/stats/top", "guess")).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let res = send(
+            &router,
+            with_token(Method::POST, "/admin/reload", "ci-token"),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let res = send(
+            &router,
+            with_token(Method::DELETE, "/admin/links/docs", "ci-token"),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+    }
+
+    fn login_request(token: &str) -> Request<Body> {
+        Request::post("/ui/login")
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .body(Body::from(format!("token={}", token)))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn ui_sessions_are_signed_and_logins_limited() {
+        let router = router(MockStore::default()).await;
+        let res = send(&router, login_request(ADMIN_TOKEN)).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        let cookie = res.headers()[SET_COOKIE].to_str().unwrap();
+        let session = cookie.split(';').next().unwrap().to_owned();
+        let page = |session: String| {
+            Request::get("/ui")
+                .header(COOKIE, session)
+                .body(Body::empty())
+        };
+        let res = send(&router, page(session.clone()).unwrap()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // a session can't be stretched, it holds on other replicas until the token changes
+        let (expires, signature) = session.split_once('.').unwrap();
+        let stretched = format!("{}9.{}", expires, signature);
+        let res = send(&router, page(stretched).unwrap()).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        let replica = self::router(MockStore::default()).await;
+        let res = send(&replica, page(session.clone()).unwrap()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let new_token = router_with(
+            MockStore::default(),
+            "[security]\nadmin_token = \"rotated\"",
+        )
+        .await;
+        let res = send(&new_token, page(session).unwrap()).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+
+        for _ in 1..LOGIN_ATTEMPTS {
+            let res = send(&router, login_request("guess")).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        }
+        let res = send(&router, login_request(ADMIN_TOKEN)).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(res.headers().contains_key(RETRY_AFTER));
+    }
+
+    #[tokio::test]
+    async fn spammy_links_are_quarantined() {
+        let router = router_with(MockStore::default(), "[features]\nquarantine_score = 1").await;
+        let res = send(
+            &router,
+            shorten_request(&ShortenRequest {
+                url: "https://example.invalid/prize".to_owned(),
+                alias: Some("qwertyu".to_owned()),
+                ..Default::default()
+            }),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = send(&router, get("/qwertyu")).await;
+        assert_eq!(error(res).await.code, "unsafe_url");
+    }
+
+    #[tokio::test]
+    async fn private_links_hide_their_stats_and_stay_unlisted() {
+        let router = router_with(
+            MockStore::with_links(&[("docs", "https://example.com/docs")]),
+            r#"
+            [features]
+            public_directory = true
+
+            [[security.tokens]]
+            name = "dashboard"
+            token = "stats-token"
+            scopes = ["stats:read"]
+            "#,
+        )
+        .await;
+        let res = send(
+            &router,
+            shorten_request(&ShortenRequest {
+                url: "https://example.com/internal".to_owned(),
+                alias: Some("internal".to_owned()),
+                visibility: Some(Visibility::Private),
+                ..Default::default()
+            }),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        let res = send(&router, get("/docs/stats/timeseries")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = send(&router, get("/internal/stats/timeseries")).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        let req = Request::get("/internal/stats/referrers")
+            .header(AUTHORIZATION, "Bearer stats-token")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(send(&router, req).await.status(), StatusCode::OK);
+
+        let res = send(&router, get("/directory")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let page = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let page = String::from_utf8(page.to_vec()).unwrap();
+        assert!(page.contains("https://sho.rt/docs"));
+        assert!(!page.contains("internal"));
+    }
+
+    #[tokio::test]
+    async fn shortening_again_leaves_the_link_private() {
+        let router = router(MockStore::default()).await;
+        let shorten = |visibility| {
+            shorten_request(&ShortenRequest {
+                url: "https://example.com/internal".to_owned(),
+                visibility: Some(visibility),
+                ..Default::default()
+            })
+        };
+        let res = send(&router, shorten(Visibility::Private)).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let created: ShortenResponse = serde_json::from_slice(&body).unwrap();
+        let id = created.url.rsplit('/').next().unwrap().to_owned();
+
+        let res = send(&router, shorten(Visibility::Public)).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let res = send(&router, get(&format!("/{}/stats/timeseries", id))).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn public_links_are_not_handed_out_as_private() {
+        let router = router(MockStore::with_links(&[(
+            "docs",
+            "https://example.invalid/docs",
+        )]))
+        .await;
+        let res = send(
+            &router,
+            shorten_request(&ShortenRequest {
+                url: "https://example.invalid/docs".to_owned(),
+                visibility: Some(Visibility::Private),
+                ..Default::default()
+            }),
+        )
+        .await;
+
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let error = error(res).await;
+        assert_eq!(error.code, "already_shortened");
+        assert_eq!(error.details.unwrap()["id"], "docs");
+    }
+
+    #[tokio::test]
+    async fn aliases_redirect_like_their_link() {
+        let router = router(MockStore::with_links(&[
+            ("docs", "https://example.com/docs"),
+            ("blog", "https://example.com/blog"),
+        ]))
+        .await;
+        let add_alias = |id: &str, alias: &str| {
+            Request::post(format!("/api/links/{}/aliases", id))
+                .header(AUTHORIZATION, format!("Bearer {}", ADMIN_TOKEN))
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::to_vec(&AliasRequest {
+                        alias: alias.to_owned(),
+                    })
+                    .unwrap(),
+                ))
+                .unwrap()
+        };
+
+        let res = send(&router, add_alias("docs", "manual")).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let link: ExportedLink = serde_json::from_slice(&body).unwrap();
+        assert_eq!(link.aliases, ["manual"]);
+
+        let res = send(&router, get("/manual")).await;
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(res.headers()[LOCATION], "https://example.com/docs");
+        let res = send(&router, get("/manual/stats/timeseries")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        for taken in ["blog", "manual"] {
+            let res = send(&router, add_alias("blog", taken)).await;
+            assert_eq!(error(res).await.code, "alias_taken");
+        }
+        let res = send(
+            &router,
+            shorten_request(&ShortenRequest {
+                url: "https://example.com/manual".to_owned(),
+                alias: Some("manual".to_owned()),
+                ..Default::default()
+            }),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+    }
+
+    #[tokio::test]
+    async fn other_reports_need_a_reason() {
+        let router = router(MockStore::with_links(&[(
+            "docs",
+            "https://example.com/docs",
+        )]))
+        .await;
+        let req = Request::post("/docs/report")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(r#"{"category": "other"}"#))
+            .unwrap();
+
+        let res = send(&router, req).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error(res).await.details.unwrap()["field"], "reason");
+    }
+
+    #[tokio::test]
+    async fn links_are_listed_by_tag_and_domain() {
+        let router = router(MockStore::with_links(&[
+            ("docs", "https://example.com/docs"),
+            ("blog", "https://blog.example.com/"),
+            ("other", "https://example.org/"),
+        ]))
+        .await;
+        let res = send(
+            &router,
+            shorten_request(&ShortenRequest {
+                url: "https://blog.example.com/rust".to_owned(),
+                alias: Some("rust".to_owned()),
+                tags: vec!["rust".to_owned()],
+                ..Default::default()
+            }),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let list = |query: &str| {
+            Request::get(format!("/api/links?{}", query))
+                .header(AUTHORIZATION, format!("Bearer {}", ADMIN_TOKEN))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let ids = |res: Response| async {
+            let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            let page: ListLinksResponse = serde_json::from_slice(&body).unwrap();
+            (
+                page.links
+                    .into_iter()
+                    .map(|link| link.id)
+                    .collect::<Vec<_>>(),
+                page.next,
+            )
+        };
+
+        let (found, _) = ids(send(&router, list("tag=Rust")).await).await;
+        assert_eq!(found, ["rust"]);
+        let (found, next) = ids(send(
+            &router,
+            list("domain=Example.com&sort=clicks&order=asc&limit=2"),
+        )
+        .await)
+        .await;
+        assert_eq!(found, ["blog", "docs"]);
+        let cursor = next.unwrap();
+        let (found, next) = ids(send(
+            &router,
+            list(&format!(
+                "domain=example.com&sort=clicks&order=asc&limit=2&after={}",
+                cursor
+            )),
+        )
+        .await)
+        .await;
+        assert_eq!(found, ["rust"]);
+        assert_eq!(next, None);
+
+        // the cursor of a page by clicks doesn't go on a page newest first
+        let res = send(&router, list(&format!("after={}", cursor))).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error(res).await.details.unwrap()["field"], "after");
+    }
+
+    #[tokio::test]
+    async fn links_come_with_only_the_fields_asked_for() {
+        let router = router(MockStore::with_links(&[(
+            "docs",
+            "https://example.com/docs",
+        )]))
+        .await;
+        let get = |path: &str| {
+            Request::get(path)
+                .header(AUTHORIZATION, format!("Bearer {}", ADMIN_TOKEN))
+                .body(Body::empty())
+                .unwrap()
+        };
+        let json = |res: Response| async {
+            let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()
+        };
+
+        let res = send(&router, get("/api/links?fields=id,clicks")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            json(res).await["links"],
+            serde_json::json!([{ "id": "docs", "clicks": 0 }])
+        );
+        let res = send(&router, get("/api/links/docs?fields=url,clicks")).await;
+        assert_eq!(
+            json(res).await,
+            serde_json::json!({ "url": "https://example.com/docs", "clicks": 0 })
+        );
+
+        let res = send(&router, get("/api/links?fields=id,owner")).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(error(res).await.message.contains("unknown field `owner`"));
+    }
+
+    #[tokio::test]
+    async fn bulk_stats_cover_every_link_asked_for() {
+        let router = router(MockStore::with_links(&[
+            ("docs", "https://example.com/docs"),
+            ("blog", "https://blog.example.com/"),
+        ]))
+        .await;
+        let bulk = |ids: &[&str]| {
+            Request::post("/api/stats/bulk")
+                .header(AUTHORIZATION, format!("Bearer {}", ADMIN_TOKEN))
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(
+                    serde_json::to_vec(&serde_json::json!({ "ids": ids })).unwrap(),
+                ))
+                .unwrap()
+        };
+
+        let res = send(&router, bulk(&["docs", "blog", "docs", "gone"])).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let stats: BulkStatsResponse = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            stats.clicks,
+            BTreeMap::from([("blog".to_owned(), 0), ("docs".to_owned(), 0)])
+        );
+        assert_eq!(stats.not_found, ["gone"]);
+
+        let res = send(&router, bulk(&[])).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error(res).await.details.unwrap()["field"], "ids");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_lookups_time_out() {
+        let router = router(MockStore::slow(Duration::from_secs(60))).await;
+        let res = send(&router, get("/docs")).await;
+
+        assert_eq!(res.status(), StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(error(res).await.code, "timeout");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_past_the_concurrency_limit_are_shed() {
+        let router = router_with(
+            MockStore::slow(Duration::from_secs(10)),
+            "[listener]\nredirect_concurrency = 1",
+        )
+        .await;
+        let stuck = tokio::spawn(router.clone().oneshot(get("/docs")));
+        tokio::time::sleep(Duration::from_millis(1)).await;
+
+        let res = send(&router, get("/docs")).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(res.headers()[RETRY_AFTER], "1");
+        assert_eq!(error(res).await.code, "overloaded");
+
+        // mutations have a budget of their own
+        let res = send(
+            &router,
+            Request::delete("/admin/links/docs")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        stuck.abort();
+    }
+
+    #[tokio::test]
+    async fn decoy_slugs_block_scanners() {
+        let router = router(MockStore::with_links(&[("docs", "https://docs.rs")])).await;
+        let from_scanner = |path: &str| {
+            let mut req = get(path);
+            req.extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from(([203, 0, 113, 7], 4711))));
+            req
+        };
+        let admin = |req: Request<Body>| {
+            let (mut parts, body) = req.into_parts();
+            parts.headers.insert(
+                AUTHORIZATION,
+                HeaderValue::from_str(&format!("Bearer {}", ADMIN_TOKEN)).unwrap(),
+            );
+            Request::from_parts(parts, body)
+        };
+
+        let res = send(&router, from_scanner("/.git/config")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = send(&router, from_scanner("/docs")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()[RETRY_AFTER], "3600");
+        // everyone else is let through
+        let res = send(&router, get("/docs")).await;
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+
+        let res = send(&router, admin(get("/admin/scanners"))).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let scanners: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(scanners[0]["ip"], "203.0.113.7");
+        assert_eq!(scanners[0]["slugs"], serde_json::json!([".git"]));
+
+        let res = send(
+            &router,
+            admin(
+                Request::delete("/admin/scanners/203.0.113.7")
+                    .body(Body::empty())
+                    .unwrap(),
+            ),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        let res = send(&router, from_scanner("/docs")).await;
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+
+        // decoys can't be taken as aliases
+        let res = send(
+            &router,
+            shorten_request(&ShortenRequest {
+                url: "https://wordpress.org".to_owned(),
+                alias: Some("wp-admin".to_owned()),
+                ..Default::default()
+            }),
+        )
+        .await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn clients_behind_a_unix_socket_are_told_apart() {
+        // the router never binds, so the socket doesn't have to exist
+        let router = router_with(
+            MockStore::with_links(&[("docs", "https://docs.rs")]),
+            "[listener]\nbind = \"unix:/run/shortener.sock\"",
+        )
+        .await;
+        let through_proxy = |path: &str, ip: &str| {
+            let mut req = get(path);
+            req.extensions_mut().insert(ConnectInfo(UNIX_PEER));
+            req.headers_mut()
+                .insert(X_FORWARDED_FOR, HeaderValue::from_str(ip).unwrap());
+            req
+        };
+
+        let res = send(&router, through_proxy("/.env", "203.0.113.7")).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        let res = send(&router, through_proxy("/docs", "203.0.113.7")).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        let res = send(&router, through_proxy("/docs", "198.51.100.1")).await;
+        assert_eq!(res.status(), StatusCode::PERMANENT_REDIRECT);
+    }
+
+    #[tokio::test]
+    async fn filtered_feeds_count_links_by_id_alias_or_tag() {
+        let store = Arc::new(MockStore::with_links(&[
+            ("docs", "https://example.com/docs"),
+            ("blog", "https://example.com/blog"),
+            ("shop", "https://example.com/shop"),
+        ]));
+        store.add_alias("docs", "manual").await.unwrap();
+        store
+            .add_tags("blog", &["launch".to_owned()])
+            .await
+            .unwrap();
+        let state = crate::state::tests::state_with("", store).await;
+        let query = FeedQuery {
+            links: Some("manual".to_owned()),
+            tags: Some("launch".to_owned()),
+        };
+        let mut filter = FeedFilter::new(&state, &query).await.unwrap().unwrap();
+        let mut events = state.metrics.subscribe();
+
+        let router = build_router(state.clone());
+        for path in [
+            "/manual",
+            "/docs",
+            "/blog",
+            "/shop",
+            "/docs/stats/referrers",
+        ] {
+            send(&router, get(path)).await;
+        }
+        let mut counts = [0; 4];
+        while let Ok(event) = events.try_recv() {
+            if filter.matches(&state, event.link()).await {
+                count_event(&mut counts, &event);
+            }
+        }
+        // requests, errors, redirects and new links of docs and blog only
+        assert_eq!(counts, [4, 0, 3, 0]);
+    }
+
+    #[tokio::test]
+    async fn links_back_to_the_host_a_proxy_forwarded_are_refused() {
+        let router = router_with(
+            MockStore::default(),
+            "[listener]\ntrusted_proxies = [\"0.0.0.0/32\"]",
+        )
+        .await;
+        let shorten = |url: &str| {
+            let mut req = shorten_request(&ShortenRequest {
+                url: url.to_owned(),
+                ..Default::default()
+            });
+            let headers = req.headers_mut();
+            headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static("https"));
+            headers.insert(X_FORWARDED_HOST, HeaderValue::from_static("go.example.com"));
+            req
+        };
+
+        let res = send(&router, shorten("https://go.example.com/docs")).await;
+        assert_eq!(res.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(error(res).await.message.contains("back to this shortener"));
+        let res = send(&router, shorten("https://example.com/docs")).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+    }
+}
@@ -0,0 +1,206 @@
+//! Bundles: named lists of links managed under `/admin/bundles`, each with a public page.
+//! Also the public directory of every public link, and the page of links that were removed.
+
+use axum::{
+    extract::{Extension, Path, Query, State},
+    http::StatusCode,
+    response::{Html, IntoResponse, Response},
+};
+use maud::{html, DOCTYPE};
+use serde::Deserialize;
+use tracing::instrument;
+
+use super::{asset_url, AppJson, AppResponse};
+use crate::api::{BundleRequest, BundleResponse, Client};
+use crate::error::AppError;
+use crate::state::AppState;
+use crate::store::Tombstone;
+use crate::validate::display_url;
+
+pub(crate) async fn list_bundles(
+    State(state): State<AppState>,
+    Extension(client): Extension<Client>,
+) -> Result<impl IntoResponse, AppError> {
+    let bundles: Vec<BundleResponse> = state
+        .store
+        .list_bundles()
+        .await?
+        .into_iter()
+        .map(|bundle| state.bundle_response(bundle, &client))
+        .collect();
+    Ok(AppResponse(bundles))
+}
+
+#[instrument(skip(state, data))]
+pub(crate) async fn create_bundle(
+    State(state): State<AppState>,
+    Extension(client): Extension<Client>,
+    AppJson(data): AppJson<BundleRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    let id = state.create_bundle(&data).await?;
+    let bundle = state.bundle(&id).await?;
+    Ok((
+        StatusCode::CREATED,
+        AppResponse(state.bundle_response(bundle, &client)),
+    ))
+}
+
+pub(crate) async fn get_bundle(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Extension(client): Extension<Client>,
+) -> Result<impl IntoResponse, AppError> {
+    let bundle = state.bundle(&id).await?;
+    Ok(AppResponse(state.bundle_response(bundle, &client)))
+}
+
+/// Replaces the bundle as a whole, which is also how its links are reordered
+#[instrument(skip(state, data))]
+pub(crate) async fn update_bundle(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Extension(client): Extension<Client>,
+    AppJson(data): AppJson<BundleRequest>,
+) -> Result<impl IntoResponse, AppError> {
+    state.update_bundle(&id, &data).await?;
+    let bundle = state.bundle(&id).await?;
+    Ok(AppResponse(state.bundle_response(bundle, &client)))
+}
+
+pub(crate) async fn delete_bundle(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, AppError> {
+    state.delete_bundle(&id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// The public page of a bundle. Its entries go through the short links so they count as
+/// clicks, disabled and private links are left out.
+#[instrument(skip(state))]
+pub(crate) async fn bundle_page(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Extension(client): Extension<Client>,
+) -> Result<Html<String>, AppError> {
+    let bundle = state.bundle_response(state.bundle(&id).await?, &client);
+    let page = html! {
+        (DOCTYPE)
+        html lang="en" {
+            head {
+                meta charset="utf-8";
+                meta name="viewport" content="width=device-width, initial-scale=1";
+                title { (bundle.title) }
+                @if let Some(description) = &bundle.description {
+                    meta name="description" content=(description);
+                }
+                link rel="icon" type="image/svg+xml" href=(asset_url("favicon.svg"));
+                link rel="stylesheet" href=(asset_url("ui.css"));
+            }
+            body {
+                main.bundle {
+                    h1 { (bundle.title) }
+                    @if let Some(description) = &bundle.description {
+                        p { (description) }
+                    }
+                    ul {
+                        @for link in bundle.links.iter().filter(|link| !link.flagged && !link.visibility.is_private()) {
+                            li {
+                                a href=(link.short_url) {
+                                    @match &link.title {
+                                        Some(title) => (title),
+                                        None => (display_url(&link.url)),
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    };
+    Ok(Html(page.into_string()))
+}
+
+/// What browsers get instead of [`AppError::Gone`] as JSON
+pub(crate) fn gone_page(tombstone: &Tombstone) -> Response {
+    let page = html! {
+        (DOCTYPE)
+        html lang="en" {
+            head {
+                meta charset="utf-8";
+                meta name="viewport" content="width=device-width, initial-scale=1";
+                title { "Link removed" }
+                link rel="icon" type="image/svg+xml" href=(asset_url("favicon.svg"));
+                link rel="stylesheet" href=(asset_url("ui.css"));
+            }
+            body {
+                main.bundle {
+                    h1 { "This link was removed" }
+                    p {
+                        "It stopped redirecting on "
+                        time datetime=(tombstone.removed_at.to_rfc3339()) {
+                            (tombstone.removed_at.format("%B %-d, %Y"))
+                        }
+                        "."
+                    }
+                    @if let Some(reason) = &tombstone.reason {
+                        p { (reason) }
+                    }
+                }
+            }
+        }
+    };
+    (StatusCode::GONE, Html(page.into_string())).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct DirectoryQuery {
+    /// id of the last link on the previous page
+    pub(crate) after: Option<String>,
+}
+
+/// `/directory`, the public links that still redirect with their tags, when
+/// `features.public_directory` is on
+#[instrument(skip(state))]
+pub(crate) async fn directory_page(
+    State(state): State<AppState>,
+    Query(query): Query<DirectoryQuery>,
+    Extension(client): Extension<Client>,
+) -> Result<Html<String>, AppError> {
+    let (links, more) = state.directory_links(query.after.as_deref()).await?;
+    let page = html! {
+        (DOCTYPE)
+        html lang="en" {
+            head {
+                meta charset="utf-8";
+                meta name="viewport" content="width=device-width, initial-scale=1";
+                title { "Directory" }
+                link rel="icon" type="image/svg+xml" href=(asset_url("favicon.svg"));
+                link rel="stylesheet" href=(asset_url("ui.css"));
+            }
+            body {
+                main.bundle {
+                    h1 { "Directory" }
+                    ul {
+                        @for link in &links {
+                            li {
+                                a href=(state.config.short_url(&link.id, &client)) { (display_url(&link.url)) }
+                                @if !link.tags.is_empty() {
+                                    " " small { (link.tags.join(", ")) }
+                                }
+                            }
+                        }
+                    }
+                    @if links.is_empty() {
+                        p { "No links yet." }
+                    }
+                    @if let (true, Some(last)) = (more, links.last()) {
+                        p { a href={ "/directory?after=" (last.id) } { "More" } }
+                    }
+                }
+            }
+        }
+    };
+    Ok(Html(page.into_string()))
+}
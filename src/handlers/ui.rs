@@ -0,0 +1,760 @@
+//! Server-rendered admin pages under `/ui`
+
+use anyhow::Result;
+use axum::{
+    extract::{Extension, Path, Query, Request, State},
+    http::{
+        header::{COOKIE, RETRY_AFTER, SET_COOKIE},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    middleware::Next,
+    response::{Html, IntoResponse, Redirect, Response},
+    Form,
+};
+use maud::{html, Markup, DOCTYPE};
+use serde::Deserialize;
+use tracing::info;
+
+use super::{asset_url, retry_after_secs};
+use crate::api::{
+    BreakdownQuery, Client, Interval, LinkCursor, LinkStatus, ListLinksQuery, ShortenRequest,
+    TimeseriesPoint, Visibility,
+};
+use crate::error::AppError;
+use crate::state::{AppState, OPEN_REPORTS_LIMIT, SESSION_LIFETIME};
+use crate::validate::normalize_domain;
+
+/// Holds the session of the HTML pages under `/ui`, see [`AppState::start_admin_session`]
+const SESSION_COOKIE: &str = "shortener_session";
+/// Links per page of `/ui`
+const UI_PAGE_SIZE: i64 = 50;
+
+/// Sends browsers without a session to the login page
+pub(crate) async fn require_session(
+    State(state): State<AppState>,
+    req: Request,
+    next: Next,
+) -> Response {
+    if !state.is_admin_session(session_cookie_value(req.headers())) {
+        return Redirect::to("/ui/login").into_response();
+    }
+    next.run(req).await
+}
+
+/// What the login page stored in [`SESSION_COOKIE`]
+fn session_cookie_value(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .find_map(|cookie| {
+            let (name, value) = cookie.trim().split_once('=')?;
+            (name == SESSION_COOKIE).then_some(value)
+        })
+}
+
+/// `Set-Cookie` for the session, `None` logs out
+fn session_cookie(session: Option<&str>, secure: bool) -> HeaderValue {
+    let mut cookie = match session {
+        Some(session) => format!(
+            "{}={}; Max-Age={}",
+            SESSION_COOKIE,
+            session,
+            SESSION_LIFETIME.as_secs()
+        ),
+        None => format!("{}=; Max-Age=0", SESSION_COOKIE),
+    };
+    // SameSite keeps other sites from posting the forms with it
+    cookie.push_str("; Path=/; HttpOnly; SameSite=Strict");
+    if secure {
+        cookie.push_str("; Secure");
+    }
+    HeaderValue::try_from(cookie).expect("sessions are digits and hex")
+}
+
+/// Whatever went wrong behind one of the `/ui` pages, as a page
+#[derive(Debug)]
+pub(crate) struct PageError(AppError);
+
+impl From<AppError> for PageError {
+    fn from(e: AppError) -> Self {
+        Self(e)
+    }
+}
+
+impl IntoResponse for PageError {
+    fn into_response(self) -> Response {
+        self.0.report();
+
+        let status = self.0.status();
+        let body = page(
+            "Error",
+            true,
+            html! {
+                h1 { (status) }
+                p.error { (self.0.message()) }
+                p { a href="/ui" { "Back to the links" } }
+            },
+        );
+        (status, body).into_response()
+    }
+}
+
+/// The frame of every `/ui` page
+fn page(title: &str, signed_in: bool, content: Markup) -> Html<String> {
+    let page = html! {
+        (DOCTYPE)
+        html lang="en" {
+            head {
+                meta charset="utf-8";
+                meta name="viewport" content="width=device-width, initial-scale=1";
+                title { (title) " · shortener" }
+                link rel="icon" type="image/svg+xml" href=(asset_url("favicon.svg"));
+                link rel="stylesheet" href=(asset_url("ui.css"));
+                script src=(asset_url("ui.js")) defer {}
+            }
+            body {
+                header {
+                    nav {
+                        a href="/ui" { "shortener" }
+                        @if signed_in {
+                            a href="/ui/reports" { "Reports" }
+                            a href="/ui/blocklist" { "Blocklist" }
+                        }
+                    }
+                    @if signed_in {
+                        form.inline method="post" action="/ui/logout" {
+                            button { "Log out" }
+                        }
+                    }
+                }
+                main { (content) }
+            }
+        }
+    };
+    Html(page.into_string())
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct LoginForm {
+    pub(crate) token: String,
+}
+
+/// Submitted by the create and edit forms, tags are comma separated
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct LinkForm {
+    pub(crate) url: String,
+    #[serde(default)]
+    pub(crate) alias: String,
+    #[serde(default)]
+    pub(crate) tags: String,
+    /// the checkbox, only sent when ticked
+    #[serde(default)]
+    pub(crate) private: Option<String>,
+}
+
+impl LinkForm {
+    pub(crate) fn visibility(&self) -> Visibility {
+        self.private.is_some().into()
+    }
+
+    pub(crate) fn tags(&self) -> Vec<String> {
+        self.tags
+            .split(',')
+            .map(str::trim)
+            .filter(|tag| !tag.is_empty())
+            .map(str::to_owned)
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct LinksPageQuery {
+    pub(crate) after: Option<LinkCursor>,
+    /// id of the link that was just created
+    pub(crate) created: Option<String>,
+}
+
+fn login_page(error: Option<&str>) -> Html<String> {
+    page(
+        "Log in",
+        false,
+        html! {
+            h1 { "Log in" }
+            @if let Some(error) = error {
+                p.error { (error) }
+            }
+            form method="post" action="/ui/login" {
+                label { "Admin token " input type="password" name="token" autofocus required; }
+                " "
+                button { "Log in" }
+            }
+        },
+    )
+}
+
+pub(crate) async fn ui_login_form() -> Html<String> {
+    login_page(None)
+}
+
+pub(crate) async fn ui_login(
+    State(state): State<AppState>,
+    Extension(client): Extension<Client>,
+    Form(form): Form<LoginForm>,
+) -> Response {
+    if let Err(wait) = state.login_limiter.check(client.ip) {
+        let secs = retry_after_secs(&wait);
+        let body = login_page(Some(&format!(
+            "Too many attempts, try again in {} seconds.",
+            secs
+        )));
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(RETRY_AFTER, HeaderValue::from(secs))],
+            body,
+        )
+            .into_response();
+    }
+    let session = match state.start_admin_session() {
+        Some(session) if state.is_admin(Some(&form.token)) => session,
+        _ => {
+            let body = login_page(Some("That is not the admin token."));
+            return (StatusCode::UNAUTHORIZED, body).into_response();
+        }
+    };
+    let secure = state.config.base_url_for(&client).scheme() == "https";
+    let cookie = session_cookie(Some(&session), secure);
+    ([(SET_COOKIE, cookie)], Redirect::to("/ui")).into_response()
+}
+
+pub(crate) async fn ui_logout(
+    State(state): State<AppState>,
+    Extension(client): Extension<Client>,
+) -> Response {
+    let secure = state.config.base_url_for(&client).scheme() == "https";
+    let cookie = session_cookie(None, secure);
+    ([(SET_COOKIE, cookie)], Redirect::to("/ui/login")).into_response()
+}
+
+/// Every link with its clicks, and a form to add one
+pub(crate) async fn ui_links(
+    State(state): State<AppState>,
+    Extension(client): Extension<Client>,
+    Query(query): Query<LinksPageQuery>,
+) -> Result<Html<String>, PageError> {
+    let notice = query
+        .created
+        .map(|id| format!("Created {}", state.config.short_url(&id, &client)));
+    links_page(&state, &client, query.after.as_ref(), notice, None).await
+}
+
+pub(crate) async fn ui_create_link(
+    State(state): State<AppState>,
+    Extension(client): Extension<Client>,
+    Form(form): Form<LinkForm>,
+) -> Result<Response, PageError> {
+    state.check_maintenance(false)?;
+    let request = ShortenRequest {
+        url: form.url.trim().to_owned(),
+        alias: Some(form.alias.trim().to_owned()).filter(|alias| !alias.is_empty()),
+        tags: form.tags(),
+        visibility: Some(form.visibility()),
+        ..Default::default()
+    };
+    match state
+        .create_link(&request, None, client.forwarded_base.as_ref())
+        .await
+    {
+        Ok(id) => Ok(Redirect::to(&format!("/ui?created={}", id)).into_response()),
+        Err(e) if e.status().is_client_error() => {
+            let status = e.status();
+            let page = links_page(&state, &client, None, None, Some((&form, e))).await?;
+            Ok((status, page).into_response())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// `failed` is a create form to show again with what was wrong with it
+async fn links_page(
+    state: &AppState,
+    client: &Client,
+    after: Option<&LinkCursor>,
+    notice: Option<String>,
+    failed: Option<(&LinkForm, AppError)>,
+) -> Result<Html<String>, PageError> {
+    let query = ListLinksQuery {
+        after: after.cloned(),
+        limit: UI_PAGE_SIZE,
+        ..Default::default()
+    };
+    let (links, next) = state.list_links(&query).await?;
+    let ids: Vec<String> = links.iter().map(|link| link.id.clone()).collect();
+    let clicks = state.store.click_totals(&ids).await?;
+    let (form, error) = match &failed {
+        Some((form, e)) => (*form, Some(e.message())),
+        None => (&LinkForm::default(), None),
+    };
+
+    Ok(page(
+        "Links",
+        true,
+        html! {
+            h1 { "Links" }
+            @if let Some(notice) = notice {
+                p.notice { (notice) }
+            }
+            form method="post" action="/ui/links" {
+                input type="url" name="url" placeholder="https://example.com/a/long/path" value=(form.url) required;
+                " "
+                input type="text" name="alias" placeholder="alias (optional)" value=(form.alias);
+                " "
+                input type="text" name="tags" placeholder="tags, comma separated" value=(form.tags);
+                " "
+                label { input type="checkbox" name="private" checked[form.private.is_some()]; " Private" }
+                " "
+                button { "Shorten" }
+                @if let Some(error) = error {
+                    p.error { (error) }
+                }
+            }
+            table {
+                thead {
+                    tr { th { "Short link" } th { "Destination" } th { "Tags" } th { "Clicks" } th {} }
+                }
+                tbody {
+                    @for link in &links {
+                        @let short_url = state.config.short_url(&link.id, client);
+                        tr {
+                            td {
+                                a href=(short_url) { (link.id) }
+                                @if link.flagged {
+                                    " " span.flagged title="Flagged as unsafe, no longer redirects" { "flagged" }
+                                }
+                                @if LinkStatus::of(link) == Some(LinkStatus::Broken) {
+                                    " " span.broken title="The destination failed its last check" { "broken" }
+                                }
+                                @if link.visibility.is_private() {
+                                    " " span.private title="Stats need a token, not listed publicly" { "private" }
+                                }
+                                @if !link.aliases.is_empty() {
+                                    br;
+                                    small { "also " (link.aliases.join(", ")) }
+                                }
+                            }
+                            td.url title=(link.url) { (link.url) }
+                            td { (link.tags.join(", ")) }
+                            td.num { (clicks.get(&link.id).copied().unwrap_or_default()) }
+                            td {
+                                a href={ "/ui/links/" (link.id) } { "Stats" }
+                                " "
+                                a href={ "/ui/links/" (link.id) "/edit" } { "Edit" }
+                                " "
+                                @if link.flagged {
+                                    form.inline method="post" action={ "/ui/links/" (link.id) "/enable" } {
+                                        button { "Enable" }
+                                    }
+                                } @else {
+                                    form.inline method="post" action={ "/ui/links/" (link.id) "/disable" }
+                                        data-confirm="Stop this link from redirecting?" {
+                                        button { "Disable" }
+                                    }
+                                }
+                                " "
+                                form.inline method="post" action={ "/ui/links/" (link.id) "/delete" }
+                                    data-confirm="Delete this link and its clicks?" {
+                                    button { "Delete" }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            @if links.is_empty() {
+                p { "No links yet." }
+            }
+            @if let Some(next) = next {
+                p { a href={ "/ui?after=" (next) } { "Next page" } }
+            }
+        },
+    ))
+}
+
+/// Clicks of one link over time, with where they came from
+pub(crate) async fn ui_link_stats(
+    State(state): State<AppState>,
+    Extension(client): Extension<Client>,
+    Path(id): Path<String>,
+    Query(query): Query<BreakdownQuery>,
+) -> Result<Html<String>, PageError> {
+    let (from, to) = query.range()?;
+    let link = state.link(&id).await?;
+    let bots = query.exclude_bots;
+    let points = state
+        .store
+        .timeseries(&id, Interval::Day, from, to, bots)
+        .await?;
+    let referrers = state.store.referrers(&id, from, to, bots).await?;
+    let countries = state.store.countries(&id, from, to, bots).await?;
+    let total: i64 = points.iter().map(|point| point.clicks).sum();
+    let visitors: i64 = points.iter().map(|point| point.unique_visitors).sum();
+    let share = |clicks: i64| {
+        if total > 0 {
+            format!("{:.1}%", clicks as f64 * 100.0 / total as f64)
+        } else {
+            String::new()
+        }
+    };
+
+    Ok(page(
+        &format!("Stats of {}", id),
+        true,
+        html! {
+            h1 { "Stats of " (id) }
+            p {
+                a href=(state.config.short_url(&id, &client)) { (state.config.short_url(&id, &client)) }
+                " → " (link.url)
+            }
+            @if let Some(checked_at) = link.checked_at {
+                p {
+                    @match link.check_status {
+                        Some(status) => { "The destination answered with status " (status) },
+                        None => { "The destination couldn't be reached" },
+                    }
+                    " on " (checked_at.format("%Y-%m-%d %H:%M"))
+                }
+            }
+            form method="get" {
+                label { "From " input type="date" name="from" value=(from); }
+                " "
+                label { "to " input type="date" name="to" value=(to); }
+                " "
+                label { input type="checkbox" name="exclude_bots" value="true" checked[bots]; " Leave out bots" }
+                " "
+                button { "Show" }
+            }
+            h2 { (total) " clicks" }
+            p { "from " (visitors) " unique visitors, counted per day" }
+            (clicks_chart(&points))
+            h2 { "Referrers" }
+            @if referrers.is_empty() {
+                p { "No referrers in these days." }
+            } @else {
+                table {
+                    thead { tr { th { "Referrer" } th { "Clicks" } th {} } }
+                    tbody {
+                        @for referrer in &referrers {
+                            tr {
+                                td.url title=(referrer.referrer) { (referrer.referrer) }
+                                td.num { (referrer.clicks) }
+                                td.num { (share(referrer.clicks)) }
+                            }
+                        }
+                    }
+                }
+            }
+            h2 { "Countries" }
+            @if countries.is_empty() {
+                p { "No clicks in these days." }
+            } @else {
+                table {
+                    thead { tr { th { "Country" } th { "Clicks" } th {} } }
+                    tbody {
+                        @for country in &countries {
+                            tr {
+                                td { (country.country.as_deref().unwrap_or("unknown")) }
+                                td.num { (country.clicks) }
+                                td.num { (share(country.clicks)) }
+                            }
+                        }
+                    }
+                }
+            }
+        },
+    ))
+}
+
+/// Bars of clicks per day, drawn on the server so the page works without scripts
+fn clicks_chart(points: &[TimeseriesPoint]) -> Markup {
+    const WIDTH: f64 = 720.0;
+    const HEIGHT: f64 = 160.0;
+    let max = points
+        .iter()
+        .map(|point| point.clicks)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let bar = WIDTH / points.len().max(1) as f64;
+    html! {
+        svg.chart viewBox={ "0 0 " (WIDTH) " " (HEIGHT + 20.0) } role="img" aria-label="Clicks per day" {
+            @for (i, point) in points.iter().enumerate() {
+                @let height = point.clicks as f64 / max as f64 * HEIGHT;
+                rect x=(format!("{:.1}", i as f64 * bar)) y=(format!("{:.1}", HEIGHT - height))
+                    width=(format!("{:.1}", (bar - 1.0).max(1.0))) height=(format!("{:.1}", height)) {
+                    title {
+                        (point.start.format("%Y-%m-%d")) ": " (point.clicks) " clicks, "
+                        (point.unique_visitors) " visitors"
+                    }
+                }
+            }
+            @if let (Some(first), Some(last)) = (points.first(), points.last()) {
+                text x="0" y=(HEIGHT + 15.0) { (first.start.format("%Y-%m-%d")) }
+                text x=(WIDTH) y=(HEIGHT + 15.0) text-anchor="end" { (last.start.format("%Y-%m-%d")) }
+            }
+            text x=(WIDTH) y="12" text-anchor="end" { "max " (max) }
+        }
+    }
+}
+
+pub(crate) async fn ui_edit_form(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Html<String>, PageError> {
+    let link = state.link(&id).await?;
+    let form = LinkForm {
+        url: link.url,
+        alias: String::new(),
+        tags: link.tags.join(", "),
+        private: link.visibility.is_private().then(String::new),
+    };
+    Ok(edit_page(&id, &form, None))
+}
+
+pub(crate) async fn ui_edit_link(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Extension(client): Extension<Client>,
+    Form(form): Form<LinkForm>,
+) -> Result<Response, PageError> {
+    state.check_maintenance(false)?;
+    match state
+        .edit_link(
+            &id,
+            form.url.trim(),
+            &form.tags(),
+            form.visibility(),
+            client.forwarded_base.as_ref(),
+        )
+        .await
+    {
+        Ok(()) => Ok(Redirect::to("/ui").into_response()),
+        Err(e) if e.status().is_client_error() && !matches!(e, AppError::NotFound(_)) => {
+            let page = edit_page(&id, &form, Some(&e.message()));
+            Ok((e.status(), page).into_response())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn edit_page(id: &str, form: &LinkForm, error: Option<&str>) -> Html<String> {
+    page(
+        &format!("Edit {}", id),
+        true,
+        html! {
+            h1 { "Edit " (id) }
+            form method="post" action={ "/ui/links/" (id) } {
+                p { label { "Destination " input type="url" name="url" value=(form.url) required; } }
+                p { label { "Tags " input type="text" name="tags" value=(form.tags); } }
+                p { label { input type="checkbox" name="private" checked[form.private.is_some()]; " Private, stats need a token" } }
+                @if let Some(error) = error {
+                    p.error { (error) }
+                }
+                button { "Save" }
+                " "
+                a href="/ui" { "Cancel" }
+            }
+        },
+    )
+}
+
+pub(crate) async fn ui_disable_link(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Response, PageError> {
+    state.check_maintenance(false)?;
+    state.set_flagged(&id, true).await?;
+    Ok(Redirect::to("/ui").into_response())
+}
+
+pub(crate) async fn ui_enable_link(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Response, PageError> {
+    state.check_maintenance(false)?;
+    state.set_flagged(&id, false).await?;
+    Ok(Redirect::to("/ui").into_response())
+}
+
+/// Reports nobody has dealt with yet, each can disable its link or be dismissed
+pub(crate) async fn ui_reports(State(state): State<AppState>) -> Result<Html<String>, PageError> {
+    let reports = state.store.open_reports(OPEN_REPORTS_LIMIT).await?;
+    Ok(page(
+        "Reports",
+        true,
+        html! {
+            h1 { "Abuse reports" }
+            @if reports.is_empty() {
+                p { "No open reports." }
+            } @else {
+                table {
+                    thead {
+                        tr { th { "Reported" } th { "Link" } th { "Destination" } th { "Reason" } th {} }
+                    }
+                    tbody {
+                        @for report in &reports {
+                            tr {
+                                td { (report.reported_at.format("%Y-%m-%d %H:%M")) }
+                                td {
+                                    a href={ "/ui/links/" (report.url_id) } { (report.url_id) }
+                                    @if report.flagged {
+                                        " " span.flagged { "disabled" }
+                                    }
+                                }
+                                td.url title=(report.url) { (report.url) }
+                                td {
+                                    strong { (report.category) }
+                                    @if !report.reason.is_empty() {
+                                        " " (report.reason)
+                                    }
+                                }
+                                td {
+                                    @if report.flagged {
+                                        form.inline method="post" action={ "/ui/links/" (report.url_id) "/enable" } {
+                                            button { "Enable link" }
+                                        }
+                                    } @else {
+                                        form.inline method="post" action={ "/ui/reports/" (report.url_id) "/disable" }
+                                            data-confirm="Stop this link from redirecting?" {
+                                            button { "Disable link" }
+                                        }
+                                    }
+                                    " "
+                                    form.inline method="post" action={ "/ui/reports/" (report.url_id) "/dismiss" } {
+                                        button { "Dismiss" }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        },
+    ))
+}
+
+pub(crate) async fn ui_disable_reported(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Response, PageError> {
+    state.check_maintenance(false)?;
+    state.set_flagged(&id, true).await?;
+    Ok(Redirect::to("/ui/reports").into_response())
+}
+
+pub(crate) async fn ui_dismiss_reports(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Response, PageError> {
+    state.check_maintenance(false)?;
+    let dismissed = state.store.resolve_reports(&id).await?;
+    info!("Dismissed {} reports against {}", dismissed, id);
+    Ok(Redirect::to("/ui/reports").into_response())
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct BlockForm {
+    pub(crate) domain: String,
+    #[serde(default)]
+    pub(crate) reason: String,
+}
+
+pub(crate) async fn ui_blocklist(State(state): State<AppState>) -> Result<Html<String>, PageError> {
+    blocklist_page(&state, None).await
+}
+
+pub(crate) async fn ui_block_domain(
+    State(state): State<AppState>,
+    Form(form): Form<BlockForm>,
+) -> Result<Response, PageError> {
+    state.check_maintenance(false)?;
+    let reason = Some(form.reason.trim()).filter(|reason| !reason.is_empty());
+    let blocked = match normalize_domain(&form.domain) {
+        Ok(domain) => state.block_domain(&domain, reason).await,
+        Err(e) => Err(e),
+    };
+    match blocked {
+        Ok(_) => Ok(Redirect::to("/ui/blocklist").into_response()),
+        Err(e) if e.status().is_client_error() => {
+            let status = e.status();
+            let page = blocklist_page(&state, Some((&form, e))).await?;
+            Ok((status, page).into_response())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub(crate) async fn ui_unblock_domain(
+    State(state): State<AppState>,
+    Path(domain): Path<String>,
+) -> Result<Response, PageError> {
+    state.check_maintenance(false)?;
+    state.unblock_domain(&normalize_domain(&domain)?).await?;
+    Ok(Redirect::to("/ui/blocklist").into_response())
+}
+
+/// `failed` is an add form to show again with what was wrong with it
+async fn blocklist_page(
+    state: &AppState,
+    failed: Option<(&BlockForm, AppError)>,
+) -> Result<Html<String>, PageError> {
+    let domains = state.store.list_blocked().await?;
+    let (form, error) = match &failed {
+        Some((form, e)) => (*form, Some(e.message())),
+        None => (&BlockForm::default(), None),
+    };
+
+    Ok(page(
+        "Blocklist",
+        true,
+        html! {
+            h1 { "Blocked domains" }
+            p { "Links to these domains and their subdomains can't be created." }
+            form method="post" action="/ui/blocklist" {
+                input type="text" name="domain" placeholder="example.com" value=(form.domain) required;
+                " "
+                input type="text" name="reason" placeholder="reason (optional)" value=(form.reason);
+                " "
+                button { "Block" }
+                @if let Some(error) = error {
+                    p.error { (error) }
+                }
+            }
+            table {
+                thead { tr { th { "Domain" } th { "Reason" } th {} } }
+                tbody {
+                    @for blocked in &domains {
+                        tr {
+                            td { (blocked.domain) }
+                            td { (blocked.reason.as_deref().unwrap_or_default()) }
+                            td {
+                                form.inline method="post" action={ "/ui/blocklist/" (blocked.domain) "/delete" } {
+                                    button { "Unblock" }
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        },
+    ))
+}
+
+pub(crate) async fn ui_delete_link(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Response, PageError> {
+    state.check_maintenance(false)?;
+    state.delete_link(&id, None).await?;
+    Ok(Redirect::to("/ui").into_response())
+}
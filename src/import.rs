@@ -0,0 +1,336 @@
+//! Exporting links and importing them from files, Bitly and YOURLS
+
+use std::path::Path as FsPath;
+
+use anyhow::{Context as _, Result};
+use chrono::NaiveDate;
+use serde::Deserialize;
+use sqlx::FromRow;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio_stream::StreamExt;
+use tracing::info;
+
+use crate::api::ExportedLink;
+use crate::error::AppError;
+use crate::state::AppState;
+use crate::store::{InsertLink, LinkOptions, Store};
+use crate::validate::random_link_id;
+
+/// One row of a bit.ly CSV export, headers are matched ignoring case and spaces
+#[derive(Debug, Deserialize)]
+struct BitlyLink {
+    /// `abc123`, `bit.ly/abc123` or the whole short link
+    #[serde(alias = "bitlink", alias = "link")]
+    short_code: String,
+    #[serde(alias = "url")]
+    long_url: String,
+    #[serde(alias = "created_at")]
+    created: String,
+    /// all-time total, bit.ly doesn't export them per day
+    #[serde(default)]
+    clicks: Option<i64>,
+}
+
+/// A link of another shortener, as `import_foreign_links` takes it
+#[derive(Debug, FromRow)]
+struct ForeignLink {
+    code: String,
+    url: String,
+    /// the day its clicks are counted on
+    created: Option<NaiveDate>,
+    clicks: i64,
+}
+
+pub(crate) async fn export_links(store: &dyn Store, output: Option<&FsPath>) -> Result<()> {
+    let mut out: Box<dyn AsyncWrite + Send + Unpin> = match output {
+        Some(path) => Box::new(tokio::fs::File::create(path).await?),
+        None => Box::new(tokio::io::stdout()),
+    };
+    let mut links = store.export_links();
+    let mut count = 0;
+    while let Some(link) = links.try_next().await? {
+        let mut line = serde_json::to_vec(&link)?;
+        line.push(b'\n');
+        out.write_all(&line).await?;
+        count += 1;
+    }
+    out.flush().await?;
+    info!("Exported {} links", count);
+
+    Ok(())
+}
+
+/// `input`, or stdin for `-`
+async fn open_input(input: &FsPath) -> Result<Box<dyn AsyncRead + Send + Unpin>> {
+    if input == FsPath::new("-") {
+        Ok(Box::new(tokio::io::stdin()))
+    } else {
+        Ok(Box::new(tokio::fs::File::open(input).await?))
+    }
+}
+
+/// Loads links written by `export`, their URLs go through the checks of new links again as the
+/// policy may have changed since
+pub(crate) async fn import_links(state: &AppState, input: &FsPath) -> Result<()> {
+    let mut lines = BufReader::new(open_input(input).await?).lines();
+    let (mut imported, mut skipped) = (0, 0);
+    let mut line_no = 0;
+    while let Some(line) = lines.next_line().await? {
+        line_no += 1;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut link: ExportedLink = serde_json::from_str(&line)
+            .with_context(|| format!("Invalid link on line {}", line_no))?;
+        link.url = state
+            .check_import(&link.url)
+            .await
+            .with_context(|| format!("Invalid URL on line {}", line_no))?;
+        if state.store.import_link(&link).await? {
+            imported += 1;
+        } else {
+            skipped += 1;
+        }
+    }
+    info!(
+        "Imported {} links, skipped {} that already existed",
+        imported, skipped
+    );
+
+    Ok(())
+}
+
+/// Loads a bit.ly CSV export. Their click totals are counted on the day each link was created,
+/// as that's all the export has.
+pub(crate) async fn import_bitly(state: &AppState, input: &FsPath) -> Result<()> {
+    let mut data = Vec::new();
+    open_input(input).await?.read_to_end(&mut data).await?;
+    let mut csv = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(&data[..]);
+    let headers: csv::StringRecord = csv
+        .headers()?
+        .iter()
+        .map(|header| header.to_lowercase().replace([' ', '-'], "_"))
+        .collect();
+    csv.set_headers(headers);
+
+    let mut links = Vec::new();
+    for (row, link) in csv.deserialize::<BitlyLink>().enumerate() {
+        // the header is line 1
+        let line_no = row + 2;
+        let link = link.with_context(|| format!("Invalid link on line {}", line_no))?;
+        let created = parse_bitly_day(&link.created)
+            .with_context(|| format!("Invalid created date on line {}", line_no))?;
+        links.push(ForeignLink {
+            code: link
+                .short_code
+                .rsplit('/')
+                .next()
+                .unwrap_or_default()
+                .to_owned(),
+            url: link.long_url,
+            created: Some(created),
+            clicks: link.clicks.unwrap_or_default(),
+        });
+    }
+    import_foreign_links(state, links, false).await
+}
+
+/// Copies the links of a YOURLS install, their click totals are counted on the day each link
+/// was created
+#[cfg(feature = "yourls")]
+pub(crate) async fn migrate_yourls(
+    state: &AppState,
+    database_url: &str,
+    table_prefix: &str,
+    dry_run: bool,
+) -> Result<()> {
+    // goes into the query as is
+    if !table_prefix
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        anyhow::bail!("Invalid YOURLS table prefix {:?}", table_prefix);
+    }
+    let pool = sqlx::MySqlPool::connect(database_url)
+        .await
+        .context("Failed to connect to the YOURLS database")?;
+    let links = sqlx::query_as(&format!(
+        r#"
+        SELECT keyword AS code, url, CAST(`timestamp` AS DATE) AS created,
+            CAST(clicks AS SIGNED) AS clicks
+        FROM {}url
+        ORDER BY `timestamp`, keyword;
+        "#,
+        table_prefix
+    ))
+    .fetch_all(&pool)
+    .await
+    .context("Failed to read the YOURLS links")?;
+    pool.close().await;
+    import_foreign_links(state, links, dry_run).await
+}
+
+/// Adds links of another shortener under their own code where that's free and a valid alias,
+/// printing one line for each link that isn't imported as is. URLs that couldn't be shortened
+/// here are left out.
+async fn import_foreign_links(
+    state: &AppState,
+    links: Vec<ForeignLink>,
+    dry_run: bool,
+) -> Result<()> {
+    let store = state.store.as_ref();
+    let (mut imported, mut renamed, mut skipped, mut invalid) = (0, 0, 0, 0);
+    for link in links {
+        let url = match state.check_import(&link.url).await {
+            Ok(url) => url,
+            Err(e @ (AppError::Db(_) | AppError::DatabaseUnavailable(_))) => return Err(e.into()),
+            Err(e) => {
+                println!("{}\tinvalid\t{}", link.code, e);
+                invalid += 1;
+                continue;
+            }
+        };
+        if let Some(id) = store.id_for_url(&url).await? {
+            println!(
+                "{}\tskipped\t{} is shortened already as {}",
+                link.code, url, id
+            );
+            skipped += 1;
+            continue;
+        }
+        // a decoy would get every visitor of the link taken for a scanner
+        let reason = if state.check_alias(&link.code).is_err() {
+            Some("not a valid alias")
+        } else if store.link_exists(&link.code).await? {
+            Some("taken")
+        } else {
+            None
+        };
+        if dry_run {
+            if let Some(reason) = reason {
+                println!("{}\trenamed\t{}, gets a new id", link.code, reason);
+                renamed += 1;
+            }
+            imported += 1;
+            continue;
+        }
+
+        let mut id = match reason {
+            Some(_) => new_link_id(store).await?,
+            None => link.code.clone(),
+        };
+        let inserted = loop {
+            match store
+                .insert_link(&id, &url, &LinkOptions::default(), None, None)
+                .await?
+            {
+                InsertLink::Inserted => break true,
+                InsertLink::IdTaken => id = new_link_id(store).await?,
+                InsertLink::UrlExists(_) => break false,
+            }
+        };
+        if !inserted {
+            println!("{}\tskipped\t{} was shortened meanwhile", link.code, url);
+            skipped += 1;
+            continue;
+        }
+        if id != link.code {
+            println!(
+                "{}\trenamed\t{}, now {}",
+                link.code,
+                reason.unwrap_or("taken"),
+                id
+            );
+            renamed += 1;
+        }
+        imported += 1;
+        if let Some(created) = link.created.filter(|_| link.clicks > 0) {
+            store.import_clicks(&id, created, link.clicks).await?;
+        }
+    }
+    info!(
+        "{} {} links ({} under a new id), skipped {} that already existed and {} with invalid \
+         URLs",
+        if dry_run { "Would import" } else { "Imported" },
+        imported,
+        renamed,
+        skipped,
+        invalid
+    );
+
+    Ok(())
+}
+
+/// A random id no link has yet
+pub(crate) async fn new_link_id(store: &dyn Store) -> Result<String> {
+    loop {
+        let id = random_link_id();
+        if !store.link_exists(&id).await? {
+            return Ok(id);
+        }
+    }
+}
+
+/// The day of `2024-03-01T12:00:00+0000`, `2024-03-01 12:00:00` or `3/1/2024`
+fn parse_bitly_day(raw: &str) -> Option<NaiveDate> {
+    let date = raw.split(['T', ' ']).next()?;
+    NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .or_else(|_| NaiveDate::parse_from_str(date, "%m/%d/%Y"))
+        .ok()
+}
+
+impl AppState {
+    /// The URL of an imported link the way it gets stored, after the same checks as the URL of
+    /// a new one. Tracking parameters are kept, the old links may have been made for them.
+    async fn check_import(&self, raw: &str) -> Result<String, AppError> {
+        self.check_destination(raw, false, None).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::state::tests::state_with;
+    use crate::store::mock::MockStore;
+
+    #[tokio::test]
+    async fn imported_links_are_checked_like_new_ones() {
+        let store = Arc::new(MockStore::default());
+        let state = state_with(
+            r#"
+            [listener]
+            public_base_url = "https://sho.rt"
+
+            [features]
+            allowed_domains = ["example.com", "sho.rt"]
+            "#,
+            store.clone(),
+        )
+        .await;
+        let link = |code: &str, url: &str| ForeignLink {
+            code: code.to_owned(),
+            url: url.to_owned(),
+            created: None,
+            clicks: 0,
+        };
+        let links = vec![
+            link("docs", "https://example.com/docs"),
+            link("other", "https://example.org/"),
+            link("loop", "https://sho.rt/docs"),
+            link("wp-admin", "https://example.com/admin"),
+        ];
+        import_foreign_links(&state, links, false).await.unwrap();
+
+        assert!(store.link_exists("docs").await.unwrap());
+        assert!(!store.link_exists("other").await.unwrap());
+        assert!(!store.link_exists("loop").await.unwrap());
+        // codes that are decoy slugs get a new id
+        assert!(!store.link_exists("wp-admin").await.unwrap());
+        let renamed = store.id_for_url("https://example.com/admin").await.unwrap();
+        assert!(renamed.is_some());
+    }
+}
@@ -0,0 +1,936 @@
+//! Background jobs: the periodic loops, the lease that elects their leader and the job queue
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+#[cfg(feature = "s3")]
+use anyhow::Context as _;
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Days, NaiveDate, Utc};
+#[cfg(feature = "s3")]
+use object_store::{
+    aws::{AmazonS3, AmazonS3Builder},
+    path::Path as ObjectPath,
+    ObjectStore, ObjectStoreExt, PutPayload,
+};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::{debug, info, instrument, warn};
+
+use crate::api::is_broken;
+use crate::config::ExportConfig;
+#[cfg(feature = "s3")]
+use crate::config::S3Config;
+use crate::error::AppError;
+use crate::events::{OUTBOX_BATCH, OUTBOX_INTERVAL};
+use crate::state::AppState;
+use crate::store::{Lease, Store};
+use crate::validate::ThreatChecker;
+
+/// Outcome of the latest run of every background job, shown on `/status`
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Jobs(Arc<Mutex<HashMap<&'static str, JobStatus>>>);
+
+/// Makes a job run on one instance at a time. Whoever gets the lock first keeps it, the others
+/// try again every [`ELECTION_INTERVAL`] and take over once it's released.
+struct Leader {
+    job: &'static str,
+    lease: Option<Box<dyn Lease>>,
+    /// when to ask for the lock again, after another instance had it
+    retry_at: Option<Instant>,
+}
+
+/// Waits for the next run of a job, every `interval` from startup or on a cron schedule
+struct Ticker {
+    interval: Duration,
+    timer: tokio::time::Interval,
+    cron: Option<cron::Schedule>,
+    /// the latest run on `cron`, so a run isn't repeated when waking up early
+    last: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct JobStatus {
+    #[serde(skip)]
+    interval: Duration,
+    last_run: DateTime<Utc>,
+    next_run: DateTime<Utc>,
+    last_success: Option<DateTime<Utc>>,
+    last_error: Option<String>,
+    /// the last run succeeded and the job is not overdue
+    healthy: bool,
+}
+
+/// Work queued in the `jobs` table, run by [`run_jobs_periodically`] on whichever instance
+/// claims it first. Tagged as `"kind": "threat_check"`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub(crate) enum Task {
+    /// looks `url` up again after the threat check while shortening it failed, flagging its
+    /// link when it turns out to be unsafe
+    ThreatCheck { url: String },
+}
+
+/// Bucket the click rollups are copied to, one implementation per service behind its own
+/// Cargo feature
+#[async_trait]
+trait ExportTarget: Send + Sync {
+    /// Writes `body` to `key`, replacing what was there
+    async fn put(&self, key: &str, body: Vec<u8>) -> Result<()>;
+
+    /// The latest of the `day=` folders, `None` when nothing was exported yet
+    async fn last_day(&self) -> Result<Option<NaiveDate>>;
+}
+
+/// [`ExportTarget`] for S3 and compatible stores such as MinIO or R2
+#[cfg(feature = "s3")]
+struct S3Target {
+    bucket: AmazonS3,
+    prefix: String,
+}
+
+const ROLLUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How soon a change of the maintenance mode reaches the other instances
+const MAINTENANCE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often an instance asks for the lock of a job another one runs. Each attempt opens a
+/// connection of its own, the ticks of the outbox relay come much faster.
+const ELECTION_INTERVAL: Duration = Duration::from_secs(30);
+
+/// jobs an instance claims at a time, they are its own for `JOB_LOCK`
+const JOB_BATCH: i64 = 20;
+const JOB_LOCK: Duration = Duration::from_secs(5 * 60);
+const JOB_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// a job that failed this often is kept but not run again
+const MAX_JOB_ATTEMPTS: i32 = 10;
+const JOB_MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+
+const THREAT_RECHECK_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+const DEAD_LINK_CHECK_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Destinations the dead link check probes at once
+const DEAD_LINK_CHECK_BATCH: i64 = 20;
+
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const CLICK_FRAUD_INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Clicks looked at on each run, a spike is this many clicks on a link from one address that
+/// also make up this share of all its clicks
+const CLICK_FRAUD_WINDOW: Duration = Duration::from_secs(60 * 60);
+const CLICK_FRAUD_MIN_CLICKS: i64 = 50;
+const CLICK_FRAUD_MIN_SHARE: f64 = 0.5;
+/// Safe Browsing accepts at most 500 threat entries per lookup
+const THREAT_CHECK_BATCH: i64 = 500;
+
+/// Jobs that touch the shared database, the server only runs them without `--skip-jobs`
+pub(crate) fn spawn_jobs(state: &AppState) -> Result<()> {
+    if let Some(checker) = state.threat_checker.clone() {
+        tokio::spawn(recheck_urls_periodically(state.clone(), checker));
+    }
+    if state.config.features.dead_link_check {
+        tokio::spawn(check_dead_links_periodically(state.clone()));
+    }
+    let export = export_target(&state.config.export)?;
+    tokio::spawn(rollup_clicks_periodically(state.clone(), export));
+    tokio::spawn(check_click_fraud_periodically(state.clone()));
+    tokio::spawn(relay_events_periodically(state.clone()));
+    tokio::spawn(run_jobs_periodically(state.clone()));
+    let retention = &state.config.retention;
+    if retention.clicks_days.is_some() || retention.rollups_days.is_some() {
+        tokio::spawn(prune_click_data_periodically(state.clone()));
+    }
+    Ok(())
+}
+
+fn export_target(config: &ExportConfig) -> Result<Option<Arc<dyn ExportTarget>>> {
+    match &config.s3 {
+        #[cfg(feature = "s3")]
+        Some(s3) => Ok(Some(Arc::new(S3Target::new(s3)?))),
+        #[cfg(not(feature = "s3"))]
+        Some(_) => anyhow::bail!("export.s3 is set, but this build has no S3 support"),
+        None => Ok(None),
+    }
+}
+
+impl Leader {
+    fn new(job: &'static str) -> Self {
+        Self {
+            job,
+            lease: None,
+            retry_at: None,
+        }
+    }
+
+    /// Whether this instance should run the job now
+    async fn elect(&mut self, store: &dyn Store) -> bool {
+        if let Some(lease) = &mut self.lease {
+            if lease.is_held().await {
+                return true;
+            }
+            warn!("Lost the {} lock", self.job);
+            self.lease = None;
+        }
+        if self.retry_at.is_some_and(|at| Instant::now() < at) {
+            return false;
+        }
+        match store.try_lease(self.job).await {
+            Ok(Some(lease)) => {
+                info!("Running {} on this instance", self.job);
+                self.lease = Some(lease);
+                self.retry_at = None;
+                return true;
+            }
+            Ok(None) => debug!("Another instance runs {}", self.job),
+            Err(e) => warn!("Failed to take the {} lock: {:?}", self.job, e),
+        }
+        self.retry_at = Some(Instant::now() + ELECTION_INTERVAL);
+        false
+    }
+}
+
+impl Ticker {
+    /// Every `interval` starting right away, or on `cron` when there is one
+    fn new(interval: Duration, cron: Option<&cron::Schedule>) -> Self {
+        Self {
+            interval,
+            timer: tokio::time::interval(interval),
+            cron: cron.cloned(),
+            last: None,
+        }
+    }
+
+    async fn tick(&mut self) {
+        let Some(cron) = &self.cron else {
+            self.timer.tick().await;
+            return;
+        };
+        let after = self.last.map_or_else(Utc::now, |last| last.max(Utc::now()));
+        let Some(next) = cron.after(&after).next() else {
+            // the schedule has no more runs
+            return std::future::pending().await;
+        };
+        tokio::time::sleep((next - Utc::now()).to_std().unwrap_or_default()).await;
+        self.last = Some(next);
+    }
+
+    fn next_run(&self) -> DateTime<Utc> {
+        let now = Utc::now();
+        let upcoming = self.cron.as_ref().and_then(|cron| {
+            cron.after(&self.last.map_or(now, |last| last.max(now)))
+                .next()
+        });
+        upcoming.unwrap_or(now + self.interval)
+    }
+
+    /// Time between runs, between the next two for a cron schedule
+    fn period(&self) -> Duration {
+        let Some(cron) = &self.cron else {
+            return self.interval;
+        };
+        let mut upcoming = cron.upcoming(Utc);
+        match (upcoming.next(), upcoming.next()) {
+            (Some(next), Some(after)) => (after - next).to_std().unwrap_or(self.interval),
+            _ => self.interval,
+        }
+    }
+}
+
+impl Jobs {
+    /// Remember how the latest run of `name`, which runs every `interval`, went
+    pub(crate) fn record<T, E: std::fmt::Display>(
+        &self,
+        name: &'static str,
+        interval: Duration,
+        result: &Result<T, E>,
+    ) {
+        let now = Utc::now();
+        let mut jobs = self.0.lock().unwrap();
+        let last_success = jobs.get(name).and_then(|job| job.last_success);
+        jobs.insert(
+            name,
+            JobStatus {
+                interval,
+                last_run: now,
+                next_run: now + interval,
+                last_success: if result.is_ok() {
+                    Some(now)
+                } else {
+                    last_success
+                },
+                last_error: result.as_ref().err().map(|e| format!("{:#}", e)),
+                healthy: result.is_ok(),
+            },
+        );
+    }
+
+    /// Like [`Self::record`] for a job run by `ticker`
+    fn record_scheduled<T, E: std::fmt::Display>(
+        &self,
+        name: &'static str,
+        ticker: &Ticker,
+        result: &Result<T, E>,
+    ) {
+        self.record(name, ticker.period(), result);
+        if let Some(job) = self.0.lock().unwrap().get_mut(name) {
+            job.next_run = ticker.next_run();
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> BTreeMap<&'static str, JobStatus> {
+        let now = Utc::now();
+        let jobs = self.0.lock().unwrap();
+        jobs.iter()
+            .map(|(&name, job)| {
+                let overdue = (now - job.next_run)
+                    .to_std()
+                    .is_ok_and(|late| late > job.interval);
+                let job = JobStatus {
+                    healthy: job.last_error.is_none() && !overdue,
+                    ..job.clone()
+                };
+                (name, job)
+            })
+            .collect()
+    }
+}
+
+async fn recheck_urls_periodically(state: AppState, checker: Arc<dyn ThreatChecker>) {
+    let mut ticker = Ticker::new(
+        THREAT_RECHECK_INTERVAL,
+        state.config.schedule.threat_recheck.as_ref(),
+    );
+    let mut leader = Leader::new("threat_recheck");
+    loop {
+        ticker.tick().await;
+        if !leader.elect(state.store.as_ref()).await {
+            continue;
+        }
+        let ret = state.recheck_urls(checker.as_ref()).await;
+        match &ret {
+            Ok(0) => {}
+            Ok(flagged) => warn!("Flagged {} links as unsafe", flagged),
+            Err(e) => warn!("Failed to recheck links against threat intel: {:?}", e),
+        }
+        state.jobs.record_scheduled("threat_recheck", &ticker, &ret);
+    }
+}
+
+async fn check_dead_links_periodically(state: AppState) {
+    let mut ticker = Ticker::new(
+        DEAD_LINK_CHECK_INTERVAL,
+        state.config.schedule.dead_link_check.as_ref(),
+    );
+    let mut leader = Leader::new("dead_link_check");
+    loop {
+        ticker.tick().await;
+        if !leader.elect(state.store.as_ref()).await {
+            continue;
+        }
+        let ret = state.check_dead_links().await;
+        match &ret {
+            Ok(0) => {}
+            Ok(broken) => warn!("{} links have a broken destination", broken),
+            Err(e) => warn!("Failed to check links for broken destinations: {:?}", e),
+        }
+        state
+            .jobs
+            .record_scheduled("dead_link_check", &ticker, &ret);
+    }
+}
+
+async fn check_click_fraud_periodically(state: AppState) {
+    let mut ticker = Ticker::new(
+        CLICK_FRAUD_INTERVAL,
+        state.config.schedule.click_fraud.as_ref(),
+    );
+    let mut leader = Leader::new("click_fraud");
+    loop {
+        ticker.tick().await;
+        if !leader.elect(state.store.as_ref()).await {
+            continue;
+        }
+        let ret = state.check_click_fraud().await;
+        if let Err(e) = &ret {
+            warn!("Failed to check for click fraud: {:?}", e);
+        }
+        state.jobs.record_scheduled("click_fraud", &ticker, &ret);
+    }
+}
+
+/// Delivers the outbox to the event sink and webhook, each event at least once. A batch that
+/// came back full is followed by the next one right away.
+async fn relay_events_periodically(state: AppState) {
+    let mut interval = tokio::time::interval(OUTBOX_INTERVAL);
+    let mut leader = Leader::new("outbox_relay");
+    loop {
+        interval.tick().await;
+        if !leader.elect(state.store.as_ref()).await {
+            continue;
+        }
+        let ret = loop {
+            match state.relay_events().await {
+                Ok(relayed) if relayed == OUTBOX_BATCH as usize => continue,
+                ret => break ret,
+            }
+        };
+        if let Err(e) = &ret {
+            warn!("Failed to relay events: {:?}", e);
+        }
+        state.jobs.record("outbox_relay", OUTBOX_INTERVAL, &ret);
+    }
+}
+
+/// Follows the maintenance mode set through other instances. Runs on every instance, also
+/// those that leave the jobs to others.
+pub(crate) async fn poll_maintenance_periodically(state: AppState) {
+    let mut interval = tokio::time::interval(MAINTENANCE_POLL_INTERVAL);
+    // read at startup already
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        // the current mode stays when the database can't be reached, e.g. mid-migration
+        let ret = state.reload_maintenance().await;
+        if let Err(e) = &ret {
+            warn!("Failed to read the maintenance mode: {:?}", e);
+        }
+        state
+            .jobs
+            .record("maintenance_poll", MAINTENANCE_POLL_INTERVAL, &ret);
+    }
+}
+
+/// Works through the job queue. Every instance takes part, claimed jobs are skipped by the
+/// others.
+async fn run_jobs_periodically(state: AppState) {
+    let mut interval = tokio::time::interval(JOB_POLL_INTERVAL);
+    loop {
+        interval.tick().await;
+        let ret = loop {
+            match state.run_queued_jobs().await {
+                Ok(ran) if ran == JOB_BATCH as usize => continue,
+                ret => break ret,
+            }
+        };
+        if let Err(e) = &ret {
+            warn!("Failed to run queued jobs: {:?}", e);
+        }
+        state.jobs.record("job_queue", JOB_POLL_INTERVAL, &ret);
+    }
+}
+
+/// Deletes the clicks and rollups older than `retention` allows
+async fn prune_click_data_periodically(state: AppState) {
+    let mut ticker = Ticker::new(RETENTION_INTERVAL, state.config.schedule.retention.as_ref());
+    let mut leader = Leader::new("retention");
+    loop {
+        ticker.tick().await;
+        if !leader.elect(state.store.as_ref()).await {
+            continue;
+        }
+        let ret = state.prune_click_data().await;
+        match &ret {
+            Ok((clicks, rollups)) => info!(
+                "Deleted {} clicks and {} daily rollups past their retention",
+                clicks, rollups
+            ),
+            Err(e) => warn!("Failed to delete old click data: {:?}", e),
+        }
+        state.jobs.record_scheduled("retention", &ticker, &ret);
+    }
+}
+
+/// Keeps `daily_stats` current and copies every finished day to `export`. The first run catches
+/// up from the last rolled up day, later runs redo yesterday and today so late clicks are still
+/// counted.
+async fn rollup_clicks_periodically(state: AppState, export: Option<Arc<dyn ExportTarget>>) {
+    let mut ticker = Ticker::new(ROLLUP_INTERVAL, state.config.schedule.click_rollup.as_ref());
+    let mut leader = Leader::new("click_rollup");
+    let mut since = match state.store.last_rollup_day().await {
+        Ok(day) => day,
+        Err(e) => {
+            warn!("Failed to find the last rolled up day: {:?}", e);
+            None
+        }
+    };
+    loop {
+        ticker.tick().await;
+        if !leader.elect(state.store.as_ref()).await {
+            continue;
+        }
+        let yesterday = Utc::now().date_naive() - Days::new(1);
+        let from = since.map_or(yesterday, |day| day.min(yesterday));
+        let ret = state.store.rollup_clicks(from).await;
+        match &ret {
+            Ok(rows) => {
+                info!("Rolled up clicks since {} into {} daily rows", from, rows);
+                since = Some(yesterday);
+            }
+            Err(e) => warn!("Failed to roll up clicks: {:?}", e),
+        }
+        state.jobs.record_scheduled("click_rollup", &ticker, &ret);
+
+        // yesterday is final once it's rolled up after midnight
+        let Some(export) = export.as_deref().filter(|_| ret.is_ok()) else {
+            continue;
+        };
+        let ret = export_new_days(state.store.as_ref(), export, yesterday).await;
+        if let Err(e) = &ret {
+            warn!("Failed to export daily stats: {:?}", e);
+        }
+        state.jobs.record_scheduled("stats_export", &ticker, &ret);
+    }
+}
+
+/// Exports the days after the last one in `export` through `yesterday`. The bucket says where
+/// to carry on, whichever instance exported last. An empty one gets yesterday only.
+async fn export_new_days(
+    store: &dyn Store,
+    export: &dyn ExportTarget,
+    yesterday: NaiveDate,
+) -> Result<()> {
+    let from = export
+        .last_day()
+        .await?
+        .map_or(yesterday, |day| day + Days::new(1));
+    if from > yesterday {
+        return Ok(());
+    }
+    export_daily_stats(store, export, from, yesterday).await
+}
+
+/// The day of a `day=YYYY-MM-DD` folder
+#[cfg_attr(not(feature = "s3"), allow(dead_code))]
+fn exported_day(folder: &str) -> Option<NaiveDate> {
+    folder.strip_prefix("day=")?.parse().ok()
+}
+
+/// Writes the rollups of each day from `from` to `to` to its own CSV file
+async fn export_daily_stats(
+    store: &dyn Store,
+    export: &dyn ExportTarget,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<()> {
+    for day in from.iter_days().take_while(|day| *day <= to) {
+        let rows = store.daily_stats(day).await?;
+        // the header is written by hand so days without clicks still get one
+        let mut csv = csv::WriterBuilder::new()
+            .has_headers(false)
+            .from_writer(Vec::new());
+        csv.write_record([
+            "url_id",
+            "day",
+            "clicks",
+            "unique_visitors",
+            "top_referrers",
+        ])?;
+        for row in &rows {
+            csv.serialize(row)?;
+        }
+        let body = csv.into_inner().map_err(|e| e.into_error())?;
+        let key = format!("day={}/daily_stats.csv", day);
+        export.put(&key, body).await?;
+        info!("Exported {} daily stats rows to {}", rows.len(), key);
+    }
+    Ok(())
+}
+
+impl AppState {
+    /// Queues `task` to run in `delay` at the earliest
+    pub(crate) async fn enqueue(&self, task: &Task, delay: Duration) -> Result<(), AppError> {
+        let payload = serde_json::to_string(task).map_err(anyhow::Error::from)?;
+        self.store
+            .enqueue_job(task.kind(), &payload, Utc::now() + delay)
+            .await
+    }
+
+    /// Claims a batch of due jobs and runs them, the ones that fail run again later on. Returns
+    /// how many jobs the batch had.
+    async fn run_queued_jobs(&self) -> Result<usize, AppError> {
+        let jobs = self
+            .store
+            .claim_jobs(JOB_BATCH, Utc::now() + JOB_LOCK)
+            .await?;
+        for job in &jobs {
+            let ret = match serde_json::from_str::<Task>(&job.payload) {
+                Ok(task) => self.run_task(&task).await,
+                Err(e) => Err(e.into()),
+            };
+            match ret {
+                Ok(()) => self.store.complete_job(job.id).await?,
+                Err(e) => {
+                    let retry_at = (job.attempts + 1 < MAX_JOB_ATTEMPTS).then(|| {
+                        let backoff = JOB_POLL_INTERVAL
+                            .saturating_mul(2u32.saturating_pow(job.attempts.clamp(0, 31) as u32))
+                            .min(JOB_MAX_BACKOFF);
+                        Utc::now() + backoff
+                    });
+                    match retry_at {
+                        Some(_) => warn!("Job {} failed, trying again later: {:?}", job.id, e),
+                        None => warn!("Job {} failed for good: {:?}", job.id, e),
+                    }
+                    self.store
+                        .fail_job(job.id, &format!("{:#}", e), retry_at)
+                        .await?;
+                }
+            }
+        }
+        Ok(jobs.len())
+    }
+
+    async fn run_task(&self, task: &Task) -> Result<()> {
+        match task {
+            Task::ThreatCheck { url } => {
+                // the checker was turned off since
+                let Some(checker) = &self.threat_checker else {
+                    return Ok(());
+                };
+                let found = checker.unsafe_urls(std::slice::from_ref(url)).await?;
+                if !found.is_empty() && self.store.flag_urls(&found).await? > 0 {
+                    warn!("Flagged the link to {} as unsafe", url);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Run every link that is not flagged yet through `checker`, returning how many got flagged
+    #[instrument(name = "threats.recheck", skip_all)]
+    async fn recheck_urls(&self, checker: &dyn ThreatChecker) -> Result<u64> {
+        let mut last_id = String::new();
+        let mut flagged = 0;
+        loop {
+            let batch = self
+                .store
+                .unflagged_links(&last_id, THREAT_CHECK_BATCH)
+                .await?;
+            let Some(last) = batch.last() else {
+                return Ok(flagged);
+            };
+            last_id = last.id.clone();
+
+            let urls: Vec<String> = batch.into_iter().map(|r| r.url).collect();
+            let found = checker.unsafe_urls(&urls).await?;
+            if !found.is_empty() {
+                flagged += self.store.flag_urls(&found).await?;
+            }
+        }
+    }
+
+    /// Probes the destinations of the links that aren't flagged and keeps what they answered,
+    /// returning how many are broken
+    async fn check_dead_links(&self) -> Result<u64, AppError> {
+        let mut last_id = String::new();
+        let mut broken = 0;
+        loop {
+            let batch = self
+                .store
+                .unflagged_links(&last_id, DEAD_LINK_CHECK_BATCH)
+                .await?;
+            let Some(last) = batch.last() else {
+                return Ok(broken);
+            };
+            last_id = last.id.clone();
+
+            let mut probes = tokio::task::JoinSet::new();
+            for link in batch {
+                let state = self.clone();
+                probes.spawn(async move {
+                    let status = state.probe_destination(&link.url).await;
+                    (link.id, status)
+                });
+            }
+            while let Some(probe) = probes.join_next().await {
+                let (id, status) = probe.map_err(anyhow::Error::from)?;
+                if is_broken(status) {
+                    broken += 1;
+                }
+                self.store.set_link_check(&id, status).await?;
+            }
+        }
+    }
+
+    /// Deletes raw clicks and rollups from before their retention, returns how many clicks and
+    /// daily rollups that was
+    async fn prune_click_data(&self) -> Result<(u64, u64), AppError> {
+        let retention = &self.config.retention;
+        let clicks = match retention.clicks_days {
+            Some(days) => {
+                let before = Utc::now() - Days::new(days.into());
+                self.store.prune_clicks(before).await?
+            }
+            None => 0,
+        };
+        let rollups = match retention.rollups_days {
+            Some(days) => {
+                let before = Utc::now().date_naive() - Days::new(days.into());
+                self.store.prune_rollups(before).await?
+            }
+            None => 0,
+        };
+        Ok((clicks, rollups))
+    }
+
+    /// Marks the clicks of recent spikes from single addresses as suspicious, which leaves
+    /// them out of the stats like bots, and tells the event sink and webhook about each link
+    /// that had some through the outbox. Returns how many links that was.
+    async fn check_click_fraud(&self) -> Result<usize, AppError> {
+        let since = Utc::now() - CLICK_FRAUD_WINDOW;
+        let publish = self.events.is_some() || self.config.features.click_fraud_webhook.is_some();
+        let spikes = self
+            .store
+            .mark_click_spikes(
+                since,
+                CLICK_FRAUD_MIN_CLICKS,
+                CLICK_FRAUD_MIN_SHARE,
+                publish,
+            )
+            .await?;
+        for spike in &spikes {
+            warn!(
+                "Marked {} clicks on {} as suspicious, they came from a single address",
+                spike.clicks, spike.url_id
+            );
+        }
+        Ok(spikes.len())
+    }
+}
+
+impl Task {
+    /// The `kind` it is tagged with
+    fn kind(&self) -> &'static str {
+        match self {
+            Task::ThreatCheck { .. } => "threat_check",
+        }
+    }
+}
+
+#[cfg(feature = "s3")]
+impl S3Target {
+    fn new(config: &S3Config) -> Result<Self> {
+        let mut builder = AmazonS3Builder::from_env().with_bucket_name(&config.bucket);
+        if let Some(endpoint) = &config.endpoint {
+            builder = builder
+                .with_endpoint(endpoint)
+                .with_allow_http(endpoint.starts_with("http://"));
+        }
+        if let Some(region) = &config.region {
+            builder = builder.with_region(region);
+        }
+        if let Some(access_key_id) = &config.access_key_id {
+            builder = builder.with_access_key_id(access_key_id);
+        }
+        if let Some(secret_access_key) = &config.secret_access_key {
+            builder = builder.with_secret_access_key(secret_access_key);
+        }
+        let bucket = builder
+            .build()
+            .with_context(|| format!("Invalid S3 settings for bucket {}", config.bucket))?;
+        info!("Exporting daily stats to S3 bucket {}", config.bucket);
+        Ok(Self {
+            bucket,
+            prefix: config.prefix.clone(),
+        })
+    }
+}
+
+#[cfg(feature = "s3")]
+#[async_trait]
+impl ExportTarget for S3Target {
+    async fn put(&self, key: &str, body: Vec<u8>) -> Result<()> {
+        let path = ObjectPath::from(format!("{}{}", self.prefix, key));
+        self.bucket.put(&path, PutPayload::from(body)).await?;
+        Ok(())
+    }
+
+    async fn last_day(&self) -> Result<Option<NaiveDate>> {
+        // the prefix may end within a folder name, as in `stats/daily-`
+        let (parent, name_prefix) = self.prefix.rsplit_once('/').unwrap_or(("", &self.prefix));
+        let parent = ObjectPath::from(parent);
+        let listed = self
+            .bucket
+            .list_with_delimiter((!parent.as_ref().is_empty()).then_some(&parent))
+            .await?;
+        Ok(listed
+            .common_prefixes
+            .iter()
+            .filter_map(|folder| exported_day(folder.filename()?.strip_prefix(name_prefix)?))
+            .max())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::Ipv4Addr,
+        sync::atomic::{AtomicBool, Ordering},
+    };
+
+    use axum::{serve, Router};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::api::{LinkStatus, ListLinksQuery, ShortenRequest};
+    use crate::state::tests::state_with;
+    use crate::store::mock::MockStore;
+    use crate::validate::THREAT_CHECK_RETRY_DELAY;
+
+    #[tokio::test(start_paused = true)]
+    async fn instances_without_the_lock_ask_for_it_less_often() {
+        let store = MockStore::default();
+        let mut leader = Leader::new("outbox_relay");
+        for _ in 0..5 {
+            assert!(!leader.elect(&store).await);
+            tokio::time::advance(OUTBOX_INTERVAL).await;
+        }
+        assert_eq!(store.lease_attempts(), 1);
+
+        tokio::time::advance(ELECTION_INTERVAL).await;
+        assert!(!leader.elect(&store).await);
+        assert_eq!(store.lease_attempts(), 2);
+    }
+
+    #[tokio::test]
+    async fn dead_links_are_found_and_listed() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let destination = Router::new().route("/docs", axum::routing::get(|| async { "docs" }));
+        tokio::spawn(async move { serve(listener, destination).await.unwrap() });
+
+        let docs = format!("http://{}/docs", addr);
+        let missing = format!("http://{}/missing", addr);
+        let store = MockStore::with_links(&[("docs", &docs), ("gone", &missing)]);
+        let state = state_with(
+            "[security]\nallow_private_destinations = true",
+            Arc::new(store),
+        )
+        .await;
+
+        assert_eq!(state.check_dead_links().await.unwrap(), 1);
+        let listed = |status| {
+            let state = state.clone();
+            async move {
+                let query = ListLinksQuery {
+                    status: Some(status),
+                    ..Default::default()
+                };
+                let (links, _) = state.list_links(&query).await.unwrap();
+                links.into_iter().map(|link| link.id).collect::<Vec<_>>()
+            }
+        };
+        assert_eq!(listed(LinkStatus::Broken).await, ["gone"]);
+        assert_eq!(listed(LinkStatus::Reachable).await, ["docs"]);
+    }
+
+    /// Finds every URL unsafe, or fails while `down`
+    #[derive(Default)]
+    struct StrictChecker {
+        down: AtomicBool,
+    }
+
+    #[async_trait]
+    impl ThreatChecker for StrictChecker {
+        async fn unsafe_urls(&self, urls: &[String]) -> Result<Vec<String>> {
+            anyhow::ensure!(!self.down.load(Ordering::Relaxed), "lookup is down");
+            Ok(urls.to_vec())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_threat_checks_are_queued_for_later() {
+        let store = Arc::new(MockStore::default());
+        let mut state = state_with("", store.clone()).await;
+        let checker = Arc::new(StrictChecker::default());
+        checker.down.store(true, Ordering::Relaxed);
+        state.threat_checker = Some(checker.clone());
+        let req = ShortenRequest {
+            url: "https://example.com/promo".to_owned(),
+            ..ShortenRequest::default()
+        };
+        let id = state.create_link(&req, None, None).await.unwrap();
+        assert_eq!(state.run_queued_jobs().await.unwrap(), 0);
+
+        // still down, the job is queued again with a backoff
+        tokio::time::advance(THREAT_CHECK_RETRY_DELAY).await;
+        assert_eq!(state.run_queued_jobs().await.unwrap(), 1);
+        assert!(!store.get_link(&id).await.unwrap().unwrap().flagged);
+        checker.down.store(false, Ordering::Relaxed);
+        assert_eq!(state.run_queued_jobs().await.unwrap(), 0);
+
+        tokio::time::advance(JOB_POLL_INTERVAL).await;
+        assert_eq!(state.run_queued_jobs().await.unwrap(), 1);
+        assert!(store.get_link(&id).await.unwrap().unwrap().flagged);
+        assert_eq!(state.run_queued_jobs().await.unwrap(), 0);
+    }
+
+    /// [`ExportTarget`] keeping what was written by key
+    #[derive(Default)]
+    struct MemoryTarget(Mutex<BTreeMap<String, Vec<u8>>>);
+
+    #[async_trait]
+    impl ExportTarget for MemoryTarget {
+        async fn put(&self, key: &str, body: Vec<u8>) -> Result<()> {
+            self.0.lock().unwrap().insert(key.to_owned(), body);
+            Ok(())
+        }
+
+        async fn last_day(&self) -> Result<Option<NaiveDate>> {
+            Ok(self.days().into_iter().max())
+        }
+    }
+
+    impl MemoryTarget {
+        fn days(&self) -> Vec<NaiveDate> {
+            let keys = self.0.lock().unwrap();
+            keys.keys()
+                .filter_map(|key| exported_day(key.split('/').next()?))
+                .collect()
+        }
+    }
+
+    #[tokio::test]
+    async fn exports_carry_on_after_the_last_day_in_the_bucket() {
+        let store = MockStore::default();
+        let yesterday = Utc::now().date_naive() - Days::new(1);
+
+        let export = MemoryTarget::default();
+        export_new_days(&store, &export, yesterday).await.unwrap();
+        assert_eq!(export.days(), [yesterday]);
+        // nothing left to do until the next day
+        export_new_days(&store, &export, yesterday).await.unwrap();
+        assert_eq!(export.days(), [yesterday]);
+
+        // another instance exported up to three days ago
+        let export = MemoryTarget::default();
+        let last = yesterday - Days::new(2);
+        export
+            .put(&format!("day={}/daily_stats.csv", last), Vec::new())
+            .await
+            .unwrap();
+        export_new_days(&store, &export, yesterday).await.unwrap();
+        assert_eq!(
+            export.days(),
+            [last, last + Days::new(1), yesterday],
+            "the days in between are caught up"
+        );
+    }
+
+    #[tokio::test]
+    async fn cron_schedules_pick_the_next_run() {
+        let daily: cron::Schedule = "0 30 3 * * *".parse().unwrap();
+        let ticker = Ticker::new(ROLLUP_INTERVAL, Some(&daily));
+        assert_eq!(ticker.period(), Duration::from_secs(24 * 60 * 60));
+        let next = ticker.next_run();
+        assert!(next > Utc::now());
+        assert_eq!(
+            next.time(),
+            chrono::NaiveTime::from_hms_opt(3, 30, 0).unwrap()
+        );
+
+        let ticker = Ticker::new(ROLLUP_INTERVAL, None);
+        assert_eq!(ticker.period(), ROLLUP_INTERVAL);
+    }
+}
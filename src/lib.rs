@@ -0,0 +1,44 @@
+//! URL shortener shared by the `shortener` server and the `shortener_worker` job runner,
+//! plus [`client::ShortenerClient`] for calling it from other services
+
+#[cfg(not(feature = "postgres"))]
+compile_error!("the shortener needs a storage backend, enable the `postgres` feature");
+
+mod api;
+mod cli;
+mod clicks;
+pub mod client;
+mod config;
+mod error;
+mod events;
+#[cfg(feature = "graphql")]
+mod graphql;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod handlers;
+mod import;
+mod jobs;
+mod limits;
+mod server;
+mod state;
+mod store;
+mod telemetry;
+mod validate;
+
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzzing;
+
+pub use api::{
+    AliasRequest, BreakdownQuery, BulkStatsRequest, BulkStatsResponse, BundleEntry, BundleLink,
+    BundleRequest, BundleResponse, CountriesResponse, CountryClicks, ExportedLink, ForwardQuery,
+    Interval, LinkCursor, LinkField, LinkFields, LinkQuery, LinkSort, LinkStatus, ListLinksQuery,
+    ListLinksResponse, Period, PreviewResponse, ReferrerClicks, ReferrersResponse, ReportCategory,
+    ReportRequest, ShortenRequest, ShortenResponse, SortOrder, TimeseriesPoint, TimeseriesQuery,
+    TimeseriesResponse, TopLink, TopLinksQuery, TopLinksResponse, Visibility,
+};
+pub use cli::{migrate, run, run_worker, Args, WorkerArgs};
+pub use config::Config;
+pub use error::ErrorResponse;
+pub use handlers::build_router;
+pub use state::AppState;
@@ -0,0 +1,169 @@
+//! Rate limiting, load shedding and the honeypot
+
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::config::HoneypotConfig;
+use crate::error::AppError;
+
+/// Fixed window request counter per client IP
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    limit: AtomicU32,
+    /// in milliseconds, both are atomics so the limit can be reloaded
+    window_ms: AtomicU64,
+    clients: Mutex<HashMap<IpAddr, RateWindow>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct RateWindow {
+    started: Instant,
+    count: u32,
+}
+
+/// Caps the requests handled at once, API mutations separately so a burst of writes can't
+/// crowd out redirects. Past the cap requests are turned away instead of queueing up until
+/// they time out.
+#[derive(Debug, Clone)]
+pub(crate) struct LoadShedder {
+    redirects: Option<Arc<Semaphore>>,
+    mutations: Option<Arc<Semaphore>>,
+}
+
+/// The decoy slugs of [`HoneypotConfig`] and the addresses that asked for one, which are
+/// turned away until their block runs out
+#[derive(Debug, Clone)]
+pub(crate) struct Honeypot {
+    slugs: Arc<HashSet<String>>,
+    block: Duration,
+    /// when the block of each scanner ends
+    scanners: Arc<Mutex<HashMap<IpAddr, Instant>>>,
+}
+
+/// What clients turned away by the [`LoadShedder`] are told to wait
+const LOAD_SHED_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+impl RateLimiter {
+    pub(crate) fn new(limit: u32, window: Duration) -> Self {
+        Self {
+            limit: AtomicU32::new(limit),
+            window_ms: AtomicU64::new(window.as_millis() as u64),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Applies from the next request on, windows already running keep their start
+    pub(crate) fn set_limit(&self, limit: u32, window: Duration) {
+        self.limit.store(limit, Ordering::Relaxed);
+        self.window_ms
+            .store(window.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Count a request from `ip`, returning how long to wait when it is over the limit
+    pub(crate) fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let limit = self.limit.load(Ordering::Relaxed);
+        let window_len = Duration::from_millis(self.window_ms.load(Ordering::Relaxed));
+        let now = Instant::now();
+        let mut clients = self.clients.lock().unwrap();
+        if clients.len() > 10_000 {
+            clients.retain(|_, w| now.duration_since(w.started) < window_len);
+        }
+
+        let window = clients.entry(ip).or_insert(RateWindow {
+            started: now,
+            count: 0,
+        });
+        if now.duration_since(window.started) >= window_len {
+            *window = RateWindow {
+                started: now,
+                count: 0,
+            };
+        }
+        if window.count >= limit {
+            return Err(window_len - now.duration_since(window.started));
+        }
+        window.count += 1;
+
+        Ok(())
+    }
+}
+
+impl LoadShedder {
+    /// `0` leaves that kind of request unlimited
+    pub(crate) fn new(redirects: usize, mutations: usize) -> Self {
+        let budget = |permits| (permits > 0).then(|| Arc::new(Semaphore::new(permits)));
+        Self {
+            redirects: budget(redirects),
+            mutations: budget(mutations),
+        }
+    }
+
+    /// Takes a slot for a request, to be held until its response is ready
+    pub(crate) fn admit(&self, mutation: bool) -> Result<Option<OwnedSemaphorePermit>, AppError> {
+        let budget = if mutation {
+            &self.mutations
+        } else {
+            &self.redirects
+        };
+        match budget {
+            Some(budget) => budget
+                .clone()
+                .try_acquire_owned()
+                .map(Some)
+                .map_err(|_| AppError::Overloaded(LOAD_SHED_RETRY_AFTER)),
+            None => Ok(None),
+        }
+    }
+}
+
+impl Honeypot {
+    pub(crate) fn new(config: &HoneypotConfig) -> Self {
+        Self {
+            slugs: Arc::new(config.slugs.iter().cloned().collect()),
+            block: Duration::from_secs(config.block_secs),
+            scanners: Arc::default(),
+        }
+    }
+
+    pub(crate) fn is_decoy(&self, slug: &str) -> bool {
+        self.slugs.contains(slug)
+    }
+
+    /// Starts the block of `ip` over
+    pub(crate) fn flag(&self, ip: IpAddr) {
+        if self.block.is_zero() {
+            return;
+        }
+        let now = Instant::now();
+        let mut scanners = self.scanners.lock().unwrap();
+        if scanners.len() > 10_000 {
+            scanners.retain(|_, until| *until > now);
+        }
+        scanners.insert(ip, now + self.block);
+    }
+
+    /// How much longer `ip` is blocked, `Ok` when it isn't
+    pub(crate) fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        match self.scanners.lock().unwrap().get(&ip) {
+            Some(until) => match until.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => Err(left),
+                _ => Ok(()),
+            },
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn release(&self, ip: IpAddr) {
+        self.scanners.lock().unwrap().remove(&ip);
+    }
+}
@@ -0,0 +1,355 @@
+//! Serving the router over HTTP, HTTPS and unix sockets until shutdown
+
+use std::{
+    net::SocketAddr,
+    path::{Path as FsPath, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::{Context as _, Result};
+use axum::{
+    extract::{ConnectInfo, Extension},
+    serve, Router,
+};
+use axum_server::tls_rustls::RustlsConfig;
+use tokio::{net::TcpListener, sync::watch};
+use tracing::{info, warn};
+
+use crate::cli::ServeArgs;
+use crate::clicks::reload_geoip_periodically;
+use crate::config::{BindAddr, Config, TlsConfig, UNIX_PEER};
+use crate::error::AppError;
+#[cfg(feature = "grpc")]
+use crate::grpc::serve_grpc;
+use crate::handlers::build_router;
+use crate::jobs::{poll_maintenance_periodically, spawn_jobs, Jobs};
+use crate::state::AppState;
+use crate::store::Store;
+use crate::telemetry::{push_statsd_periodically, statsd_client, LogLevel};
+
+const CERT_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+pub(crate) async fn run_server(
+    mut config: Config,
+    store: Arc<dyn Store>,
+    args: ServeArgs,
+    log_level: LogLevel,
+) -> Result<()> {
+    if !args.skip_migrations {
+        store.migrate().await?;
+    }
+    if let Some(bind) = args.bind {
+        // generated links fall back to the bind address, so they have to see the override too
+        config.listener.bind = bind;
+    }
+    let bind_addr = config.listener.bind.clone();
+    if matches!(bind_addr, BindAddr::Unix(_)) && config.listener.tls.is_some() {
+        anyhow::bail!("TLS can't be served on a Unix socket, terminate it at the proxy instead");
+    }
+    #[cfg(not(feature = "grpc"))]
+    if config.listener.grpc_bind.is_some() {
+        anyhow::bail!("listener.grpc_bind is set, but this build has no gRPC support");
+    }
+    let state = AppState::try_new(config, store, log_level).await?;
+
+    if !args.skip_jobs {
+        spawn_jobs(&state)?;
+    }
+    tokio::spawn(reload_geoip_periodically(
+        state.geoip.clone(),
+        state.jobs.clone(),
+    ));
+    tokio::spawn(poll_maintenance_periodically(state.clone()));
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(state.clone()));
+    if let Some(statsd) = &state.config.metrics.statsd {
+        tokio::spawn(push_statsd_periodically(
+            state.clone(),
+            statsd_client(statsd)?,
+        ));
+    }
+
+    let router = build_router(state.clone());
+
+    // long-lived connections such as the click stream would otherwise hold up shutdown forever
+    let timeout = Duration::from_secs(state.config.listener.shutdown_timeout_secs);
+    #[cfg(feature = "grpc")]
+    let grpc = match state.config.listener.grpc_bind {
+        Some(addr) => {
+            let listener = TcpListener::bind(addr).await.map_err(AppError::Io)?;
+            Some(tokio::spawn(serve_grpc(state.clone(), listener, timeout)))
+        }
+        None => None,
+    };
+    match (bind_addr, state.config.listener.tls.clone()) {
+        (BindAddr::Tcp(addr), Some(tls)) => {
+            serve_https(router, addr, tls, timeout, state.jobs.clone()).await?
+        }
+        (BindAddr::Tcp(addr), None) => serve_http(router, addr, timeout).await?,
+        #[cfg(unix)]
+        (BindAddr::Unix(path), _) => {
+            serve_unix(router, &path, state.config.listener.socket_mode, timeout).await?
+        }
+        #[cfg(not(unix))]
+        (BindAddr::Unix(_), _) => anyhow::bail!("Unix sockets are not supported on this platform"),
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(grpc) = grpc {
+        grpc.await??;
+    }
+
+    // undelivered events stay in the outbox for the next relay
+    state.clicks.flush().await;
+    state.store.close().await;
+    info!("Shut down cleanly");
+
+    Ok(())
+}
+
+async fn serve_http(router: Router, bind_addr: SocketAddr, timeout: Duration) -> Result<()> {
+    let listener = TcpListener::bind(bind_addr).await.map_err(AppError::Io)?;
+    info!("Listening on http://{}", bind_addr);
+
+    let (draining, mut drain_started) = watch::channel(false);
+    let server = serve(
+        listener,
+        router.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .with_graceful_shutdown(async move {
+        shutdown_signal().await;
+        info!("Shutting down, waiting for in-flight requests");
+        draining.send_replace(true);
+    });
+    let deadline = async move {
+        let _ = drain_started.wait_for(|draining| *draining).await;
+        tokio::time::sleep(timeout).await;
+    };
+    tokio::select! {
+        ret = server => ret?,
+        _ = deadline => warn!("Requests still running after {:?}, shutting down anyway", timeout),
+    }
+
+    Ok(())
+}
+
+async fn serve_https(
+    router: Router,
+    bind_addr: SocketAddr,
+    tls: TlsConfig,
+    timeout: Duration,
+    jobs: Jobs,
+) -> Result<()> {
+    // ring is the only provider compiled in, but rustls wants it picked explicitly
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let rustls_config = RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to load TLS certificate {} and key {}",
+                tls.cert.display(),
+                tls.key.display()
+            )
+        })?;
+    if tls.watch {
+        tokio::spawn(reload_certs_periodically(rustls_config.clone(), tls, jobs));
+    }
+
+    let handle = axum_server::Handle::new();
+    tokio::spawn({
+        let handle = handle.clone();
+        async move {
+            shutdown_signal().await;
+            info!("Shutting down, waiting for in-flight requests");
+            handle.graceful_shutdown(Some(timeout));
+        }
+    });
+    info!("Listening on https://{}", bind_addr);
+    axum_server::bind_rustls(bind_addr, rustls_config)
+        .handle(handle)
+        .serve(router.into_make_service_with_connect_info::<SocketAddr>())
+        .await?;
+
+    Ok(())
+}
+
+/// Serves plain HTTP on a Unix socket. There's no peer address, so `ConnectInfo` reports
+/// [`UNIX_PEER`] and the real client comes from the proxy's headers.
+#[cfg(unix)]
+async fn serve_unix(router: Router, path: &FsPath, mode: u32, timeout: Duration) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    use hyper_util::{
+        rt::{TokioExecutor, TokioIo},
+        server::{conn::auto, graceful::GracefulShutdown},
+        service::TowerToHyperService,
+    };
+
+    remove_stale_socket(path)?;
+    let listener = tokio::net::UnixListener::bind(path)
+        .with_context(|| format!("Failed to bind {}", path.display()))?;
+    let socket = SocketFile(path.to_owned());
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+        .with_context(|| format!("Failed to set permissions on {}", path.display()))?;
+    info!("Listening on unix:{}", path.display());
+
+    let router = router.layer(Extension(ConnectInfo(UNIX_PEER)));
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let graceful = GracefulShutdown::new();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        let stream = tokio::select! {
+            conn = listener.accept() => match conn {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    // usually out of file descriptors, give some connections time to close
+                    warn!("Failed to accept connection: {:?}", e);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            },
+            _ = &mut shutdown => break,
+        };
+        let conn = builder
+            .serve_connection_with_upgrades(
+                TokioIo::new(stream),
+                TowerToHyperService::new(router.clone()),
+            )
+            .into_owned();
+        let conn = graceful.watch(conn);
+        tokio::spawn(async move {
+            if let Err(e) = conn.await {
+                tracing::debug!("Connection closed with an error: {:?}", e);
+            }
+        });
+    }
+
+    drop(listener);
+    drop(socket);
+    info!("Shutting down, waiting for in-flight requests");
+    if tokio::time::timeout(timeout, graceful.shutdown())
+        .await
+        .is_err()
+    {
+        warn!(
+            "Requests still running after {:?}, shutting down anyway",
+            timeout
+        );
+    }
+
+    Ok(())
+}
+
+/// Removes the socket file when the server stops, whichever way that happens
+#[cfg(unix)]
+struct SocketFile(PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            warn!("Failed to remove {}: {:?}", self.0.display(), e);
+        }
+    }
+}
+
+/// A socket left behind by a crashed server would make the bind fail. Anything that isn't a
+/// socket, or one another server still answers on, is left alone.
+#[cfg(unix)]
+fn remove_stale_socket(path: &FsPath) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let Ok(metadata) = std::fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    anyhow::ensure!(
+        metadata.file_type().is_socket(),
+        "{} exists and is not a socket",
+        path.display()
+    );
+    anyhow::ensure!(
+        std::os::unix::net::UnixStream::connect(path).is_err(),
+        "{} is in use by another server",
+        path.display()
+    );
+    std::fs::remove_file(path).with_context(|| format!("Failed to remove {}", path.display()))
+}
+
+/// Swaps in the certificate once either file changes, e.g. after a renewal. A broken
+/// pair is logged and the current certificate kept.
+async fn reload_certs_periodically(rustls_config: RustlsConfig, tls: TlsConfig, jobs: Jobs) {
+    let modified = |tls: &TlsConfig| -> Result<SystemTime> {
+        let cert = std::fs::metadata(&tls.cert)?.modified()?;
+        let key = std::fs::metadata(&tls.key)?.modified()?;
+        Ok(cert.max(key))
+    };
+    let mut loaded = modified(&tls).ok();
+    let mut interval = tokio::time::interval(CERT_RELOAD_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let ret = match modified(&tls) {
+            Ok(current) if Some(current) != loaded => rustls_config
+                .reload_from_pem_file(&tls.cert, &tls.key)
+                .await
+                .map_err(anyhow::Error::from)
+                .map(|_| {
+                    info!("Reloaded TLS certificate {}", tls.cert.display());
+                    loaded = Some(current);
+                }),
+            Ok(_) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = &ret {
+            warn!("Failed to reload TLS certificate: {:?}", e);
+        }
+        jobs.record("tls_reload", CERT_RELOAD_INTERVAL, &ret);
+    }
+}
+
+/// `kill -HUP` applies the same reload as `POST /admin/reload`
+#[cfg(unix)]
+async fn reload_on_sighup(state: AppState) {
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(signal) => signal,
+        Err(e) => {
+            warn!("Failed to listen for SIGHUP: {:?}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        info!("Received SIGHUP, reloading configuration");
+        if let Err(e) = state.reload().await {
+            warn!("Failed to reload configuration: {}", e);
+        }
+    }
+}
+
+pub(crate) async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for Ctrl+C: {:?}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {:?}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
@@ -0,0 +1,1154 @@
+//! [`AppState`], what the handlers share, and the operations on links and bundles
+
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    path::Path as FsPath,
+    sync::{atomic::Ordering, Arc, RwLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use chrono::{Days, NaiveDate, Utc};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tracing::{info, warn};
+use url::Url;
+
+use crate::api::{
+    BulkStatsResponse, BundleLink, BundleRequest, BundleResponse, Client, ExportedLink, LinkCursor,
+    LinkField, LinkFields, ListLinksQuery, Maintenance, Period, ReportCategory, ReportRequest,
+    ShortenRequest, TopLink, Visibility,
+};
+use crate::cli::connect_store;
+use crate::clicks::{ClickRecorder, GeoIp};
+use crate::config::{Config, ReachabilityCheck, Scope};
+use crate::error::AppError;
+use crate::events::{event_sink, EventSink};
+use crate::handlers::ReloadResponse;
+use crate::import::new_link_id;
+use crate::jobs::Jobs;
+use crate::limits::{Honeypot, LoadShedder, RateLimiter};
+use crate::store::{
+    BlockedDomain, BundleRecord, InsertLink, LinkOptions, Quarantine, Store, UpdateLink, UrlRecord,
+};
+use crate::telemetry::{AccessLog, FeedEvent, LogLevel, Metrics};
+use crate::validate::{
+    destination_client, load_allowlist, normalize_domain, random_link_id, secrets_match,
+    validate_cache_control, validate_tags, Blocklist, SafeBrowsing, ThreatChecker, ALIAS_LENGTH,
+    HTTP_TIMEOUT,
+};
+
+/// Everything the handlers share, cheap to clone
+#[derive(Clone)]
+pub struct AppState {
+    pub(crate) config: Arc<Config>,
+    pub(crate) store: Arc<dyn Store>,
+    pub(crate) blocklist: Blocklist,
+    pub(crate) allowlist: Option<Arc<HashSet<String>>>,
+    pub(crate) threat_checker: Option<Arc<dyn ThreatChecker>>,
+    pub(crate) http: reqwest::Client,
+    /// for requests to destinations, which only reach public addresses
+    pub(crate) destination_http: reqwest::Client,
+    strip_tracking: bool,
+    pub(crate) shorten_limiter: Arc<RateLimiter>,
+    /// counts shortens per address to notice bursts, which only score as spam
+    pub(crate) burst_limiter: Arc<RateLimiter>,
+    pub(crate) login_limiter: Arc<RateLimiter>,
+    pub(crate) load: LoadShedder,
+    pub(crate) honeypot: Honeypot,
+    pub(crate) clicks: ClickRecorder,
+    pub(crate) geoip: GeoIp,
+    log_level: LogLevel,
+    pub(crate) maintenance: Arc<RwLock<Maintenance>>,
+    admin_token: Option<Arc<str>>,
+    /// signs the sessions of `/ui`, derived from the admin token so every replica and restart
+    /// accepts them. `None` without an admin token, there's no logging in then.
+    session_key: Option<[u8; 32]>,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) access_log: Option<AccessLog>,
+    /// where the relay delivers the outbox to
+    pub(crate) events: Option<Arc<dyn EventSink>>,
+    pub(crate) jobs: Jobs,
+    pub(crate) started_at: Instant,
+}
+
+/// Seeds the blocklist when `security.blocklist_file` is unset and it exists
+const BLOCKLIST_FILE: &str = "blocklist.txt";
+
+/// More shortens than this from one address within the window make a burst
+const SPAM_BURST_REQUESTS: u32 = 10;
+const SPAM_BURST_WINDOW: Duration = Duration::from_secs(60);
+
+/// Files the review of quarantined links, in place of a visitor's hashed address
+const QUARANTINE_REPORTER: &str = "quarantine";
+
+const MAX_TOP_LIMIT: i64 = 100;
+
+/// Links whose clicks a single `/api/stats/bulk` request may ask for
+const MAX_BULK_STATS_IDS: usize = 500;
+const BUNDLE_TITLE_LENGTH: std::ops::RangeInclusive<usize> = 1..=100;
+const MAX_BUNDLE_DESCRIPTION_LENGTH: usize = 500;
+const MAX_BUNDLE_LINKS: usize = 100;
+const MAX_REPORT_LENGTH: usize = 1000;
+/// Open reports listed by `/admin/reports` and `/ui/reports`, oldest first
+pub(crate) const OPEN_REPORTS_LIMIT: i64 = 200;
+/// Logins to `/ui` one address may try per window, so the admin token can't be guessed online
+pub(crate) const LOGIN_ATTEMPTS: u32 = 5;
+const LOGIN_WINDOW: Duration = Duration::from_secs(60);
+/// How long a session of `/ui` lasts before logging in again
+pub(crate) const SESSION_LIFETIME: Duration = Duration::from_secs(12 * 60 * 60);
+/// Addresses listed by `/admin/scanners`, the latest first
+pub(crate) const SCANNERS_LIMIT: i64 = 200;
+/// Destinations of a rotating link besides its own URL
+const MAX_ROTATE: usize = 20;
+
+/// Sets the session key apart from whatever else might be derived from the admin token
+const SESSION_KEY_INFO: &[u8] = b"shortener ui session";
+
+const MAX_PAGE_SIZE: i64 = 500;
+/// Links per page of `/directory`
+const DIRECTORY_PAGE_SIZE: i64 = 100;
+
+impl AppState {
+    /// Connects to the database of `config` and wires up everything around it, the schema must
+    /// already be migrated
+    pub async fn connect(config: Config) -> Result<Self> {
+        let store = connect_store(&config).await?;
+        let log_level = LogLevel::new(config.log.level);
+        Ok(Self::try_new(config, store, log_level).await?)
+    }
+
+    /// Wires up everything around `store`, which must already be migrated
+    pub(crate) async fn try_new(
+        config: Config,
+        store: Arc<dyn Store>,
+        log_level: LogLevel,
+    ) -> Result<Self, AppError> {
+        let events = event_sink(&config.events).await?;
+        let state = Self {
+            clicks: ClickRecorder::spawn(
+                store.clone(),
+                config.security.ip_hash_salt.clone(),
+                config.security.anonymize_ips,
+                events.is_some(),
+            ),
+            geoip: match &config.features.geoip_db {
+                Some(path) => GeoIp::open(path)?,
+                None => GeoIp::default(),
+            },
+            log_level,
+            maintenance: Arc::default(),
+            store,
+            blocklist: Blocklist::default(),
+            allowlist: load_allowlist(config.features.allowed_domains.as_deref())?.map(Arc::new),
+            http: reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .timeout(HTTP_TIMEOUT)
+                .build()
+                .map_err(anyhow::Error::from)?,
+            destination_http: destination_client(config.security.allow_private_destinations)?,
+            shorten_limiter: Arc::new(RateLimiter::new(
+                config.rate_limit.shorten_requests,
+                Duration::from_secs(config.rate_limit.shorten_window_secs),
+            )),
+            burst_limiter: Arc::new(RateLimiter::new(SPAM_BURST_REQUESTS, SPAM_BURST_WINDOW)),
+            login_limiter: Arc::new(RateLimiter::new(LOGIN_ATTEMPTS, LOGIN_WINDOW)),
+            load: LoadShedder::new(
+                config.listener.redirect_concurrency,
+                config.listener.mutation_concurrency,
+            ),
+            honeypot: Honeypot::new(&config.security.honeypot),
+            strip_tracking: config.features.strip_tracking_params,
+            threat_checker: config
+                .features
+                .safe_browsing_api_key
+                .clone()
+                .map(|api_key| Arc::new(SafeBrowsing::new(api_key)) as Arc<dyn ThreatChecker>),
+            admin_token: config.security.admin_token.clone().map(Into::into),
+            session_key: config.security.admin_token.as_deref().map(session_key),
+            metrics: Arc::default(),
+            access_log: match &config.log.access_log {
+                Some(target) => Some(AccessLog::open(target).await?),
+                None => None,
+            },
+            events,
+            jobs: Jobs::default(),
+            started_at: Instant::now(),
+            config: Arc::new(config),
+        };
+        state
+            .import_blocklist(state.config.security.blocklist_file.as_deref())
+            .await?;
+        state.reload_blocklist().await?;
+        state.reload_maintenance().await?;
+
+        Ok(state)
+    }
+
+    /// Seed the blocklist table from a file with one domain per line, `#` starts a comment.
+    /// Without `path`, [`BLOCKLIST_FILE`] is read if there is one.
+    async fn import_blocklist(&self, path: Option<&FsPath>) -> Result<(), AppError> {
+        let path = match path {
+            Some(path) => path,
+            None if FsPath::new(BLOCKLIST_FILE).exists() => FsPath::new(BLOCKLIST_FILE),
+            None => return Ok(()),
+        };
+
+        let content = tokio::fs::read_to_string(path).await?;
+        let mut count = 0;
+        for line in content.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let domain = normalize_domain(line)?;
+            self.store.seed_blocked(&domain).await?;
+            count += 1;
+        }
+        info!("Imported {} blocked domains from {}", count, path.display());
+
+        Ok(())
+    }
+
+    /// Reads the config file and environment again and applies what can change while running:
+    /// the log level, rate limits and GeoIP database. The blocklist is read again from its
+    /// file and table too. Other settings need a restart.
+    pub(crate) async fn reload(&self) -> Result<ReloadResponse, AppError> {
+        let config =
+            Config::load(self.config.file.as_deref()).map_err(|e| anyhow::anyhow!("{:#}", e))?;
+        self.geoip
+            .set_path(config.features.geoip_db.clone())
+            .map_err(|e| anyhow::anyhow!("Failed to load GeoIP database: {:#}", e))?;
+        self.import_blocklist(config.security.blocklist_file.as_deref())
+            .await?;
+        self.reload_blocklist().await?;
+        self.log_level.set(config.log.level);
+        self.shorten_limiter.set_limit(
+            config.rate_limit.shorten_requests,
+            Duration::from_secs(config.rate_limit.shorten_window_secs),
+        );
+        info!("Reloaded configuration");
+
+        Ok(ReloadResponse {
+            log_level: config.log.level.to_string(),
+            shorten_requests: config.rate_limit.shorten_requests,
+            shorten_window_secs: config.rate_limit.shorten_window_secs,
+            geoip_db: config.features.geoip_db,
+            blocked_domains: self.blocklist.len(),
+        })
+    }
+
+    async fn reload_blocklist(&self) -> Result<(), AppError> {
+        let domains = self.store.list_blocked().await?;
+        self.blocklist
+            .replace(domains.into_iter().map(|d| d.domain).collect());
+        Ok(())
+    }
+
+    pub(crate) async fn block_domain(
+        &self,
+        domain: &str,
+        reason: Option<&str>,
+    ) -> Result<BlockedDomain, AppError> {
+        let blocked = self.store.block_domain(domain, reason).await?;
+        self.blocklist.insert(blocked.domain.clone());
+        Ok(blocked)
+    }
+
+    pub(crate) async fn unblock_domain(&self, domain: &str) -> Result<(), AppError> {
+        self.store.unblock_domain(domain).await?;
+        self.blocklist.remove(domain);
+        Ok(())
+    }
+
+    /// Blocks `ip` for asking for the decoy `slug` and lists it with the scanners
+    pub(crate) async fn trip_honeypot(&self, ip: IpAddr, slug: &str) -> Result<(), AppError> {
+        self.honeypot.flag(ip);
+        warn!("Scanner at {} asked for decoy {}", ip, slug);
+        self.store.record_scanner(&ip.to_string(), slug).await
+    }
+
+    /// Lifts the block of `ip` and takes it off the scanners
+    pub(crate) async fn forget_scanner(&self, ip: IpAddr) -> Result<(), AppError> {
+        self.honeypot.release(ip);
+        self.store.forget_scanner(&ip.to_string()).await?;
+        Ok(())
+    }
+
+    /// Stops `id` from redirecting, or lets it again. Either way an operator has looked at it,
+    /// which deals with its reports.
+    pub(crate) async fn set_flagged(&self, id: &str, flagged: bool) -> Result<(), AppError> {
+        if !self.store.set_flagged(id, flagged).await? {
+            return Err(AppError::NotFound(id.to_owned()));
+        }
+        let resolved = self.store.resolve_reports(id).await?;
+        if flagged {
+            info!("Disabled link {}, closing {} reports", id, resolved);
+        } else {
+            info!("Enabled link {}, closing {} reports", id, resolved);
+        }
+        Ok(())
+    }
+
+    /// Files a report against `id` from `client`. Enough different reporters disable the link
+    /// until an operator reviews it, the reports stay open for that.
+    pub(crate) async fn report_link(
+        &self,
+        id: &str,
+        report: &ReportRequest,
+        client: &Client,
+    ) -> Result<(), AppError> {
+        let reason = report.reason.trim();
+        if reason.chars().count() > MAX_REPORT_LENGTH
+            || (reason.is_empty() && report.category == ReportCategory::Other)
+        {
+            return Err(AppError::validation(
+                "reason",
+                format!("must be 1 to {} characters", MAX_REPORT_LENGTH),
+            ));
+        }
+        let reporter = self.clicks.hash_ip(client.ip);
+        let reporters = self
+            .store
+            .report_link(id, report.category.as_str(), reason, &reporter)
+            .await?
+            .ok_or_else(|| AppError::NotFound(id.to_owned()))?;
+        info!(
+            category = report.category.as_str(),
+            reporters, "Link {} was reported", id
+        );
+
+        let threshold = self.config.features.disable_after_reports;
+        if threshold.is_some_and(|threshold| reporters >= u64::from(threshold))
+            && self.store.set_flagged(id, true).await?
+        {
+            warn!(
+                "Disabled link {} after reports from {} visitors, it needs a review",
+                id, reporters
+            );
+        }
+        Ok(())
+    }
+
+    /// Shortens `req` for the visitor at `client`, `None` for operators. Links from visitors
+    /// that look like spam are quarantined. `forwarded_base` is where the request reached us
+    /// through a trusted proxy, a link back there would redirect to itself.
+    pub(crate) async fn create_link(
+        &self,
+        req: &ShortenRequest,
+        client: Option<IpAddr>,
+        forwarded_base: Option<&Url>,
+    ) -> Result<String, AppError> {
+        if let Some(alias) = &req.alias {
+            self.check_alias(alias)?;
+            if self.store.alias_target(alias).await?.is_some() {
+                return Err(AppError::AliasTaken {
+                    alias: alias.clone(),
+                    suggestions: self.suggest_aliases(alias).await?,
+                });
+            }
+        }
+        let tags = validate_tags(&req.tags)?;
+        if let Some(cache_control) = &req.cache_control {
+            validate_cache_control(cache_control)?;
+        }
+        let strip_tracking = req.strip_tracking.unwrap_or(self.strip_tracking);
+        let url = self
+            .check_destination(&req.url, strip_tracking, forwarded_base)
+            .await?;
+        let check = match self.config.features.reachability_check {
+            ReachabilityCheck::Off => None,
+            mode => Some(self.check_reachability(&url, mode).await?),
+        };
+        let signals = match (client, self.config.features.quarantine_score) {
+            (Some(ip), Some(_)) => self.spam_signals(ip, &url, req.alias.as_deref()),
+            _ => Vec::new(),
+        };
+        // stored flagged right away, so it never redirects before a review
+        let reason = self.quarantine_reason(&signals);
+        let quarantine = reason.as_deref().map(|reason| Quarantine {
+            category: ReportCategory::Spam.as_str(),
+            reason,
+            reporter: QUARANTINE_REPORTER,
+        });
+        let quarantine = quarantine.as_ref();
+        let private = req
+            .visibility
+            .is_some_and(|visibility| visibility.is_private());
+        let options = LinkOptions {
+            private,
+            forward_query: req.forward_query.unwrap_or_default(),
+            cache_control: req
+                .cache_control
+                .as_deref()
+                .map(str::trim)
+                .filter(|value| !value.is_empty()),
+        };
+        let (id, created) = if !req.rotate.is_empty() {
+            let rotate = self
+                .check_rotation(&url, &req.rotate, strip_tracking, forwarded_base)
+                .await?;
+            let id = self
+                .shorten_rotating(
+                    &url,
+                    &rotate,
+                    req.alias.as_deref(),
+                    &tags,
+                    &options,
+                    quarantine,
+                )
+                .await?;
+            (id, true)
+        } else {
+            match &req.alias {
+                Some(alias) => {
+                    self.shorten_with_alias(&url, alias, &tags, &options, quarantine)
+                        .await?
+                }
+                None => self.shorten(&url, &tags, &options, quarantine).await?,
+            }
+        };
+        // the URL may be shortened already, anyone may shorten it again but only edits change
+        // it. Handing out a public link to whoever asked for a private one would expose it.
+        if !created && private && !self.get_url(&id).await?.private {
+            return Err(AppError::AlreadyShortened { url, id });
+        }
+        if created && !tags.is_empty() {
+            self.store.add_tags(&id, &tags).await?;
+        }
+        if let Some(status) = check {
+            self.store.set_link_check(&id, status).await?;
+        }
+        if created && quarantine.is_some() {
+            warn!(signals = ?signals, "Quarantined new link {}", id);
+        }
+        if created {
+            self.metrics.links_created.fetch_add(1, Ordering::Relaxed);
+            self.metrics.publish(FeedEvent::Created { id: id.clone() });
+        }
+        Ok(id)
+    }
+
+    /// Points `id` at `url` and replaces its tags and visibility, `forwarded_base` as for
+    /// [`AppState::create_link`]
+    pub(crate) async fn edit_link(
+        &self,
+        id: &str,
+        url: &str,
+        tags: &[String],
+        visibility: Visibility,
+        forwarded_base: Option<&Url>,
+    ) -> Result<(), AppError> {
+        let tags = validate_tags(tags)?;
+        let url = self
+            .check_destination(url, self.strip_tracking, forwarded_base)
+            .await?;
+        match self.store.update_link(id, &url).await? {
+            UpdateLink::Updated => {}
+            UpdateLink::NotFound => return Err(AppError::NotFound(id.to_owned())),
+            UpdateLink::UrlExists(existing) if existing == id => {}
+            UpdateLink::UrlExists(existing) => {
+                return Err(AppError::AlreadyShortened { url, id: existing })
+            }
+        }
+        self.store.set_tags(id, &tags).await?;
+        self.store.set_private(id, visibility.is_private()).await?;
+        info!("Edited link {}", id);
+        Ok(())
+    }
+
+    pub(crate) async fn delete_link(&self, id: &str, reason: Option<&str>) -> Result<(), AppError> {
+        if reason.is_some_and(|reason| reason.chars().count() > MAX_REPORT_LENGTH) {
+            return Err(AppError::validation(
+                "reason",
+                format!("must be at most {} characters", MAX_REPORT_LENGTH),
+            ));
+        }
+        if !self.store.delete_link(id, reason).await? {
+            return Err(AppError::NotFound(id.to_owned()));
+        }
+        info!("Deleted link {}", id);
+        Ok(())
+    }
+
+    /// The id of `url` and whether it was shortened just now
+    async fn shorten(
+        &self,
+        url: &str,
+        tags: &[String],
+        options: &LinkOptions<'_>,
+        quarantine: Option<&Quarantine<'_>>,
+    ) -> Result<(String, bool), AppError> {
+        let id = self.create_id().await.map_err(AppError::InternalServer)?;
+        let created = self.created_event(&id, url, tags);
+        let stored = self
+            .store
+            .insert_or_get_link(&id, url, options, created.as_ref(), quarantine)
+            .await?;
+        let created = stored == id;
+        // a rotating link would send some of the clicks elsewhere
+        if !created && self.get_url(&stored).await?.rotating {
+            return Err(AppError::AlreadyShortened {
+                url: url.to_owned(),
+                id: stored,
+            });
+        }
+        Ok((stored, created))
+    }
+
+    async fn shorten_with_alias(
+        &self,
+        url: &str,
+        alias: &str,
+        tags: &[String],
+        options: &LinkOptions<'_>,
+        quarantine: Option<&Quarantine<'_>>,
+    ) -> Result<(String, bool), AppError> {
+        let created = self.created_event(alias, url, tags);
+        match self
+            .store
+            .insert_link(alias, url, options, created.as_ref(), quarantine)
+            .await?
+        {
+            InsertLink::Inserted => Ok((alias.to_owned(), true)),
+            InsertLink::IdTaken => Err(AppError::AliasTaken {
+                alias: alias.to_owned(),
+                suggestions: self.suggest_aliases(alias).await?,
+            }),
+            InsertLink::UrlExists(id) if id == alias => Ok((id, false)),
+            InsertLink::UrlExists(id) => Err(AppError::AlreadyShortened {
+                url: url.to_owned(),
+                id,
+            }),
+        }
+    }
+
+    /// The other destinations of a rotating link to `url`, as they get stored
+    async fn check_rotation(
+        &self,
+        url: &str,
+        rotate: &[String],
+        strip_tracking: bool,
+        forwarded_base: Option<&Url>,
+    ) -> Result<Vec<String>, AppError> {
+        if rotate.len() > MAX_ROTATE {
+            return Err(AppError::validation(
+                "rotate",
+                format!("at most {} destinations besides `url`", MAX_ROTATE),
+            ));
+        }
+        let mut checked: Vec<String> = Vec::with_capacity(rotate.len());
+        for raw in rotate {
+            let destination = self
+                .check_destination(raw, strip_tracking, forwarded_base)
+                .await
+                .map_err(|e| match e {
+                    AppError::Validation { message, .. } => AppError::validation("rotate", message),
+                    e => e,
+                })?;
+            if destination == url || checked.contains(&destination) {
+                return Err(AppError::validation(
+                    "rotate",
+                    format!("{} is listed more than once", destination),
+                ));
+            }
+            checked.push(destination);
+        }
+        Ok(checked)
+    }
+
+    /// A new link taking turns between `url` and `rotate`
+    async fn shorten_rotating(
+        &self,
+        url: &str,
+        rotate: &[String],
+        alias: Option<&str>,
+        tags: &[String],
+        options: &LinkOptions<'_>,
+        quarantine: Option<&Quarantine<'_>>,
+    ) -> Result<String, AppError> {
+        loop {
+            let id = alias.map_or_else(random_link_id, ToOwned::to_owned);
+            let created = self.created_event(&id, url, tags);
+            match self
+                .store
+                .insert_rotating_link(&id, url, rotate, options, created.as_ref(), quarantine)
+                .await?
+            {
+                InsertLink::Inserted => return Ok(id),
+                InsertLink::IdTaken => {
+                    if let Some(alias) = alias {
+                        return Err(AppError::AliasTaken {
+                            alias: alias.to_owned(),
+                            suggestions: self.suggest_aliases(alias).await?,
+                        });
+                    }
+                }
+                InsertLink::UrlExists(id) => {
+                    return Err(AppError::AlreadyShortened {
+                        url: url.to_owned(),
+                        id,
+                    })
+                }
+            }
+        }
+    }
+
+    /// A few free variations of a taken alias
+    async fn suggest_aliases(&self, alias: &str) -> Result<Vec<String>, AppError> {
+        let base: String = alias.chars().take(ALIAS_LENGTH.end() - 3).collect();
+        let candidates: Vec<String> = (0..5)
+            .map(|_| format!("{}-{}", base, nanoid::nanoid!(2)))
+            .collect();
+        let taken = self.store.taken_ids(&candidates).await?;
+
+        Ok(candidates
+            .into_iter()
+            .filter(|c| !taken.contains(c))
+            .take(3)
+            .collect())
+    }
+
+    async fn create_id(&self) -> Result<String> {
+        new_link_id(self.store.as_ref()).await
+    }
+
+    /// Link `id`, a deleted one is [`AppError::Gone`]
+    pub(crate) async fn get_url(&self, id: &str) -> Result<UrlRecord, AppError> {
+        if let Some(record) = self.store.get_link(id).await? {
+            return Ok(record);
+        }
+        match self.store.tombstone(id).await? {
+            Some(tombstone) => Err(AppError::Gone(tombstone)),
+            None => Err(AppError::NotFound(id.to_owned())),
+        }
+    }
+
+    /// `id` with its tags and aliases, the link it stands for if it is an alias
+    pub(crate) async fn link(&self, id: &str) -> Result<ExportedLink, AppError> {
+        self.link_fields(id, None).await
+    }
+
+    /// Link `id`, looking up only what `fields` asks for. Its clicks only when asked for.
+    pub(crate) async fn link_fields(
+        &self,
+        id: &str,
+        fields: Option<&LinkFields>,
+    ) -> Result<ExportedLink, AppError> {
+        let record = self.get_url(id).await?;
+        let wants = |field| LinkFields::wants(fields, field);
+        let tags = if wants(LinkField::Tags) {
+            self.store.link_tags(&record.id).await?
+        } else {
+            Vec::new()
+        };
+        let aliases = if wants(LinkField::Aliases) {
+            self.store.link_aliases(&record.id).await?
+        } else {
+            Vec::new()
+        };
+        let clicks = if fields.is_some() && wants(LinkField::Clicks) {
+            let totals = self
+                .store
+                .click_totals(std::slice::from_ref(&record.id))
+                .await?;
+            Some(totals.get(&record.id).copied().unwrap_or_default())
+        } else {
+            None
+        };
+        Ok(ExportedLink {
+            tags,
+            aliases,
+            created_at: record.created_at,
+            check_status: record.check_status,
+            checked_at: record.checked_at,
+            clicks,
+            id: record.id,
+            url: record.url,
+            flagged: record.flagged,
+            visibility: record.private.into(),
+            forward_query: record.forward_query,
+            cache_control: record.cache_control,
+        })
+    }
+
+    pub(crate) async fn bundle(&self, id: &str) -> Result<BundleRecord, AppError> {
+        self.store
+            .get_bundle(id)
+            .await?
+            .ok_or_else(|| AppError::BundleNotFound(id.to_owned()))
+    }
+
+    /// Stores the bundle under its alias or a new id, which is returned
+    pub(crate) async fn create_bundle(&self, req: &BundleRequest) -> Result<String, AppError> {
+        self.validate_bundle(req).await?;
+        if let Some(alias) = &req.alias {
+            self.check_alias(alias)?;
+            if !self.store.insert_bundle(alias, req).await? {
+                return Err(AppError::AliasTaken {
+                    alias: alias.clone(),
+                    suggestions: Vec::new(),
+                });
+            }
+            info!("Created bundle {}", alias);
+            return Ok(alias.clone());
+        }
+        loop {
+            let id = random_link_id();
+            if self.store.insert_bundle(&id, req).await? {
+                info!("Created bundle {}", id);
+                return Ok(id);
+            }
+        }
+    }
+
+    /// Replaces everything about bundle `id` but its id
+    pub(crate) async fn update_bundle(
+        &self,
+        id: &str,
+        req: &BundleRequest,
+    ) -> Result<(), AppError> {
+        self.validate_bundle(req).await?;
+        if !self.store.update_bundle(id, req).await? {
+            return Err(AppError::BundleNotFound(id.to_owned()));
+        }
+        info!("Edited bundle {}", id);
+        Ok(())
+    }
+
+    pub(crate) async fn delete_bundle(&self, id: &str) -> Result<(), AppError> {
+        if !self.store.delete_bundle(id).await? {
+            return Err(AppError::BundleNotFound(id.to_owned()));
+        }
+        info!("Deleted bundle {}", id);
+        Ok(())
+    }
+
+    /// Every link of a bundle has to exist and be in it once
+    async fn validate_bundle(&self, req: &BundleRequest) -> Result<(), AppError> {
+        if !BUNDLE_TITLE_LENGTH.contains(&req.title.trim().chars().count()) {
+            return Err(AppError::validation(
+                "title",
+                format!(
+                    "must be between {} and {} characters",
+                    BUNDLE_TITLE_LENGTH.start(),
+                    BUNDLE_TITLE_LENGTH.end()
+                ),
+            ));
+        }
+        if req
+            .description
+            .as_ref()
+            .is_some_and(|d| d.chars().count() > MAX_BUNDLE_DESCRIPTION_LENGTH)
+        {
+            return Err(AppError::validation(
+                "description",
+                format!(
+                    "must be at most {} characters",
+                    MAX_BUNDLE_DESCRIPTION_LENGTH
+                ),
+            ));
+        }
+        if req.links.len() > MAX_BUNDLE_LINKS {
+            return Err(AppError::validation(
+                "links",
+                format!("at most {} links per bundle", MAX_BUNDLE_LINKS),
+            ));
+        }
+        let mut ids = Vec::with_capacity(req.links.len());
+        for entry in &req.links {
+            if ids.contains(&entry.id) {
+                return Err(AppError::validation(
+                    "links",
+                    format!("{} is listed more than once", entry.id),
+                ));
+            }
+            if entry
+                .title
+                .as_ref()
+                .is_some_and(|t| !BUNDLE_TITLE_LENGTH.contains(&t.chars().count()))
+            {
+                return Err(AppError::validation(
+                    "links",
+                    format!(
+                        "titles must be between {} and {} characters",
+                        BUNDLE_TITLE_LENGTH.start(),
+                        BUNDLE_TITLE_LENGTH.end()
+                    ),
+                ));
+            }
+            ids.push(entry.id.clone());
+        }
+        let existing = self.store.taken_ids(&ids).await?;
+        if let Some(missing) = ids.iter().find(|id| !existing.contains(id)) {
+            return Err(AppError::validation(
+                "links",
+                format!("there is no link {}", missing),
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn bundle_response(&self, bundle: BundleRecord, client: &Client) -> BundleResponse {
+        BundleResponse {
+            url: self.config.bundle_url(&bundle.id, client),
+            links: bundle
+                .links
+                .into_iter()
+                .map(|link| BundleLink {
+                    short_url: self.config.short_url(&link.url_id, client),
+                    id: link.url_id,
+                    title: link.title,
+                    url: link.url,
+                    flagged: link.flagged,
+                    visibility: link.private.into(),
+                })
+                .collect(),
+            id: bundle.id,
+            title: bundle.title,
+            description: bundle.description,
+        }
+    }
+
+    /// The most clicked links over `period` with the first and last day counted
+    pub(crate) async fn top_links(
+        &self,
+        period: Period,
+        limit: i64,
+        exclude_bots: bool,
+    ) -> Result<(NaiveDate, NaiveDate, Vec<TopLink>), AppError> {
+        if !(1..=MAX_TOP_LIMIT).contains(&limit) {
+            return Err(AppError::validation(
+                "limit",
+                format!("must be between 1 and {}", MAX_TOP_LIMIT),
+            ));
+        }
+        // the window ends today, so `1d` is today's clicks so far
+        let to = Utc::now().date_naive();
+        let from = to - Days::new(period.days - 1);
+
+        let links = self.store.top_links(from, to, limit, exclude_bots).await?;
+        Ok((from, to, links))
+    }
+
+    /// Clicks of all time of each of `ids` in a single lookup
+    pub(crate) async fn bulk_stats(&self, ids: &[String]) -> Result<BulkStatsResponse, AppError> {
+        if !(1..=MAX_BULK_STATS_IDS).contains(&ids.len()) {
+            return Err(AppError::validation(
+                "ids",
+                format!("must list between 1 and {} links", MAX_BULK_STATS_IDS),
+            ));
+        }
+        let mut ids = ids.to_vec();
+        ids.sort();
+        ids.dedup();
+        let existing = self.store.taken_ids(&ids).await?;
+        let totals = self.store.click_totals(&existing).await?;
+        let (found, not_found): (Vec<String>, Vec<String>) =
+            ids.into_iter().partition(|id| existing.contains(id));
+        let clicks = found
+            .into_iter()
+            .map(|id| {
+                let clicks = totals.get(&id).copied().unwrap_or_default();
+                (id, clicks)
+            })
+            .collect();
+        Ok(BulkStatsResponse { clicks, not_found })
+    }
+
+    /// One page of the links `query` asks for, with the cursor of the next page unless it is
+    /// the last one
+    pub(crate) async fn list_links(
+        &self,
+        query: &ListLinksQuery,
+    ) -> Result<(Vec<ExportedLink>, Option<LinkCursor>), AppError> {
+        let limit = query.limit;
+        if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+            return Err(AppError::validation(
+                "limit",
+                format!("must be between 1 and {}", MAX_PAGE_SIZE),
+            ));
+        }
+        if let Some(after) = &query.after {
+            if after.sort() != query.sort {
+                return Err(AppError::validation(
+                    "after",
+                    "is from a page with another sort",
+                ));
+            }
+        }
+        // one extra row tells whether another page follows
+        let query = ListLinksQuery {
+            limit: limit + 1,
+            tag: query.tag.as_deref().map(|tag| tag.trim().to_lowercase()),
+            domain: query.domain.as_deref().map(normalize_domain).transpose()?,
+            ..query.clone()
+        };
+        let mut links = self.store.list_links(&query).await?;
+        let more = links.len() as i64 > limit;
+        links.truncate(limit as usize);
+        let next = more
+            .then(|| {
+                links
+                    .last()
+                    .and_then(|link| LinkCursor::after(link, query.sort))
+            })
+            .flatten();
+        Ok((links, next))
+    }
+
+    /// One page of the public links that still redirect for `/directory`, and whether more follow
+    pub(crate) async fn directory_links(
+        &self,
+        after: Option<&str>,
+    ) -> Result<(Vec<ExportedLink>, bool), AppError> {
+        if !self.config.features.public_directory {
+            return Err(AppError::NotFound("directory".to_owned()));
+        }
+        let mut links = self
+            .store
+            .directory_links(after, DIRECTORY_PAGE_SIZE + 1)
+            .await?;
+        let more = links.len() as i64 > DIRECTORY_PAGE_SIZE;
+        links.truncate(DIRECTORY_PAGE_SIZE as usize);
+        Ok((links, more))
+    }
+
+    /// Lets the stats of `id` through, those of private links only with a `stats:read` token.
+    /// Returns the id they are kept under, which differs for aliases.
+    pub(crate) async fn check_stats_access(
+        &self,
+        id: &str,
+        token: Option<&str>,
+    ) -> Result<String, AppError> {
+        let record = self.get_url(id).await?;
+        if record.private {
+            self.check_scope(token, Scope::StatsRead)?;
+        }
+        Ok(record.id)
+    }
+
+    /// Lets `alias` redirect like link `id`, its clicks are counted for `id`
+    pub(crate) async fn add_alias(&self, id: &str, alias: &str) -> Result<(), AppError> {
+        self.check_alias(alias)?;
+        let record = self.get_url(id).await?;
+        if !self.store.add_alias(&record.id, alias).await? {
+            return Err(AppError::AliasTaken {
+                alias: alias.to_owned(),
+                suggestions: self.suggest_aliases(alias).await?,
+            });
+        }
+        info!("Added alias {} to {}", alias, record.id);
+        Ok(())
+    }
+
+    /// The link `id` is, or stands for if it is an alias, and where it redirects to unless it
+    /// was flagged or its domain blocked since. The parameters of `query` are merged in if the
+    /// link forwards them.
+    pub(crate) async fn destination(
+        &self,
+        id: &str,
+        query: Option<&str>,
+    ) -> Result<(UrlRecord, String), AppError> {
+        let record = self.get_url(id).await?;
+        if record.flagged {
+            info!(
+                "Refused to redirect flagged link {} to {}",
+                record.id, record.url
+            );
+            return Err(AppError::FlaggedLink(record.id));
+        }
+        let url = match record.rotating {
+            true => self
+                .store
+                .next_rotation(&record.id)
+                .await?
+                .unwrap_or_else(|| record.url.clone()),
+            false => record.url.clone(),
+        };
+        if let Ok(parsed) = Url::parse(&url) {
+            self.blocklist.check(&parsed)?;
+        }
+        let url = match query {
+            Some(query) => record.forward_query.apply(&url, query),
+            None => url,
+        };
+        Ok((record, url))
+    }
+
+    /// Whether `token`, sent as `Bearer <token>`, is the admin token
+    pub(crate) fn is_admin(&self, token: Option<&str>) -> bool {
+        matches!(
+            (token, self.admin_token.as_deref()),
+            (Some(token), Some(expected)) if secrets_match(token, expected)
+        )
+    }
+
+    /// Lets `token` through if it is the admin token or one of `security.tokens` with `scope`
+    pub(crate) fn check_scope(&self, token: Option<&str>, scope: Scope) -> Result<(), AppError> {
+        if self.is_admin(token) {
+            return Ok(());
+        }
+        let scoped = token
+            .and_then(|token| {
+                self.config
+                    .security
+                    .tokens
+                    .iter()
+                    .find(|scoped| secrets_match(token, &scoped.token))
+            })
+            .ok_or(AppError::Unauthorized)?;
+        if scoped.scopes.contains(&scope) || scoped.scopes.contains(&Scope::Admin) {
+            Ok(())
+        } else {
+            warn!(
+                token = scoped.name,
+                "Token without the {} scope",
+                scope.as_str()
+            );
+            Err(AppError::MissingScope(scope.as_str()))
+        }
+    }
+
+    /// What the session cookie of `/ui` holds: when it expires, signed with a key derived from
+    /// the admin token so changing the token ends every session
+    pub(crate) fn start_admin_session(&self) -> Option<String> {
+        let expires = Utc::now().timestamp() + SESSION_LIFETIME.as_secs() as i64;
+        let signature = self.session_signature(expires)?;
+        Some(format!("{}.{}", expires, signature))
+    }
+
+    fn session_signature(&self, expires: i64) -> Option<String> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.session_key.as_ref()?)
+            .expect("HMAC takes keys of any length");
+        mac.update(expires.to_string().as_bytes());
+        Some(format!("{:x}", mac.finalize().into_bytes()))
+    }
+
+    pub(crate) fn is_admin_session(&self, session: Option<&str>) -> bool {
+        let Some((expires, signature)) = session.and_then(|session| session.split_once('.')) else {
+            return false;
+        };
+        let Ok(expires) = expires.parse::<i64>() else {
+            return false;
+        };
+        expires > Utc::now().timestamp()
+            && self
+                .session_signature(expires)
+                .is_some_and(|expected| secrets_match(signature, &expected))
+    }
+
+    /// Puts every instance into `maintenance`, this one right away and the others on their
+    /// next poll
+    pub(crate) async fn set_maintenance(&self, maintenance: Maintenance) -> Result<(), AppError> {
+        self.store.set_maintenance(&maintenance).await?;
+        if maintenance.enabled {
+            warn!(
+                serve_reads = maintenance.serve_reads,
+                "Maintenance mode on, changes are refused"
+            );
+        } else {
+            info!("Maintenance mode off");
+        }
+        *self.maintenance.write().unwrap() = maintenance;
+        Ok(())
+    }
+
+    /// Follows the maintenance mode set through any instance
+    pub(crate) async fn reload_maintenance(&self) -> Result<(), AppError> {
+        let Some(maintenance) = self.store.maintenance().await? else {
+            return Ok(());
+        };
+        let mut current = self.maintenance.write().unwrap();
+        if current.enabled != maintenance.enabled {
+            info!(
+                enabled = maintenance.enabled,
+                "Maintenance mode changed on another instance"
+            );
+        }
+        *current = maintenance;
+        Ok(())
+    }
+
+    /// Rejects writes, and reads too unless they are still served, during maintenance
+    pub(crate) fn check_maintenance(&self, read: bool) -> Result<(), AppError> {
+        let maintenance = self.maintenance.read().unwrap().clone();
+        if maintenance.enabled && !(read && maintenance.serve_reads) {
+            return Err(AppError::Maintenance {
+                message: maintenance.message,
+                retry_after_secs: maintenance.retry_after_secs,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// The key sessions of `/ui` are signed with, the same for every process with `admin_token`
+fn session_key(admin_token: &str) -> [u8; 32] {
+    let mut key = [0; 32];
+    Hkdf::<Sha256>::new(None, admin_token.as_bytes())
+        .expand(SESSION_KEY_INFO, &mut key)
+        .expect("32 bytes is a valid HKDF output length");
+    key
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use figment::{
+        providers::{Format, Toml},
+        Figment,
+    };
+    use tracing::level_filters::LevelFilter;
+
+    use super::*;
+    use crate::store::mock::MockStore;
+
+    pub(crate) async fn state_with(toml: &str, store: Arc<MockStore>) -> AppState {
+        let config: Config = Figment::from(Toml::string(toml)).extract().unwrap();
+        AppState::try_new(config, store, LogLevel::new(LevelFilter::OFF))
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn the_blocklist_file_can_be_configured() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(file.path(), "# seen in spam\nEvil.example\n").unwrap();
+        let state = state_with(
+            &format!("[security]\nblocklist_file = {:?}", file.path()),
+            Arc::default(),
+        )
+        .await;
+
+        let req = ShortenRequest {
+            url: "https://evil.example/promo".to_owned(),
+            ..ShortenRequest::default()
+        };
+        let err = state.create_link(&req, None, None).await.unwrap_err();
+        assert!(matches!(err, AppError::BlockedDomain(domain) if domain == "evil.example"));
+    }
+
+    #[tokio::test]
+    async fn maintenance_mode_reaches_every_instance() {
+        let store = Arc::new(MockStore::default());
+        let toggled = state_with("", store.clone()).await;
+        let other = state_with("", store.clone()).await;
+
+        toggled
+            .set_maintenance(Maintenance {
+                enabled: true,
+                ..Maintenance::default()
+            })
+            .await
+            .unwrap();
+        assert!(toggled.check_maintenance(false).is_err());
+        other.reload_maintenance().await.unwrap();
+        assert!(other.check_maintenance(false).is_err());
+        assert!(other.check_maintenance(true).is_ok());
+        // and instances started afterwards
+        let restarted = state_with("", store).await;
+        assert!(restarted.check_maintenance(false).is_err());
+    }
+}
@@ -0,0 +1,2361 @@
+//! Where links and their clicks are kept
+
+mod breaker;
+#[cfg(test)]
+pub(crate) mod mock;
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+use sqlx::FromRow;
+#[cfg(feature = "postgres")]
+use sqlx::{pool::PoolConnection, PgConnection, PgPool, Postgres, Transaction};
+use tokio_stream::{Stream, StreamExt};
+use tracing::{instrument, warn};
+
+use crate::api::{
+    BundleRequest, CountryClicks, CursorKey, DailyStats, ExportedLink, ForwardQuery, Interval,
+    LinkField, LinkFields, LinkSort, ListLinksQuery, Maintenance, ReferrerClicks, SortOrder,
+    TimeseriesPoint, TopLink,
+};
+use crate::clicks::ClickEvent;
+use crate::error::AppError;
+use crate::events::LinkEvent;
+pub(crate) use breaker::BreakerStore;
+
+/// Left behind by a deleted link, so its id answers 410 Gone rather than 404
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub(crate) struct Tombstone {
+    pub(crate) id: String,
+    pub(crate) reason: Option<String>,
+    pub(crate) removed_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub(crate) struct BlockedDomain {
+    pub(crate) domain: String,
+    pub(crate) reason: Option<String>,
+}
+
+/// Where links, clicks and the blocklist are kept. Nothing else talks to the database, so a
+/// backend is one implementation behind its own Cargo feature.
+#[async_trait]
+pub(crate) trait Store: Send + Sync {
+    /// Create or update the schema, safe to run again
+    async fn migrate(&self) -> Result<(), AppError>;
+
+    async fn ping(&self) -> Result<(), AppError>;
+
+    /// `None` for stores without a connection pool
+    fn pool_stats(&self) -> Option<PoolStats>;
+
+    /// Waits for running queries, nothing can be stored afterwards
+    async fn close(&self);
+
+    /// Link `id` or the one it is an alias of
+    async fn get_link(&self, id: &str) -> Result<Option<UrlRecord>, AppError>;
+
+    async fn link_exists(&self, id: &str) -> Result<bool, AppError>;
+
+    /// The id `url` is shortened under, if it is
+    async fn id_for_url(&self, url: &str) -> Result<Option<String>, AppError>;
+
+    /// The subset of `ids` that is already in use, by links or aliases
+    async fn taken_ids(&self, ids: &[String]) -> Result<Vec<String>, AppError>;
+
+    /// Stores `url` under `id` with `options` unless it is shortened already, returns the id it
+    /// is stored under. `created` goes to the outbox along with a new link, which is flagged and
+    /// filed for review with `quarantine`.
+    async fn insert_or_get_link(
+        &self,
+        id: &str,
+        url: &str,
+        options: &LinkOptions<'_>,
+        created: Option<&LinkEvent>,
+        quarantine: Option<&Quarantine<'_>>,
+    ) -> Result<String, AppError>;
+
+    /// Stores `url` under exactly `id` with `options`, and `created` in the outbox along with
+    /// it. With `quarantine` it is stored flagged and filed for review.
+    async fn insert_link(
+        &self,
+        id: &str,
+        url: &str,
+        options: &LinkOptions<'_>,
+        created: Option<&LinkEvent>,
+        quarantine: Option<&Quarantine<'_>>,
+    ) -> Result<InsertLink, AppError>;
+
+    /// Stores a link under exactly `id` with `options` that takes turns between `url` and
+    /// `rotate`, and `created` in the outbox along with it. With `quarantine` it is stored
+    /// flagged and filed for review.
+    async fn insert_rotating_link(
+        &self,
+        id: &str,
+        url: &str,
+        rotate: &[String],
+        options: &LinkOptions<'_>,
+        created: Option<&LinkEvent>,
+        quarantine: Option<&Quarantine<'_>>,
+    ) -> Result<InsertLink, AppError>;
+
+    /// Where the next click on rotating link `id` goes, its URL and then each of the others in
+    /// turn. `None` when `id` doesn't rotate.
+    async fn next_rotation(&self, id: &str) -> Result<Option<String>, AppError>;
+
+    /// Points `id` at `url` instead
+    async fn update_link(&self, id: &str, url: &str) -> Result<UpdateLink, AppError>;
+
+    /// Adds `tags` to the ones `id` has already
+    async fn add_tags(&self, id: &str, tags: &[String]) -> Result<(), AppError>;
+
+    /// Replaces the tags of `id` with `tags`
+    async fn set_tags(&self, id: &str, tags: &[String]) -> Result<(), AppError>;
+
+    async fn link_tags(&self, id: &str) -> Result<Vec<String>, AppError>;
+
+    /// Lets `alias` stand for link `id`, `false` when it is taken by a link or another alias
+    async fn add_alias(&self, id: &str, alias: &str) -> Result<bool, AppError>;
+
+    /// `false` when `alias` doesn't stand for `id`
+    async fn remove_alias(&self, id: &str, alias: &str) -> Result<bool, AppError>;
+
+    /// The link `alias` stands for, if it is one
+    async fn alias_target(&self, alias: &str) -> Result<Option<String>, AppError>;
+
+    async fn link_aliases(&self, id: &str) -> Result<Vec<String>, AppError>;
+
+    /// Up to `query.limit` links with their tags and clicks that match the filters of
+    /// `query`, in its order and after its cursor. Tag and domain come normalized. Tags,
+    /// aliases and clicks not among its `fields` may be left empty.
+    async fn list_links(&self, query: &ListLinksQuery) -> Result<Vec<ExportedLink>, AppError>;
+
+    /// Up to `limit` public links that aren't flagged with an id after `after`, ordered by id
+    async fn directory_links(
+        &self,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<ExportedLink>, AppError>;
+
+    /// Makes `id` private or public again, see [`crate::Visibility`]
+    async fn set_private(&self, id: &str, private: bool) -> Result<(), AppError>;
+
+    /// Removes `id` along with its clicks and stats, `false` when there was no such link,
+    /// and leaves a [`Tombstone`] with `reason` for it and its aliases
+    async fn delete_link(&self, id: &str, reason: Option<&str>) -> Result<bool, AppError>;
+
+    /// Keeps what the destination of `id` answered just now, `None` when it couldn't be reached
+    async fn set_link_check(&self, id: &str, status: Option<i16>) -> Result<(), AppError>;
+
+    /// What is known about `id` if it belonged to a link that was deleted
+    async fn tombstone(&self, id: &str) -> Result<Option<Tombstone>, AppError>;
+
+    /// Adds a link from `export` as is with its tags and aliases, `false` when its id or URL
+    /// exists already
+    async fn import_link(&self, link: &ExportedLink) -> Result<bool, AppError>;
+
+    /// Every link, ordered by id
+    fn export_links(&self) -> BoxStream<'_, Result<ExportedLink, AppError>>;
+
+    /// Up to `limit` links that are not flagged yet with an id after `after`, ordered by id
+    async fn unflagged_links(&self, after: &str, limit: i64) -> Result<Vec<UrlRecord>, AppError>;
+
+    /// Flags every link to one of `urls`, returning how many there were
+    async fn flag_urls(&self, urls: &[String]) -> Result<u64, AppError>;
+
+    /// Stores `clicks`, with a `link.clicked` event for each in the outbox when `publish`
+    async fn insert_clicks(&self, clicks: &[ClickEvent], publish: bool) -> Result<(), AppError>;
+
+    /// The last rolled up day, or the day of the first click if nothing is rolled up yet
+    async fn last_rollup_day(&self) -> Result<Option<NaiveDate>, AppError>;
+
+    /// Aggregate raw clicks from `from` (UTC day) onwards into daily and hourly counts,
+    /// returning the number of daily rows written. Days are recomputed as a whole so reruns
+    /// are harmless.
+    async fn rollup_clicks(&self, from: NaiveDate) -> Result<u64, AppError>;
+
+    /// Deletes the raw clicks from before `before`, returns how many
+    async fn prune_clicks(&self, before: DateTime<Utc>) -> Result<u64, AppError>;
+
+    /// Deletes the daily, hourly and per country rollups of the days before `before`, returns
+    /// how many daily rows
+    async fn prune_rollups(&self, before: NaiveDate) -> Result<u64, AppError>;
+
+    /// Adds `clicks` counted elsewhere to the rolled up clicks of `id` on `day`
+    async fn import_clicks(&self, id: &str, day: NaiveDate, clicks: i64) -> Result<(), AppError>;
+
+    /// Rolled up clicks of all time for those of `ids` that were clicked
+    async fn click_totals(&self, ids: &[String]) -> Result<HashMap<String, i64>, AppError>;
+
+    /// Rolled up clicks of every link clicked on `day`
+    async fn daily_stats(&self, day: NaiveDate) -> Result<Vec<DailyStats>, AppError>;
+
+    /// Clicks per bucket between the `from` and `to` days (inclusive), empty buckets included.
+    /// Weeks start on Monday.
+    async fn timeseries(
+        &self,
+        id: &str,
+        interval: Interval,
+        from: NaiveDate,
+        to: NaiveDate,
+        exclude_bots: bool,
+    ) -> Result<Vec<TimeseriesPoint>, AppError>;
+
+    /// Referrers of `id` between the `from` and `to` days (inclusive), from the ones each day
+    /// kept in its rollup
+    async fn referrers(
+        &self,
+        id: &str,
+        from: NaiveDate,
+        to: NaiveDate,
+        exclude_bots: bool,
+    ) -> Result<Vec<ReferrerClicks>, AppError>;
+
+    /// Where the clicks on `id` between the `from` and `to` days (inclusive) came from
+    async fn countries(
+        &self,
+        id: &str,
+        from: NaiveDate,
+        to: NaiveDate,
+        exclude_bots: bool,
+    ) -> Result<Vec<CountryClicks>, AppError>;
+
+    /// Marks the clicks since `since` as suspicious where one address sent at least
+    /// `min_clicks` of a link's clicks and at least `min_share` of them. Returns how many were
+    /// newly marked per link, so a spike is only reported once. With `publish`, a
+    /// `link.click_spike` event for each goes to the outbox along with the marks.
+    async fn mark_click_spikes(
+        &self,
+        since: DateTime<Utc>,
+        min_clicks: i64,
+        min_share: f64,
+        publish: bool,
+    ) -> Result<Vec<ClickSpike>, AppError>;
+
+    /// Up to `limit` events of the outbox that are due for delivery, oldest first
+    async fn pending_events(&self, limit: i64) -> Result<Vec<OutboxEntry>, AppError>;
+
+    /// Removes delivered events from the outbox
+    async fn delete_events(&self, ids: &[i64]) -> Result<(), AppError>;
+
+    /// Counts a failed delivery of event `id` and puts off the next one until `at`
+    async fn retry_event(&self, id: i64, at: DateTime<Utc>) -> Result<(), AppError>;
+
+    /// Queues a job of `kind` that runs once `run_after` has passed
+    async fn enqueue_job(
+        &self,
+        kind: &str,
+        payload: &str,
+        run_after: DateTime<Utc>,
+    ) -> Result<(), AppError>;
+
+    /// Claims up to `limit` due jobs until `locked_until`, other instances skip them meanwhile
+    /// and get them once the lock runs out without the job completing
+    async fn claim_jobs(
+        &self,
+        limit: i64,
+        locked_until: DateTime<Utc>,
+    ) -> Result<Vec<QueuedJob>, AppError>;
+
+    /// Removes job `id` from the queue once it ran
+    async fn complete_job(&self, id: i64) -> Result<(), AppError>;
+
+    /// Counts a failed run of job `id`, which runs again at `retry_at`. Without one the job is
+    /// kept with `error` but never runs again.
+    async fn fail_job(
+        &self,
+        id: i64,
+        error: &str,
+        retry_at: Option<DateTime<Utc>>,
+    ) -> Result<(), AppError>;
+
+    /// Links with the most clicks between the `from` and `to` days (inclusive)
+    async fn top_links(
+        &self,
+        from: NaiveDate,
+        to: NaiveDate,
+        limit: i64,
+        exclude_bots: bool,
+    ) -> Result<Vec<TopLink>, AppError>;
+
+    async fn list_blocked(&self) -> Result<Vec<BlockedDomain>, AppError>;
+
+    /// Adds `domain` or updates its reason
+    async fn block_domain(
+        &self,
+        domain: &str,
+        reason: Option<&str>,
+    ) -> Result<BlockedDomain, AppError>;
+
+    /// Adds `domain` unless it is there already, keeping its reason
+    async fn seed_blocked(&self, domain: &str) -> Result<(), AppError>;
+
+    async fn unblock_domain(&self, domain: &str) -> Result<(), AppError>;
+
+    /// Flags `id` so it no longer redirects, or lifts that, `false` when there is no such link
+    async fn set_flagged(&self, id: &str, flagged: bool) -> Result<bool, AppError>;
+
+    /// Files a report against `id` from the visitor hashed as `reporter`, returning how many
+    /// different visitors it has open reports from. `None` when there is no such link.
+    async fn report_link(
+        &self,
+        id: &str,
+        category: &str,
+        reason: &str,
+        reporter: &str,
+    ) -> Result<Option<u64>, AppError>;
+
+    /// Up to `limit` reports nobody has dealt with yet, oldest first
+    async fn open_reports(&self, limit: i64) -> Result<Vec<AbuseReport>, AppError>;
+
+    /// Closes the open reports against `id`, returning how many there were
+    async fn resolve_reports(&self, id: &str) -> Result<u64, AppError>;
+
+    /// Counts a request for the decoy `slug` from `ip`
+    async fn record_scanner(&self, ip: &str, slug: &str) -> Result<(), AppError>;
+
+    /// Up to `limit` addresses caught by the honeypot, the latest first
+    async fn list_scanners(&self, limit: i64) -> Result<Vec<Scanner>, AppError>;
+
+    /// Drops `ip` from the scanners, `false` when it wasn't one
+    async fn forget_scanner(&self, ip: &str) -> Result<bool, AppError>;
+
+    /// Stores `bundle` under `id`, `false` when `id` is taken by another bundle
+    async fn insert_bundle(&self, id: &str, bundle: &BundleRequest) -> Result<bool, AppError>;
+
+    /// Replaces title, description and links of bundle `id`, `false` when there is no such bundle
+    async fn update_bundle(&self, id: &str, bundle: &BundleRequest) -> Result<bool, AppError>;
+
+    async fn get_bundle(&self, id: &str) -> Result<Option<BundleRecord>, AppError>;
+
+    /// Every bundle with its links, ordered by id
+    async fn list_bundles(&self) -> Result<Vec<BundleRecord>, AppError>;
+
+    /// Removes bundle `id` but not its links, `false` when there was no such bundle
+    async fn delete_bundle(&self, id: &str) -> Result<bool, AppError>;
+
+    /// Takes the lock named `name` unless another instance holds it. It's held until the
+    /// [`Lease`] is dropped or the instance goes away.
+    async fn try_lease(&self, name: &str) -> Result<Option<Box<dyn Lease>>, AppError>;
+
+    /// The maintenance mode all instances follow, `None` if it was never set
+    async fn maintenance(&self) -> Result<Option<Maintenance>, AppError>;
+
+    async fn set_maintenance(&self, maintenance: &Maintenance) -> Result<(), AppError>;
+}
+
+/// Proof that this instance is the only one running a job
+#[async_trait]
+pub(crate) trait Lease: Send {
+    /// Whether the lock is still ours, it's gone if the connection holding it broke
+    async fn is_held(&mut self) -> bool;
+}
+
+pub(crate) type BoxStream<'a, T> = Pin<Box<dyn Stream<Item = T> + Send + 'a>>;
+
+/// Outcome of [`Store::insert_link`]
+#[derive(Debug)]
+pub(crate) enum InsertLink {
+    Inserted,
+    IdTaken,
+    /// the URL is shortened already, under this id
+    UrlExists(String),
+}
+
+/// What a new link is stored with besides its URL, in the same statement so it is never
+/// around without them
+#[derive(Debug, Default)]
+pub(crate) struct LinkOptions<'a> {
+    pub(crate) private: bool,
+    pub(crate) forward_query: ForwardQuery,
+    pub(crate) cache_control: Option<&'a str>,
+}
+
+/// The report a new link is filed with for review, it doesn't redirect until then
+#[derive(Debug)]
+pub(crate) struct Quarantine<'a> {
+    pub(crate) category: &'a str,
+    pub(crate) reason: &'a str,
+    pub(crate) reporter: &'a str,
+}
+
+/// Clicks on a link that were just marked as suspicious
+#[derive(Debug, FromRow)]
+pub(crate) struct ClickSpike {
+    pub(crate) url_id: String,
+    pub(crate) clicks: i64,
+}
+
+/// An event waiting in the outbox until the relay delivers it
+#[derive(Debug, FromRow)]
+pub(crate) struct OutboxEntry {
+    pub(crate) id: i64,
+    /// the [`LinkEvent`] as JSON
+    pub(crate) payload: String,
+    /// failed deliveries so far
+    pub(crate) attempts: i32,
+}
+
+/// How the connection pool is doing, the counters cover the time since startup
+#[derive(Debug, Clone, Copy, Serialize)]
+pub(crate) struct PoolStats {
+    /// open connections, at most `max`
+    pub(crate) size: u32,
+    pub(crate) idle: u32,
+    pub(crate) in_use: u32,
+    pub(crate) max: u32,
+    pub(crate) acquires: u64,
+    /// time spent waiting for connections by all acquires together
+    pub(crate) acquire_wait_ms: u64,
+    /// acquires that waited longer than `database.slow_acquire_ms`
+    pub(crate) slow_acquires: u64,
+    pub(crate) acquire_timeouts: u64,
+}
+
+/// A job claimed from the queue
+#[derive(Debug, FromRow)]
+pub(crate) struct QueuedJob {
+    pub(crate) id: i64,
+    /// the [`Task`](crate::jobs::Task) as JSON
+    pub(crate) payload: String,
+    /// failed runs so far
+    pub(crate) attempts: i32,
+}
+
+/// Reported through `POST /:id/report`, with what is known about the link
+#[derive(Debug, Serialize, FromRow)]
+pub(crate) struct AbuseReport {
+    pub(crate) url_id: String,
+    pub(crate) url: String,
+    pub(crate) flagged: bool,
+    pub(crate) category: String,
+    pub(crate) reason: String,
+    pub(crate) reported_at: DateTime<Utc>,
+}
+
+/// An address that asked for decoy slugs, see [`crate::config::HoneypotConfig`]
+#[derive(Debug, Clone, Serialize, FromRow)]
+pub(crate) struct Scanner {
+    pub(crate) ip: String,
+    pub(crate) hits: i64,
+    /// the decoys it tried, in the order it first did
+    pub(crate) slugs: Vec<String>,
+    pub(crate) first_seen: DateTime<Utc>,
+    pub(crate) last_seen: DateTime<Utc>,
+}
+
+/// A bundle with its links in the order they are listed
+#[derive(Debug, FromRow)]
+pub(crate) struct BundleRecord {
+    pub(crate) id: String,
+    pub(crate) title: String,
+    pub(crate) description: Option<String>,
+    #[sqlx(skip)]
+    pub(crate) links: Vec<BundleLinkRecord>,
+}
+
+#[derive(Debug, Clone, FromRow)]
+pub(crate) struct BundleLinkRecord {
+    pub(crate) bundle_id: String,
+    pub(crate) url_id: String,
+    pub(crate) url: String,
+    pub(crate) flagged: bool,
+    pub(crate) private: bool,
+    pub(crate) title: Option<String>,
+}
+
+/// Outcome of [`Store::update_link`]
+#[derive(Debug)]
+pub(crate) enum UpdateLink {
+    Updated,
+    NotFound,
+    /// the URL is shortened already, under this id
+    UrlExists(String),
+}
+
+/// [`Store`] on Postgres
+#[cfg(feature = "postgres")]
+pub(crate) struct PostgresStore {
+    pub(crate) pool: PgPool,
+    /// waiting longer than this for a connection is logged
+    slow_acquire: Duration,
+    acquires: AcquireCounters,
+}
+
+/// How connections were handed out of the pool since startup
+#[derive(Debug, Default)]
+struct AcquireCounters {
+    count: AtomicU64,
+    wait_micros: AtomicU64,
+    slow: AtomicU64,
+    timeouts: AtomicU64,
+}
+
+/// Session advisory lock on a connection of its own, closing it releases the lock
+#[cfg(feature = "postgres")]
+pub(crate) struct PostgresLease {
+    pub(crate) conn: PgConnection,
+}
+
+#[derive(Clone, FromRow)]
+pub(crate) struct UrlRecord {
+    #[sqlx(default)]
+    pub(crate) id: String,
+    #[sqlx(default)]
+    pub(crate) url: String,
+    #[sqlx(default)]
+    pub(crate) flagged: bool,
+    /// takes turns between several destinations, see [`Store::next_rotation`]
+    #[sqlx(default)]
+    pub(crate) rotating: bool,
+    /// stats only for tokens with `stats:read`, see [`crate::Visibility`]
+    #[sqlx(default)]
+    pub(crate) private: bool,
+    #[sqlx(default, try_from = "String")]
+    pub(crate) forward_query: ForwardQuery,
+    #[sqlx(default)]
+    pub(crate) cache_control: Option<String>,
+    #[sqlx(default)]
+    pub(crate) created_at: Option<DateTime<Utc>>,
+    #[sqlx(default)]
+    pub(crate) check_status: Option<i16>,
+    #[sqlx(default)]
+    pub(crate) checked_at: Option<DateTime<Utc>>,
+}
+
+/// Referrers kept per link and day in `daily_stats`
+const ROLLUP_TOP_REFERRERS: i64 = 5;
+
+#[cfg(feature = "postgres")]
+impl PostgresStore {
+    /// Waits for connections longer than `slow_acquire` are logged
+    pub(crate) async fn connect(url: &str, slow_acquire: Duration) -> Result<Self, AppError> {
+        Ok(Self {
+            pool: PgPool::connect(url).await?,
+            slow_acquire,
+            acquires: AcquireCounters::default(),
+        })
+    }
+
+    /// A connection of the pool, see [`Self::acquired`]
+    async fn conn(&self) -> Result<PoolConnection<Postgres>, AppError> {
+        self.acquired(self.pool.acquire()).await
+    }
+
+    /// A transaction on a connection of the pool, see [`Self::acquired`]
+    async fn begin(&self) -> Result<Transaction<'static, Postgres>, AppError> {
+        self.acquired(self.pool.begin()).await
+    }
+
+    /// Waits for `acquire`, counting how long that took for [`PoolStats`]
+    async fn acquired<T>(
+        &self,
+        acquire: impl Future<Output = Result<T, sqlx::Error>>,
+    ) -> Result<T, AppError> {
+        let started = Instant::now();
+        let ret = acquire.await;
+        let waited = started.elapsed();
+        let counters = &self.acquires;
+        counters.count.fetch_add(1, Ordering::Relaxed);
+        counters
+            .wait_micros
+            .fetch_add(waited.as_micros() as u64, Ordering::Relaxed);
+        if let Err(sqlx::Error::PoolTimedOut) = ret {
+            counters.timeouts.fetch_add(1, Ordering::Relaxed);
+        } else if waited >= self.slow_acquire {
+            counters.slow.fetch_add(1, Ordering::Relaxed);
+            warn!(
+                wait_ms = waited.as_millis() as u64,
+                size = self.pool.size(),
+                idle = self.pool.num_idle(),
+                "slow connection acquire, the pool is busy"
+            );
+        }
+        Ok(ret?)
+    }
+
+    /// The links of the bundles `ids` in the order each lists them
+    async fn bundle_links(&self, ids: &[String]) -> Result<Vec<BundleLinkRecord>, AppError> {
+        let links = sqlx::query_as(
+            r#"
+            SELECT b.bundle_id, b.url_id, u.url, u.flagged, u.private, b.title
+            FROM bundle_links b
+            JOIN urls u ON u.id = b.url_id
+            WHERE b.bundle_id = ANY($1)
+            ORDER BY b.bundle_id, b.position;"#,
+        )
+        .bind(ids)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(links)
+    }
+}
+
+/// Writes `events` to the outbox as part of the transaction on `conn`
+#[cfg(feature = "postgres")]
+async fn enqueue_events<'a>(
+    conn: &mut PgConnection,
+    events: impl IntoIterator<Item = &'a LinkEvent>,
+) -> Result<(), AppError> {
+    let payloads = events
+        .into_iter()
+        .map(serde_json::to_string)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(anyhow::Error::from)?;
+    if payloads.is_empty() {
+        return Ok(());
+    }
+    sqlx::query("INSERT INTO outbox(payload) SELECT p::JSONB FROM UNNEST($1::TEXT[]) AS p;")
+        .bind(payloads)
+        .execute(conn)
+        .await?;
+    Ok(())
+}
+
+/// Files `quarantine` against link `id` as part of the transaction on `conn`
+#[cfg(feature = "postgres")]
+async fn file_quarantine(
+    conn: &mut PgConnection,
+    id: &str,
+    quarantine: Option<&Quarantine<'_>>,
+) -> Result<(), AppError> {
+    let Some(quarantine) = quarantine else {
+        return Ok(());
+    };
+    sqlx::query(
+        "INSERT INTO abuse_reports(url_id, category, reason, reporter) VALUES ($1, $2, $3, $4);",
+    )
+    .bind(id)
+    .bind(quarantine.category)
+    .bind(quarantine.reason)
+    .bind(quarantine.reporter)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+/// Adds the links of `bundle` to bundle `id`, numbered in the order they are listed
+#[cfg(feature = "postgres")]
+async fn insert_bundle_links(
+    conn: &mut PgConnection,
+    id: &str,
+    bundle: &BundleRequest,
+) -> Result<(), AppError> {
+    let (ids, titles): (Vec<&str>, Vec<Option<&str>>) = bundle
+        .links
+        .iter()
+        .map(|entry| (entry.id.as_str(), entry.title.as_deref()))
+        .unzip();
+    sqlx::query(
+        r#"
+        INSERT INTO bundle_links(bundle_id, url_id, position, title)
+        SELECT $1, l.url_id, l.position, l.title
+        FROM UNNEST($2::TEXT[], $3::TEXT[]) WITH ORDINALITY AS l(url_id, title, position);"#,
+    )
+    .bind(id)
+    .bind(ids)
+    .bind(titles)
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+#[cfg(feature = "postgres")]
+#[async_trait]
+impl Lease for PostgresLease {
+    async fn is_held(&mut self) -> bool {
+        sqlx::query("SELECT 1;")
+            .execute(&mut self.conn)
+            .await
+            .is_ok()
+    }
+}
+
+#[cfg(feature = "postgres")]
+#[async_trait]
+impl Store for PostgresStore {
+    async fn migrate(&self) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS urls (
+                    id VARCHAR(32) PRIMARY KEY,
+                    url TEXT NOT NULL UNIQUE
+                );"#,
+        )
+        .execute(&self.pool)
+        .await?;
+        // ids used to be fixed length, custom aliases need more room
+        sqlx::query("ALTER TABLE urls ALTER COLUMN id TYPE VARCHAR(32);")
+            .execute(&self.pool)
+            .await?;
+        sqlx::query(
+            "ALTER TABLE urls ADD COLUMN IF NOT EXISTS flagged BOOLEAN NOT NULL DEFAULT FALSE;",
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            "ALTER TABLE urls ADD COLUMN IF NOT EXISTS private BOOLEAN NOT NULL DEFAULT FALSE;",
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            "ALTER TABLE urls ADD COLUMN IF NOT EXISTS forward_query TEXT NOT NULL DEFAULT 'off';",
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query("ALTER TABLE urls ADD COLUMN IF NOT EXISTS cache_control TEXT;")
+            .execute(&self.pool)
+            .await?;
+        // HTTP status of the destination, NULL with a checked_at when it couldn't be reached
+        sqlx::query(
+            "ALTER TABLE urls ADD COLUMN IF NOT EXISTS check_status SMALLINT, ADD COLUMN IF NOT EXISTS checked_at TIMESTAMPTZ;",
+        )
+        .execute(&self.pool)
+        .await?;
+        // links from before this column all get the time of the migration, their ids order them
+        sqlx::query(
+            "ALTER TABLE urls ADD COLUMN IF NOT EXISTS created_at TIMESTAMPTZ NOT NULL DEFAULT NOW();",
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS urls_created_at ON urls(created_at, id);")
+            .execute(&self.pool)
+            .await?;
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS clicks (
+                    id BIGSERIAL PRIMARY KEY,
+                    url_id VARCHAR(32) NOT NULL REFERENCES urls(id) ON DELETE CASCADE,
+                    referrer TEXT,
+                    user_agent TEXT,
+                    ip_hash TEXT NOT NULL,
+                    country CHAR(2),
+                    clicked_at TIMESTAMPTZ NOT NULL
+                );"#,
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query("ALTER TABLE clicks ADD COLUMN IF NOT EXISTS city TEXT;")
+            .execute(&self.pool)
+            .await?;
+        // older clicks are told apart by their address alone
+        sqlx::query("ALTER TABLE clicks ADD COLUMN IF NOT EXISTS visitor_hash TEXT;")
+            .execute(&self.pool)
+            .await?;
+        // and count as people
+        sqlx::query(
+            "ALTER TABLE clicks ADD COLUMN IF NOT EXISTS bot BOOLEAN NOT NULL DEFAULT false;",
+        )
+        .execute(&self.pool)
+        .await?;
+        // set by the click fraud check, rolled up with the bots
+        sqlx::query(
+            "ALTER TABLE clicks ADD COLUMN IF NOT EXISTS suspicious BOOLEAN NOT NULL DEFAULT false;",
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS clicks_url_id_clicked_at ON clicks(url_id, clicked_at);",
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS clicks_clicked_at ON clicks(clicked_at);")
+            .execute(&self.pool)
+            .await?;
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS daily_stats (
+                    url_id VARCHAR(32) NOT NULL REFERENCES urls(id) ON DELETE CASCADE,
+                    day DATE NOT NULL,
+                    clicks BIGINT NOT NULL,
+                    unique_visitors BIGINT NOT NULL,
+                    top_referrers JSONB NOT NULL DEFAULT '[]',
+                    PRIMARY KEY (url_id, day)
+                );"#,
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS hourly_stats (
+                    url_id VARCHAR(32) NOT NULL REFERENCES urls(id) ON DELETE CASCADE,
+                    hour TIMESTAMPTZ NOT NULL,
+                    clicks BIGINT NOT NULL,
+                    PRIMARY KEY (url_id, hour)
+                );"#,
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            "ALTER TABLE hourly_stats ADD COLUMN IF NOT EXISTS unique_visitors BIGINT NOT NULL DEFAULT 0;",
+        )
+        .execute(&self.pool)
+        .await?;
+        // the part of the clicks and visitors that were bots, so they can be left out
+        for table in ["daily_stats", "hourly_stats"] {
+            sqlx::query(&format!(
+                r#"
+                ALTER TABLE {table}
+                    ADD COLUMN IF NOT EXISTS bot_clicks BIGINT NOT NULL DEFAULT 0,
+                    ADD COLUMN IF NOT EXISTS bot_visitors BIGINT NOT NULL DEFAULT 0;"#
+            ))
+            .execute(&self.pool)
+            .await?;
+        }
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS daily_countries (
+                    url_id VARCHAR(32) NOT NULL REFERENCES urls(id) ON DELETE CASCADE,
+                    day DATE NOT NULL,
+                    -- empty for clicks that couldn't be located
+                    country VARCHAR(2) NOT NULL,
+                    clicks BIGINT NOT NULL,
+                    PRIMARY KEY (url_id, day, country)
+                );"#,
+        )
+        .execute(&self.pool)
+        .await?;
+        // the rollup only redoes recent days, the ones before it learned about countries are
+        // filled in once
+        sqlx::query(
+            r#"
+                INSERT INTO daily_countries(url_id, day, country, clicks)
+                SELECT url_id, (clicked_at AT TIME ZONE 'UTC')::DATE, COALESCE(country, ''), count(*)
+                FROM clicks
+                WHERE NOT EXISTS (SELECT 1 FROM daily_countries)
+                GROUP BY 1, 2, 3;"#,
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            "ALTER TABLE daily_countries ADD COLUMN IF NOT EXISTS bot_clicks BIGINT NOT NULL DEFAULT 0;",
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS blocked_domains (
+                    domain TEXT PRIMARY KEY,
+                    reason TEXT
+                );"#,
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS link_tags (
+                    url_id VARCHAR(32) NOT NULL REFERENCES urls(id) ON DELETE CASCADE,
+                    tag VARCHAR(32) NOT NULL,
+                    PRIMARY KEY (url_id, tag)
+                );"#,
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS link_tags_tag ON link_tags(tag);")
+            .execute(&self.pool)
+            .await?;
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS link_aliases (
+                    alias VARCHAR(32) PRIMARY KEY,
+                    url_id VARCHAR(32) NOT NULL REFERENCES urls(id) ON DELETE CASCADE
+                );"#,
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS link_aliases_url_id ON link_aliases(url_id);")
+            .execute(&self.pool)
+            .await?;
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS abuse_reports (
+                    id BIGSERIAL PRIMARY KEY,
+                    url_id VARCHAR(32) NOT NULL REFERENCES urls(id) ON DELETE CASCADE,
+                    reason TEXT NOT NULL,
+                    reported_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+                    resolved_at TIMESTAMPTZ
+                );"#,
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS abuse_reports_open ON abuse_reports(url_id) WHERE resolved_at IS NULL;",
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            "ALTER TABLE abuse_reports ADD COLUMN IF NOT EXISTS category TEXT NOT NULL DEFAULT 'other';",
+        )
+        .execute(&self.pool)
+        .await?;
+        // hashed like the clicks, reports from before it was kept don't count towards disabling
+        sqlx::query("ALTER TABLE abuse_reports ADD COLUMN IF NOT EXISTS reporter TEXT;")
+            .execute(&self.pool)
+            .await?;
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS rotations (
+                    url_id VARCHAR(32) PRIMARY KEY REFERENCES urls(id) ON DELETE CASCADE,
+                    destinations TEXT[] NOT NULL,
+                    turn BIGINT NOT NULL DEFAULT 0
+                );"#,
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS bundles (
+                    id VARCHAR(32) PRIMARY KEY,
+                    title TEXT NOT NULL,
+                    description TEXT,
+                    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+                );"#,
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS bundle_links (
+                    bundle_id VARCHAR(32) NOT NULL REFERENCES bundles(id) ON DELETE CASCADE,
+                    url_id VARCHAR(32) NOT NULL REFERENCES urls(id) ON DELETE CASCADE,
+                    position INT NOT NULL,
+                    title TEXT,
+                    PRIMARY KEY (bundle_id, url_id)
+                );"#,
+        )
+        .execute(&self.pool)
+        .await?;
+        // events are written here with the change they are about, see `relay_events`
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS outbox (
+                    id BIGSERIAL PRIMARY KEY,
+                    payload JSONB NOT NULL,
+                    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+                    attempts INT NOT NULL DEFAULT 0,
+                    deliver_after TIMESTAMPTZ NOT NULL DEFAULT now()
+                );"#,
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS outbox_deliver_after ON outbox(deliver_after, id);",
+        )
+        .execute(&self.pool)
+        .await?;
+        // work for any instance, claimed with SKIP LOCKED. Jobs that gave up keep `failed_at`.
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS jobs (
+                    id BIGSERIAL PRIMARY KEY,
+                    kind TEXT NOT NULL,
+                    payload JSONB NOT NULL,
+                    attempts INT NOT NULL DEFAULT 0,
+                    run_after TIMESTAMPTZ NOT NULL DEFAULT now(),
+                    locked_until TIMESTAMPTZ,
+                    last_error TEXT,
+                    failed_at TIMESTAMPTZ,
+                    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+                );"#,
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS jobs_run_after ON jobs(run_after, id) WHERE failed_at IS NULL;",
+        )
+        .execute(&self.pool)
+        .await?;
+        // ids of deleted links and their aliases, a new link with the same id hides its tombstone
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS tombstones (
+                    id VARCHAR(32) PRIMARY KEY,
+                    reason TEXT,
+                    removed_at TIMESTAMPTZ NOT NULL DEFAULT now()
+                );"#,
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS scanners (
+                    ip VARCHAR(45) PRIMARY KEY,
+                    hits BIGINT NOT NULL DEFAULT 1,
+                    slugs TEXT[] NOT NULL,
+                    first_seen TIMESTAMPTZ NOT NULL DEFAULT now(),
+                    last_seen TIMESTAMPTZ NOT NULL DEFAULT now()
+                );"#,
+        )
+        .execute(&self.pool)
+        .await?;
+        // state shared by every instance, e.g. the maintenance mode
+        sqlx::query(
+            r#"
+                CREATE TABLE IF NOT EXISTS settings (
+                    name TEXT PRIMARY KEY,
+                    value JSONB NOT NULL
+                );"#,
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    fn pool_stats(&self) -> Option<PoolStats> {
+        let size = self.pool.size();
+        let idle = self.pool.num_idle() as u32;
+        let counters = &self.acquires;
+        Some(PoolStats {
+            size,
+            idle,
+            in_use: size.saturating_sub(idle),
+            max: self.pool.options().get_max_connections(),
+            acquires: counters.count.load(Ordering::Relaxed),
+            acquire_wait_ms: counters.wait_micros.load(Ordering::Relaxed) / 1000,
+            slow_acquires: counters.slow.load(Ordering::Relaxed),
+            acquire_timeouts: counters.timeouts.load(Ordering::Relaxed),
+        })
+    }
+
+    #[instrument(name = "db.ping", skip(self))]
+    async fn ping(&self) -> Result<(), AppError> {
+        sqlx::query("SELECT 1;")
+            .execute(&mut *self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(name = "db.link_exists", skip(self))]
+    async fn link_exists(&self, id: &str) -> Result<bool, AppError> {
+        let exists = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM urls WHERE id = $1);")
+            .bind(id)
+            .fetch_one(&mut *self.conn().await?)
+            .await?;
+        Ok(exists)
+    }
+
+    #[instrument(name = "db.id_for_url", skip(self))]
+    async fn id_for_url(&self, url: &str) -> Result<Option<String>, AppError> {
+        let id = sqlx::query_scalar("SELECT id FROM urls WHERE url = $1;")
+            .bind(url)
+            .fetch_optional(&mut *self.conn().await?)
+            .await?;
+        Ok(id)
+    }
+
+    #[instrument(name = "db.timeseries", skip(self))]
+    async fn timeseries(
+        &self,
+        id: &str,
+        interval: Interval,
+        from: NaiveDate,
+        to: NaiveDate,
+        exclude_bots: bool,
+    ) -> Result<Vec<TimeseriesPoint>, AppError> {
+        let query = match interval {
+            Interval::Hour => sqlx::query_as(
+                r#"
+                SELECT b.start,
+                    COALESCE(s.clicks - CASE WHEN $5 THEN s.bot_clicks ELSE 0 END, 0) AS clicks,
+                    COALESCE(s.unique_visitors - CASE WHEN $5 THEN s.bot_visitors ELSE 0 END, 0)
+                        AS unique_visitors
+                FROM generate_series(
+                    $2::DATE::TIMESTAMP AT TIME ZONE 'UTC',
+                    ($3::DATE + 1)::TIMESTAMP AT TIME ZONE 'UTC' - INTERVAL '1 hour',
+                    INTERVAL '1 hour'
+                ) AS b(start)
+                LEFT JOIN hourly_stats s ON s.url_id = $1 AND s.hour = b.start
+                ORDER BY b.start;
+                "#,
+            ),
+            Interval::Day | Interval::Week => sqlx::query_as(
+                r#"
+                SELECT b.start AT TIME ZONE 'UTC' AS start,
+                    COALESCE(sum(s.clicks - CASE WHEN $5 THEN s.bot_clicks ELSE 0 END), 0)::BIGINT
+                        AS clicks,
+                    COALESCE(sum(s.unique_visitors - CASE WHEN $5 THEN s.bot_visitors ELSE 0 END), 0)::BIGINT
+                        AS unique_visitors
+                FROM generate_series(
+                    date_trunc($4, $2::DATE::TIMESTAMP),
+                    $3::DATE::TIMESTAMP,
+                    ('1 ' || $4)::INTERVAL
+                ) AS b(start)
+                LEFT JOIN daily_stats s ON s.url_id = $1
+                    AND s.day BETWEEN $2 AND $3
+                    AND s.day >= b.start::DATE
+                    AND s.day < (b.start + ('1 ' || $4)::INTERVAL)::DATE
+                GROUP BY b.start
+                ORDER BY b.start;
+                "#,
+            ),
+        };
+        let unit = match interval {
+            Interval::Hour => "hour",
+            Interval::Day => "day",
+            Interval::Week => "week",
+        };
+
+        let points = query
+            .bind(id)
+            .bind(from)
+            .bind(to)
+            .bind(unit)
+            .bind(exclude_bots)
+            .fetch_all(&mut *self.conn().await?)
+            .await?;
+        Ok(points)
+    }
+
+    #[instrument(name = "db.referrers", skip(self))]
+    async fn referrers(
+        &self,
+        id: &str,
+        from: NaiveDate,
+        to: NaiveDate,
+        exclude_bots: bool,
+    ) -> Result<Vec<ReferrerClicks>, AppError> {
+        // days rolled up before bots were told apart have no `bot_clicks`
+        let referrers = sqlx::query_as(
+            r#"
+            SELECT r.referrer,
+                sum(r.clicks - CASE WHEN $4 THEN COALESCE(r.bot_clicks, 0) ELSE 0 END)::BIGINT AS clicks
+            FROM daily_stats s,
+                jsonb_to_recordset(s.top_referrers) AS r(referrer TEXT, clicks BIGINT, bot_clicks BIGINT)
+            WHERE s.url_id = $1 AND s.day BETWEEN $2 AND $3
+            GROUP BY r.referrer
+            HAVING sum(r.clicks - CASE WHEN $4 THEN COALESCE(r.bot_clicks, 0) ELSE 0 END) > 0
+            ORDER BY clicks DESC, r.referrer;
+            "#,
+        )
+        .bind(id)
+        .bind(from)
+        .bind(to)
+        .bind(exclude_bots)
+        .fetch_all(&mut *self.conn().await?)
+        .await?;
+        Ok(referrers)
+    }
+
+    #[instrument(name = "db.countries", skip(self))]
+    async fn countries(
+        &self,
+        id: &str,
+        from: NaiveDate,
+        to: NaiveDate,
+        exclude_bots: bool,
+    ) -> Result<Vec<CountryClicks>, AppError> {
+        let countries = sqlx::query_as(
+            r#"
+            SELECT NULLIF(country, '') AS country,
+                sum(clicks - CASE WHEN $4 THEN bot_clicks ELSE 0 END)::BIGINT AS clicks
+            FROM daily_countries
+            WHERE url_id = $1 AND day BETWEEN $2 AND $3
+            GROUP BY country
+            HAVING sum(clicks - CASE WHEN $4 THEN bot_clicks ELSE 0 END) > 0
+            ORDER BY clicks DESC, country;
+            "#,
+        )
+        .bind(id)
+        .bind(from)
+        .bind(to)
+        .bind(exclude_bots)
+        .fetch_all(&mut *self.conn().await?)
+        .await?;
+        Ok(countries)
+    }
+
+    #[instrument(name = "db.mark_click_spikes", skip(self))]
+    async fn mark_click_spikes(
+        &self,
+        since: DateTime<Utc>,
+        min_clicks: i64,
+        min_share: f64,
+        publish: bool,
+    ) -> Result<Vec<ClickSpike>, AppError> {
+        let mut tx = self.begin().await?;
+        let spikes: Vec<ClickSpike> = sqlx::query_as(
+            r#"
+            WITH recent AS (
+                SELECT url_id, ip_hash FROM clicks WHERE clicked_at >= $1
+            ), totals AS (
+                SELECT url_id, count(*) AS clicks FROM recent GROUP BY url_id
+            ), spikes AS (
+                SELECT r.url_id, r.ip_hash
+                FROM recent r
+                JOIN totals t ON t.url_id = r.url_id
+                GROUP BY r.url_id, r.ip_hash, t.clicks
+                HAVING count(*) >= $2 AND count(*) >= $3 * t.clicks
+            ), marked AS (
+                UPDATE clicks c SET suspicious = true
+                FROM spikes s
+                WHERE c.url_id = s.url_id AND c.ip_hash = s.ip_hash
+                    AND c.clicked_at >= $1 AND NOT c.suspicious
+                RETURNING c.url_id
+            )
+            SELECT url_id, count(*) AS clicks FROM marked GROUP BY url_id ORDER BY url_id;
+            "#,
+        )
+        .bind(since)
+        .bind(min_clicks)
+        .bind(min_share)
+        .fetch_all(&mut *tx)
+        .await?;
+        if publish {
+            let detected_at = Utc::now();
+            let events: Vec<LinkEvent> = spikes
+                .iter()
+                .map(|spike| LinkEvent::ClickSpike {
+                    id: spike.url_id.clone(),
+                    suspicious_clicks: spike.clicks,
+                    detected_at,
+                })
+                .collect();
+            enqueue_events(&mut tx, &events).await?;
+        }
+        tx.commit().await?;
+        Ok(spikes)
+    }
+
+    #[instrument(name = "db.pending_events", skip(self))]
+    async fn pending_events(&self, limit: i64) -> Result<Vec<OutboxEntry>, AppError> {
+        let events = sqlx::query_as(
+            r#"
+            SELECT id, payload::TEXT AS payload, attempts FROM outbox
+            WHERE deliver_after <= now()
+            ORDER BY id
+            LIMIT $1;"#,
+        )
+        .bind(limit)
+        .fetch_all(&mut *self.conn().await?)
+        .await?;
+        Ok(events)
+    }
+
+    #[instrument(name = "db.delete_events", skip_all, fields(count = ids.len()))]
+    async fn delete_events(&self, ids: &[i64]) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM outbox WHERE id = ANY($1);")
+            .bind(ids)
+            .execute(&mut *self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(name = "db.retry_event", skip(self))]
+    async fn retry_event(&self, id: i64, at: DateTime<Utc>) -> Result<(), AppError> {
+        sqlx::query("UPDATE outbox SET attempts = attempts + 1, deliver_after = $2 WHERE id = $1;")
+            .bind(id)
+            .bind(at)
+            .execute(&mut *self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(name = "db.enqueue_job", skip(self, payload))]
+    async fn enqueue_job(
+        &self,
+        kind: &str,
+        payload: &str,
+        run_after: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        sqlx::query("INSERT INTO jobs(kind, payload, run_after) VALUES ($1, $2::JSONB, $3);")
+            .bind(kind)
+            .bind(payload)
+            .bind(run_after)
+            .execute(&mut *self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(name = "db.claim_jobs", skip(self))]
+    async fn claim_jobs(
+        &self,
+        limit: i64,
+        locked_until: DateTime<Utc>,
+    ) -> Result<Vec<QueuedJob>, AppError> {
+        let jobs = sqlx::query_as(
+            r#"
+            UPDATE jobs SET locked_until = $2
+            WHERE id IN (
+                SELECT id FROM jobs
+                WHERE failed_at IS NULL AND run_after <= now()
+                    AND (locked_until IS NULL OR locked_until < now())
+                ORDER BY id
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, payload::TEXT AS payload, attempts;"#,
+        )
+        .bind(limit)
+        .bind(locked_until)
+        .fetch_all(&mut *self.conn().await?)
+        .await?;
+        Ok(jobs)
+    }
+
+    #[instrument(name = "db.complete_job", skip(self))]
+    async fn complete_job(&self, id: i64) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM jobs WHERE id = $1;")
+            .bind(id)
+            .execute(&mut *self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(name = "db.fail_job", skip(self))]
+    async fn fail_job(
+        &self,
+        id: i64,
+        error: &str,
+        retry_at: Option<DateTime<Utc>>,
+    ) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            UPDATE jobs SET attempts = attempts + 1, last_error = $2, locked_until = NULL,
+                run_after = COALESCE($3, run_after),
+                failed_at = CASE WHEN $3::TIMESTAMPTZ IS NULL THEN now() END
+            WHERE id = $1;"#,
+        )
+        .bind(id)
+        .bind(error)
+        .bind(retry_at)
+        .execute(&mut *self.conn().await?)
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(name = "db.top_links", skip(self))]
+    async fn top_links(
+        &self,
+        from: NaiveDate,
+        to: NaiveDate,
+        limit: i64,
+        exclude_bots: bool,
+    ) -> Result<Vec<TopLink>, AppError> {
+        let links = sqlx::query_as(
+            r#"
+            SELECT u.id, u.url,
+                sum(s.clicks - CASE WHEN $4 THEN s.bot_clicks ELSE 0 END)::BIGINT AS clicks,
+                sum(s.unique_visitors - CASE WHEN $4 THEN s.bot_visitors ELSE 0 END)::BIGINT
+                    AS unique_visitors
+            FROM daily_stats s
+            JOIN urls u ON u.id = s.url_id
+            WHERE s.day BETWEEN $1 AND $2
+            GROUP BY u.id
+            HAVING sum(s.clicks - CASE WHEN $4 THEN s.bot_clicks ELSE 0 END) > 0
+            ORDER BY clicks DESC, u.id
+            LIMIT $3;
+            "#,
+        )
+        .bind(from)
+        .bind(to)
+        .bind(limit)
+        .bind(exclude_bots)
+        .fetch_all(&mut *self.conn().await?)
+        .await?;
+        Ok(links)
+    }
+
+    #[instrument(name = "db.import_clicks", skip(self))]
+    async fn import_clicks(&self, id: &str, day: NaiveDate, clicks: i64) -> Result<(), AppError> {
+        // visitors aren't known, only how often the link was followed
+        sqlx::query(
+            r#"
+            INSERT INTO daily_stats(url_id, day, clicks, unique_visitors)
+            VALUES ($1, $2, $3, 0)
+            ON CONFLICT (url_id, day) DO UPDATE SET clicks = daily_stats.clicks + EXCLUDED.clicks;
+            "#,
+        )
+        .bind(id)
+        .bind(day)
+        .bind(clicks)
+        .execute(&mut *self.conn().await?)
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(name = "db.click_totals", skip(self))]
+    async fn click_totals(&self, ids: &[String]) -> Result<HashMap<String, i64>, AppError> {
+        let totals: Vec<(String, i64)> = sqlx::query_as(
+            r#"
+            SELECT url_id, sum(clicks)::BIGINT
+            FROM daily_stats
+            WHERE url_id = ANY($1)
+            GROUP BY url_id;
+            "#,
+        )
+        .bind(ids)
+        .fetch_all(&mut *self.conn().await?)
+        .await?;
+        Ok(totals.into_iter().collect())
+    }
+
+    #[instrument(name = "db.daily_stats", skip(self))]
+    async fn daily_stats(&self, day: NaiveDate) -> Result<Vec<DailyStats>, AppError> {
+        let rows = sqlx::query_as(
+            r#"
+            SELECT url_id, day, clicks, unique_visitors, top_referrers::TEXT AS top_referrers
+            FROM daily_stats
+            WHERE day = $1
+            ORDER BY url_id;
+            "#,
+        )
+        .bind(day)
+        .fetch_all(&mut *self.conn().await?)
+        .await?;
+        Ok(rows)
+    }
+
+    async fn close(&self) {
+        self.pool.close().await;
+    }
+
+    #[instrument(name = "db.try_lease", skip(self))]
+    async fn try_lease(&self, name: &str) -> Result<Option<Box<dyn Lease>>, AppError> {
+        // taken out of the pool, a pooled connection would keep the lock after being returned
+        let mut conn = self.pool.acquire().await?.detach();
+        // the two key form keeps clear of integer keys other applications lock
+        let locked: bool =
+            sqlx::query_scalar("SELECT pg_try_advisory_lock(hashtext('shortener'), hashtext($1));")
+                .bind(name)
+                .fetch_one(&mut conn)
+                .await?;
+        Ok(locked.then(|| Box::new(PostgresLease { conn }) as Box<dyn Lease>))
+    }
+
+    #[instrument(name = "db.maintenance", skip(self))]
+    async fn maintenance(&self) -> Result<Option<Maintenance>, AppError> {
+        let value: Option<String> =
+            sqlx::query_scalar("SELECT value::TEXT FROM settings WHERE name = 'maintenance';")
+                .fetch_optional(&mut *self.conn().await?)
+                .await?;
+        let maintenance = value
+            .map(|value| serde_json::from_str(&value))
+            .transpose()
+            .map_err(anyhow::Error::from)?;
+        Ok(maintenance)
+    }
+
+    #[instrument(name = "db.set_maintenance", skip(self))]
+    async fn set_maintenance(&self, maintenance: &Maintenance) -> Result<(), AppError> {
+        let value = serde_json::to_string(maintenance).map_err(anyhow::Error::from)?;
+        sqlx::query(
+            r#"
+            INSERT INTO settings(name, value) VALUES ('maintenance', $1::JSONB)
+            ON CONFLICT(name) DO UPDATE SET value = EXCLUDED.value;"#,
+        )
+        .bind(value)
+        .execute(&mut *self.conn().await?)
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(name = "db.get_link", skip(self))]
+    async fn get_link(&self, id: &str) -> Result<Option<UrlRecord>, AppError> {
+        let record = sqlx::query_as(
+            r#"
+            SELECT id, url, flagged, private, forward_query, cache_control, created_at,
+                check_status, checked_at,
+                EXISTS(SELECT 1 FROM rotations WHERE url_id = urls.id) AS rotating
+            FROM urls
+            WHERE id = COALESCE((SELECT url_id FROM link_aliases WHERE alias = $1), $1);"#,
+        )
+        .bind(id)
+        .fetch_optional(&mut *self.conn().await?)
+        .await?;
+        Ok(record)
+    }
+
+    #[instrument(name = "db.taken_ids", skip(self))]
+    async fn taken_ids(&self, ids: &[String]) -> Result<Vec<String>, AppError> {
+        let taken = sqlx::query_scalar(
+            "SELECT id FROM urls WHERE id = ANY($1) UNION SELECT alias FROM link_aliases WHERE alias = ANY($1);",
+        )
+            .bind(ids)
+            .fetch_all(&mut *self.conn().await?)
+            .await?;
+        Ok(taken)
+    }
+
+    #[instrument(name = "db.insert_or_get_link", skip(self))]
+    async fn insert_or_get_link(
+        &self,
+        id: &str,
+        url: &str,
+        options: &LinkOptions<'_>,
+        created: Option<&LinkEvent>,
+        quarantine: Option<&Quarantine<'_>>,
+    ) -> Result<String, AppError> {
+        let mut tx = self.begin().await?;
+        // the update leaves the flags and options of a link that was there already alone
+        let stored: String = sqlx::query_scalar(
+            r#"
+            INSERT INTO urls(id, url, flagged, private, forward_query, cache_control)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT(url) DO UPDATE SET url=EXCLUDED.url RETURNING id;"#,
+        )
+        .bind(id)
+        .bind(url)
+        .bind(quarantine.is_some())
+        .bind(options.private)
+        .bind(options.forward_query.as_str())
+        .bind(options.cache_control)
+        .fetch_one(&mut *tx)
+        .await?;
+        if stored == id {
+            enqueue_events(&mut tx, created).await?;
+            file_quarantine(&mut tx, id, quarantine).await?;
+        }
+        tx.commit().await?;
+        Ok(stored)
+    }
+
+    #[instrument(name = "db.insert_link", skip(self))]
+    async fn insert_link(
+        &self,
+        id: &str,
+        url: &str,
+        options: &LinkOptions<'_>,
+        created: Option<&LinkEvent>,
+        quarantine: Option<&Quarantine<'_>>,
+    ) -> Result<InsertLink, AppError> {
+        let mut tx = self.begin().await?;
+        let ret = sqlx::query(
+            r#"
+            INSERT INTO urls(id, url, flagged, private, forward_query, cache_control)
+            VALUES ($1, $2, $3, $4, $5, $6);"#,
+        )
+        .bind(id)
+        .bind(url)
+        .bind(quarantine.is_some())
+        .bind(options.private)
+        .bind(options.forward_query.as_str())
+        .bind(options.cache_control)
+        .execute(&mut *tx)
+        .await;
+        match ret {
+            Ok(_) => {
+                enqueue_events(&mut tx, created).await?;
+                file_quarantine(&mut tx, id, quarantine).await?;
+                tx.commit().await?;
+                Ok(InsertLink::Inserted)
+            }
+            Err(sqlx::Error::Database(e)) if e.constraint() == Some("urls_pkey") => {
+                Ok(InsertLink::IdTaken)
+            }
+            Err(sqlx::Error::Database(e)) if e.constraint() == Some("urls_url_key") => {
+                let existing = sqlx::query_scalar("SELECT id FROM urls WHERE url = $1;")
+                    .bind(url)
+                    .fetch_one(&mut *self.conn().await?)
+                    .await?;
+                Ok(InsertLink::UrlExists(existing))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    #[instrument(name = "db.insert_rotating_link", skip(self))]
+    async fn insert_rotating_link(
+        &self,
+        id: &str,
+        url: &str,
+        rotate: &[String],
+        options: &LinkOptions<'_>,
+        created: Option<&LinkEvent>,
+        quarantine: Option<&Quarantine<'_>>,
+    ) -> Result<InsertLink, AppError> {
+        let mut tx = self.begin().await?;
+        let inserted = sqlx::query(
+            r#"
+            INSERT INTO urls(id, url, flagged, private, forward_query, cache_control)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT DO NOTHING;"#,
+        )
+        .bind(id)
+        .bind(url)
+        .bind(quarantine.is_some())
+        .bind(options.private)
+        .bind(options.forward_query.as_str())
+        .bind(options.cache_control)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if inserted == 0 {
+            drop(tx);
+            return match self.id_for_url(url).await? {
+                Some(existing) => Ok(InsertLink::UrlExists(existing)),
+                None => Ok(InsertLink::IdTaken),
+            };
+        }
+        sqlx::query("INSERT INTO rotations(url_id, destinations) VALUES ($1, $2);")
+            .bind(id)
+            .bind(rotate)
+            .execute(&mut *tx)
+            .await?;
+        enqueue_events(&mut tx, created).await?;
+        file_quarantine(&mut tx, id, quarantine).await?;
+        tx.commit().await?;
+        Ok(InsertLink::Inserted)
+    }
+
+    #[instrument(name = "db.next_rotation", skip(self))]
+    async fn next_rotation(&self, id: &str) -> Result<Option<String>, AppError> {
+        // `turn` counts the clicks before this one, turn 0 goes to the link's own URL
+        let url = sqlx::query_scalar(
+            r#"
+            UPDATE rotations r SET turn = r.turn + 1
+            FROM urls u
+            WHERE r.url_id = $1 AND u.id = r.url_id
+            RETURNING (ARRAY[u.url] || r.destinations)[(r.turn - 1) % (cardinality(r.destinations) + 1) + 1];"#,
+        )
+        .bind(id)
+        .fetch_optional(&mut *self.conn().await?)
+        .await?;
+        Ok(url)
+    }
+
+    #[instrument(name = "db.delete_link", skip(self))]
+    async fn delete_link(&self, id: &str, reason: Option<&str>) -> Result<bool, AppError> {
+        let mut tx = self.begin().await?;
+        // before the aliases go with the link
+        sqlx::query(
+            r#"
+            INSERT INTO tombstones(id, reason)
+            SELECT id, $2 FROM urls WHERE id = $1
+            UNION ALL
+            SELECT alias, $2 FROM link_aliases WHERE url_id = $1
+            ON CONFLICT (id) DO UPDATE SET reason = EXCLUDED.reason, removed_at = now();"#,
+        )
+        .bind(id)
+        .bind(reason)
+        .execute(&mut *tx)
+        .await?;
+        let deleted = sqlx::query("DELETE FROM urls WHERE id = $1;")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        tx.commit().await?;
+        Ok(deleted > 0)
+    }
+
+    #[instrument(name = "db.tombstone", skip(self))]
+    async fn tombstone(&self, id: &str) -> Result<Option<Tombstone>, AppError> {
+        let tombstone =
+            sqlx::query_as("SELECT id, reason, removed_at FROM tombstones WHERE id = $1;")
+                .bind(id)
+                .fetch_optional(&mut *self.conn().await?)
+                .await?;
+        Ok(tombstone)
+    }
+
+    async fn import_link(&self, link: &ExportedLink) -> Result<bool, AppError> {
+        let mut tx = self.begin().await?;
+        let ret = sqlx::query(
+            r#"
+            INSERT INTO urls(id, url, flagged, private, forward_query, cache_control, created_at)
+            VALUES ($1, $2, $3, $4, $5, $6, COALESCE($7, NOW()))
+            ON CONFLICT DO NOTHING;"#,
+        )
+        .bind(&link.id)
+        .bind(&link.url)
+        .bind(link.flagged)
+        .bind(link.visibility.is_private())
+        .bind(link.forward_query.as_str())
+        .bind(&link.cache_control)
+        .bind(link.created_at)
+        .execute(&mut *tx)
+        .await?;
+        if ret.rows_affected() == 0 {
+            return Ok(false);
+        }
+        sqlx::query(
+            "INSERT INTO link_tags(url_id, tag) SELECT $1, UNNEST($2::TEXT[]) ON CONFLICT DO NOTHING;",
+        )
+        .bind(&link.id)
+        .bind(&link.tags)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO link_aliases(alias, url_id)
+            SELECT alias, $1 FROM UNNEST($2::TEXT[]) AS alias
+            WHERE NOT EXISTS(SELECT 1 FROM urls WHERE id = alias)
+            ON CONFLICT DO NOTHING;"#,
+        )
+        .bind(&link.id)
+        .bind(&link.aliases)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    fn export_links(&self) -> BoxStream<'_, Result<ExportedLink, AppError>> {
+        Box::pin(
+            sqlx::query_as(
+                r#"
+                SELECT u.id, u.url, u.flagged, u.private, u.forward_query, u.cache_control,
+                    u.created_at,
+                    ARRAY(SELECT tag FROM link_tags WHERE url_id = u.id ORDER BY tag) AS tags,
+                    ARRAY(SELECT alias FROM link_aliases WHERE url_id = u.id ORDER BY alias) AS aliases
+                FROM urls u
+                ORDER BY u.id;"#,
+            )
+            .fetch(&self.pool)
+            .map(|link| link.map_err(AppError::from)),
+        )
+    }
+
+    #[instrument(name = "db.update_link", skip(self))]
+    async fn update_link(&self, id: &str, url: &str) -> Result<UpdateLink, AppError> {
+        let ret = sqlx::query("UPDATE urls SET url = $2 WHERE id = $1;")
+            .bind(id)
+            .bind(url)
+            .execute(&mut *self.conn().await?)
+            .await;
+        match ret {
+            Ok(ret) if ret.rows_affected() == 0 => Ok(UpdateLink::NotFound),
+            Ok(_) => Ok(UpdateLink::Updated),
+            Err(sqlx::Error::Database(e)) if e.is_unique_violation() => {
+                let existing = self.id_for_url(url).await?;
+                // deleted meanwhile, which makes the URL free again
+                Ok(existing.map_or(UpdateLink::NotFound, UpdateLink::UrlExists))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    #[instrument(name = "db.add_tags", skip(self))]
+    async fn add_tags(&self, id: &str, tags: &[String]) -> Result<(), AppError> {
+        sqlx::query(
+            "INSERT INTO link_tags(url_id, tag) SELECT $1, UNNEST($2::TEXT[]) ON CONFLICT DO NOTHING;",
+        )
+        .bind(id)
+        .bind(tags)
+        .execute(&mut *self.conn().await?)
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(name = "db.set_tags", skip(self))]
+    async fn set_tags(&self, id: &str, tags: &[String]) -> Result<(), AppError> {
+        let mut tx = self.begin().await?;
+        sqlx::query("DELETE FROM link_tags WHERE url_id = $1;")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "INSERT INTO link_tags(url_id, tag) SELECT $1, UNNEST($2::TEXT[]) ON CONFLICT DO NOTHING;",
+        )
+        .bind(id)
+        .bind(tags)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    #[instrument(name = "db.link_tags", skip(self))]
+    async fn link_tags(&self, id: &str) -> Result<Vec<String>, AppError> {
+        let tags = sqlx::query_scalar("SELECT tag FROM link_tags WHERE url_id = $1 ORDER BY tag;")
+            .bind(id)
+            .fetch_all(&mut *self.conn().await?)
+            .await?;
+        Ok(tags)
+    }
+
+    #[instrument(name = "db.add_alias", skip(self))]
+    async fn add_alias(&self, id: &str, alias: &str) -> Result<bool, AppError> {
+        let ret = sqlx::query(
+            r#"
+            INSERT INTO link_aliases(alias, url_id)
+            SELECT $2, $1 WHERE NOT EXISTS(SELECT 1 FROM urls WHERE id = $2)
+            ON CONFLICT DO NOTHING;"#,
+        )
+        .bind(id)
+        .bind(alias)
+        .execute(&mut *self.conn().await?)
+        .await?;
+        Ok(ret.rows_affected() > 0)
+    }
+
+    #[instrument(name = "db.remove_alias", skip(self))]
+    async fn remove_alias(&self, id: &str, alias: &str) -> Result<bool, AppError> {
+        let ret = sqlx::query("DELETE FROM link_aliases WHERE alias = $2 AND url_id = $1;")
+            .bind(id)
+            .bind(alias)
+            .execute(&mut *self.conn().await?)
+            .await?;
+        Ok(ret.rows_affected() > 0)
+    }
+
+    #[instrument(name = "db.alias_target", skip(self))]
+    async fn alias_target(&self, alias: &str) -> Result<Option<String>, AppError> {
+        let id = sqlx::query_scalar("SELECT url_id FROM link_aliases WHERE alias = $1;")
+            .bind(alias)
+            .fetch_optional(&mut *self.conn().await?)
+            .await?;
+        Ok(id)
+    }
+
+    async fn link_aliases(&self, id: &str) -> Result<Vec<String>, AppError> {
+        let aliases =
+            sqlx::query_scalar("SELECT alias FROM link_aliases WHERE url_id = $1 ORDER BY alias;")
+                .bind(id)
+                .fetch_all(&mut *self.conn().await?)
+                .await?;
+        Ok(aliases)
+    }
+
+    #[instrument(name = "db.list_links", skip(self))]
+    async fn list_links(&self, query: &ListLinksQuery) -> Result<Vec<ExportedLink>, AppError> {
+        let wants = |field| LinkFields::wants(query.fields.as_ref(), field);
+        // only these constant fragments go into the SQL, all values are bound
+        let (key, cursor_key) = match query.sort {
+            LinkSort::CreatedAt => ("u.created_at", "$4"),
+            LinkSort::Clicks => ("c.clicks", "$5"),
+        };
+        let (direction, past) = match query.order {
+            SortOrder::Asc => ("ASC", ">"),
+            SortOrder::Desc => ("DESC", "<"),
+        };
+        let (created_at, clicks) = match query.after.as_ref().map(|cursor| cursor.key) {
+            Some(CursorKey::CreatedAt(created_at)) => (Some(created_at), None),
+            Some(CursorKey::Clicks(clicks)) => (None, Some(clicks)),
+            None => (None, None),
+        };
+        let links = sqlx::query_as(&format!(
+            r#"
+            SELECT u.id, u.url, u.flagged, u.private, u.forward_query, u.cache_control,
+                u.created_at, u.check_status, u.checked_at, c.clicks,
+                CASE WHEN $8 THEN ARRAY(SELECT tag FROM link_tags WHERE url_id = u.id ORDER BY tag)
+                    ELSE '{{}}' END AS tags,
+                CASE WHEN $9
+                    THEN ARRAY(SELECT alias FROM link_aliases WHERE url_id = u.id ORDER BY alias)
+                    ELSE '{{}}' END AS aliases
+            FROM urls u
+            CROSS JOIN LATERAL (
+                SELECT CASE WHEN $10 THEN COALESCE(sum(clicks), 0)::BIGINT END AS clicks
+                FROM daily_stats WHERE $10 AND url_id = u.id
+            ) c
+            CROSS JOIN LATERAL (
+                SELECT lower(substring(u.url FROM '^[^:]+://(?:[^@/]*@)?([^/:?#]+)')) AS host
+            ) h
+            WHERE ($1::TEXT IS NULL
+                    OR EXISTS (SELECT 1 FROM link_tags WHERE url_id = u.id AND tag = $1))
+                AND ($2::TEXT IS NULL
+                    OR h.host = $2 OR right(h.host, char_length($2) + 1) = '.' || $2)
+                AND ($3::TIMESTAMPTZ IS NULL OR u.created_at > $3)
+                AND ($6::VARCHAR IS NULL OR ({key}, u.id) {past} ({cursor_key}, $6))
+                AND ($11::TEXT IS NULL OR (u.checked_at IS NOT NULL
+                    AND (u.check_status IS NULL OR u.check_status >= 400) = ($11 = 'broken')))
+            ORDER BY {key} {direction}, u.id {direction}
+            LIMIT $7;"#
+        ))
+        .bind(query.tag.as_deref())
+        .bind(query.domain.as_deref())
+        .bind(query.created_after)
+        .bind(created_at)
+        .bind(clicks)
+        .bind(query.after.as_ref().map(|cursor| cursor.id.as_str()))
+        .bind(query.limit)
+        .bind(wants(LinkField::Tags))
+        .bind(wants(LinkField::Aliases))
+        .bind(query.sort == LinkSort::Clicks || wants(LinkField::Clicks))
+        .bind(query.status.map(|status| status.as_str()))
+        .fetch_all(&mut *self.conn().await?)
+        .await?;
+        Ok(links)
+    }
+
+    #[instrument(name = "db.directory_links", skip(self))]
+    async fn directory_links(
+        &self,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<ExportedLink>, AppError> {
+        let links = sqlx::query_as(
+            r#"
+            SELECT u.id, u.url, u.flagged, u.private, u.forward_query, u.cache_control,
+                u.created_at,
+                ARRAY(SELECT tag FROM link_tags WHERE url_id = u.id ORDER BY tag) AS tags,
+                ARRAY(SELECT alias FROM link_aliases WHERE url_id = u.id ORDER BY alias) AS aliases
+            FROM urls u
+            WHERE NOT u.private AND NOT u.flagged AND ($1::VARCHAR IS NULL OR u.id > $1)
+            ORDER BY u.id
+            LIMIT $2;"#,
+        )
+        .bind(after)
+        .bind(limit)
+        .fetch_all(&mut *self.conn().await?)
+        .await?;
+        Ok(links)
+    }
+
+    #[instrument(name = "db.set_private", skip(self))]
+    async fn set_private(&self, id: &str, private: bool) -> Result<(), AppError> {
+        sqlx::query("UPDATE urls SET private = $2 WHERE id = $1;")
+            .bind(id)
+            .bind(private)
+            .execute(&mut *self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(name = "db.set_link_check", skip(self))]
+    async fn set_link_check(&self, id: &str, status: Option<i16>) -> Result<(), AppError> {
+        sqlx::query("UPDATE urls SET check_status = $2, checked_at = now() WHERE id = $1;")
+            .bind(id)
+            .bind(status)
+            .execute(&mut *self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(name = "db.unflagged_links", skip(self))]
+    async fn unflagged_links(&self, after: &str, limit: i64) -> Result<Vec<UrlRecord>, AppError> {
+        let links = sqlx::query_as(
+            "SELECT id, url FROM urls WHERE NOT flagged AND id > $1 ORDER BY id LIMIT $2;",
+        )
+        .bind(after)
+        .bind(limit)
+        .fetch_all(&mut *self.conn().await?)
+        .await?;
+        Ok(links)
+    }
+
+    #[instrument(name = "db.flag_urls", skip_all, fields(count = urls.len()))]
+    async fn flag_urls(&self, urls: &[String]) -> Result<u64, AppError> {
+        let ret = sqlx::query("UPDATE urls SET flagged = TRUE WHERE url = ANY($1);")
+            .bind(urls)
+            .execute(&mut *self.conn().await?)
+            .await?;
+        Ok(ret.rows_affected())
+    }
+
+    #[instrument(name = "db.insert_clicks", skip_all, fields(count = clicks.len()))]
+    async fn insert_clicks(&self, clicks: &[ClickEvent], publish: bool) -> Result<(), AppError> {
+        let mut url_ids = Vec::with_capacity(clicks.len());
+        let mut referrers = Vec::with_capacity(clicks.len());
+        let mut user_agents = Vec::with_capacity(clicks.len());
+        let mut ip_hashes = Vec::with_capacity(clicks.len());
+        let mut visitor_hashes = Vec::with_capacity(clicks.len());
+        let mut bots = Vec::with_capacity(clicks.len());
+        let mut countries = Vec::with_capacity(clicks.len());
+        let mut cities = Vec::with_capacity(clicks.len());
+        let mut clicked_at = Vec::with_capacity(clicks.len());
+        for click in clicks {
+            url_ids.push(click.url_id.as_str());
+            referrers.push(click.referrer.as_deref());
+            user_agents.push(click.user_agent.as_deref());
+            ip_hashes.push(click.ip_hash.as_str());
+            visitor_hashes.push(click.visitor_hash.as_str());
+            bots.push(click.bot);
+            countries.push(click.country.as_deref());
+            cities.push(click.city.as_deref());
+            clicked_at.push(click.clicked_at);
+        }
+
+        let mut tx = self.begin().await?;
+        sqlx::query(
+            r#"
+            INSERT INTO clicks(url_id, referrer, user_agent, ip_hash, visitor_hash, bot, country, city, clicked_at)
+            SELECT * FROM UNNEST($1::VARCHAR[], $2::TEXT[], $3::TEXT[], $4::TEXT[], $5::TEXT[], $6::BOOLEAN[], $7::CHAR(2)[], $8::TEXT[], $9::TIMESTAMPTZ[])
+            "#,
+        )
+        .bind(url_ids)
+        .bind(referrers)
+        .bind(user_agents)
+        .bind(ip_hashes)
+        .bind(visitor_hashes)
+        .bind(bots)
+        .bind(countries)
+        .bind(cities)
+        .bind(clicked_at)
+        .execute(&mut *tx)
+        .await?;
+        if publish {
+            let events: Vec<LinkEvent> = clicks.iter().cloned().map(LinkEvent::Clicked).collect();
+            enqueue_events(&mut tx, &events).await?;
+        }
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    #[instrument(name = "db.last_rollup_day", skip(self))]
+    async fn last_rollup_day(&self) -> Result<Option<NaiveDate>, AppError> {
+        let day: Option<NaiveDate> = sqlx::query_scalar(
+            r#"
+            SELECT COALESCE(
+                (SELECT max(day) FROM daily_stats),
+                (SELECT min(clicked_at AT TIME ZONE 'UTC')::DATE FROM clicks)
+            );"#,
+        )
+        .fetch_one(&mut *self.conn().await?)
+        .await?;
+        Ok(day)
+    }
+
+    #[instrument(name = "db.rollup_clicks", skip(self))]
+    async fn rollup_clicks(&self, from: NaiveDate) -> Result<u64, AppError> {
+        let ret = sqlx::query(
+            r#"
+            WITH raw AS (
+                SELECT url_id, (clicked_at AT TIME ZONE 'UTC')::DATE AS day, referrer,
+                    bot OR suspicious AS bot, COALESCE(visitor_hash, ip_hash) AS visitor
+                FROM clicks
+                WHERE clicked_at >= $1::DATE::TIMESTAMP AT TIME ZONE 'UTC'
+            ), totals AS (
+                SELECT url_id, day, count(*) AS clicks, count(DISTINCT visitor) AS unique_visitors,
+                    count(*) FILTER (WHERE bot) AS bot_clicks,
+                    count(DISTINCT visitor) FILTER (WHERE bot) AS bot_visitors
+                FROM raw
+                GROUP BY url_id, day
+            ), referrers AS (
+                SELECT url_id, day, referrer, count(*) AS clicks,
+                    count(*) FILTER (WHERE bot) AS bot_clicks,
+                    row_number() OVER (PARTITION BY url_id, day ORDER BY count(*) DESC, referrer) AS rank
+                FROM raw
+                WHERE referrer IS NOT NULL
+                GROUP BY url_id, day, referrer
+            )
+            INSERT INTO daily_stats(url_id, day, clicks, unique_visitors, bot_clicks, bot_visitors, top_referrers)
+            SELECT t.url_id, t.day, t.clicks, t.unique_visitors, t.bot_clicks, t.bot_visitors, COALESCE((
+                SELECT jsonb_agg(jsonb_build_object(
+                    'referrer', r.referrer, 'clicks', r.clicks, 'bot_clicks', r.bot_clicks
+                ) ORDER BY r.rank)
+                FROM referrers r
+                WHERE r.url_id = t.url_id AND r.day = t.day AND r.rank <= $2
+            ), '[]')
+            FROM totals t
+            ON CONFLICT (url_id, day) DO UPDATE SET
+                clicks = EXCLUDED.clicks,
+                unique_visitors = EXCLUDED.unique_visitors,
+                bot_clicks = EXCLUDED.bot_clicks,
+                bot_visitors = EXCLUDED.bot_visitors,
+                top_referrers = EXCLUDED.top_referrers;
+            "#,
+        )
+        .bind(from)
+        .bind(ROLLUP_TOP_REFERRERS)
+        .execute(&mut *self.conn().await?)
+        .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO hourly_stats(url_id, hour, clicks, unique_visitors, bot_clicks, bot_visitors)
+            SELECT url_id, date_trunc('hour', clicked_at), count(*),
+                count(DISTINCT COALESCE(visitor_hash, ip_hash)),
+                count(*) FILTER (WHERE bot OR suspicious),
+                count(DISTINCT COALESCE(visitor_hash, ip_hash)) FILTER (WHERE bot OR suspicious)
+            FROM clicks
+            WHERE clicked_at >= $1::DATE::TIMESTAMP AT TIME ZONE 'UTC'
+            GROUP BY 1, 2
+            ON CONFLICT (url_id, hour) DO UPDATE SET
+                clicks = EXCLUDED.clicks,
+                unique_visitors = EXCLUDED.unique_visitors,
+                bot_clicks = EXCLUDED.bot_clicks,
+                bot_visitors = EXCLUDED.bot_visitors;
+            "#,
+        )
+        .bind(from)
+        .execute(&mut *self.conn().await?)
+        .await?;
+        sqlx::query(
+            r#"
+            INSERT INTO daily_countries(url_id, day, country, clicks, bot_clicks)
+            SELECT url_id, (clicked_at AT TIME ZONE 'UTC')::DATE, COALESCE(country, ''), count(*),
+                count(*) FILTER (WHERE bot OR suspicious)
+            FROM clicks
+            WHERE clicked_at >= $1::DATE::TIMESTAMP AT TIME ZONE 'UTC'
+            GROUP BY 1, 2, 3
+            ON CONFLICT (url_id, day, country) DO UPDATE SET
+                clicks = EXCLUDED.clicks,
+                bot_clicks = EXCLUDED.bot_clicks;
+            "#,
+        )
+        .bind(from)
+        .execute(&mut *self.conn().await?)
+        .await?;
+
+        Ok(ret.rows_affected())
+    }
+
+    #[instrument(name = "db.prune_clicks", skip(self))]
+    async fn prune_clicks(&self, before: DateTime<Utc>) -> Result<u64, AppError> {
+        let ret = sqlx::query("DELETE FROM clicks WHERE clicked_at < $1;")
+            .bind(before)
+            .execute(&mut *self.conn().await?)
+            .await?;
+        Ok(ret.rows_affected())
+    }
+
+    #[instrument(name = "db.prune_rollups", skip(self))]
+    async fn prune_rollups(&self, before: NaiveDate) -> Result<u64, AppError> {
+        let mut tx = self.begin().await?;
+        let ret = sqlx::query("DELETE FROM daily_stats WHERE day < $1;")
+            .bind(before)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query(
+            "DELETE FROM hourly_stats WHERE hour < $1::DATE::TIMESTAMP AT TIME ZONE 'UTC';",
+        )
+        .bind(before)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM daily_countries WHERE day < $1;")
+            .bind(before)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(ret.rows_affected())
+    }
+
+    #[instrument(name = "db.list_blocked", skip(self))]
+    async fn list_blocked(&self) -> Result<Vec<BlockedDomain>, AppError> {
+        let domains = sqlx::query_as("SELECT domain, reason FROM blocked_domains ORDER BY domain;")
+            .fetch_all(&mut *self.conn().await?)
+            .await?;
+        Ok(domains)
+    }
+
+    #[instrument(name = "db.block_domain", skip(self))]
+    async fn block_domain(
+        &self,
+        domain: &str,
+        reason: Option<&str>,
+    ) -> Result<BlockedDomain, AppError> {
+        let blocked = sqlx::query_as(
+            "INSERT INTO blocked_domains(domain, reason) VALUES ($1, $2) ON CONFLICT(domain) DO UPDATE SET reason=EXCLUDED.reason RETURNING domain, reason;",
+        )
+        .bind(domain)
+        .bind(reason)
+        .fetch_one(&mut *self.conn().await?)
+        .await?;
+        Ok(blocked)
+    }
+
+    #[instrument(name = "db.unblock_domain", skip(self))]
+    async fn unblock_domain(&self, domain: &str) -> Result<(), AppError> {
+        sqlx::query("DELETE FROM blocked_domains WHERE domain = $1;")
+            .bind(domain)
+            .execute(&mut *self.conn().await?)
+            .await?;
+        Ok(())
+    }
+
+    #[instrument(name = "db.set_flagged", skip(self))]
+    async fn set_flagged(&self, id: &str, flagged: bool) -> Result<bool, AppError> {
+        let updated = sqlx::query("UPDATE urls SET flagged = $2 WHERE id = $1;")
+            .bind(id)
+            .bind(flagged)
+            .execute(&mut *self.conn().await?)
+            .await?
+            .rows_affected();
+        Ok(updated > 0)
+    }
+
+    #[instrument(name = "db.report_link", skip(self, reason))]
+    async fn report_link(
+        &self,
+        id: &str,
+        category: &str,
+        reason: &str,
+        reporter: &str,
+    ) -> Result<Option<u64>, AppError> {
+        let inserted = sqlx::query(
+            r#"
+            INSERT INTO abuse_reports(url_id, category, reason, reporter)
+            SELECT id, $2, $3, $4 FROM urls WHERE id = $1;"#,
+        )
+        .bind(id)
+        .bind(category)
+        .bind(reason)
+        .bind(reporter)
+        .execute(&mut *self.conn().await?)
+        .await?
+        .rows_affected();
+        if inserted == 0 {
+            return Ok(None);
+        }
+        let reporters: i64 = sqlx::query_scalar(
+            "SELECT COUNT(DISTINCT reporter) FROM abuse_reports WHERE url_id = $1 AND resolved_at IS NULL;",
+        )
+        .bind(id)
+        .fetch_one(&mut *self.conn().await?)
+        .await?;
+        Ok(Some(reporters as u64))
+    }
+
+    #[instrument(name = "db.open_reports", skip(self))]
+    async fn open_reports(&self, limit: i64) -> Result<Vec<AbuseReport>, AppError> {
+        let reports = sqlx::query_as(
+            r#"
+            SELECT r.url_id, u.url, u.flagged, r.category, r.reason, r.reported_at
+            FROM abuse_reports r
+            JOIN urls u ON u.id = r.url_id
+            WHERE r.resolved_at IS NULL
+            ORDER BY r.reported_at, r.id
+            LIMIT $1;
+            "#,
+        )
+        .bind(limit)
+        .fetch_all(&mut *self.conn().await?)
+        .await?;
+        Ok(reports)
+    }
+
+    #[instrument(name = "db.resolve_reports", skip(self))]
+    async fn resolve_reports(&self, id: &str) -> Result<u64, AppError> {
+        let resolved = sqlx::query(
+            "UPDATE abuse_reports SET resolved_at = NOW() WHERE url_id = $1 AND resolved_at IS NULL;",
+        )
+        .bind(id)
+        .execute(&mut *self.conn().await?)
+        .await?
+        .rows_affected();
+        Ok(resolved)
+    }
+
+    #[instrument(name = "db.record_scanner", skip(self))]
+    async fn record_scanner(&self, ip: &str, slug: &str) -> Result<(), AppError> {
+        sqlx::query(
+            r#"
+            INSERT INTO scanners(ip, slugs) VALUES ($1, ARRAY[$2])
+            ON CONFLICT(ip) DO UPDATE SET
+                hits = scanners.hits + 1,
+                slugs = CASE WHEN $2 = ANY(scanners.slugs) THEN scanners.slugs
+                    ELSE array_append(scanners.slugs, $2) END,
+                last_seen = now();"#,
+        )
+        .bind(ip)
+        .bind(slug)
+        .execute(&mut *self.conn().await?)
+        .await?;
+        Ok(())
+    }
+
+    #[instrument(name = "db.list_scanners", skip(self))]
+    async fn list_scanners(&self, limit: i64) -> Result<Vec<Scanner>, AppError> {
+        let scanners = sqlx::query_as(
+            "SELECT ip, hits, slugs, first_seen, last_seen FROM scanners ORDER BY last_seen DESC LIMIT $1;",
+        )
+        .bind(limit)
+        .fetch_all(&mut *self.conn().await?)
+        .await?;
+        Ok(scanners)
+    }
+
+    #[instrument(name = "db.forget_scanner", skip(self))]
+    async fn forget_scanner(&self, ip: &str) -> Result<bool, AppError> {
+        let deleted = sqlx::query("DELETE FROM scanners WHERE ip = $1;")
+            .bind(ip)
+            .execute(&mut *self.conn().await?)
+            .await?
+            .rows_affected();
+        Ok(deleted > 0)
+    }
+
+    #[instrument(name = "db.insert_bundle", skip(self, bundle))]
+    async fn insert_bundle(&self, id: &str, bundle: &BundleRequest) -> Result<bool, AppError> {
+        let mut tx = self.begin().await?;
+        let inserted = sqlx::query(
+            "INSERT INTO bundles(id, title, description) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING;",
+        )
+        .bind(id)
+        .bind(&bundle.title)
+        .bind(&bundle.description)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if inserted == 0 {
+            return Ok(false);
+        }
+        insert_bundle_links(&mut tx, id, bundle).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    #[instrument(name = "db.update_bundle", skip(self, bundle))]
+    async fn update_bundle(&self, id: &str, bundle: &BundleRequest) -> Result<bool, AppError> {
+        let mut tx = self.begin().await?;
+        let updated = sqlx::query("UPDATE bundles SET title = $2, description = $3 WHERE id = $1;")
+            .bind(id)
+            .bind(&bundle.title)
+            .bind(&bundle.description)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        if updated == 0 {
+            return Ok(false);
+        }
+        sqlx::query("DELETE FROM bundle_links WHERE bundle_id = $1;")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        insert_bundle_links(&mut tx, id, bundle).await?;
+        tx.commit().await?;
+        Ok(true)
+    }
+
+    #[instrument(name = "db.get_bundle", skip(self))]
+    async fn get_bundle(&self, id: &str) -> Result<Option<BundleRecord>, AppError> {
+        let bundle: Option<BundleRecord> =
+            sqlx::query_as("SELECT id, title, description FROM bundles WHERE id = $1;")
+                .bind(id)
+                .fetch_optional(&mut *self.conn().await?)
+                .await?;
+        let Some(mut bundle) = bundle else {
+            return Ok(None);
+        };
+        bundle.links = self.bundle_links(&[bundle.id.clone()]).await?;
+        Ok(Some(bundle))
+    }
+
+    #[instrument(name = "db.list_bundles", skip(self))]
+    async fn list_bundles(&self) -> Result<Vec<BundleRecord>, AppError> {
+        let mut bundles: Vec<BundleRecord> =
+            sqlx::query_as("SELECT id, title, description FROM bundles ORDER BY id;")
+                .fetch_all(&mut *self.conn().await?)
+                .await?;
+        let ids: Vec<String> = bundles.iter().map(|b| b.id.clone()).collect();
+        let links = self.bundle_links(&ids).await?;
+        for bundle in &mut bundles {
+            bundle.links = links
+                .iter()
+                .filter(|link| link.bundle_id == bundle.id)
+                .cloned()
+                .collect();
+        }
+        Ok(bundles)
+    }
+
+    #[instrument(name = "db.delete_bundle", skip(self))]
+    async fn delete_bundle(&self, id: &str) -> Result<bool, AppError> {
+        let deleted = sqlx::query("DELETE FROM bundles WHERE id = $1;")
+            .bind(id)
+            .execute(&mut *self.conn().await?)
+            .await?
+            .rows_affected();
+        Ok(deleted > 0)
+    }
+
+    #[instrument(name = "db.seed_blocked", skip(self))]
+    async fn seed_blocked(&self, domain: &str) -> Result<(), AppError> {
+        sqlx::query("INSERT INTO blocked_domains(domain) VALUES ($1) ON CONFLICT DO NOTHING;")
+            .bind(domain)
+            .execute(&mut *self.conn().await?)
+            .await?;
+        Ok(())
+    }
+}
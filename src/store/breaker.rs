@@ -0,0 +1,634 @@
+//! [`Store`] that stops calling the database while it is failing, so requests fail fast
+//! instead of queueing up for a pool that can't serve them
+
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+use super::{
+    AbuseReport, BlockedDomain, BoxStream, BundleRecord, ClickSpike, InsertLink, Lease,
+    LinkOptions, OutboxEntry, PoolStats, Quarantine, QueuedJob, Scanner, Store, Tombstone,
+    UpdateLink, UrlRecord,
+};
+use crate::api::{
+    BundleRequest, CountryClicks, DailyStats, ExportedLink, Interval, ListLinksQuery, Maintenance,
+    ReferrerClicks, TimeseriesPoint, TopLink,
+};
+use crate::clicks::ClickEvent;
+use crate::error::AppError;
+use crate::events::LinkEvent;
+
+/// Opens after `failures` outages in a row. Calls fail right away with
+/// [`AppError::DatabaseUnavailable`] while it's open, after `cooldown` a single call is let
+/// through to see whether the database is back.
+pub(crate) struct BreakerStore {
+    inner: Arc<dyn Store>,
+    failures: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+    /// the last lookup of every recently redirected id, served while the breaker is open
+    known_links: Mutex<HashMap<String, UrlRecord>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BreakerState {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// one call is finding out whether the database is back
+    HalfOpen {
+        since: Instant,
+    },
+}
+
+/// Links [`BreakerStore`] remembers for redirects while the breaker is open
+const KNOWN_LINKS: usize = 10_000;
+
+impl BreakerStore {
+    pub(crate) fn new(inner: Arc<dyn Store>, failures: u32, cooldown: Duration) -> Self {
+        Self {
+            inner,
+            failures,
+            cooldown,
+            state: Mutex::new(BreakerState::Closed { failures: 0 }),
+            known_links: Mutex::default(),
+        }
+    }
+
+    async fn call<T>(
+        &self,
+        call: impl Future<Output = Result<T, AppError>>,
+    ) -> Result<T, AppError> {
+        self.admit()?;
+        let ret = call.await;
+        self.record(ret.as_ref().err().is_some_and(is_outage));
+        ret
+    }
+
+    /// Whether a call may go to the database now
+    fn admit(&self) -> Result<(), AppError> {
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            BreakerState::Closed { .. } => Ok(()),
+            BreakerState::Open { until } if now < until => {
+                Err(AppError::DatabaseUnavailable(until - now))
+            }
+            // a probe whose caller gave up never reports back, so another one may go
+            BreakerState::HalfOpen { since } if now - since < self.cooldown => {
+                Err(AppError::DatabaseUnavailable(self.cooldown - (now - since)))
+            }
+            BreakerState::Open { .. } | BreakerState::HalfOpen { .. } => {
+                *state = BreakerState::HalfOpen { since: now };
+                Ok(())
+            }
+        }
+    }
+
+    fn record(&self, outage: bool) {
+        let mut state = self.state.lock().unwrap();
+        *state = match (*state, outage) {
+            (BreakerState::Closed { .. }, false) => BreakerState::Closed { failures: 0 },
+            (BreakerState::Closed { failures }, true) if failures + 1 < self.failures => {
+                BreakerState::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (BreakerState::Closed { .. } | BreakerState::HalfOpen { .. }, true) => {
+                warn!(
+                    "The database is failing, not calling it for {:?}",
+                    self.cooldown
+                );
+                BreakerState::Open {
+                    until: Instant::now() + self.cooldown,
+                }
+            }
+            (BreakerState::HalfOpen { .. }, false) => {
+                info!("The database is back");
+                BreakerState::Closed { failures: 0 }
+            }
+            // a call that started before the breaker opened
+            (open @ BreakerState::Open { .. }, _) => open,
+        };
+    }
+
+    fn forget(&self, id: &str) {
+        self.known_links.lock().unwrap().remove(id);
+    }
+
+    /// Forgets the links to any of `urls`
+    fn forget_urls(&self, urls: &[String]) {
+        self.known_links
+            .lock()
+            .unwrap()
+            .retain(|_, link| !urls.contains(&link.url));
+    }
+}
+
+/// Errors that say the database is down or overloaded, rather than that a query was refused
+fn is_outage(err: &AppError) -> bool {
+    match err {
+        AppError::Db(sqlx::Error::Database(e)) => e.kind() == sqlx::error::ErrorKind::Other,
+        AppError::Db(
+            sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::Protocol(_)
+            | sqlx::Error::WorkerCrashed,
+        ) => true,
+        _ => false,
+    }
+}
+
+#[async_trait]
+impl Store for BreakerStore {
+    async fn migrate(&self) -> Result<(), AppError> {
+        self.inner.migrate().await
+    }
+
+    fn pool_stats(&self) -> Option<PoolStats> {
+        self.inner.pool_stats()
+    }
+
+    async fn ping(&self) -> Result<(), AppError> {
+        self.call(self.inner.ping()).await
+    }
+
+    async fn close(&self) {
+        self.inner.close().await
+    }
+
+    async fn get_link(&self, id: &str) -> Result<Option<UrlRecord>, AppError> {
+        match self.call(self.inner.get_link(id)).await {
+            Ok(link) => {
+                let mut known = self.known_links.lock().unwrap();
+                match &link {
+                    Some(link) => {
+                        if known.len() >= KNOWN_LINKS && !known.contains_key(id) {
+                            // any one will do, they're only a fallback
+                            let evicted = known.keys().next().cloned();
+                            known.remove(&evicted.unwrap_or_default());
+                        }
+                        known.insert(id.to_owned(), link.clone());
+                    }
+                    None => {
+                        known.remove(id);
+                    }
+                }
+                Ok(link)
+            }
+            Err(e @ AppError::DatabaseUnavailable(_)) => {
+                match self.known_links.lock().unwrap().get(id) {
+                    Some(link) => Ok(Some(link.clone())),
+                    None => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn link_exists(&self, id: &str) -> Result<bool, AppError> {
+        self.call(self.inner.link_exists(id)).await
+    }
+
+    async fn id_for_url(&self, url: &str) -> Result<Option<String>, AppError> {
+        self.call(self.inner.id_for_url(url)).await
+    }
+
+    async fn taken_ids(&self, ids: &[String]) -> Result<Vec<String>, AppError> {
+        self.call(self.inner.taken_ids(ids)).await
+    }
+
+    async fn insert_or_get_link(
+        &self,
+        id: &str,
+        url: &str,
+        options: &LinkOptions<'_>,
+        created: Option<&LinkEvent>,
+        quarantine: Option<&Quarantine<'_>>,
+    ) -> Result<String, AppError> {
+        self.call(
+            self.inner
+                .insert_or_get_link(id, url, options, created, quarantine),
+        )
+        .await
+    }
+
+    async fn insert_link(
+        &self,
+        id: &str,
+        url: &str,
+        options: &LinkOptions<'_>,
+        created: Option<&LinkEvent>,
+        quarantine: Option<&Quarantine<'_>>,
+    ) -> Result<InsertLink, AppError> {
+        self.call(
+            self.inner
+                .insert_link(id, url, options, created, quarantine),
+        )
+        .await
+    }
+
+    async fn insert_rotating_link(
+        &self,
+        id: &str,
+        url: &str,
+        rotate: &[String],
+        options: &LinkOptions<'_>,
+        created: Option<&LinkEvent>,
+        quarantine: Option<&Quarantine<'_>>,
+    ) -> Result<InsertLink, AppError> {
+        self.call(
+            self.inner
+                .insert_rotating_link(id, url, rotate, options, created, quarantine),
+        )
+        .await
+    }
+
+    async fn next_rotation(&self, id: &str) -> Result<Option<String>, AppError> {
+        self.call(self.inner.next_rotation(id)).await
+    }
+
+    async fn update_link(&self, id: &str, url: &str) -> Result<UpdateLink, AppError> {
+        self.forget(id);
+        self.call(self.inner.update_link(id, url)).await
+    }
+
+    async fn add_tags(&self, id: &str, tags: &[String]) -> Result<(), AppError> {
+        self.call(self.inner.add_tags(id, tags)).await
+    }
+
+    async fn set_tags(&self, id: &str, tags: &[String]) -> Result<(), AppError> {
+        self.call(self.inner.set_tags(id, tags)).await
+    }
+
+    async fn link_tags(&self, id: &str) -> Result<Vec<String>, AppError> {
+        self.call(self.inner.link_tags(id)).await
+    }
+
+    async fn add_alias(&self, id: &str, alias: &str) -> Result<bool, AppError> {
+        self.call(self.inner.add_alias(id, alias)).await
+    }
+
+    async fn remove_alias(&self, id: &str, alias: &str) -> Result<bool, AppError> {
+        self.forget(alias);
+        self.call(self.inner.remove_alias(id, alias)).await
+    }
+
+    async fn alias_target(&self, alias: &str) -> Result<Option<String>, AppError> {
+        self.call(self.inner.alias_target(alias)).await
+    }
+
+    async fn link_aliases(&self, id: &str) -> Result<Vec<String>, AppError> {
+        self.call(self.inner.link_aliases(id)).await
+    }
+
+    async fn list_links(&self, query: &ListLinksQuery) -> Result<Vec<ExportedLink>, AppError> {
+        self.call(self.inner.list_links(query)).await
+    }
+
+    async fn directory_links(
+        &self,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<ExportedLink>, AppError> {
+        self.call(self.inner.directory_links(after, limit)).await
+    }
+
+    async fn set_private(&self, id: &str, private: bool) -> Result<(), AppError> {
+        self.call(self.inner.set_private(id, private)).await
+    }
+
+    async fn set_link_check(&self, id: &str, status: Option<i16>) -> Result<(), AppError> {
+        self.forget(id);
+        self.call(self.inner.set_link_check(id, status)).await
+    }
+
+    async fn delete_link(&self, id: &str, reason: Option<&str>) -> Result<bool, AppError> {
+        self.forget(id);
+        self.call(self.inner.delete_link(id, reason)).await
+    }
+
+    async fn tombstone(&self, id: &str) -> Result<Option<Tombstone>, AppError> {
+        self.call(self.inner.tombstone(id)).await
+    }
+
+    async fn import_link(&self, link: &ExportedLink) -> Result<bool, AppError> {
+        self.call(self.inner.import_link(link)).await
+    }
+
+    fn export_links(&self) -> BoxStream<'_, Result<ExportedLink, AppError>> {
+        self.inner.export_links()
+    }
+
+    async fn unflagged_links(&self, after: &str, limit: i64) -> Result<Vec<UrlRecord>, AppError> {
+        self.call(self.inner.unflagged_links(after, limit)).await
+    }
+
+    async fn flag_urls(&self, urls: &[String]) -> Result<u64, AppError> {
+        self.forget_urls(urls);
+        self.call(self.inner.flag_urls(urls)).await
+    }
+
+    async fn insert_clicks(&self, clicks: &[ClickEvent], publish: bool) -> Result<(), AppError> {
+        self.call(self.inner.insert_clicks(clicks, publish)).await
+    }
+
+    async fn last_rollup_day(&self) -> Result<Option<NaiveDate>, AppError> {
+        self.call(self.inner.last_rollup_day()).await
+    }
+
+    async fn rollup_clicks(&self, from: NaiveDate) -> Result<u64, AppError> {
+        self.call(self.inner.rollup_clicks(from)).await
+    }
+
+    async fn prune_clicks(&self, before: DateTime<Utc>) -> Result<u64, AppError> {
+        self.call(self.inner.prune_clicks(before)).await
+    }
+
+    async fn prune_rollups(&self, before: NaiveDate) -> Result<u64, AppError> {
+        self.call(self.inner.prune_rollups(before)).await
+    }
+
+    async fn import_clicks(&self, id: &str, day: NaiveDate, clicks: i64) -> Result<(), AppError> {
+        self.call(self.inner.import_clicks(id, day, clicks)).await
+    }
+
+    async fn click_totals(&self, ids: &[String]) -> Result<HashMap<String, i64>, AppError> {
+        self.call(self.inner.click_totals(ids)).await
+    }
+
+    async fn daily_stats(&self, day: NaiveDate) -> Result<Vec<DailyStats>, AppError> {
+        self.call(self.inner.daily_stats(day)).await
+    }
+
+    async fn timeseries(
+        &self,
+        id: &str,
+        interval: Interval,
+        from: NaiveDate,
+        to: NaiveDate,
+        exclude_bots: bool,
+    ) -> Result<Vec<TimeseriesPoint>, AppError> {
+        self.call(self.inner.timeseries(id, interval, from, to, exclude_bots))
+            .await
+    }
+
+    async fn referrers(
+        &self,
+        id: &str,
+        from: NaiveDate,
+        to: NaiveDate,
+        exclude_bots: bool,
+    ) -> Result<Vec<ReferrerClicks>, AppError> {
+        self.call(self.inner.referrers(id, from, to, exclude_bots))
+            .await
+    }
+
+    async fn countries(
+        &self,
+        id: &str,
+        from: NaiveDate,
+        to: NaiveDate,
+        exclude_bots: bool,
+    ) -> Result<Vec<CountryClicks>, AppError> {
+        self.call(self.inner.countries(id, from, to, exclude_bots))
+            .await
+    }
+
+    async fn mark_click_spikes(
+        &self,
+        since: DateTime<Utc>,
+        min_clicks: i64,
+        min_share: f64,
+        publish: bool,
+    ) -> Result<Vec<ClickSpike>, AppError> {
+        self.call(
+            self.inner
+                .mark_click_spikes(since, min_clicks, min_share, publish),
+        )
+        .await
+    }
+
+    async fn pending_events(&self, limit: i64) -> Result<Vec<OutboxEntry>, AppError> {
+        self.call(self.inner.pending_events(limit)).await
+    }
+
+    async fn delete_events(&self, ids: &[i64]) -> Result<(), AppError> {
+        self.call(self.inner.delete_events(ids)).await
+    }
+
+    async fn retry_event(&self, id: i64, at: DateTime<Utc>) -> Result<(), AppError> {
+        self.call(self.inner.retry_event(id, at)).await
+    }
+
+    async fn enqueue_job(
+        &self,
+        kind: &str,
+        payload: &str,
+        run_after: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        self.call(self.inner.enqueue_job(kind, payload, run_after))
+            .await
+    }
+
+    async fn claim_jobs(
+        &self,
+        limit: i64,
+        locked_until: DateTime<Utc>,
+    ) -> Result<Vec<QueuedJob>, AppError> {
+        self.call(self.inner.claim_jobs(limit, locked_until)).await
+    }
+
+    async fn complete_job(&self, id: i64) -> Result<(), AppError> {
+        self.call(self.inner.complete_job(id)).await
+    }
+
+    async fn fail_job(
+        &self,
+        id: i64,
+        error: &str,
+        retry_at: Option<DateTime<Utc>>,
+    ) -> Result<(), AppError> {
+        self.call(self.inner.fail_job(id, error, retry_at)).await
+    }
+
+    async fn top_links(
+        &self,
+        from: NaiveDate,
+        to: NaiveDate,
+        limit: i64,
+        exclude_bots: bool,
+    ) -> Result<Vec<TopLink>, AppError> {
+        self.call(self.inner.top_links(from, to, limit, exclude_bots))
+            .await
+    }
+
+    async fn list_blocked(&self) -> Result<Vec<BlockedDomain>, AppError> {
+        self.call(self.inner.list_blocked()).await
+    }
+
+    async fn block_domain(
+        &self,
+        domain: &str,
+        reason: Option<&str>,
+    ) -> Result<BlockedDomain, AppError> {
+        self.call(self.inner.block_domain(domain, reason)).await
+    }
+
+    async fn seed_blocked(&self, domain: &str) -> Result<(), AppError> {
+        self.call(self.inner.seed_blocked(domain)).await
+    }
+
+    async fn unblock_domain(&self, domain: &str) -> Result<(), AppError> {
+        self.call(self.inner.unblock_domain(domain)).await
+    }
+
+    async fn set_flagged(&self, id: &str, flagged: bool) -> Result<bool, AppError> {
+        self.forget(id);
+        self.call(self.inner.set_flagged(id, flagged)).await
+    }
+
+    async fn report_link(
+        &self,
+        id: &str,
+        category: &str,
+        reason: &str,
+        reporter: &str,
+    ) -> Result<Option<u64>, AppError> {
+        // enough reports disable the link
+        self.forget(id);
+        self.call(self.inner.report_link(id, category, reason, reporter))
+            .await
+    }
+
+    async fn open_reports(&self, limit: i64) -> Result<Vec<AbuseReport>, AppError> {
+        self.call(self.inner.open_reports(limit)).await
+    }
+
+    async fn resolve_reports(&self, id: &str) -> Result<u64, AppError> {
+        self.call(self.inner.resolve_reports(id)).await
+    }
+
+    async fn record_scanner(&self, ip: &str, slug: &str) -> Result<(), AppError> {
+        self.call(self.inner.record_scanner(ip, slug)).await
+    }
+
+    async fn list_scanners(&self, limit: i64) -> Result<Vec<Scanner>, AppError> {
+        self.call(self.inner.list_scanners(limit)).await
+    }
+
+    async fn forget_scanner(&self, ip: &str) -> Result<bool, AppError> {
+        self.call(self.inner.forget_scanner(ip)).await
+    }
+
+    async fn insert_bundle(&self, id: &str, bundle: &BundleRequest) -> Result<bool, AppError> {
+        self.call(self.inner.insert_bundle(id, bundle)).await
+    }
+
+    async fn update_bundle(&self, id: &str, bundle: &BundleRequest) -> Result<bool, AppError> {
+        self.call(self.inner.update_bundle(id, bundle)).await
+    }
+
+    async fn get_bundle(&self, id: &str) -> Result<Option<BundleRecord>, AppError> {
+        self.call(self.inner.get_bundle(id)).await
+    }
+
+    async fn list_bundles(&self) -> Result<Vec<BundleRecord>, AppError> {
+        self.call(self.inner.list_bundles()).await
+    }
+
+    async fn delete_bundle(&self, id: &str) -> Result<bool, AppError> {
+        self.call(self.inner.delete_bundle(id)).await
+    }
+
+    async fn try_lease(&self, name: &str) -> Result<Option<Box<dyn Lease>>, AppError> {
+        self.call(self.inner.try_lease(name)).await
+    }
+
+    async fn maintenance(&self) -> Result<Option<Maintenance>, AppError> {
+        self.call(self.inner.maintenance()).await
+    }
+
+    async fn set_maintenance(&self, maintenance: &Maintenance) -> Result<(), AppError> {
+        self.call(self.inner.set_maintenance(maintenance)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::mock::MockStore;
+
+    #[tokio::test(start_paused = true)]
+    async fn an_open_breaker_fails_fast_and_serves_known_links() {
+        let mock = Arc::new(MockStore::with_links(&[
+            ("docs", "https://example.com/docs"),
+            ("blog", "https://blog.example.com/"),
+        ]));
+        let store = BreakerStore::new(mock.clone(), 2, Duration::from_secs(10));
+        assert!(store.get_link("docs").await.unwrap().is_some());
+
+        mock.set_down(true);
+        for _ in 0..2 {
+            assert!(matches!(store.get_link("blog").await, Err(AppError::Db(_))));
+        }
+        // open now, the database isn't asked
+        mock.set_down(false);
+        assert_eq!(
+            store.get_link("docs").await.unwrap().unwrap().url,
+            "https://example.com/docs"
+        );
+        assert!(matches!(
+            store.get_link("blog").await,
+            Err(AppError::DatabaseUnavailable(_))
+        ));
+
+        tokio::time::advance(Duration::from_secs(10)).await;
+        assert!(store.get_link("blog").await.unwrap().is_some());
+        assert!(store.link_exists("docs").await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flagged_links_are_not_served_by_an_open_breaker() {
+        let mock = Arc::new(MockStore::with_links(&[
+            ("docs", "https://example.com/docs"),
+            ("blog", "https://blog.example.com/"),
+        ]));
+        let store = BreakerStore::new(mock.clone(), 1, Duration::from_secs(10));
+        assert!(store.get_link("docs").await.unwrap().is_some());
+        assert!(store.get_link("blog").await.unwrap().is_some());
+        store
+            .flag_urls(&["https://example.com/docs".to_owned()])
+            .await
+            .unwrap();
+        store
+            .report_link("blog", "spam", "", "visitor")
+            .await
+            .unwrap();
+
+        mock.set_down(true);
+        assert!(matches!(
+            store.link_exists("docs").await,
+            Err(AppError::Db(_))
+        ));
+        for id in ["docs", "blog"] {
+            assert!(matches!(
+                store.get_link(id).await,
+                Err(AppError::DatabaseUnavailable(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,880 @@
+//! In-memory [`Store`] for handler tests. It keeps links, tags, bundles, the blocklist, the
+//! outbox, the job queue, tombstones, scanners and the maintenance mode, everything about
+//! clicks is empty.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex, MutexGuard,
+    },
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, Utc};
+use tokio::time::Instant;
+use url::Url;
+
+use super::{
+    AbuseReport, BlockedDomain, BoxStream, BundleLinkRecord, BundleRecord, ClickSpike, InsertLink,
+    Lease, LinkOptions, OutboxEntry, PoolStats, Quarantine, QueuedJob, Scanner, Store, Tombstone,
+    UpdateLink, UrlRecord,
+};
+use crate::api::{
+    BundleRequest, CountryClicks, CursorKey, DailyStats, ExportedLink, ForwardQuery, Interval,
+    LinkSort, LinkStatus, ListLinksQuery, Maintenance, ReferrerClicks, SortOrder, TimeseriesPoint,
+    TopLink, Visibility,
+};
+use crate::clicks::ClickEvent;
+use crate::error::AppError;
+use crate::events::LinkEvent;
+
+#[derive(Default)]
+pub(crate) struct MockStore {
+    links: Mutex<BTreeMap<String, ExportedLink>>,
+    blocked: Mutex<BTreeMap<String, Option<String>>>,
+    bundles: Mutex<BTreeMap<String, BundleRequest>>,
+    /// the other destinations of rotating links and how often each was clicked
+    rotations: Mutex<HashMap<String, (Vec<String>, usize)>>,
+    /// who the open reports against each link came from
+    reporters: Mutex<HashMap<String, HashSet<String>>>,
+    /// how long every link lookup takes, to run into request timeouts
+    delay: Option<Duration>,
+    /// link lookups fail as if the database was unreachable
+    down: AtomicBool,
+    /// undelivered events as `(id, payload, attempts)`, oldest first
+    outbox: Mutex<Vec<(i64, String, i32)>>,
+    /// queued jobs as `(id, payload, attempts, due)`, never locked
+    jobs: Mutex<Vec<(i64, String, i32, Instant)>>,
+    tombstones: Mutex<HashMap<String, Tombstone>>,
+    scanners: Mutex<HashMap<String, Scanner>>,
+    /// how often a lock was asked for, none is ever given
+    lease_attempts: AtomicUsize,
+    maintenance: Mutex<Option<Maintenance>>,
+}
+
+impl MockStore {
+    /// A store with `links` as `(id, url)` pairs
+    pub(crate) fn with_links(links: &[(&str, &str)]) -> Self {
+        let store = Self::default();
+        for (id, url) in links {
+            store.link_map().insert(
+                id.to_string(),
+                ExportedLink {
+                    id: id.to_string(),
+                    url: url.to_string(),
+                    flagged: false,
+                    tags: Vec::new(),
+                    visibility: Visibility::Public,
+                    forward_query: ForwardQuery::Off,
+                    cache_control: None,
+                    aliases: Vec::new(),
+                    created_at: Some(Utc::now()),
+                    check_status: None,
+                    checked_at: None,
+                    clicks: None,
+                },
+            );
+        }
+        store
+    }
+
+    /// A store whose link lookups take `delay`
+    pub(crate) fn slow(delay: Duration) -> Self {
+        Self {
+            delay: Some(delay),
+            ..Self::default()
+        }
+    }
+
+    fn link_map(&self) -> MutexGuard<'_, BTreeMap<String, ExportedLink>> {
+        self.links.lock().unwrap()
+    }
+
+    fn bundle_record(&self, id: &str, bundle: &BundleRequest) -> BundleRecord {
+        let links = self.link_map();
+        BundleRecord {
+            id: id.to_owned(),
+            title: bundle.title.clone(),
+            description: bundle.description.clone(),
+            links: bundle
+                .links
+                .iter()
+                .filter_map(|entry| {
+                    let link = links.get(&entry.id)?;
+                    Some(BundleLinkRecord {
+                        bundle_id: id.to_owned(),
+                        url_id: link.id.clone(),
+                        url: link.url.clone(),
+                        flagged: link.flagged,
+                        private: link.visibility.is_private(),
+                        title: entry.title.clone(),
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    pub(crate) fn lease_attempts(&self) -> usize {
+        self.lease_attempts.load(Ordering::Relaxed)
+    }
+
+    /// Makes link lookups fail until it's called with `false` again
+    pub(crate) fn set_down(&self, down: bool) {
+        self.down.store(down, Ordering::Relaxed);
+    }
+
+    fn enqueue(&self, event: &LinkEvent) -> Result<(), AppError> {
+        let payload = serde_json::to_string(event).map_err(anyhow::Error::from)?;
+        let mut outbox = self.outbox.lock().unwrap();
+        let id = outbox.last().map_or(1, |(id, ..)| id + 1);
+        outbox.push((id, payload, 0));
+        Ok(())
+    }
+
+    async fn lookup(&self) -> Result<(), AppError> {
+        if let Some(delay) = self.delay {
+            tokio::time::sleep(delay).await;
+        }
+        if self.down.load(Ordering::Relaxed) {
+            return Err(AppError::Db(sqlx::Error::PoolTimedOut));
+        }
+        Ok(())
+    }
+}
+
+fn record(link: &ExportedLink) -> UrlRecord {
+    UrlRecord {
+        id: link.id.clone(),
+        url: link.url.clone(),
+        flagged: link.flagged,
+        rotating: false,
+        private: link.visibility.is_private(),
+        forward_query: link.forward_query,
+        cache_control: link.cache_control.clone(),
+        created_at: link.created_at,
+        check_status: link.check_status,
+        checked_at: link.checked_at,
+    }
+}
+
+/// When a job to run `at` is due by tokio's clock, which paused tests move ahead
+fn due_at(at: DateTime<Utc>) -> Instant {
+    Instant::now() + (at - Utc::now()).to_std().unwrap_or_default()
+}
+
+#[async_trait]
+impl Store for MockStore {
+    async fn migrate(&self) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    fn pool_stats(&self) -> Option<PoolStats> {
+        None
+    }
+
+    async fn ping(&self) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    async fn close(&self) {}
+
+    async fn get_link(&self, id: &str) -> Result<Option<UrlRecord>, AppError> {
+        self.lookup().await?;
+        let links = self.link_map();
+        let link = links.get(id).or_else(|| {
+            links
+                .values()
+                .find(|link| link.aliases.iter().any(|a| a == id))
+        });
+        Ok(link.map(|link| UrlRecord {
+            rotating: self.rotations.lock().unwrap().contains_key(&link.id),
+            ..record(link)
+        }))
+    }
+
+    async fn link_exists(&self, id: &str) -> Result<bool, AppError> {
+        self.lookup().await?;
+        Ok(self.link_map().contains_key(id))
+    }
+
+    async fn id_for_url(&self, url: &str) -> Result<Option<String>, AppError> {
+        self.lookup().await?;
+        Ok(self
+            .link_map()
+            .values()
+            .find(|link| link.url == url)
+            .map(|link| link.id.clone()))
+    }
+
+    async fn taken_ids(&self, ids: &[String]) -> Result<Vec<String>, AppError> {
+        let links = self.link_map();
+        Ok(ids
+            .iter()
+            .filter(|id| {
+                links.contains_key(*id) || links.values().any(|link| link.aliases.contains(id))
+            })
+            .cloned()
+            .collect())
+    }
+
+    async fn insert_or_get_link(
+        &self,
+        id: &str,
+        url: &str,
+        options: &LinkOptions<'_>,
+        created: Option<&LinkEvent>,
+        quarantine: Option<&Quarantine<'_>>,
+    ) -> Result<String, AppError> {
+        match self
+            .insert_link(id, url, options, created, quarantine)
+            .await?
+        {
+            InsertLink::Inserted => Ok(id.to_owned()),
+            InsertLink::UrlExists(existing) => Ok(existing),
+            InsertLink::IdTaken => Err(AppError::InternalServer(anyhow::anyhow!(
+                "Link id {} is taken",
+                id
+            ))),
+        }
+    }
+
+    async fn insert_link(
+        &self,
+        id: &str,
+        url: &str,
+        options: &LinkOptions<'_>,
+        created: Option<&LinkEvent>,
+        quarantine: Option<&Quarantine<'_>>,
+    ) -> Result<InsertLink, AppError> {
+        let mut links = self.link_map();
+        if let Some(existing) = links.values().find(|link| link.url == url) {
+            return Ok(InsertLink::UrlExists(existing.id.clone()));
+        }
+        if links.contains_key(id) {
+            return Ok(InsertLink::IdTaken);
+        }
+        links.insert(
+            id.to_owned(),
+            ExportedLink {
+                id: id.to_owned(),
+                url: url.to_owned(),
+                flagged: quarantine.is_some(),
+                tags: Vec::new(),
+                visibility: Visibility::from(options.private),
+                forward_query: options.forward_query,
+                cache_control: options.cache_control.map(ToOwned::to_owned),
+                aliases: Vec::new(),
+                created_at: Some(Utc::now()),
+                check_status: None,
+                checked_at: None,
+                clicks: None,
+            },
+        );
+        if let Some(event) = created {
+            self.enqueue(event)?;
+        }
+        if let Some(quarantine) = quarantine {
+            let mut reporters = self.reporters.lock().unwrap();
+            let reporters = reporters.entry(id.to_owned()).or_default();
+            reporters.insert(quarantine.reporter.to_owned());
+        }
+        Ok(InsertLink::Inserted)
+    }
+
+    async fn insert_rotating_link(
+        &self,
+        id: &str,
+        url: &str,
+        rotate: &[String],
+        options: &LinkOptions<'_>,
+        created: Option<&LinkEvent>,
+        quarantine: Option<&Quarantine<'_>>,
+    ) -> Result<InsertLink, AppError> {
+        let inserted = self
+            .insert_link(id, url, options, created, quarantine)
+            .await?;
+        if let InsertLink::Inserted = inserted {
+            self.rotations
+                .lock()
+                .unwrap()
+                .insert(id.to_owned(), (rotate.to_vec(), 0));
+        }
+        Ok(inserted)
+    }
+
+    async fn next_rotation(&self, id: &str) -> Result<Option<String>, AppError> {
+        let url = match self.link_map().get(id) {
+            Some(link) => link.url.clone(),
+            None => return Ok(None),
+        };
+        let mut rotations = self.rotations.lock().unwrap();
+        let Some((rotate, turn)) = rotations.get_mut(id) else {
+            return Ok(None);
+        };
+        let next = match *turn % (rotate.len() + 1) {
+            0 => url,
+            i => rotate[i - 1].clone(),
+        };
+        *turn += 1;
+        Ok(Some(next))
+    }
+
+    async fn update_link(&self, id: &str, url: &str) -> Result<UpdateLink, AppError> {
+        let mut links = self.link_map();
+        if let Some(other) = links.values().find(|link| link.url == url && link.id != id) {
+            return Ok(UpdateLink::UrlExists(other.id.clone()));
+        }
+        match links.get_mut(id) {
+            Some(link) => {
+                link.url = url.to_owned();
+                Ok(UpdateLink::Updated)
+            }
+            None => Ok(UpdateLink::NotFound),
+        }
+    }
+
+    async fn add_tags(&self, id: &str, tags: &[String]) -> Result<(), AppError> {
+        if let Some(link) = self.link_map().get_mut(id) {
+            link.tags.extend(tags.iter().cloned());
+            link.tags.sort();
+            link.tags.dedup();
+        }
+        Ok(())
+    }
+
+    async fn set_tags(&self, id: &str, tags: &[String]) -> Result<(), AppError> {
+        if let Some(link) = self.link_map().get_mut(id) {
+            link.tags = tags.to_vec();
+        }
+        Ok(())
+    }
+
+    async fn link_tags(&self, id: &str) -> Result<Vec<String>, AppError> {
+        Ok(self
+            .link_map()
+            .get(id)
+            .map(|link| link.tags.clone())
+            .unwrap_or_default())
+    }
+
+    async fn add_alias(&self, id: &str, alias: &str) -> Result<bool, AppError> {
+        let mut links = self.link_map();
+        let taken = links.contains_key(alias)
+            || links
+                .values()
+                .any(|link| link.aliases.iter().any(|a| a == alias));
+        match links.get_mut(id) {
+            Some(link) if !taken => {
+                link.aliases.push(alias.to_owned());
+                link.aliases.sort();
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn remove_alias(&self, id: &str, alias: &str) -> Result<bool, AppError> {
+        let mut links = self.link_map();
+        let Some(link) = links.get_mut(id) else {
+            return Ok(false);
+        };
+        let before = link.aliases.len();
+        link.aliases.retain(|a| a != alias);
+        Ok(link.aliases.len() < before)
+    }
+
+    async fn alias_target(&self, alias: &str) -> Result<Option<String>, AppError> {
+        Ok(self
+            .link_map()
+            .values()
+            .find(|link| link.aliases.iter().any(|a| a == alias))
+            .map(|link| link.id.clone()))
+    }
+
+    async fn link_aliases(&self, id: &str) -> Result<Vec<String>, AppError> {
+        Ok(self
+            .link_map()
+            .get(id)
+            .map(|link| link.aliases.clone())
+            .unwrap_or_default())
+    }
+
+    async fn list_links(&self, query: &ListLinksQuery) -> Result<Vec<ExportedLink>, AppError> {
+        // nothing is ever clicked here, so sorting by clicks is by id
+        let key = |link: &ExportedLink| match query.sort {
+            LinkSort::CreatedAt => (link.created_at.map(CursorKey::CreatedAt), link.id.clone()),
+            LinkSort::Clicks => (Some(CursorKey::Clicks(0)), link.id.clone()),
+        };
+        let past = |link: &ExportedLink| {
+            query.after.as_ref().is_none_or(|after| {
+                let after = (Some(after.key), after.id.clone());
+                match query.order {
+                    SortOrder::Asc => key(link) > after,
+                    SortOrder::Desc => key(link) < after,
+                }
+            })
+        };
+        let host = |link: &ExportedLink| {
+            Url::parse(&link.url)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_owned))
+        };
+        let mut links: Vec<ExportedLink> = self
+            .link_map()
+            .values()
+            .filter(|link| query.tag.as_ref().is_none_or(|tag| link.tags.contains(tag)))
+            .filter(|link| {
+                query.domain.as_ref().is_none_or(|domain| {
+                    host(link).is_some_and(|host| {
+                        host == *domain || host.ends_with(&format!(".{}", domain))
+                    })
+                })
+            })
+            .filter(|link| {
+                query
+                    .created_after
+                    .is_none_or(|since| link.created_at.is_some_and(|at| at > since))
+            })
+            .filter(|link| {
+                query
+                    .status
+                    .is_none_or(|status| LinkStatus::of(link) == Some(status))
+            })
+            .filter(|link| past(link))
+            .cloned()
+            .map(|link| ExportedLink {
+                clicks: Some(0),
+                ..link
+            })
+            .collect();
+        links.sort_by_key(key);
+        if query.order == SortOrder::Desc {
+            links.reverse();
+        }
+        links.truncate(query.limit as usize);
+        Ok(links)
+    }
+
+    async fn directory_links(
+        &self,
+        after: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<ExportedLink>, AppError> {
+        Ok(self
+            .link_map()
+            .values()
+            .filter(|link| !link.flagged && !link.visibility.is_private())
+            .filter(|link| after.is_none_or(|after| link.id.as_str() > after))
+            .take(limit as usize)
+            .cloned()
+            .collect())
+    }
+
+    async fn set_private(&self, id: &str, private: bool) -> Result<(), AppError> {
+        if let Some(link) = self.link_map().get_mut(id) {
+            link.visibility = private.into();
+        }
+        Ok(())
+    }
+
+    async fn set_link_check(&self, id: &str, status: Option<i16>) -> Result<(), AppError> {
+        if let Some(link) = self.link_map().get_mut(id) {
+            link.check_status = status;
+            link.checked_at = Some(Utc::now());
+        }
+        Ok(())
+    }
+
+    async fn delete_link(&self, id: &str, reason: Option<&str>) -> Result<bool, AppError> {
+        self.rotations.lock().unwrap().remove(id);
+        let Some(link) = self.link_map().remove(id) else {
+            return Ok(false);
+        };
+        let mut tombstones = self.tombstones.lock().unwrap();
+        for id in std::iter::once(link.id).chain(link.aliases) {
+            let tombstone = Tombstone {
+                id: id.clone(),
+                reason: reason.map(ToOwned::to_owned),
+                removed_at: Utc::now(),
+            };
+            tombstones.insert(id, tombstone);
+        }
+        Ok(true)
+    }
+
+    async fn tombstone(&self, id: &str) -> Result<Option<Tombstone>, AppError> {
+        Ok(self.tombstones.lock().unwrap().get(id).cloned())
+    }
+
+    async fn import_link(&self, link: &ExportedLink) -> Result<bool, AppError> {
+        let mut links = self.link_map();
+        if links.contains_key(&link.id) {
+            return Ok(false);
+        }
+        links.insert(link.id.clone(), link.clone());
+        Ok(true)
+    }
+
+    fn export_links(&self) -> BoxStream<'_, Result<ExportedLink, AppError>> {
+        let links: Vec<_> = self.link_map().values().cloned().map(Ok).collect();
+        Box::pin(tokio_stream::iter(links))
+    }
+
+    async fn unflagged_links(&self, after: &str, limit: i64) -> Result<Vec<UrlRecord>, AppError> {
+        Ok(self
+            .link_map()
+            .values()
+            .filter(|link| !link.flagged && link.id.as_str() > after)
+            .take(limit as usize)
+            .map(record)
+            .collect())
+    }
+
+    async fn flag_urls(&self, urls: &[String]) -> Result<u64, AppError> {
+        let mut flagged = 0;
+        for link in self.link_map().values_mut() {
+            if urls.contains(&link.url) {
+                link.flagged = true;
+                flagged += 1;
+            }
+        }
+        Ok(flagged)
+    }
+
+    async fn insert_clicks(&self, _clicks: &[ClickEvent], _publish: bool) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    async fn last_rollup_day(&self) -> Result<Option<NaiveDate>, AppError> {
+        Ok(None)
+    }
+
+    async fn rollup_clicks(&self, _from: NaiveDate) -> Result<u64, AppError> {
+        Ok(0)
+    }
+
+    async fn import_clicks(
+        &self,
+        _id: &str,
+        _day: NaiveDate,
+        _clicks: i64,
+    ) -> Result<(), AppError> {
+        Ok(())
+    }
+
+    async fn prune_clicks(&self, _before: DateTime<Utc>) -> Result<u64, AppError> {
+        Ok(0)
+    }
+
+    async fn prune_rollups(&self, _before: NaiveDate) -> Result<u64, AppError> {
+        Ok(0)
+    }
+
+    async fn click_totals(&self, _ids: &[String]) -> Result<HashMap<String, i64>, AppError> {
+        Ok(HashMap::new())
+    }
+
+    async fn daily_stats(&self, _day: NaiveDate) -> Result<Vec<DailyStats>, AppError> {
+        Ok(Vec::new())
+    }
+
+    async fn timeseries(
+        &self,
+        _id: &str,
+        _interval: Interval,
+        _from: NaiveDate,
+        _to: NaiveDate,
+        _exclude_bots: bool,
+    ) -> Result<Vec<TimeseriesPoint>, AppError> {
+        Ok(Vec::new())
+    }
+
+    async fn referrers(
+        &self,
+        _id: &str,
+        _from: NaiveDate,
+        _to: NaiveDate,
+        _exclude_bots: bool,
+    ) -> Result<Vec<ReferrerClicks>, AppError> {
+        Ok(Vec::new())
+    }
+
+    async fn countries(
+        &self,
+        _id: &str,
+        _from: NaiveDate,
+        _to: NaiveDate,
+        _exclude_bots: bool,
+    ) -> Result<Vec<CountryClicks>, AppError> {
+        Ok(Vec::new())
+    }
+
+    async fn mark_click_spikes(
+        &self,
+        _since: DateTime<Utc>,
+        _min_clicks: i64,
+        _min_share: f64,
+        _publish: bool,
+    ) -> Result<Vec<ClickSpike>, AppError> {
+        Ok(Vec::new())
+    }
+
+    async fn pending_events(&self, limit: i64) -> Result<Vec<OutboxEntry>, AppError> {
+        Ok(self
+            .outbox
+            .lock()
+            .unwrap()
+            .iter()
+            .take(limit as usize)
+            .map(|(id, payload, attempts)| OutboxEntry {
+                id: *id,
+                payload: payload.clone(),
+                attempts: *attempts,
+            })
+            .collect())
+    }
+
+    async fn delete_events(&self, ids: &[i64]) -> Result<(), AppError> {
+        self.outbox
+            .lock()
+            .unwrap()
+            .retain(|(id, ..)| !ids.contains(id));
+        Ok(())
+    }
+
+    async fn enqueue_job(
+        &self,
+        _kind: &str,
+        payload: &str,
+        run_after: DateTime<Utc>,
+    ) -> Result<(), AppError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let id = jobs.last().map_or(1, |(id, ..)| id + 1);
+        jobs.push((id, payload.to_owned(), 0, due_at(run_after)));
+        Ok(())
+    }
+
+    async fn claim_jobs(
+        &self,
+        limit: i64,
+        _locked_until: DateTime<Utc>,
+    ) -> Result<Vec<QueuedJob>, AppError> {
+        let now = Instant::now();
+        Ok(self
+            .jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(.., due)| *due <= now)
+            .take(limit as usize)
+            .map(|(id, payload, attempts, _)| QueuedJob {
+                id: *id,
+                payload: payload.clone(),
+                attempts: *attempts,
+            })
+            .collect())
+    }
+
+    async fn complete_job(&self, id: i64) -> Result<(), AppError> {
+        self.jobs.lock().unwrap().retain(|(job, ..)| *job != id);
+        Ok(())
+    }
+
+    async fn fail_job(
+        &self,
+        id: i64,
+        _error: &str,
+        retry_at: Option<DateTime<Utc>>,
+    ) -> Result<(), AppError> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let Some(retry_at) = retry_at else {
+            jobs.retain(|(job, ..)| *job != id);
+            return Ok(());
+        };
+        for (job, _, attempts, due) in jobs.iter_mut() {
+            if *job == id {
+                *attempts += 1;
+                *due = due_at(retry_at);
+            }
+        }
+        Ok(())
+    }
+
+    async fn retry_event(&self, id: i64, _at: DateTime<Utc>) -> Result<(), AppError> {
+        for (event, _, attempts) in self.outbox.lock().unwrap().iter_mut() {
+            if *event == id {
+                *attempts += 1;
+            }
+        }
+        Ok(())
+    }
+
+    async fn top_links(
+        &self,
+        _from: NaiveDate,
+        _to: NaiveDate,
+        _limit: i64,
+        _exclude_bots: bool,
+    ) -> Result<Vec<TopLink>, AppError> {
+        Ok(Vec::new())
+    }
+
+    async fn list_blocked(&self) -> Result<Vec<BlockedDomain>, AppError> {
+        Ok(self
+            .blocked
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(domain, reason)| BlockedDomain {
+                domain: domain.clone(),
+                reason: reason.clone(),
+            })
+            .collect())
+    }
+
+    async fn block_domain(
+        &self,
+        domain: &str,
+        reason: Option<&str>,
+    ) -> Result<BlockedDomain, AppError> {
+        let reason = reason.map(ToOwned::to_owned);
+        self.blocked
+            .lock()
+            .unwrap()
+            .insert(domain.to_owned(), reason.clone());
+        Ok(BlockedDomain {
+            domain: domain.to_owned(),
+            reason,
+        })
+    }
+
+    async fn seed_blocked(&self, domain: &str) -> Result<(), AppError> {
+        self.blocked
+            .lock()
+            .unwrap()
+            .entry(domain.to_owned())
+            .or_default();
+        Ok(())
+    }
+
+    async fn unblock_domain(&self, domain: &str) -> Result<(), AppError> {
+        self.blocked.lock().unwrap().remove(domain);
+        Ok(())
+    }
+
+    async fn set_flagged(&self, id: &str, flagged: bool) -> Result<bool, AppError> {
+        match self.link_map().get_mut(id) {
+            Some(link) => {
+                link.flagged = flagged;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn report_link(
+        &self,
+        id: &str,
+        _category: &str,
+        _reason: &str,
+        reporter: &str,
+    ) -> Result<Option<u64>, AppError> {
+        if !self.link_map().contains_key(id) {
+            return Ok(None);
+        }
+        let mut reporters = self.reporters.lock().unwrap();
+        let link = reporters.entry(id.to_owned()).or_default();
+        link.insert(reporter.to_owned());
+        Ok(Some(link.len() as u64))
+    }
+
+    async fn open_reports(&self, _limit: i64) -> Result<Vec<AbuseReport>, AppError> {
+        Ok(Vec::new())
+    }
+
+    async fn resolve_reports(&self, id: &str) -> Result<u64, AppError> {
+        let resolved = self.reporters.lock().unwrap().remove(id);
+        Ok(resolved.map_or(0, |reporters| reporters.len() as u64))
+    }
+
+    async fn record_scanner(&self, ip: &str, slug: &str) -> Result<(), AppError> {
+        let now = Utc::now();
+        let mut scanners = self.scanners.lock().unwrap();
+        let scanner = scanners.entry(ip.to_owned()).or_insert_with(|| Scanner {
+            ip: ip.to_owned(),
+            hits: 0,
+            slugs: Vec::new(),
+            first_seen: now,
+            last_seen: now,
+        });
+        scanner.hits += 1;
+        if !scanner.slugs.iter().any(|tried| tried == slug) {
+            scanner.slugs.push(slug.to_owned());
+        }
+        scanner.last_seen = now;
+        Ok(())
+    }
+
+    async fn list_scanners(&self, limit: i64) -> Result<Vec<Scanner>, AppError> {
+        let mut scanners: Vec<_> = self.scanners.lock().unwrap().values().cloned().collect();
+        scanners.sort_by_key(|scanner| std::cmp::Reverse(scanner.last_seen));
+        scanners.truncate(limit as usize);
+        Ok(scanners)
+    }
+
+    async fn forget_scanner(&self, ip: &str) -> Result<bool, AppError> {
+        Ok(self.scanners.lock().unwrap().remove(ip).is_some())
+    }
+
+    async fn insert_bundle(&self, id: &str, bundle: &BundleRequest) -> Result<bool, AppError> {
+        let mut bundles = self.bundles.lock().unwrap();
+        if bundles.contains_key(id) {
+            return Ok(false);
+        }
+        bundles.insert(id.to_owned(), bundle.clone());
+        Ok(true)
+    }
+
+    async fn update_bundle(&self, id: &str, bundle: &BundleRequest) -> Result<bool, AppError> {
+        match self.bundles.lock().unwrap().get_mut(id) {
+            Some(existing) => {
+                *existing = bundle.clone();
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn get_bundle(&self, id: &str) -> Result<Option<BundleRecord>, AppError> {
+        let bundles = self.bundles.lock().unwrap();
+        Ok(bundles.get(id).map(|bundle| self.bundle_record(id, bundle)))
+    }
+
+    async fn list_bundles(&self) -> Result<Vec<BundleRecord>, AppError> {
+        let bundles = self.bundles.lock().unwrap();
+        Ok(bundles
+            .iter()
+            .map(|(id, bundle)| self.bundle_record(id, bundle))
+            .collect())
+    }
+
+    async fn delete_bundle(&self, id: &str) -> Result<bool, AppError> {
+        Ok(self.bundles.lock().unwrap().remove(id).is_some())
+    }
+
+    async fn try_lease(&self, _name: &str) -> Result<Option<Box<dyn Lease>>, AppError> {
+        self.lease_attempts.fetch_add(1, Ordering::Relaxed);
+        Ok(None)
+    }
+
+    async fn maintenance(&self) -> Result<Option<Maintenance>, AppError> {
+        Ok(self.maintenance.lock().unwrap().clone())
+    }
+
+    async fn set_maintenance(&self, maintenance: &Maintenance) -> Result<(), AppError> {
+        *self.maintenance.lock().unwrap() = Some(maintenance.clone());
+        Ok(())
+    }
+}
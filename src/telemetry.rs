@@ -0,0 +1,371 @@
+//! Logging, tracing, the access log and metrics
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context as _, Result};
+use cadence::{Counted, Gauged, MetricError, StatsdClient, UdpMetricSink};
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::SpanExporter;
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncWrite, AsyncWriteExt},
+    sync::{broadcast, mpsc},
+};
+use tracing::{
+    info,
+    level_filters::LevelFilter,
+    span::{Attributes, Id},
+    warn, Metadata, Subscriber,
+};
+use tracing_subscriber::{
+    filter::{filter_fn, FilterFn},
+    fmt::Layer,
+    layer::{Context, SubscriberExt},
+    registry::LookupSpan,
+    util::SubscriberInitExt,
+    Layer as _,
+};
+
+use crate::config::{Config, LogFormat, StatsdConfig};
+use crate::error::AppError;
+use crate::state::AppState;
+
+/// Process-wide counters behind the dashboard feed
+#[derive(Debug)]
+pub(crate) struct Metrics {
+    pub(crate) requests: AtomicU64,
+    /// responses with a 5xx status
+    pub(crate) errors: AtomicU64,
+    pub(crate) redirects: AtomicU64,
+    pub(crate) links_created: AtomicU64,
+    /// what the counters count, per link, for feeds that only follow some links
+    events: broadcast::Sender<FeedEvent>,
+}
+
+/// Something that happened to link `id`, which may be an alias of it for requests
+#[derive(Debug, Clone)]
+pub(crate) enum FeedEvent {
+    /// `error` when the response had a 5xx status
+    Request {
+        id: String,
+        error: bool,
+    },
+    Redirect {
+        id: String,
+    },
+    Created {
+        id: String,
+    },
+}
+
+/// Apache combined-format access log, written by a background task so requests never wait on it
+#[derive(Debug, Clone)]
+pub(crate) struct AccessLog {
+    tx: mpsc::Sender<String>,
+}
+
+/// Log verbosity shared by every output, so it can be changed without a restart
+#[derive(Debug, Clone)]
+pub(crate) struct LogLevel(Arc<RwLock<LevelFilter>>);
+
+/// Warns about storage calls, i.e. `db.*` spans, that take longer than `threshold`
+struct SlowQueryLayer {
+    threshold: Duration,
+}
+
+/// When a `db.*` span was created, kept in the span's extensions
+struct QueryStart(Instant);
+
+/// Access log lines waiting to be written before new ones are dropped
+const ACCESS_LOG_BUFFER: usize = 10_000;
+
+/// Feed events a filtered feed can fall behind on before it misses some
+const FEED_EVENT_BUFFER: usize = 4096;
+
+const STATSD_PUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Logging and tracing, set up the same way by both binaries
+pub(crate) struct Telemetry {
+    pub(crate) log_level: LogLevel,
+    tracer_provider: Option<SdkTracerProvider>,
+    #[cfg(feature = "sentry")]
+    _sentry: sentry::ClientInitGuard,
+}
+
+impl Telemetry {
+    pub(crate) fn init(config: &Config) -> Result<Self> {
+        let log_level = LogLevel::new(config.log.level);
+        let json = config.log.format == LogFormat::Json;
+        // logs go to stderr so `export` can write links to stdout
+        let json_layer = json.then(|| {
+            Layer::new()
+                .with_writer(std::io::stderr)
+                .json()
+                .flatten_event(true)
+                .with_current_span(true)
+                .with_span_list(false)
+                .with_filter(log_level.filter())
+        });
+        let pretty_layer = (!json).then(|| {
+            Layer::new()
+                .with_writer(std::io::stderr)
+                .pretty()
+                .with_filter(log_level.filter())
+        });
+        let tracer_provider = init_tracer_provider()?;
+        let otel_layer = tracer_provider.as_ref().map(|provider| {
+            tracing_opentelemetry::layer()
+                .with_tracer(provider.tracer("shortener"))
+                .with_filter(log_level.filter())
+        });
+        tracing_subscriber::registry()
+            .with(json_layer)
+            .with(pretty_layer)
+            .with(otel_layer)
+            .with(SlowQueryLayer {
+                threshold: Duration::from_millis(config.log.slow_query_ms),
+            })
+            .init();
+
+        Ok(Self {
+            log_level,
+            tracer_provider,
+            // reporting is only active when SENTRY_DSN is set
+            #[cfg(feature = "sentry")]
+            _sentry: sentry::init(sentry::ClientOptions {
+                release: sentry::release_name!(),
+                ..Default::default()
+            }),
+        })
+    }
+
+    /// Export the spans still buffered
+    pub(crate) fn shutdown(self) -> Result<()> {
+        if let Some(provider) = self.tracer_provider {
+            provider.shutdown()?;
+        }
+        Ok(())
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            requests: AtomicU64::default(),
+            errors: AtomicU64::default(),
+            redirects: AtomicU64::default(),
+            links_created: AtomicU64::default(),
+            events: broadcast::Sender::new(FEED_EVENT_BUFFER),
+        }
+    }
+}
+
+impl FeedEvent {
+    pub(crate) fn link(&self) -> &str {
+        match self {
+            Self::Request { id, .. } | Self::Redirect { id } | Self::Created { id } => id,
+        }
+    }
+}
+
+impl Metrics {
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<FeedEvent> {
+        self.events.subscribe()
+    }
+
+    /// Hands `event` to the filtered feeds, nothing is kept when none is open
+    pub(crate) fn publish(&self, event: FeedEvent) {
+        if self.events.receiver_count() > 0 {
+            let _ = self.events.send(event);
+        }
+    }
+}
+
+impl AccessLog {
+    /// `-` writes to stdout, any other target is a file to append to
+    pub(crate) async fn open(target: &str) -> Result<Self, AppError> {
+        let out: Box<dyn AsyncWrite + Send + Unpin> = if target == "-" {
+            Box::new(tokio::io::stdout())
+        } else {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(target)
+                .await?;
+            Box::new(file)
+        };
+        let (tx, rx) = mpsc::channel(ACCESS_LOG_BUFFER);
+        tokio::spawn(write_access_log(out, rx));
+
+        Ok(Self { tx })
+    }
+
+    pub(crate) fn record(&self, line: String) {
+        if self.tx.try_send(line).is_err() {
+            warn!("Access log buffer is full, dropping line");
+        }
+    }
+}
+
+async fn write_access_log(
+    mut out: Box<dyn AsyncWrite + Send + Unpin>,
+    mut rx: mpsc::Receiver<String>,
+) {
+    while let Some(line) = rx.recv().await {
+        if let Err(e) = out.write_all(line.as_bytes()).await {
+            warn!("Failed to write access log: {:?}", e);
+        }
+        if rx.is_empty() {
+            let _ = out.flush().await;
+        }
+    }
+}
+
+impl<S> tracing_subscriber::Layer<S> for SlowQueryLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        if !attrs.metadata().name().starts_with("db.") {
+            return;
+        }
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(QueryStart(Instant::now()));
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let Some(elapsed) = span.extensions().get::<QueryStart>().map(|s| s.0.elapsed()) else {
+            return;
+        };
+        if elapsed >= self.threshold {
+            warn!(
+                query = span.name(),
+                duration_ms = elapsed.as_millis() as u64,
+                "slow query"
+            );
+        }
+    }
+}
+
+/// Spans are exported over OTLP/HTTP when `OTEL_EXPORTER_OTLP_ENDPOINT` is set, the
+/// exporter picks up the endpoint and the other standard `OTEL_*` variables itself.
+fn init_tracer_provider() -> Result<Option<SdkTracerProvider>> {
+    if std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_none() {
+        return Ok(None);
+    }
+
+    let exporter = SpanExporter::builder().with_http().build()?;
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(Resource::builder().with_service_name("shortener").build())
+        .build();
+    Ok(Some(provider))
+}
+
+impl LogLevel {
+    pub(crate) fn new(level: LevelFilter) -> Self {
+        Self(Arc::new(RwLock::new(level)))
+    }
+
+    fn get(&self) -> LevelFilter {
+        *self.0.read().unwrap()
+    }
+
+    pub(crate) fn set(&self, level: LevelFilter) {
+        *self.0.write().unwrap() = level;
+        // filters remember their answer per callsite, make them ask again
+        tracing::callsite::rebuild_interest_cache();
+    }
+
+    /// Per-output filter following the shared level
+    fn filter(&self) -> FilterFn<impl Fn(&Metadata<'_>) -> bool> {
+        let level = self.clone();
+        filter_fn(move |metadata| level.get() >= *metadata.level())
+    }
+}
+
+pub(crate) fn statsd_client(config: &StatsdConfig) -> Result<StatsdClient> {
+    let socket = std::net::UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_nonblocking(true)?;
+    let sink = UdpMetricSink::from(config.addr.as_str(), socket)
+        .with_context(|| format!("Invalid statsd address {}", config.addr))?;
+    let mut builder = StatsdClient::builder(&config.prefix, sink);
+    for tag in config.tags.iter().flatten() {
+        builder = match tag.split_once(':') {
+            Some((key, value)) => builder.with_tag(key, value),
+            None => builder.with_tag_value(tag),
+        };
+    }
+    info!("Pushing metrics to statsd at {}", config.addr);
+    Ok(builder.build())
+}
+
+/// Sends the [`Metrics`] counters as what changed since the previous push, so the agent can
+/// sum them across instances, and the clicks waiting to be written as a gauge
+pub(crate) async fn push_statsd_periodically(state: AppState, statsd: StatsdClient) {
+    let metrics = &state.metrics;
+    let counters = || {
+        [
+            ("requests", metrics.requests.load(Ordering::Relaxed)),
+            ("errors", metrics.errors.load(Ordering::Relaxed)),
+            ("redirects", metrics.redirects.load(Ordering::Relaxed)),
+            (
+                "links_created",
+                metrics.links_created.load(Ordering::Relaxed),
+            ),
+        ]
+    };
+    let mut last = counters();
+    let mut last_pool = state.store.pool_stats();
+    let mut interval = tokio::time::interval(STATSD_PUSH_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let current = counters();
+        let pool = state.store.pool_stats();
+        let ret = (|| -> Result<(), MetricError> {
+            for ((name, value), (_, previous)) in current.iter().zip(&last) {
+                statsd.count(name, value - previous)?;
+            }
+            statsd.gauge("clicks_buffered", state.clicks.buffered() as u64)?;
+            if let Some(pool) = pool {
+                statsd.gauge("db_pool.size", pool.size as u64)?;
+                statsd.gauge("db_pool.idle", pool.idle as u64)?;
+                statsd.gauge("db_pool.in_use", pool.in_use as u64)?;
+                if let Some(last) = last_pool {
+                    statsd.count("db_pool.acquires", pool.acquires - last.acquires)?;
+                    statsd.count(
+                        "db_pool.acquire_wait_ms",
+                        pool.acquire_wait_ms - last.acquire_wait_ms,
+                    )?;
+                    statsd.count(
+                        "db_pool.slow_acquires",
+                        pool.slow_acquires - last.slow_acquires,
+                    )?;
+                    statsd.count(
+                        "db_pool.acquire_timeouts",
+                        pool.acquire_timeouts - last.acquire_timeouts,
+                    )?;
+                }
+            }
+            Ok(())
+        })();
+        last = current;
+        last_pool = pool;
+        if let Err(e) = &ret {
+            warn!("Failed to push metrics to statsd: {:?}", e);
+        }
+        state.jobs.record("statsd_push", STATSD_PUSH_INTERVAL, &ret);
+    }
+}
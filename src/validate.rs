@@ -0,0 +1,1107 @@
+//! Checks on destinations, aliases and tags before they are stored: URL parsing, the blocklist,
+//! spam signals and threat lookups
+
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::http::{
+    header::{LOCATION, RANGE},
+    HeaderValue, StatusCode,
+};
+use serde::Deserialize;
+use subtle::ConstantTimeEq;
+use tracing::{info, instrument, warn};
+use url::{Host, Position, Url};
+
+use crate::api::is_broken;
+use crate::config::{BindAddr, Config, ReachabilityCheck, TrailingSlash};
+use crate::error::AppError;
+use crate::jobs::Task;
+use crate::state::AppState;
+
+/// Source of truth about whether a destination is known to be malicious
+#[async_trait]
+pub(crate) trait ThreatChecker: Send + Sync {
+    /// Returns the subset of `urls` that should not be served
+    async fn unsafe_urls(&self, urls: &[String]) -> Result<Vec<String>>;
+}
+
+/// [`ThreatChecker`] backed by the Google Safe Browsing v4 lookup API
+pub(crate) struct SafeBrowsing {
+    client: reqwest::Client,
+    api_key: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct SafeBrowsingResponse {
+    #[serde(default)]
+    matches: Vec<SafeBrowsingMatch>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SafeBrowsingMatch {
+    threat: SafeBrowsingThreat,
+}
+
+#[derive(Debug, Deserialize)]
+struct SafeBrowsingThreat {
+    url: String,
+}
+
+/// In-memory copy of the `blocked_domains` table, consulted on every shorten and redirect
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Blocklist(Arc<RwLock<HashSet<String>>>);
+
+const MAX_URL_LENGTH: usize = 2048;
+
+/// Generated link ids, 64^6 of them are enough to rarely hit a taken one
+pub(crate) const ID_LENGTH: usize = 6;
+pub(crate) const ID_ALPHABET: [char; 64] = nanoid::alphabet::SAFE;
+pub(crate) const ALIAS_LENGTH: std::ops::RangeInclusive<usize> = 3..=32;
+/// Left out of generated ids, `0`/`O`/`o` and `1`/`l`/`I` look alike in a lot of fonts
+const LOOKALIKES: &[char] = &['0', 'O', 'o', '1', 'l', 'I'];
+/// Kept out of generated ids and the words of aliases, after reading digits as letters
+const OFFENSIVE_WORDS: &[&str] = &[
+    "anal", "anus", "arse", "ass", "bastard", "bitch", "bollock", "boner", "boob", "butt", "clit",
+    "cock", "coon", "crap", "cum", "cunt", "dick", "dildo", "dyke", "fag", "fuck", "hitler",
+    "jizz", "kike", "milf", "nazi", "nigga", "nigger", "penis", "piss", "porn", "prick", "pube",
+    "pussy", "rape", "retard", "sex", "shit", "slut", "spic", "tit", "twat", "vagina", "wank",
+    "whore",
+];
+/// Offensive words this long count inside the words of an alias too, shorter ones are part of
+/// too many harmless words
+const MIN_EMBEDDED_WORD: usize = 5;
+const TAG_LENGTH: std::ops::RangeInclusive<usize> = 1..=32;
+const MAX_TAGS: usize = 10;
+const MAX_CACHE_CONTROL_LENGTH: usize = 256;
+/// Path segments used by routes other than redirect
+pub(crate) const RESERVED_ALIASES: &[&str] = &[
+    "admin",
+    "api",
+    "b",
+    "directory",
+    "graphql",
+    "static",
+    "status",
+    "ui",
+    "ws",
+];
+
+/// How long the shortener's own HTTP clients wait for an answer, unless a request sets its own
+pub(crate) const HTTP_TIMEOUT: Duration = Duration::from_secs(2);
+/// How many redirects of a destination are followed when looking for a loop back to us
+const LOOP_CHECK_MAX_HOPS: usize = 5;
+/// How long the destination of a new link gets to answer `features.reachability_check`
+const REACHABILITY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Asked for by the GET to servers that refuse HEAD, the body is never read either way
+const REACHABILITY_MAX_BYTES: usize = 1024;
+/// Query parameters that only exist to track people across sites, `utm_*` is handled separately
+const TRACKING_PARAMS: &[&str] = &[
+    "gclid", "gclsrc", "dclid", "fbclid", "msclkid", "yclid", "twclid", "igshid", "mc_cid",
+    "mc_eid", "_ga", "_gl",
+];
+
+/// Top-level domains that are cheap to register and far more common in spam than elsewhere
+const SUSPICIOUS_TLDS: &[&str] = &[
+    "cf", "click", "ga", "gq", "icu", "ml", "tk", "top", "xyz", "zip",
+];
+/// Other shorteners, links to them hide where a click really ends up
+const SHORTENER_DOMAINS: &[&str] = &[
+    "bit.ly",
+    "buff.ly",
+    "cutt.ly",
+    "goo.gl",
+    "is.gd",
+    "ow.ly",
+    "rb.gy",
+    "t.co",
+    "tinyurl.com",
+    "v.gd",
+];
+
+/// Aliases running along a keyboard row or without vowels for this long look mashed
+const MASHED_RUN: usize = 5;
+const KEYBOARD_ROWS: &[&str] = &["qwertyuiop", "asdfghjkl", "zxcvbnm"];
+
+/// how long a failed threat check waits, so the link is stored and the lookup may be back
+pub(crate) const THREAT_CHECK_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Drawn again until it neither spells an offensive word nor has characters that are mistaken
+/// for each other in print
+pub(crate) fn random_link_id() -> String {
+    loop {
+        let id = nanoid::nanoid!(ID_LENGTH, &ID_ALPHABET);
+        if !is_unfit_id(&id) {
+            return id;
+        }
+    }
+}
+
+fn is_unfit_id(id: &str) -> bool {
+    if id.contains(LOOKALIKES) || id.starts_with(['-', '_']) || id.ends_with(['-', '_']) {
+        return true;
+    }
+    let letters: String = id.chars().filter(|c| !matches!(c, '-' | '_')).collect();
+    readings(&letters)
+        .iter()
+        .any(|reading| OFFENSIVE_WORDS.iter().any(|word| reading.contains(word)))
+}
+
+/// Whether one of the words of an alias is offensive. Longer words count anywhere in them,
+/// `classic` or `peacock` are fine but `bitchy` isn't.
+fn is_offensive_alias(alias: &str) -> bool {
+    alias.split(['-', '_']).any(|part| {
+        readings(part).iter().any(|reading| {
+            OFFENSIVE_WORDS.iter().any(|word| {
+                reading == word || (word.len() >= MIN_EMBEDDED_WORD && reading.contains(word))
+            })
+        })
+    })
+}
+
+/// `word` in lower case with digits read as the letters they look like, `1` once as `i` and
+/// once as `l`
+fn readings(word: &str) -> [String; 2] {
+    let read = |one| {
+        word.chars()
+            .map(|c| match c.to_ascii_lowercase() {
+                '0' => 'o',
+                '1' => one,
+                '3' => 'e',
+                '4' => 'a',
+                '5' => 's',
+                '7' => 't',
+                '8' => 'b',
+                '9' => 'g',
+                c => c,
+            })
+            .collect()
+    };
+    [read('i'), read('l')]
+}
+
+pub(crate) fn validate_url(raw: &str) -> Result<Url, AppError> {
+    if raw.len() > MAX_URL_LENGTH {
+        return Err(AppError::validation(
+            "url",
+            format!("must be at most {} characters", MAX_URL_LENGTH),
+        ));
+    }
+
+    let url = Url::parse(raw).map_err(|e| AppError::validation("url", e.to_string()))?;
+    // only plain web links are allowed, this also rules out javascript: and data: URIs
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(AppError::validation(
+            "url",
+            format!(
+                "unsupported scheme `{}`, expected http or https",
+                url.scheme()
+            ),
+        ));
+    }
+    if url.host_str().is_none() {
+        return Err(AppError::validation("url", "missing host"));
+    }
+
+    Ok(url)
+}
+
+/// Allowlist mode is turned on by listing domains in `features.allowed_domains`, only those (and
+/// their subdomains) can then be shortened.
+pub(crate) fn load_allowlist(
+    domains: Option<&[String]>,
+) -> Result<Option<HashSet<String>>, AppError> {
+    let Some(domains) = domains else {
+        return Ok(None);
+    };
+    let domains = domains
+        .iter()
+        .map(|d| normalize_domain(d))
+        .collect::<Result<HashSet<_>, _>>()?;
+    info!("Allowlist mode enabled for {} domains", domains.len());
+    Ok(Some(domains))
+}
+
+/// Whether `host` or any of its parent domains is in `domains`. The root dot of a fully
+/// qualified `host` is ignored, `domains` are stored without it.
+fn matches_domain(domains: &HashSet<String>, host: &str) -> bool {
+    let mut candidate = host.trim_end_matches('.');
+    loop {
+        if domains.contains(candidate) {
+            return true;
+        }
+        match candidate.split_once('.') {
+            Some((_, parent)) => candidate = parent,
+            None => return false,
+        }
+    }
+}
+
+/// Why a new link looks like spam, it's quarantined once enough of them add up
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SpamSignal {
+    /// many links from the same address in a short time
+    Burst,
+    SuspiciousTld,
+    /// the destination is another shortener
+    ShortenerChain,
+    /// the alias looks like someone ran a hand over the keyboard
+    MashedAlias,
+}
+
+impl SpamSignal {
+    fn as_str(&self) -> &'static str {
+        match self {
+            SpamSignal::Burst => "burst",
+            SpamSignal::SuspiciousTld => "suspicious_tld",
+            SpamSignal::ShortenerChain => "shortener_chain",
+            SpamSignal::MashedAlias => "mashed_alias",
+        }
+    }
+}
+
+/// The signals `url` and `alias` give away by themselves
+pub(crate) fn spam_signals(url: &Url, alias: Option<&str>) -> Vec<SpamSignal> {
+    let mut signals = Vec::new();
+    let host = url.host_str().unwrap_or_default().trim_end_matches('.');
+    let tld = host.rsplit('.').next().unwrap_or_default();
+    if url.domain().is_some() && SUSPICIOUS_TLDS.contains(&tld) {
+        signals.push(SpamSignal::SuspiciousTld);
+    }
+    let is_shortener = |domain: &&str| {
+        host.strip_suffix(domain)
+            .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+    };
+    if SHORTENER_DOMAINS.iter().any(is_shortener) {
+        signals.push(SpamSignal::ShortenerChain);
+    }
+    if alias.is_some_and(looks_mashed) {
+        signals.push(SpamSignal::MashedAlias);
+    }
+    signals
+}
+
+/// A long run of consonants or of neighbouring keys, which chosen aliases rarely have
+fn looks_mashed(alias: &str) -> bool {
+    let alias = alias.to_ascii_lowercase();
+    let mut consonants = 0;
+    for c in alias.chars() {
+        if c.is_ascii_alphabetic() && !"aeiouy".contains(c) {
+            consonants += 1;
+            if consonants >= MASHED_RUN {
+                return true;
+            }
+        } else {
+            consonants = 0;
+        }
+    }
+    alias.as_bytes().windows(MASHED_RUN).any(|run| {
+        KEYBOARD_ROWS
+            .iter()
+            .any(|row| row.as_bytes().windows(MASHED_RUN).any(|keys| keys == run))
+    })
+}
+
+/// Whether `url` points at this shortener, which would make the short link redirect to itself.
+/// `forwarded_base` is where a trusted proxy says the client reached us.
+fn is_self_url(url: &Url, config: &Config, forwarded_base: Option<&Url>) -> bool {
+    let same_origin = |base: &Url| {
+        url.host_str() == base.host_str()
+            && url.port_or_known_default() == base.port_or_known_default()
+    };
+    if same_origin(&config.public_base_url()) || forwarded_base.is_some_and(same_origin) {
+        return true;
+    }
+    let BindAddr::Tcp(bind) = config.listener.bind else {
+        return false;
+    };
+    if url.port_or_known_default() != Some(bind.port()) {
+        return false;
+    }
+
+    let bind_ip = bind.ip();
+    match url.host() {
+        // bound to every interface, so any local name reaches us as well
+        _ if bind_ip.is_unspecified() => matches!(
+            url.host_str().unwrap_or_default(),
+            "0.0.0.0" | "[::]" | "localhost" | "127.0.0.1" | "[::1]"
+        ),
+        Some(Host::Ipv4(ip)) => IpAddr::V4(ip) == bind_ip,
+        Some(Host::Ipv6(ip)) => IpAddr::V6(ip) == bind_ip,
+        _ => false,
+    }
+}
+
+/// Whether `ip` can be reached from the internet, requests to destinations go nowhere else
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // reserved, broadcast included
+                || a >= 240
+                // shared by carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && b & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // unique local, fc00::/7
+                    || first & 0xfe00 == 0xfc00
+                    // link-local, fe80::/10
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Resolves the hosts of destinations to their public addresses only, so that a link can't
+/// make us request something on our own network. Hosts without one fail to resolve.
+struct PublicResolver;
+
+impl reqwest::dns::Resolve for PublicResolver {
+    fn resolve(&self, name: reqwest::dns::Name) -> reqwest::dns::Resolving {
+        let host = name.as_str().to_owned();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{} has no public address", host).into());
+            }
+            Ok(Box::new(addrs.into_iter()) as reqwest::dns::Addrs)
+        })
+    }
+}
+
+/// Compares a secret sent by a client in constant time, so how long it takes doesn't tell how
+/// much of it was right
+pub(crate) fn secrets_match(given: &str, expected: &str) -> bool {
+    given.as_bytes().ct_eq(expected.as_bytes()).into()
+}
+
+/// The client requesting destinations. Redirects aren't followed, each hop has to go through
+/// [`AppState::destination_client`] again.
+pub(crate) fn destination_client(allow_private: bool) -> Result<reqwest::Client, AppError> {
+    let builder = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .timeout(HTTP_TIMEOUT)
+        // a proxy would resolve the host for us
+        .no_proxy();
+    let builder = if allow_private {
+        builder
+    } else {
+        builder.dns_resolver(Arc::new(PublicResolver))
+    };
+    Ok(builder.build().map_err(anyhow::Error::from)?)
+}
+
+pub(crate) fn normalize_domain(raw: &str) -> Result<String, AppError> {
+    let raw = raw.trim().trim_end_matches('.');
+    match Host::parse(raw) {
+        Ok(Host::Domain(domain)) => Ok(domain),
+        Ok(host) => Ok(host.to_string()),
+        Err(e) => Err(AppError::validation("domain", e.to_string())),
+    }
+}
+
+pub(crate) fn validate_alias(alias: &str) -> Result<(), AppError> {
+    if !ALIAS_LENGTH.contains(&alias.len()) {
+        return Err(AppError::validation(
+            "alias",
+            format!(
+                "must be between {} and {} characters",
+                ALIAS_LENGTH.start(),
+                ALIAS_LENGTH.end()
+            ),
+        ));
+    }
+    // same alphabet as the generated ids so aliases never need escaping in a path
+    if !alias
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(AppError::validation(
+            "alias",
+            "may only contain letters, digits, `-` and `_`",
+        ));
+    }
+    if RESERVED_ALIASES.contains(&alias) {
+        return Err(AppError::validation("alias", "is reserved"));
+    }
+    if is_offensive_alias(alias) {
+        return Err(AppError::validation(
+            "alias",
+            "contains a word that isn't allowed",
+        ));
+    }
+
+    Ok(())
+}
+
+/// An empty value is fine, it stands for the server wide setting
+pub(crate) fn validate_cache_control(value: &str) -> Result<(), AppError> {
+    if value.len() > MAX_CACHE_CONTROL_LENGTH || HeaderValue::from_str(value.trim()).is_err() {
+        return Err(AppError::validation(
+            "cache_control",
+            format!(
+                "must be a header value of at most {} characters",
+                MAX_CACHE_CONTROL_LENGTH
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// Tags are compared lowercased, duplicates are dropped
+pub(crate) fn validate_tags(tags: &[String]) -> Result<Vec<String>, AppError> {
+    let mut valid = Vec::with_capacity(tags.len());
+    for tag in tags {
+        let tag = tag.trim().to_lowercase();
+        if !TAG_LENGTH.contains(&tag.len()) {
+            return Err(AppError::validation(
+                "tags",
+                format!(
+                    "must be between {} and {} characters each",
+                    TAG_LENGTH.start(),
+                    TAG_LENGTH.end()
+                ),
+            ));
+        }
+        if !tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(AppError::validation(
+                "tags",
+                "may only contain letters, digits, `-` and `_`",
+            ));
+        }
+        if !valid.contains(&tag) {
+            valid.push(tag);
+        }
+    }
+    if valid.len() > MAX_TAGS {
+        return Err(AppError::validation(
+            "tags",
+            format!("at most {} tags per link", MAX_TAGS),
+        ));
+    }
+
+    Ok(valid)
+}
+
+pub(crate) fn strip_tracking_params(url: &mut Url) {
+    let is_tracking = |key: &str| key.starts_with("utm_") || TRACKING_PARAMS.contains(&key);
+    if !url.query_pairs().any(|(key, _)| is_tracking(&key)) {
+        // leave the query untouched, rewriting it would re-encode it
+        return;
+    }
+
+    let kept: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| !is_tracking(key))
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+    if kept.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(kept);
+    }
+}
+
+/// Bring a destination into a canonical form so that equivalent URLs share the same id.
+///
+/// Parsing with `Url` already lowercases the scheme and host, drops default ports and
+/// resolves `.`/`..` segments; on top of that the root dot of a fully qualified host and
+/// empty queries/fragments are removed, and the trailing slash policy is applied.
+pub(crate) fn normalize_url(mut url: Url, trailing_slash: TrailingSlash) -> String {
+    if let Some(domain) = url.domain().filter(|domain| domain.ends_with('.')) {
+        let domain = domain.trim_end_matches('.').to_owned();
+        // a host made of dots only has nothing left to set, it's kept as it is
+        let _ = url.set_host(Some(&domain));
+    }
+    if url.query() == Some("") {
+        url.set_query(None);
+    }
+    if url.fragment() == Some("") {
+        url.set_fragment(None);
+    }
+    if trailing_slash == TrailingSlash::Strip && url.path() != "/" {
+        let path = url.path().trim_end_matches('/').to_owned();
+        url.set_path(&path);
+    }
+
+    url.into()
+}
+
+/// `url` the way it is stored and later sent as `Location`
+pub(crate) fn storable_url(url: Url, trailing_slash: TrailingSlash) -> Result<String, AppError> {
+    let url = normalize_url(url, trailing_slash);
+    // whatever gets stored has to be usable as a Location header later on
+    if HeaderValue::from_str(&url).is_err() {
+        return Err(AppError::validation(
+            "url",
+            "contains characters that are not allowed in a redirect",
+        ));
+    }
+    Ok(url)
+}
+
+/// Destinations are stored with punycode hosts (`Url` does the conversion when parsing),
+/// this turns them back into Unicode for display.
+pub(crate) fn display_url(url: &str) -> String {
+    let Ok(parsed) = Url::parse(url) else {
+        return url.to_owned();
+    };
+    let Some(host) = parsed.host_str() else {
+        return url.to_owned();
+    };
+    match idna::domain_to_unicode(host) {
+        (unicode, Ok(())) => format!(
+            "{}{}{}",
+            &parsed[..Position::BeforeHost],
+            unicode,
+            &parsed[Position::AfterHost..]
+        ),
+        _ => url.to_owned(),
+    }
+}
+
+impl AppState {
+    fn check_allowed(&self, url: &Url) -> Result<(), AppError> {
+        let Some(allowlist) = &self.allowlist else {
+            return Ok(());
+        };
+        let host = url.host_str().unwrap_or_default();
+        if matches_domain(allowlist, host) {
+            Ok(())
+        } else {
+            Err(AppError::DomainNotAllowed(host.to_owned()))
+        }
+    }
+
+    /// Refuses `url` if it comes back to us. With `features.remote_loop_check` its redirect
+    /// chain is followed as well, e.g. through another shortener, hosts that can't be reached
+    /// end it.
+    #[instrument(name = "http.check_redirect_loop", skip_all)]
+    async fn check_redirect_loop(
+        &self,
+        url: &Url,
+        forwarded_base: Option<&Url>,
+    ) -> Result<(), AppError> {
+        if is_self_url(url, &self.config, forwarded_base) {
+            return Err(self.looped(url).await);
+        }
+        if !self.config.features.remote_loop_check {
+            return Ok(());
+        }
+
+        let mut current = url.clone();
+        for _ in 0..LOOP_CHECK_MAX_HOPS {
+            let Some(http) = self.destination_client(&current) else {
+                return Ok(());
+            };
+            let Ok(res) = http.head(current.clone()).send().await else {
+                return Ok(());
+            };
+            if !res.status().is_redirection() {
+                return Ok(());
+            }
+            let next = res
+                .headers()
+                .get(LOCATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|location| current.join(location).ok());
+            let Some(next) = next else {
+                return Ok(());
+            };
+            if is_self_url(&next, &self.config, forwarded_base) {
+                return Err(self.looped(&next).await);
+            }
+            current = next;
+        }
+
+        Ok(())
+    }
+
+    /// Why `url`, one of ours, can't be a destination. The short link it stands for is looked
+    /// up in the store, that's where the loop would carry on.
+    async fn looped(&self, url: &Url) -> AppError {
+        let id = url
+            .path_segments()
+            .and_then(|mut segments| segments.next())
+            .filter(|id| !id.is_empty());
+        let link = match id {
+            Some(id) => self.store.get_link(id).await.ok().flatten(),
+            None => None,
+        };
+        match link {
+            Some(link) => AppError::validation(
+                "url",
+                format!(
+                    "must not redirect back to this shortener, it is short link {}",
+                    link.id
+                ),
+            ),
+            None => AppError::validation("url", "must not redirect back to this shortener"),
+        }
+    }
+
+    /// The client to request `url` with, unless it points at an address that isn't public.
+    /// Names are checked once they're resolved.
+    fn destination_client(&self, url: &Url) -> Option<&reqwest::Client> {
+        let public = match url.host()? {
+            Host::Domain(_) => true,
+            Host::Ipv4(ip) => is_public(IpAddr::V4(ip)),
+            Host::Ipv6(ip) => is_public(IpAddr::V6(ip)),
+        };
+        (public || self.config.security.allow_private_destinations)
+            .then_some(&self.destination_http)
+    }
+
+    /// What the destination answers to a HEAD, or to a GET of its first bytes when it doesn't
+    /// take HEAD. `None` when it can't be reached in time, or only on an address that isn't
+    /// public.
+    #[instrument(name = "http.probe_destination", skip_all)]
+    pub(crate) async fn probe_destination(&self, url: &str) -> Option<i16> {
+        let url = Url::parse(url).ok()?;
+        let http = self.destination_client(&url)?;
+        let res = http
+            .head(url.clone())
+            .timeout(REACHABILITY_TIMEOUT)
+            .send()
+            .await
+            .ok()?;
+        if !matches!(
+            res.status(),
+            StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED
+        ) {
+            return Some(res.status().as_u16() as i16);
+        }
+        let res = http
+            .get(url)
+            .header(RANGE, format!("bytes=0-{}", REACHABILITY_MAX_BYTES - 1))
+            .timeout(REACHABILITY_TIMEOUT)
+            .send()
+            .await
+            .ok()?;
+        Some(res.status().as_u16() as i16)
+    }
+
+    /// Probes the destination of a new link, refusing it when it's broken and `mode` says so.
+    /// Returns its status to be kept with the link.
+    pub(crate) async fn check_reachability(
+        &self,
+        url: &str,
+        mode: ReachabilityCheck,
+    ) -> Result<Option<i16>, AppError> {
+        let status = self.probe_destination(url).await;
+        if is_broken(status) {
+            let problem = match status {
+                Some(status) => format!("answered with status {}", status),
+                None => "can't be reached".to_owned(),
+            };
+            if mode == ReachabilityCheck::Reject {
+                return Err(AppError::validation("url", problem));
+            }
+            warn!("Destination {} {}, shortening it anyway", url, problem);
+        }
+        Ok(status)
+    }
+
+    /// Lookup failures are logged and the link allowed, the periodic recheck will catch up
+    #[instrument(name = "threats.check", skip(self))]
+    async fn check_threats(&self, url: &str) -> Result<(), AppError> {
+        let Some(checker) = &self.threat_checker else {
+            return Ok(());
+        };
+        match checker.unsafe_urls(&[url.to_owned()]).await {
+            Ok(found) if !found.is_empty() => Err(AppError::UnsafeUrl(url.to_owned())),
+            Ok(_) => Ok(()),
+            Err(e) => {
+                warn!(
+                    "Threat check for {} failed, trying again later: {:?}",
+                    url, e
+                );
+                let task = Task::ThreatCheck {
+                    url: url.to_owned(),
+                };
+                self.enqueue(&task, THREAT_CHECK_RETRY_DELAY).await?;
+                Ok(())
+            }
+        }
+    }
+
+    /// [`validate_alias`], decoy slugs are reserved as well
+    pub(crate) fn check_alias(&self, alias: &str) -> Result<(), AppError> {
+        validate_alias(alias)?;
+        if self.honeypot.is_decoy(alias) {
+            return Err(AppError::validation("alias", "is reserved"));
+        }
+        Ok(())
+    }
+
+    /// Everything about a new link from `ip` that points to spam, one entry per signal
+    pub(crate) fn spam_signals(
+        &self,
+        ip: IpAddr,
+        url: &str,
+        alias: Option<&str>,
+    ) -> Vec<SpamSignal> {
+        let mut signals = match Url::parse(url) {
+            Ok(url) => spam_signals(&url, alias),
+            Err(_) => Vec::new(),
+        };
+        if self.burst_limiter.check(ip).is_err() {
+            signals.push(SpamSignal::Burst);
+        }
+        signals
+    }
+
+    /// Why a new link with `signals` is kept from redirecting until an operator has looked at
+    /// it, `None` when they don't add up to `features.quarantine_score`
+    pub(crate) fn quarantine_reason(&self, signals: &[SpamSignal]) -> Option<String> {
+        let score = self.config.features.quarantine_score?;
+        if signals.len() < score as usize {
+            return None;
+        }
+        let signals: Vec<&str> = signals.iter().map(SpamSignal::as_str).collect();
+        Some(format!("Quarantined when created: {}", signals.join(", ")))
+    }
+
+    /// `raw` the way it gets stored, if it may be shortened at all. `forwarded_base` is the
+    /// `forwarded_base` of the client who asked for it.
+    pub(crate) async fn check_destination(
+        &self,
+        raw: &str,
+        strip_tracking: bool,
+        forwarded_base: Option<&Url>,
+    ) -> Result<String, AppError> {
+        let mut url = validate_url(raw)?;
+        if strip_tracking {
+            strip_tracking_params(&mut url);
+        }
+        self.blocklist.check(&url)?;
+        self.check_allowed(&url)?;
+        self.check_redirect_loop(&url, forwarded_base).await?;
+        let url = storable_url(url, self.config.features.trailing_slash)?;
+        self.check_threats(&url).await?;
+        Ok(url)
+    }
+}
+
+impl SafeBrowsing {
+    pub(crate) fn new(api_key: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            api_key,
+        }
+    }
+}
+
+#[async_trait]
+impl ThreatChecker for SafeBrowsing {
+    async fn unsafe_urls(&self, urls: &[String]) -> Result<Vec<String>> {
+        let entries: Vec<_> = urls
+            .iter()
+            .map(|url| serde_json::json!({ "url": url }))
+            .collect();
+        let body = serde_json::json!({
+            "client": { "clientId": "ecosystem-homework", "clientVersion": env!("CARGO_PKG_VERSION") },
+            "threatInfo": {
+                "threatTypes": ["MALWARE", "SOCIAL_ENGINEERING", "UNWANTED_SOFTWARE", "POTENTIALLY_HARMFUL_APPLICATION"],
+                "platformTypes": ["ANY_PLATFORM"],
+                "threatEntryTypes": ["URL"],
+                "threatEntries": entries,
+            },
+        });
+        let res: SafeBrowsingResponse = self
+            .client
+            .post("https://safebrowsing.googleapis.com/v4/threatMatches:find")
+            // sent as a header rather than `?key=` so it does not end up in error messages
+            .header("x-goog-api-key", &self.api_key)
+            .json(&body)
+            .timeout(Duration::from_secs(5))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        Ok(res.matches.into_iter().map(|m| m.threat.url).collect())
+    }
+}
+
+impl Blocklist {
+    /// A host is blocked when it or any of its parent domains is on the list
+    fn is_blocked(&self, host: &str) -> bool {
+        matches_domain(&self.0.read().unwrap(), host)
+    }
+
+    pub(crate) fn check(&self, url: &Url) -> Result<(), AppError> {
+        match url.host_str().map(|host| host.trim_end_matches('.')) {
+            Some(host) if self.is_blocked(host) => Err(AppError::BlockedDomain(host.to_owned())),
+            _ => Ok(()),
+        }
+    }
+
+    pub(crate) fn insert(&self, domain: String) {
+        self.0.write().unwrap().insert(domain);
+    }
+
+    pub(crate) fn remove(&self, domain: &str) {
+        self.0.write().unwrap().remove(domain);
+    }
+
+    pub(crate) fn replace(&self, domains: HashSet<String>) {
+        *self.0.write().unwrap() = domains;
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.0.read().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use axum::{serve, Router};
+    use proptest::prelude::*;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::api::ShortenRequest;
+    use crate::state::tests::state_with;
+    use crate::store::mock::MockStore;
+
+    /// http(s) URLs with an assortment of ports, paths, queries and fragments
+    fn destination() -> impl Strategy<Value = String> {
+        (
+            prop_oneof!["http", "https", "HTTPS"],
+            "[a-zA-Z0-9-]{1,12}(\\.[a-zA-Z]{2,6}){1,2}\\.?",
+            prop::option::of(prop_oneof![Just(80u16), Just(443), 1u16..]),
+            "(/[a-zA-Z0-9._~%!$&'()*+,;=:@-]{0,10}){0,4}/?",
+            prop::option::of("[a-zA-Z0-9_=&%+./-]{0,16}"),
+            prop::option::of("[a-zA-Z0-9_=/-]{0,8}"),
+        )
+            .prop_map(|(scheme, host, port, path, query, fragment)| {
+                let mut url = format!("{}://{}", scheme, host);
+                if let Some(port) = port {
+                    url += &format!(":{}", port);
+                }
+                url += &path;
+                if let Some(query) = query {
+                    url += &format!("?{}", query);
+                }
+                if let Some(fragment) = fragment {
+                    url += &format!("#{}", fragment);
+                }
+                url
+            })
+    }
+
+    #[test]
+    fn generated_ids_use_the_id_alphabet() {
+        for _ in 0..10_000 {
+            let id = random_link_id();
+            assert_eq!(id.chars().count(), ID_LENGTH, "{}", id);
+            assert!(id.chars().all(|c| ID_ALPHABET.contains(&c)), "{}", id);
+            assert!(validate_alias(&id).is_ok() || RESERVED_ALIASES.contains(&id.as_str()));
+            assert!(!id.contains(LOOKALIKES), "{}", id);
+        }
+    }
+
+    #[test]
+    fn offensive_ids_are_refused() {
+        assert!(is_unfit_id("a5h1tb"));
+        assert!(is_unfit_id("xf-uck"));
+        assert!(is_unfit_id("-abcde"));
+        assert!(is_unfit_id("Ab0cde"));
+        assert!(!is_unfit_id("Xk7vQz"));
+
+        for alias in ["shit", "my-5h1t", "bitchy", "PORN", "m1lf_of-the-day"] {
+            assert!(validate_alias(alias).is_err(), "{}", alias);
+        }
+        for alias in ["classic", "peacock", "sussex-2024", "assets", "title"] {
+            assert!(validate_alias(alias).is_ok(), "{}", alias);
+        }
+    }
+
+    #[tokio::test]
+    async fn destinations_coming_back_to_us_are_refused() {
+        // another shortener, with a link to one of ours
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let other = Router::new().route(
+            "/x",
+            axum::routing::get(|| async {
+                axum::response::Redirect::permanent("https://sho.rt/docs")
+            }),
+        );
+        tokio::spawn(async move { serve(listener, other).await.unwrap() });
+
+        let ours = "[listener]\npublic_base_url = \"https://sho.rt\"\n";
+        let links = [("docs", "https://example.com/docs")];
+        let shorten = |url: String| ShortenRequest {
+            url,
+            ..ShortenRequest::default()
+        };
+
+        let state = state_with(ours, Arc::new(MockStore::with_links(&links))).await;
+        let err = state
+            .create_link(&shorten("https://sho.rt/docs".to_owned()), None, None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("it is short link docs"), "{}", err);
+        // nothing is requested unless asked to
+        let other = format!("http://{}/x", addr);
+        state
+            .create_link(&shorten(other.clone()), None, None)
+            .await
+            .unwrap();
+
+        // and never on our own network
+        let state = state_with(
+            &format!("{}[features]\nremote_loop_check = true", ours),
+            Arc::new(MockStore::with_links(&links)),
+        )
+        .await;
+        state
+            .create_link(&shorten(other.clone()), None, None)
+            .await
+            .unwrap();
+
+        let state = state_with(
+            &format!(
+                "{}[features]\nremote_loop_check = true\n[security]\nallow_private_destinations = true",
+                ours
+            ),
+            Arc::new(MockStore::with_links(&links)),
+        )
+        .await;
+        let err = state
+            .create_link(&shorten(other), None, None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("it is short link docs"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn unreachable_destinations_are_refused_when_asked_to() {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let destination = Router::new().route("/docs", axum::routing::get(|| async { "docs" }));
+        tokio::spawn(async move { serve(listener, destination).await.unwrap() });
+
+        let rejecting = "[features]\nreachability_check = \"reject\"\n[security]\n";
+        let shorten = |url: String| ShortenRequest {
+            url,
+            ..ShortenRequest::default()
+        };
+
+        // loopback isn't requested unless private destinations are allowed
+        let state = state_with(rejecting, Arc::default()).await;
+        let err = state
+            .create_link(&shorten(format!("http://{}/docs", addr)), None, None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("can't be reached"), "{}", err);
+        let err = state
+            .create_link(
+                &shorten(format!("http://localhost:{}/docs", addr.port())),
+                None,
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("can't be reached"), "{}", err);
+
+        let state = state_with(
+            &format!("{}allow_private_destinations = true", rejecting),
+            Arc::default(),
+        )
+        .await;
+        let id = state
+            .create_link(&shorten(format!("http://{}/docs", addr)), None, None)
+            .await
+            .unwrap();
+        let link = state.link(&id).await.unwrap();
+        assert_eq!(link.check_status, Some(200));
+        assert!(link.checked_at.is_some());
+
+        let err = state
+            .create_link(&shorten(format!("http://{}/missing", addr)), None, None)
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("answered with status 404"),
+            "{}",
+            err
+        );
+        let err = state
+            .create_link(&shorten("http://127.0.0.1:1/docs".to_owned()), None, None)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("can't be reached"), "{}", err);
+    }
+
+    #[test]
+    fn spam_signals_come_from_the_destination_and_alias() {
+        let signals = |url: &str, alias| spam_signals(&Url::parse(url).unwrap(), alias);
+
+        assert_eq!(signals("https://example.com/docs", Some("docs")), []);
+        assert_eq!(
+            signals("https://win.example.xyz/", None),
+            [SpamSignal::SuspiciousTld]
+        );
+        assert_eq!(
+            signals("https://bit.ly/abc", Some("asdfghj")),
+            [SpamSignal::ShortenerChain, SpamSignal::MashedAlias]
+        );
+        // only whole labels count
+        assert_eq!(signals("https://habit.ly/abc", None), []);
+        assert!(looks_mashed("xkcdfwq"));
+        assert!(!looks_mashed("release-notes"));
+    }
+
+    proptest! {
+        #[test]
+        fn normalizing_is_idempotent(raw in destination(), strip in any::<bool>()) {
+            let Ok(mut url) = validate_url(&raw) else {
+                return Ok(());
+            };
+            if strip {
+                strip_tracking_params(&mut url);
+            }
+            for trailing_slash in [TrailingSlash::Keep, TrailingSlash::Strip] {
+                let once = normalize_url(url.clone(), trailing_slash);
+                let mut reparsed = validate_url(&once).unwrap();
+                prop_assert!(!reparsed.host_str().unwrap().ends_with('.'), "from {}", raw);
+                if strip {
+                    strip_tracking_params(&mut reparsed);
+                }
+                let twice = normalize_url(reparsed, trailing_slash);
+                prop_assert_eq!(&once, &twice, "from {}", raw);
+            }
+        }
+    }
+}
@@ -0,0 +1,7 @@
+// Asks before submitting forms marked with data-confirm, such as deleting a link
+document.addEventListener("submit", (event) => {
+  const message = event.target.dataset.confirm;
+  if (message && !window.confirm(message)) {
+    event.preventDefault();
+  }
+});